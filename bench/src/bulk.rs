@@ -111,7 +111,16 @@ async fn client(server_addr: SocketAddr, server_cert: quinn::Certificate) -> Res
             .context("failed sending data")?;
     }
     stream.finish().await.context("failed finishing stream")?;
-    println!("sent {} bytes in {:?}", 1024 * DATA.len(), start.elapsed());
+    let sent = 1024 * DATA.len() as u64;
+    println!("sent {} bytes in {:?}", sent, start.elapsed());
+    // Includes the handshake, which is negligible next to the transfer
+    let stats = connection.stats();
+    println!(
+        "sent {} packets, {} bytes on the wire ({:.2}% overhead)",
+        stats.sent_packets,
+        stats.sent_bytes,
+        (stats.sent_bytes - sent) as f64 * 100.0 / sent as f64
+    );
     Ok(())
 }
 
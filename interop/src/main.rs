@@ -195,7 +195,7 @@ impl State {
         tokio::spawn(h3_driver.unwrap_or_else(|_| ()));
         tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
 
-        h3_get(&conn)
+        h3_get(&conn, &self.host)
             .await
             .map_err(|e| anyhow!("h3 request failed: {}", e))?;
         conn.close();
@@ -247,13 +247,12 @@ fn run(options: Opt) -> Result<()> {
     if options.keylog {
         tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
     }
-    let client_config = quinn::ClientConfig {
-        crypto: Arc::new(tls_config),
-        transport: Arc::new(quinn::TransportConfig {
-            idle_timeout: 1_000,
-            ..Default::default()
-        }),
-    };
+    let mut client_config = quinn::ClientConfig::default();
+    client_config.crypto = Arc::new(tls_config);
+    client_config.transport = Arc::new(quinn::TransportConfig {
+        idle_timeout: 1_000,
+        ..Default::default()
+    });
 
     let (endpoint_driver, endpoint, _) =
         runtime.enter(|| quinn::Endpoint::builder().bind(&"[::]:0".parse().unwrap()))?;
@@ -341,12 +340,12 @@ fn run(options: Opt) -> Result<()> {
     Ok(())
 }
 
-async fn h3_get(conn: &quinn_h3::client::Connection) -> Result<()> {
+async fn h3_get(conn: &quinn_h3::client::Connection, host: &str) -> Result<()> {
     let (response, _) = conn
         .send_request(
             http::Request::builder()
                 .method(http::Method::GET)
-                .uri("/")
+                .uri(format!("https://{}/", host))
                 .body(())?,
         )
         .await?;
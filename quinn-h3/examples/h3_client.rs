@@ -90,7 +90,7 @@ async fn request(client: Client, url: &Url) -> Result<()> {
 
     let request = Request::builder()
         .method(Method::GET)
-        .uri(url.path())
+        .uri(url.as_str())
         .header("client", "quinn-h3:0.0.1")
         .body(())
         .expect("failed to build request");
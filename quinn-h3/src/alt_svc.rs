@@ -0,0 +1,495 @@
+//! HTTP Alternative Services (RFC 7838) support
+//!
+//! Servers reachable over HTTP/1.1 or HTTP/2 advertise HTTP/3 availability through the `Alt-Svc`
+//! response header. Clients record those advertisements in an `AltSvcCache` and look the origin up
+//! before opening subsequent connections to it. `client::Builder::alt_svc_cache` has the HTTP/3
+//! client do both.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use http::{
+    header::{AGE, ALT_SVC},
+    HeaderMap, Response, Uri,
+};
+
+/// Freshness lifetime of an advertisement which doesn't specify `ma`
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest freshness lifetime honored, so that expiry times stay representable
+const MAX_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// An HTTP/3 endpoint advertised as an alternative service for an origin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AltSvc {
+    /// Host to connect to, which is the origin's own host unless the advertisement named another
+    pub host: String,
+    /// UDP port to connect to
+    pub port: u16,
+    /// Time after which the advertisement must no longer be used
+    pub expires: Instant,
+    /// Whether the advertisement survives changes to the client's network configuration
+    pub persist: bool,
+}
+
+/// Alternative services advertised by origins, keyed by origin
+///
+/// Only alternatives for the HTTP/3 draft implemented by this crate are kept. When an origin
+/// advertises several, the first one in its order of preference is retained.
+#[derive(Debug, Default)]
+pub struct AltSvcCache {
+    entries: HashMap<Origin, AltSvc>,
+}
+
+impl AltSvcCache {
+    /// Create an empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the alternative services advertised in a response received from `origin`
+    ///
+    /// As prescribed by RFC 7838, the advertisement replaces anything previously cached for the
+    /// origin, and `Alt-Svc: clear` removes the cached entry. Responses without an `Alt-Svc`
+    /// header leave the cache untouched.
+    pub fn update<T>(&mut self, origin: &Uri, response: &Response<T>) {
+        self.update_at(origin, response.headers(), Instant::now());
+    }
+
+    /// Look up the HTTP/3 endpoint to connect to for `origin`, if one is known and still fresh
+    pub fn get(&self, origin: &Uri) -> Option<&AltSvc> {
+        self.get_at(origin, Instant::now())
+    }
+
+    /// Forget the alternatives which weren't advertised with `persist=1`
+    ///
+    /// To be called when the client's network configuration changes, for example when it moves to
+    /// another network interface.
+    pub fn network_changed(&mut self) {
+        self.entries.retain(|_, x| x.persist);
+    }
+
+    /// Drop expired entries
+    pub fn purge(&mut self) {
+        let now = Instant::now();
+        self.entries.retain(|_, x| x.expires > now);
+    }
+
+    fn update_at(&mut self, origin: &Uri, headers: &HeaderMap, now: Instant) {
+        let origin = match Origin::from_uri(origin) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let mut alternatives = Vec::new();
+        let mut present = false;
+        for value in headers.get_all(ALT_SVC) {
+            let value = match value.to_str() {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            present = true;
+            match parse(value) {
+                Directive::Clear => {
+                    self.entries.remove(&origin);
+                    return;
+                }
+                Directive::Alternatives(x) => alternatives.extend(x),
+            }
+        }
+        if !present {
+            return;
+        }
+
+        let age = headers
+            .get(AGE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+
+        self.entries.remove(&origin);
+        let alternative = match alternatives
+            .into_iter()
+            .find(|x| x.protocol.as_bytes() == crate::ALPN)
+        {
+            Some(x) => x,
+            None => return,
+        };
+        let max_age = match alternative.max_age.checked_sub(age) {
+            Some(x) if x > Duration::from_secs(0) => x,
+            _ => return,
+        };
+        let entry = AltSvc {
+            host: alternative.host.unwrap_or_else(|| origin.host.clone()),
+            port: alternative.port,
+            expires: now + max_age,
+            persist: alternative.persist,
+        };
+        self.entries.insert(origin, entry);
+    }
+
+    fn get_at(&self, origin: &Uri, now: Instant) -> Option<&AltSvc> {
+        self.entries
+            .get(&Origin::from_uri(origin)?)
+            .filter(|x| x.expires > now)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Origin {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+impl Origin {
+    fn from_uri(uri: &Uri) -> Option<Self> {
+        let scheme = uri.scheme_str()?.to_ascii_lowercase();
+        let port = match (uri.port_u16(), scheme.as_str()) {
+            (Some(port), _) => port,
+            (None, "https") => 443,
+            (None, "http") => 80,
+            _ => return None,
+        };
+        Some(Self {
+            host: uri.host()?.to_ascii_lowercase(),
+            scheme,
+            port,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Directive {
+    Clear,
+    Alternatives(Vec<Alternative>),
+}
+
+#[derive(Debug, PartialEq)]
+struct Alternative {
+    protocol: String,
+    host: Option<String>,
+    port: u16,
+    max_age: Duration,
+    persist: bool,
+}
+
+/// Parse an `Alt-Svc` field value
+///
+/// Alternatives that can't be understood are skipped, as are unknown parameters.
+fn parse(value: &str) -> Directive {
+    let value = value.trim();
+    if value == "clear" {
+        return Directive::Clear;
+    }
+
+    let mut alternatives = Vec::new();
+    for alternative in split_unquoted(value, ',') {
+        let mut parts = split_unquoted(alternative, ';').into_iter();
+        let (protocol, authority) = match parts.next().and_then(|x| split_pair(x)) {
+            Some(x) => x,
+            None => continue,
+        };
+        // Unlike parameter values, the authority is always a quoted string
+        if !authority.starts_with('"') {
+            continue;
+        }
+        let (host, port) = match unquote(authority).and_then(|x| parse_authority(&x)) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let mut max_age = DEFAULT_MAX_AGE;
+        let mut persist = false;
+        for (name, value) in parts.filter_map(split_pair) {
+            let value = match unquote(value) {
+                Some(x) => x,
+                None => continue,
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "ma" => {
+                    if let Ok(x) = value.parse() {
+                        max_age = Duration::from_secs(x).min(MAX_MAX_AGE);
+                    }
+                }
+                "persist" => persist = value == "1",
+                _ => {}
+            }
+        }
+
+        alternatives.push(Alternative {
+            protocol: protocol.to_owned(),
+            host,
+            port,
+            max_age,
+            persist,
+        });
+    }
+
+    Directive::Alternatives(alternatives)
+}
+
+/// Split `s` on each `sep` which isn't part of a quoted string, dropping empty elements
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut quoted, mut escaped) = (false, false);
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == sep && !quoted => {
+                parts.push(s[start..i].trim());
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].trim());
+    parts.retain(|x| !x.is_empty());
+    parts
+}
+
+fn split_pair(s: &str) -> Option<(&str, &str)> {
+    let i = s.find('=')?;
+    let name = s[..i].trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, s[i + 1..].trim()))
+}
+
+/// Decode a token or a quoted string
+fn unquote(s: &str) -> Option<String> {
+    if !s.starts_with('"') {
+        if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"') {
+            return None;
+        }
+        return Some(s.to_owned());
+    }
+    if s.len() < 2 || !s.ends_with('"') {
+        return None;
+    }
+    let mut out = String::with_capacity(s.len() - 2);
+    let mut escaped = false;
+    for c in s[1..s.len() - 1].chars() {
+        match c {
+            _ if escaped => {
+                out.push(c);
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            '"' => return None,
+            _ => out.push(c),
+        }
+    }
+    if escaped {
+        return None;
+    }
+    Some(out)
+}
+
+/// Parse an `alt-authority`, where the host is optional but the port is not
+fn parse_authority(s: &str) -> Option<(Option<String>, u16)> {
+    let i = s.rfind(':')?;
+    let port = s[i + 1..].parse().ok()?;
+    let host = &s[..i];
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    };
+    if host.contains(|c: char| c.is_whitespace() || c == '/' || c == '"') {
+        return None;
+    }
+    Some((
+        Some(host.to_ascii_lowercase()).filter(|x| !x.is_empty()),
+        port,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(ALT_SVC, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn origin() -> Uri {
+        Uri::from_static("https://example.com/index.html")
+    }
+
+    #[test]
+    fn same_host() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        cache.update_at(&origin(), &headers(&[r#"h3-24=":4433""#]), now);
+        let entry = cache.get_at(&origin(), now).unwrap();
+        assert_eq!(entry.host, "example.com");
+        assert_eq!(entry.port, 4433);
+        assert_eq!(entry.expires, now + DEFAULT_MAX_AGE);
+        assert!(!entry.persist);
+    }
+
+    #[test]
+    fn origin_normalization() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        cache.update_at(&origin(), &headers(&[r#"h3-24=":443""#]), now);
+        assert!(cache
+            .get_at(&Uri::from_static("https://EXAMPLE.com:443/other"), now)
+            .is_some());
+        assert!(cache
+            .get_at(&Uri::from_static("https://example.com:8443/"), now)
+            .is_none());
+        assert!(cache
+            .get_at(&Uri::from_static("http://example.com/"), now)
+            .is_none());
+        assert!(cache.get_at(&Uri::from_static("/relative"), now).is_none());
+    }
+
+    #[test]
+    fn other_host_and_params() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        cache.update_at(
+            &origin(),
+            &headers(&[r#"h2=":443", h3-24="alt.example.com:443"; ma=60; persist=1"#]),
+            now,
+        );
+        let entry = cache.get_at(&origin(), now).unwrap();
+        assert_eq!(entry.host, "alt.example.com");
+        assert_eq!(entry.port, 443);
+        assert_eq!(entry.expires, now + Duration::from_secs(60));
+        assert!(entry.persist);
+    }
+
+    #[test]
+    fn ipv6_host() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        cache.update_at(&origin(), &headers(&[r#"h3-24="[::1]:4433""#]), now);
+        assert_eq!(cache.get_at(&origin(), now).unwrap().host, "::1");
+    }
+
+    #[test]
+    fn preference_order() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        cache.update_at(
+            &origin(),
+            &headers(&[r#"h3-23=":1", h3-24="a.example.com:2""#, r#"h3-24=":3""#]),
+            now,
+        );
+        assert_eq!(cache.get_at(&origin(), now).unwrap().port, 2);
+    }
+
+    #[test]
+    fn expiry() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        cache.update_at(&origin(), &headers(&[r#"h3-24=":443"; ma=10"#]), now);
+        assert!(cache
+            .get_at(&origin(), now + Duration::from_secs(9))
+            .is_some());
+        assert!(cache
+            .get_at(&origin(), now + Duration::from_secs(10))
+            .is_none());
+    }
+
+    #[test]
+    fn max_age_capped() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        cache.update_at(
+            &origin(),
+            &headers(&[r#"h3-24=":443"; ma=18446744073709551615"#]),
+            now,
+        );
+        assert_eq!(
+            cache.get_at(&origin(), now).unwrap().expires,
+            now + MAX_MAX_AGE
+        );
+    }
+
+    #[test]
+    fn age_shortens_lifetime() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        let mut fields = headers(&[r#"h3-24=":443"; ma=10"#]);
+        fields.insert(AGE, HeaderValue::from_static("4"));
+        cache.update_at(&origin(), &fields, now);
+        assert_eq!(
+            cache.get_at(&origin(), now).unwrap().expires,
+            now + Duration::from_secs(6)
+        );
+
+        fields.insert(AGE, HeaderValue::from_static("10"));
+        cache.update_at(&origin(), &fields, now);
+        assert!(cache.get_at(&origin(), now).is_none());
+    }
+
+    #[test]
+    fn replace_and_clear() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        cache.update_at(&origin(), &headers(&[r#"h3-24=":443""#]), now);
+
+        cache.update_at(&origin(), &HeaderMap::new(), now);
+        assert!(cache.get_at(&origin(), now).is_some());
+
+        cache.update_at(&origin(), &headers(&[r#"h2=":443""#]), now);
+        assert!(cache.get_at(&origin(), now).is_none());
+
+        cache.update_at(&origin(), &headers(&[r#"h3-24=":443""#]), now);
+        cache.update_at(&origin(), &headers(&["clear"]), now);
+        assert!(cache.get_at(&origin(), now).is_none());
+    }
+
+    #[test]
+    fn network_changed() {
+        let mut cache = AltSvcCache::new();
+        let now = Instant::now();
+        let other = Uri::from_static("https://other.example.com");
+        cache.update_at(&origin(), &headers(&[r#"h3-24=":443"; persist=1"#]), now);
+        cache.update_at(&other, &headers(&[r#"h3-24=":443""#]), now);
+        cache.network_changed();
+        assert!(cache.get_at(&origin(), now).is_some());
+        assert!(cache.get_at(&other, now).is_none());
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(
+            parse(r#"h3-24=:443, h3-24="nope", =":1", h3-24=":443"; ma=x; persist"#),
+            Directive::Alternatives(vec![Alternative {
+                protocol: "h3-24".into(),
+                host: None,
+                port: 443,
+                max_age: DEFAULT_MAX_AGE,
+                persist: false,
+            }])
+        );
+        assert_eq!(parse(r#"h3-24="a\"b:1""#), Directive::Alternatives(vec![]));
+        assert_eq!(
+            parse(r#"h3-24="a,b;c:1""#),
+            Directive::Alternatives(vec![Alternative {
+                protocol: "h3-24".into(),
+                host: Some("a,b;c".into()),
+                port: 1,
+                max_age: DEFAULT_MAX_AGE,
+                persist: false,
+            }])
+        );
+    }
+}
@@ -10,16 +10,17 @@ use bytes::Bytes;
 use futures::{
     io::{AsyncRead, AsyncWrite},
     ready,
-    stream::Stream,
+    stream::{Stream, StreamExt},
 };
 use http::HeaderMap;
 use quinn::SendStream;
 use quinn_proto::StreamId;
 use std::future::Future;
+use tokio::sync::OwnedSemaphorePermit;
 
 use crate::{
     connection::ConnectionRef,
-    frame::{FrameStream, WriteFrame},
+    frame::{self, FrameStream, WriteFrame},
     headers::{DecodeHeaders, SendHeaders},
     proto::{
         frame::{DataFrame, HeadersFrame, HttpFrame},
@@ -53,6 +54,11 @@ impl From<&str> for Body {
     }
 }
 
+/// Incoming body of a request or response
+///
+/// Data is only read from the stream as it's consumed, whether through `data`, `AsyncRead` or
+/// `Stream`, and flow control credit is returned to the peer as it's read. A handler that reads
+/// slowly, or stops reading, therefore throttles the sender rather than buffering the body.
 pub struct BodyReader {
     recv: Option<FrameStream>,
     trailers: Option<HeadersFrame>,
@@ -60,6 +66,10 @@ pub struct BodyReader {
     stream_id: StreamId,
     buf: Option<Bytes>,
     finish_request: bool,
+    /// Whether the end of the body was read, so there is nothing left to stop
+    finished: bool,
+    /// Slot held by the request under the client's concurrency limit, released on drop
+    permit: Option<OwnedSemaphorePermit>,
 }
 
 impl BodyReader {
@@ -73,31 +83,80 @@ impl BodyReader {
             conn,
             stream_id,
             finish_request,
+            finished: false,
             buf: None,
             trailers: None,
             recv: Some(recv),
+            permit: None,
         }
     }
 
+    pub(crate) fn permit(mut self, permit: Option<OwnedSemaphorePermit>) -> Self {
+        self.permit = permit;
+        self
+    }
+
     pub async fn data(&mut self) -> Option<Result<Bytes, Error>> {
         futures_util::future::poll_fn(move |cx| self.poll_read(cx)).await
     }
 
-    pub async fn trailers(&mut self) -> Option<Result<Header, Error>> {
+    /// Receive the next chunk of body data into `buf`, returning the part of `buf` filled
+    ///
+    /// Unlike `data`, payload which hasn't been buffered yet is read straight from the stream into
+    /// `buf` instead of a newly allocated `Bytes`, so proxies can forward bodies out of a reusable
+    /// buffer, such as the free region of a ring buffer. The returned span borrows `buf`, so it must
+    /// be consumed before the next read. `buf` must not be empty.
+    pub async fn data_into<'a>(&mut self, buf: &'a mut [u8]) -> Option<Result<&'a [u8], Error>> {
+        assert!(!buf.is_empty(), "empty buffer");
+        let len = futures_util::future::poll_fn(|cx| self.poll_data_into(cx, buf)).await;
+        match len? {
+            Ok(len) => Some(Ok(&buf[..len])),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Receive the trailing headers, if any
+    ///
+    /// Waits for the end of the body, so trailers following an empty body, as in gRPC status
+    /// responses, can be received without reading the body first. Fails with `Error::UnreadBody`
+    /// if body data arrives instead, which is kept to be read before asking again. Trailers
+    /// carrying pseudo-headers are malformed and fail with `Error::Peer`.
+    pub async fn trailers(&mut self) -> Option<Result<HeaderMap, Error>> {
+        if self.trailers.is_none() && self.recv.is_some() {
+            while let Some(data) = self.data().await {
+                match data {
+                    Ok(data) if data.is_empty() => {}
+                    Ok(data) => {
+                        self.buf_put(data);
+                        return Some(Err(Error::UnreadBody));
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }
         let trailers = self.trailers.take();
         let Self {
             conn, stream_id, ..
         } = &self;
         match trailers {
             None => None,
-            Some(t) => Some(DecodeHeaders::new(t, conn.clone(), *stream_id).await),
+            Some(t) => Some(
+                DecodeHeaders::new(t, conn.clone(), *stream_id)
+                    .await
+                    .and_then(trailer_fields),
+            ),
         }
     }
 
-    pub fn cancel(mut self) {
-        if let Some(recv) = self.recv.take() {
-            recv.reset(ErrorCode::REQUEST_CANCELLED);
-        }
+    /// Stop receiving the body, asking the peer to stop sending with `code`
+    ///
+    /// Has no effect if the body was already fully received.
+    pub fn stop(mut self, code: ErrorCode) {
+        self.stop_unfinished(code);
+    }
+
+    pub fn cancel(self) {
+        self.stop(ErrorCode::REQUEST_CANCELLED);
     }
 
     #[doc(hidden)]
@@ -110,10 +169,20 @@ impl BodyReader {
             return match Pin::new(self.recv.as_mut().unwrap()).poll_next(cx) {
                 Poll::Pending => Poll::Pending,
                 Poll::Ready(Some(Ok(HttpFrame::Reserved))) => continue,
-                Poll::Ready(None) => Poll::Ready(None),
+                Poll::Ready(Some(Ok(HttpFrame::PushPromise(p)))) => {
+                    match self.conn.on_push_promise(self.stream_id, p) {
+                        Ok(()) => continue,
+                        Err(e) => Poll::Ready(Some(Err(e))),
+                    }
+                }
+                Poll::Ready(None) => {
+                    self.finished = true;
+                    Poll::Ready(None)
+                }
                 Poll::Ready(Some(Ok(HttpFrame::Data(d)))) => Poll::Ready(Some(Ok(d.payload))),
                 Poll::Ready(Some(Ok(HttpFrame::Headers(d)))) => {
                     self.trailers = Some(d);
+                    self.finished = true;
                     Poll::Ready(None)
                 }
                 Poll::Ready(Some(Err(e))) => {
@@ -131,6 +200,54 @@ impl BodyReader {
         }
     }
 
+    #[doc(hidden)]
+    pub fn poll_data_into(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Option<Result<usize, Error>>> {
+        let size = self.buf_read(buf);
+        if size > 0 {
+            return Poll::Ready(Some(Ok(size)));
+        }
+
+        let recv = self.recv.as_mut().unwrap();
+        let remaining = recv.decoder().data_remaining();
+        if remaining == 0 || !recv.read_buffer().is_empty() {
+            // Frame headers and already buffered payload go through the decoder
+            return match ready!(self.poll_read(cx)) {
+                Some(Ok(mut data)) => {
+                    let size = cmp::min(buf.len(), data.len());
+                    buf[..size].copy_from_slice(&data.split_to(size));
+                    if !data.is_empty() {
+                        self.buf_put(data);
+                    }
+                    Poll::Ready(Some(Ok(size)))
+                }
+                Some(Err(e)) => Poll::Ready(Some(Err(e))),
+                None => Poll::Ready(None),
+            };
+        }
+
+        // Within a DATA frame with nothing buffered, so read the payload straight into `buf`
+        let len = cmp::min(buf.len(), remaining);
+        match ready!(Pin::new(recv.get_mut()).poll_read(cx, &mut buf[..len])) {
+            Ok(0) => {
+                self.recv.take().unwrap().reset(ErrorCode::FRAME_ERROR);
+                Poll::Ready(Some(Err(Error::peer("stream ended within a DATA frame"))))
+            }
+            Ok(size) => {
+                recv.decoder_mut().data_read(size);
+                Poll::Ready(Some(Ok(size)))
+            }
+            Err(e) => {
+                let e = frame::Error::Io(e);
+                self.recv.take().unwrap().reset(e.code());
+                Poll::Ready(Some(Err(e.into())))
+            }
+        }
+    }
+
     fn buf_read(&mut self, buf: &mut [u8]) -> usize {
         match self.buf {
             None => 0,
@@ -149,6 +266,15 @@ impl BodyReader {
         assert!(self.buf.is_none());
         self.buf = Some(buf)
     }
+
+    /// Ask the peer to stop sending with `code`, unless the body was read to its end
+    fn stop_unfinished(&mut self, code: ErrorCode) {
+        if let Some(recv) = self.recv.take() {
+            if !self.finished {
+                recv.reset(code);
+            }
+        }
+    }
 }
 
 impl AsyncRead for BodyReader {
@@ -165,14 +291,18 @@ impl AsyncRead for BodyReader {
         loop {
             return match Pin::new(self.recv.as_mut().unwrap()).poll_next(cx) {
                 Poll::Ready(Some(Ok(HttpFrame::Reserved))) => continue,
-                Poll::Ready(None) => Poll::Ready(Ok(size)),
-                Poll::Pending => {
-                    if size > 0 {
-                        Poll::Ready(Ok(size))
-                    } else {
-                        Poll::Ready(Err(io::Error::new(ErrorKind::WouldBlock, "stream blocked")))
+                Poll::Ready(Some(Ok(HttpFrame::PushPromise(p)))) => {
+                    match self.conn.on_push_promise(self.stream_id, p) {
+                        Ok(()) => continue,
+                        Err(e) => Poll::Ready(Err(io::Error::new(ErrorKind::InvalidData, e))),
                     }
                 }
+                Poll::Ready(None) => {
+                    self.finished = true;
+                    Poll::Ready(Ok(size))
+                }
+                Poll::Pending if size > 0 => Poll::Ready(Ok(size)),
+                Poll::Pending => Poll::Pending,
                 Poll::Ready(Some(Err(e))) => {
                     self.recv.take().unwrap().reset(e.code());
                     Poll::Ready(Err(io::Error::new(
@@ -181,6 +311,10 @@ impl AsyncRead for BodyReader {
                     )))
                 }
                 Poll::Ready(Some(Ok(HttpFrame::Data(mut d)))) => {
+                    if size == 0 && d.payload.is_empty() {
+                        // An empty read would signal the end of the body
+                        continue;
+                    }
                     if d.payload.len() >= buf.len() - size {
                         let tail = d.payload.split_off(buf.len() - size);
                         self.buf_put(tail);
@@ -190,6 +324,7 @@ impl AsyncRead for BodyReader {
                 }
                 Poll::Ready(Some(Ok(HttpFrame::Headers(d)))) => {
                     self.trailers = Some(d);
+                    self.finished = true;
                     Poll::Ready(Ok(size))
                 }
                 Poll::Ready(Some(Ok(_))) => {
@@ -204,6 +339,14 @@ impl AsyncRead for BodyReader {
     }
 }
 
+impl Stream for BodyReader {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        BodyReader::poll_read(&mut self, cx)
+    }
+}
+
 impl tokio::io::AsyncRead for BodyReader {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -216,6 +359,8 @@ impl tokio::io::AsyncRead for BodyReader {
 
 impl Drop for BodyReader {
     fn drop(&mut self) {
+        // Let the peer know we won't read the rest of the body, if any
+        self.stop_unfinished(ErrorCode::REQUEST_CANCELLED);
         if self.finish_request {
             self.conn
                 .h3
@@ -227,11 +372,23 @@ impl Drop for BodyReader {
     }
 }
 
+fn trailer_fields(header: Header) -> Result<HeaderMap, Error> {
+    let len = header.len();
+    let fields = header.into_fields();
+    if fields.len() != len {
+        return Err(Error::peer("pseudo-header in trailers"));
+    }
+    Ok(fields)
+}
+
 pub struct BodyWriter {
     state: BodyWriterState,
     conn: ConnectionRef,
     stream_id: StreamId,
     finish_request: bool,
+    /// Body length declared in the `content-length` header, if any
+    content_length: Option<u64>,
+    written: u64,
 }
 
 impl BodyWriter {
@@ -246,10 +403,65 @@ impl BodyWriter {
             stream_id,
             state: BodyWriterState::Idle(send),
             finish_request,
+            content_length: None,
+            written: 0,
         }
     }
 
-    pub async fn trailers(mut self, trailers: HeaderMap) -> Result<(), Error> {
+    /// Require the body to be `len` bytes long, `written` of which have been sent already
+    pub(crate) fn content_length(mut self, len: Option<u64>, written: u64) -> Self {
+        self.content_length = len;
+        self.written = written;
+        self
+    }
+
+    /// Reset the stream rather than end a body whose length doesn't match its `content-length`
+    fn check_length(&mut self) -> Result<(), Error> {
+        match self.content_length {
+            Some(declared) if declared != self.written => {
+                let written = self.written;
+                match mem::replace(&mut self.state, BodyWriterState::Finished) {
+                    BodyWriterState::Idle(mut send) => {
+                        send.reset(ErrorCode::REQUEST_CANCELLED.into());
+                    }
+                    _ => unreachable!(),
+                }
+                Err(Error::ContentLength { declared, written })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Bytes that can be written to the stream right away under the peer's flow control limit
+    ///
+    /// Each write is sent as a DATA frame whose header also takes a few bytes of this credit.
+    /// Connection-level flow control and congestion control may still hold writes back. Zero
+    /// while a write is in progress.
+    pub fn available_credit(&self) -> u64 {
+        match self.state {
+            BodyWriterState::Idle(ref send) => send.credit().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Wait until the peer permits writing more to the stream
+    ///
+    /// Resolves to `available_credit` immediately if there is some, and otherwise once the peer
+    /// raises the stream's flow control limit.
+    pub async fn credit_available(&mut self) -> Result<u64, Error> {
+        match self.state {
+            BodyWriterState::Idle(ref mut send) => Ok(send.credit_available().await?),
+            _ => panic!("cannot wait for credit while not in idle state"),
+        }
+    }
+
+    /// Finish the body with trailing headers
+    ///
+    /// Like `close`, fails with `Error::ContentLength` if the body doesn't match its declared length.
+    pub async fn send_trailers(mut self, trailers: HeaderMap) -> Result<(), Error> {
+        if let BodyWriterState::Idle(_) = self.state {
+            self.check_length()?;
+        }
         match mem::replace(&mut self.state, BodyWriterState::Finished) {
             BodyWriterState::Idle(send) => {
                 let mut stream =
@@ -261,7 +473,40 @@ impl BodyWriter {
         }
     }
 
+    pub(crate) async fn write_stream<S, E>(mut self, mut body: S) -> Result<(), Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut send = match mem::replace(&mut self.state, BodyWriterState::Finished) {
+            BodyWriterState::Idle(send) => send,
+            _ => panic!("cannot send a body stream while not in idle state"),
+        };
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(payload) if payload.is_empty() => continue,
+                Ok(payload) => {
+                    self.written += payload.len() as u64;
+                    send = WriteFrame::new(send, DataFrame { payload }).await?;
+                }
+                Err(e) => {
+                    send.reset(ErrorCode::REQUEST_CANCELLED.into());
+                    return Err(Error::Body(e.into()));
+                }
+            }
+        }
+        self.state = BodyWriterState::Idle(send);
+        self.close().await
+    }
+
+    /// Finish the body
+    ///
+    /// Fails with `Error::ContentLength`, resetting the stream instead, if the number of bytes
+    /// written doesn't match a `content-length` header sent with the request.
     pub async fn close(mut self) -> Result<(), Error> {
+        if let BodyWriterState::Idle(_) = self.state {
+            self.check_length()?;
+        }
         let state = mem::replace(&mut self.state, BodyWriterState::Finished);
         match state {
             BodyWriterState::Idle(mut send) => send.finish().await.map_err(Into::into),
@@ -269,14 +514,19 @@ impl BodyWriter {
         }
     }
 
-    pub fn cancel(mut self) {
+    pub fn cancel(self) {
+        self.reset(ErrorCode::REQUEST_CANCELLED);
+    }
+
+    /// Abandon the body, resetting the stream with `code`
+    pub fn reset(mut self, code: ErrorCode) {
         let state = mem::replace(&mut self.state, BodyWriterState::Finished);
         match state {
             BodyWriterState::Idle(mut send) => {
-                send.reset(ErrorCode::REQUEST_CANCELLED.into());
+                send.reset(code.into());
             }
             BodyWriterState::Writing(write) => {
-                write.reset(ErrorCode::REQUEST_CANCELLED);
+                write.reset(code);
             }
             _ => (),
         }
@@ -312,6 +562,7 @@ impl AsyncWrite for BodyWriter {
                 BodyWriterState::Writing(ref mut write) => {
                     let send = ready!(Pin::new(write).poll(cx))?;
                     self.state = BodyWriterState::Idle(send);
+                    self.written += buf.len() as u64;
                     return Poll::Ready(Ok(buf.len()));
                 }
             }
@@ -372,12 +623,17 @@ impl tokio::io::AsyncWrite for BodyWriter {
 impl Drop for BodyWriter {
     fn drop(&mut self) {
         if self.finish_request {
-            self.conn
-                .h3
-                .lock()
-                .unwrap()
-                .inner
-                .request_finished(self.stream_id);
+            let mut conn = self.conn.h3.lock().unwrap();
+            conn.inner.request_finished(self.stream_id);
+            conn.priorities.remove(&self.stream_id);
+            if let BodyWriterState::Idle(send) =
+                mem::replace(&mut self.state, BodyWriterState::Finished)
+            {
+                conn.finish_response(send);
+            } else if conn.inner.is_closing() {
+                // Let the driver close the connection if this was the last request
+                conn.wake();
+            }
         }
     }
 }
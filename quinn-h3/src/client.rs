@@ -1,34 +1,64 @@
+#[cfg(feature = "blocking")]
+use std::net::ToSocketAddrs;
+#[cfg(feature = "runtime-tokio")]
+use std::{collections::HashMap, task::Waker};
 use std::{
     future::Future,
-    mem,
+    io, mem,
     net::SocketAddr,
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::Duration,
 };
 
-use futures::{ready, Stream};
-use http::{request, Request, Response};
+use bytes::{Bytes, BytesMut};
+#[cfg(any(feature = "blocking", feature = "runtime-tokio"))]
+use futures::FutureExt;
+use futures::{channel::oneshot, future::AbortHandle, ready, Stream};
+#[cfg(feature = "runtime-tokio")]
+use futures::{future, stream::FuturesUnordered, StreamExt};
+use http::{request, uri::Authority, HeaderMap, Request, Response, StatusCode};
 use quinn::{Certificate, Endpoint};
-use quinn_proto::{Side, StreamId};
+#[cfg(feature = "runtime-tokio")]
+use quinn_proto::IpPreference;
+use quinn_proto::{Side, StreamId, TransportErrorCode};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+#[cfg(feature = "runtime-tokio")]
+use tokio::time::{delay_for, timeout};
+#[cfg(feature = "runtime-tokio")]
+use tracing::error;
 use tracing::trace;
 
 use crate::{
+    alt_svc::AltSvcCache,
     body::{Body, BodyReader, BodyWriter},
-    connection::{ConnectionDriver, ConnectionRef},
+    connection::{ConnectionDriver, ConnectionRef, HandshakeSummary, SettingsGrace},
+    datagram::DatagramFlow,
     frame::{FrameDecoder, FrameStream, WriteFrame},
     headers::{DecodeHeaders, SendHeaders},
+    priority::Priority,
     proto::{
         frame::{DataFrame, HttpFrame},
         headers::Header,
         ErrorCode,
     },
+    qpack::HeaderField,
     streams::Reset,
+    webtransport::WebTransportSession,
     Error, Settings,
 };
 
 pub struct Builder {
     settings: Settings,
+    settings_grace: Option<SettingsGrace>,
     client_config: quinn::ClientConfigBuilder,
+    max_concurrent_handshakes: Option<usize>,
+    bind_mode: BindMode,
+    allowed_statuses: Option<Arc<[StatusCode]>>,
+    max_local_concurrent_requests: Option<usize>,
+    max_pushes: u64,
+    alt_svc: Option<Arc<Mutex<AltSvcCache>>>,
 }
 
 impl Default for Builder {
@@ -39,6 +69,13 @@ impl Default for Builder {
         Self {
             client_config,
             settings: Settings::default(),
+            settings_grace: None,
+            max_concurrent_handshakes: None,
+            bind_mode: BindMode::Unspecified,
+            allowed_statuses: None,
+            max_local_concurrent_requests: None,
+            max_pushes: 0,
+            alt_svc: None,
         }
     }
 }
@@ -49,6 +86,13 @@ impl Builder {
         Self {
             client_config,
             settings: Settings::default(),
+            settings_grace: None,
+            max_concurrent_handshakes: None,
+            bind_mode: BindMode::Unspecified,
+            allowed_statuses: None,
+            max_local_concurrent_requests: None,
+            max_pushes: 0,
+            alt_svc: None,
         }
     }
 
@@ -57,6 +101,75 @@ impl Builder {
         self
     }
 
+    /// Stop waiting for the server's SETTINGS `period` after the connection is established
+    ///
+    /// If they haven't arrived by then, the connection proceeds as if the server had sent default
+    /// settings, which disables the QPACK dynamic table. With `strict`, the connection is closed
+    /// with `H3_MISSING_SETTINGS` instead. By default, the client waits indefinitely.
+    pub fn settings_grace_period(&mut self, period: Duration, strict: bool) -> &mut Self {
+        self.settings_grace = Some(SettingsGrace { period, strict });
+        self
+    }
+
+    /// Limit the number of handshakes in progress at once on the endpoint created by `build`
+    ///
+    /// See `quinn::EndpointBuilder::max_concurrent_handshakes`.
+    pub fn max_concurrent_handshakes(&mut self, max: usize) -> &mut Self {
+        self.max_concurrent_handshakes = Some(max);
+        self
+    }
+
+    /// Reject responses whose status isn't among `statuses`
+    ///
+    /// Such responses fail with `Error::UnexpectedStatus` instead of being returned, and their
+    /// stream is reset without reading the body. By default, every status is accepted.
+    pub fn allowed_statuses(
+        &mut self,
+        statuses: impl IntoIterator<Item = StatusCode>,
+    ) -> &mut Self {
+        self.allowed_statuses = Some(statuses.into_iter().collect());
+        self
+    }
+
+    /// Limit the number of requests each connection has in flight at once
+    ///
+    /// Further calls to `send_request` wait, in the order they were made, for an earlier request
+    /// to complete before opening a stream. A request completes once its response body is dropped,
+    /// or its `RecvResponse` if no response was received. This bounds local resource use
+    /// regardless of how many streams the server allows. Unlimited by default.
+    ///
+    /// Panics if `max` is zero.
+    pub fn max_local_concurrent_requests(&mut self, max: usize) -> &mut Self {
+        assert!(max > 0, "at least one request must be allowed");
+        self.max_local_concurrent_requests = Some(max);
+        self
+    }
+
+    /// Allow the server to push up to `max` responses on each connection
+    ///
+    /// Pushed responses are received through `Connection::incoming_pushes`. Disabled by default.
+    pub fn max_pushes(&mut self, max: u64) -> &mut Self {
+        self.max_pushes = max;
+        self
+    }
+
+    /// Record the alternative services advertised by responses in `cache`
+    ///
+    /// `Alt-Svc` headers of final responses are stored under the origin of their request. `Pool`
+    /// and `BlockingClient` connect to a fresh alternative of an origin in its place, and fall back
+    /// to the origin itself if that fails. The cache may be shared with other clients, such as an
+    /// HTTP/1.1 or HTTP/2 client discovering HTTP/3 endpoints.
+    pub fn alt_svc_cache(&mut self, cache: Arc<Mutex<AltSvcCache>>) -> &mut Self {
+        self.alt_svc = Some(cache);
+        self
+    }
+
+    /// How `build` binds the client's UDP sockets
+    pub fn bind_mode(&mut self, mode: BindMode) -> &mut Self {
+        self.bind_mode = mode;
+        self
+    }
+
     pub fn add_certificate_authority(
         &mut self,
         cert: Certificate,
@@ -66,30 +179,184 @@ impl Builder {
     }
 
     pub fn endpoint(self, endpoint: Endpoint) -> Client {
+        let options = Arc::new(self.connection_options());
         Client {
             endpoint,
-            settings: self.settings,
+            endpoint_v4: None,
+            client_config: self.client_config.build(),
+            options,
+        }
+    }
+
+    fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            settings: self.settings.clone(),
+            settings_grace: self.settings_grace,
+            allowed_statuses: self.allowed_statuses.clone(),
+            max_local_concurrent_requests: self.max_local_concurrent_requests,
+            max_pushes: self.max_pushes,
+            alt_svc: self.alt_svc.clone(),
         }
     }
 
-    pub fn build(self) -> Result<(quinn::EndpointDriver, Client), quinn::EndpointError> {
+    pub fn build(self) -> Result<(ClientDriver, Client), quinn::EndpointError> {
+        let options = Arc::new(self.connection_options());
+        let client_config = self.client_config.build();
         let mut endpoint_builder = quinn::Endpoint::builder();
-        endpoint_builder.default_client_config(self.client_config.build());
-        let (endpoint_driver, endpoint, _) = endpoint_builder.bind(&"[::]:0".parse().unwrap())?;
+        endpoint_builder.default_client_config(client_config.clone());
+        if let Some(max) = self.max_concurrent_handshakes {
+            endpoint_builder.max_concurrent_handshakes(max);
+        }
+
+        let dual_stack = match self.bind_mode {
+            BindMode::Unspecified => {
+                Some(std::net::UdpSocket::bind("[::]:0").map_err(quinn::EndpointError::Socket)?)
+            }
+            BindMode::DualStack => match bind_dual_stack() {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    trace!("dual-stack socket unavailable, binding separately: {}", e);
+                    None
+                }
+            },
+            BindMode::Separate => None,
+        };
+        let (v6, v4) = match dual_stack {
+            Some(socket) => (Some(socket), None),
+            None => match (bind_v6_only(), std::net::UdpSocket::bind("0.0.0.0:0")) {
+                (Err(e6), Err(e4)) => {
+                    return Err(quinn::EndpointError::Socket(io::Error::new(
+                        e4.kind(),
+                        format!(
+                            "binding failed for both IPv6 ({}) and IPv4 ({}) sockets",
+                            e6, e4
+                        ),
+                    )));
+                }
+                (v6, v4) => (v6.ok(), v4.ok()),
+            },
+        };
+
+        let mut drivers = Vec::new();
+        let mut endpoints = Vec::new();
+        for socket in v6.into_iter().chain(v4) {
+            let (driver, endpoint, _) = endpoint_builder.clone().with_socket(socket)?;
+            drivers.push(driver);
+            endpoints.push(endpoint);
+        }
+        let endpoint = endpoints.remove(0);
+        let endpoint_v4 = endpoints.pop();
 
         Ok((
-            endpoint_driver,
+            ClientDriver(drivers),
             Client {
                 endpoint,
-                settings: self.settings,
+                endpoint_v4,
+                client_config,
+                options,
             },
         ))
     }
+
+    /// Build a synchronous client, which drives its endpoint on a runtime of its own
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking(self) -> Result<BlockingClient, quinn::EndpointError> {
+        let runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .map_err(quinn::EndpointError::Socket)?;
+        let (driver, client) = runtime.enter(|| self.build())?;
+        runtime.spawn(driver.map(|_| ()));
+        Ok(BlockingClient { runtime, client })
+    }
+
+    /// Build a client whose endpoint is driven on the ambient tokio runtime
+    ///
+    /// Like `build`, but spawns the `ClientDriver` instead of returning it. The driver stops
+    /// once the `Client` and all of its connections are gone.
+    #[cfg(feature = "runtime-tokio")]
+    pub fn build_spawned(self) -> Result<Client, quinn::EndpointError> {
+        let (driver, client) = self.build()?;
+        tokio::spawn(driver.map(|result| {
+            if let Err(e) = result {
+                error!("endpoint driver failed: {}", e);
+            }
+        }));
+        Ok(client)
+    }
+}
+
+/// How a client built by `Builder::build` binds its UDP sockets
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BindMode {
+    /// A single socket bound to `[::]:0`, leaving whether it can reach IPv4 servers to the
+    /// platform's default for `IPV6_V6ONLY`
+    Unspecified,
+    /// A single IPv6 socket with `IPV6_V6ONLY` cleared, reaching IPv4 servers through mapped
+    /// addresses
+    ///
+    /// Falls back to `Separate` on platforms without dual-stack sockets.
+    DualStack,
+    /// Separate IPv6 and IPv4 sockets, each used for servers of its address family
+    ///
+    /// If only one of them can be bound, the client is limited to that address family.
+    Separate,
+}
+
+fn bind_dual_stack() -> io::Result<std::net::UdpSocket> {
+    net2::UdpBuilder::new_v6()?.only_v6(false)?.bind("[::]:0")
+}
+
+fn bind_v6_only() -> io::Result<std::net::UdpSocket> {
+    net2::UdpBuilder::new_v6()?.only_v6(true)?.bind("[::]:0")
 }
 
+/// Drives the UDP sockets of a `Client` built by `Builder::build`
+///
+/// Completes once every socket has stopped, or as soon as one of them fails.
+pub struct ClientDriver(Vec<quinn::EndpointDriver>);
+
+impl Future for ClientDriver {
+    type Output = Result<(), io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut i = 0;
+        while i < self.0.len() {
+            match Pin::new(&mut self.0[i]).poll(cx) {
+                Poll::Pending => i += 1,
+                Poll::Ready(Ok(())) => {
+                    drop(self.0.swap_remove(i));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            }
+        }
+        if self.0.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Client {
     endpoint: Endpoint,
+    /// Used for IPv4 servers when the client has separate sockets for each address family
+    endpoint_v4: Option<Endpoint>,
+    /// Base configuration for connections overriding part of the endpoint's default
+    client_config: quinn::ClientConfig,
+    options: Arc<ConnectionOptions>,
+}
+
+/// How the client sets up each of its connections, from the `Builder`
+struct ConnectionOptions {
     settings: Settings,
+    settings_grace: Option<SettingsGrace>,
+    allowed_statuses: Option<Arc<[StatusCode]>>,
+    max_local_concurrent_requests: Option<usize>,
+    max_pushes: u64,
+    alt_svc: Option<Arc<Mutex<AltSvcCache>>>,
 }
 
 impl Client {
@@ -98,14 +365,609 @@ impl Client {
         addr: &SocketAddr,
         server_name: &str,
     ) -> Result<Connecting, quinn::ConnectError> {
+        self.connect_with(None, addr, server_name)
+    }
+
+    /// Connect using `transport` in place of the client's transport configuration
+    ///
+    /// Only this connection is affected; the TLS configuration and HTTP/3 settings are still
+    /// those configured on the `Builder`.
+    pub fn connect_with_transport(
+        &self,
+        transport: Arc<quinn::TransportConfig>,
+        addr: &SocketAddr,
+        server_name: &str,
+    ) -> Result<Connecting, quinn::ConnectError> {
+        let mut config = self.client_config.clone();
+        config.transport = transport;
+        self.connect_with(Some(config), addr, server_name)
+    }
+
+    fn connect_with(
+        &self,
+        config: Option<quinn::ClientConfig>,
+        addr: &SocketAddr,
+        server_name: &str,
+    ) -> Result<Connecting, quinn::ConnectError> {
+        let endpoint = match self.endpoint_v4 {
+            Some(ref endpoint) if addr.is_ipv4() => endpoint,
+            _ => &self.endpoint,
+        };
         Ok(Connecting {
-            settings: self.settings.clone(),
-            connecting: self.endpoint.connect(addr, server_name)?,
+            options: self.options.clone(),
+            connecting: Some(match config {
+                Some(config) => endpoint.connect_with(config, addr, server_name)?,
+                None => endpoint.connect(addr, server_name)?,
+            }),
         })
     }
+
+    /// Host and port of the alternative service cached for the `https` origin `host`:`port`
+    #[cfg(any(feature = "blocking", feature = "runtime-tokio"))]
+    fn alternative(&self, host: &str, port: u16) -> Option<(String, u16)> {
+        let cache = self.options.alt_svc.as_ref()?;
+        let origin = match host.contains(':') {
+            true => format!("https://[{}]:{}/", host, port),
+            false => format!("https://{}:{}/", host, port),
+        };
+        let origin = origin.parse::<http::Uri>().ok()?;
+        let alt = cache.lock().unwrap().get(&origin)?.clone();
+        if alt.host == host && alt.port == port {
+            return None;
+        }
+        Some((alt.host, alt.port))
+    }
+
+    /// Number of handshakes currently in progress
+    pub fn handshakes_in_progress(&self) -> usize {
+        self.endpoint.handshakes_in_progress()
+            + self
+                .endpoint_v4
+                .as_ref()
+                .map_or(0, |x| x.handshakes_in_progress())
+    }
+
+    /// Connect to a server, driving the connection on the ambient tokio runtime
+    ///
+    /// Like `connect`, but spawns the `quinn::ConnectionDriver` and `ConnectionDriver` instead of
+    /// returning them. Dropping the `Connection` stops its drivers, closing the connection once
+    /// any outstanding responses are gone as well.
+    #[cfg(feature = "runtime-tokio")]
+    pub async fn connect_spawned(
+        &self,
+        addr: &SocketAddr,
+        server_name: &str,
+    ) -> Result<Connection, Error> {
+        let (quic_driver, driver, mut conn) = self.connect(addr, server_name)?.await?;
+        // Ends by itself once the QUIC connection is closed
+        tokio::spawn(quic_driver.map(|_| ()));
+        let (driver, abort) = future::abortable(driver);
+        tokio::spawn(driver.map(|_| ()));
+        conn.driver = Some(abort);
+        Ok(conn)
+    }
+
+    /// Connect to whichever of `addrs` completes a handshake first
+    ///
+    /// Attempts are started one at a time, `RacingConfig::stagger_delay` apart or as soon as the
+    /// previous one fails, alternating between address families. The client's
+    /// `TransportConfig::ip_preference` picks the family to start with; with
+    /// `IpPreference::LowestRtt`, the first address of each family is attempted at once, starting
+    /// with the family of the first address, so the faster family wins. The first connection
+    /// established is returned, and attempts still in progress are abandoned. Fails with the error
+    /// of the last attempt if every address fails, or with `Error::TimedOut` once
+    /// `RacingConfig::overall_timeout` elapses.
+    #[cfg(feature = "runtime-tokio")]
+    pub async fn connect_racing(
+        &self,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        server_name: &str,
+        config: RacingConfig,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, Connection), Error> {
+        let preference = self.client_config.transport.ip_preference;
+        let mut addrs = interleave_families(addrs, preference)
+            .into_iter()
+            .peekable();
+        let mut first = true;
+        let mut attempts = FuturesUnordered::new();
+        let mut deadline = delay_for(config.overall_timeout);
+        let mut stagger = delay_for(config.stagger_delay);
+        let mut start_next = true;
+        let mut last_error = None;
+        future::poll_fn(|cx| loop {
+            if deadline.poll_unpin(cx).is_ready() {
+                return Poll::Ready(Err(Error::TimedOut));
+            }
+            if start_next || stagger.poll_unpin(cx).is_ready() {
+                start_next = false;
+                match addrs.next() {
+                    Some(addr) => {
+                        match self.connect(&addr, server_name) {
+                            Ok(connecting) => {
+                                trace!("racing connection attempt to {}", addr);
+                                attempts.push(timeout(config.attempt_timeout, connecting));
+                                stagger = delay_for(config.stagger_delay);
+                                // Race the other family right away if neither is preferred
+                                start_next = first
+                                    && preference == IpPreference::LowestRtt
+                                    && addrs.peek().map(SocketAddr::is_ipv6)
+                                        == Some(!addr.is_ipv6());
+                                first = false;
+                            }
+                            Err(e) => {
+                                last_error = Some(e.into());
+                                start_next = true;
+                            }
+                        }
+                        continue;
+                    }
+                    None if attempts.is_empty() => {
+                        return Poll::Ready(Err(last_error.take().unwrap_or_else(|| {
+                            Error::Unavailable("no address to connect to".into())
+                        })));
+                    }
+                    None => {}
+                }
+            }
+            match ready!(attempts.poll_next_unpin(cx)) {
+                Some(Ok(Ok(conn))) => return Poll::Ready(Ok(conn)),
+                Some(Ok(Err(e))) => last_error = Some(e),
+                Some(Err(_)) => last_error = Some(Error::TimedOut),
+                None => {}
+            }
+            // Don't wait out the stagger delay once an attempt has failed
+            start_next = true;
+        })
+        .await
+    }
+
+    /// Keep a connection to a server established in the background
+    ///
+    /// See `WarmConnection`.
+    #[cfg(feature = "runtime-tokio")]
+    pub fn warm_connection(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+        config: WarmConfig,
+    ) -> WarmConnection {
+        let shared = Arc::new(Mutex::new(WarmShared {
+            status: WarmStatus::Connecting,
+            waiters: Vec::new(),
+        }));
+        let (task, abort) = future::abortable(warm(
+            self.clone(),
+            addr,
+            server_name.to_owned(),
+            config,
+            shared.clone(),
+        ));
+        tokio::spawn(task.map(|_| ()));
+        WarmConnection { shared, abort }
+    }
+}
+
+/// Timers governing `Client::connect_racing`
+#[cfg(feature = "runtime-tokio")]
+#[derive(Debug, Clone)]
+pub struct RacingConfig {
+    /// Delay before starting the next attempt while earlier ones are still in progress
+    pub stagger_delay: Duration,
+    /// Time each attempt is given to complete its handshake before it's abandoned
+    pub attempt_timeout: Duration,
+    /// Time the whole operation is given, across all attempts
+    pub overall_timeout: Duration,
 }
 
-pub struct Connection(ConnectionRef);
+#[cfg(feature = "runtime-tokio")]
+impl Default for RacingConfig {
+    fn default() -> Self {
+        Self {
+            stagger_delay: Duration::from_millis(250),
+            attempt_timeout: Duration::from_secs(5),
+            overall_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Order `addrs` alternating between address families, starting with the preferred one
+///
+/// Without a preference, that's the family of the first address.
+#[cfg(feature = "runtime-tokio")]
+fn interleave_families(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    preference: IpPreference,
+) -> Vec<SocketAddr> {
+    let mut addrs = addrs.into_iter().peekable();
+    let first_v6 = match preference {
+        IpPreference::Ipv6 => true,
+        IpPreference::Ipv4 => false,
+        IpPreference::LowestRtt => addrs.peek().map(SocketAddr::is_ipv6) == Some(true),
+    };
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.partition(|x| x.is_ipv6() == first_v6);
+    let mut result = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return result,
+            (x, y) => result.extend(x.into_iter().chain(y)),
+        }
+    }
+}
+
+/// How a `WarmConnection` keeps its connection alive and re-establishes it
+#[cfg(feature = "runtime-tokio")]
+#[derive(Debug, Clone)]
+pub struct WarmConfig {
+    /// Interval at which the connection is pinged, keeping it and any NAT bindings alive
+    ///
+    /// Should be well under the idle timeout.
+    pub keep_alive: Duration,
+    /// Number of consecutive failed connection attempts retried before giving up
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after each further failure
+    pub retry_delay: Duration,
+    /// Upper bound on the delay between retries
+    pub max_retry_delay: Duration,
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl Default for WarmConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: Duration::from_secs(5),
+            max_retries: 5,
+            retry_delay: Duration::from_millis(100),
+            max_retry_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl WarmConfig {
+    /// Delay before retrying after `failures` consecutive failed attempts
+    pub(crate) fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.retry_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_retry_delay)
+            .min(self.max_retry_delay)
+    }
+}
+
+/// The state of a `WarmConnection`, for health checks
+#[cfg(feature = "runtime-tokio")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WarmState {
+    /// Establishing the connection, initially or after the previous one was lost
+    Connecting,
+    /// A connection is established
+    Ready,
+    /// Every connection attempt failed; no further attempts are made
+    Failed,
+}
+
+/// A connection to a single server kept established by a background task
+///
+/// The task pings the connection to keep it from idling out and, if it's lost anyway, connects
+/// again, retrying failed attempts with exponential backoff. `connection` hands out the current
+/// connection. Dropping the `WarmConnection` stops the task and drops its connection.
+#[cfg(feature = "runtime-tokio")]
+pub struct WarmConnection {
+    shared: Arc<Mutex<WarmShared>>,
+    abort: AbortHandle,
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl WarmConnection {
+    /// Wait for a usable connection
+    ///
+    /// Fails with `Error::Unavailable` once the retries are exhausted.
+    pub async fn connection(&self) -> Result<Connection, Error> {
+        future::poll_fn(|cx| {
+            let mut shared = self.shared.lock().unwrap();
+            match shared.status {
+                WarmStatus::Ready(ref conn) => Poll::Ready(Ok(conn.handle())),
+                WarmStatus::Failed(ref reason) => {
+                    Poll::Ready(Err(Error::Unavailable(reason.clone())))
+                }
+                WarmStatus::Connecting => {
+                    if !shared.waiters.iter().any(|x| x.will_wake(cx.waker())) {
+                        shared.waiters.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// What the background task is doing, for health checks
+    pub fn state(&self) -> WarmState {
+        match self.shared.lock().unwrap().status {
+            WarmStatus::Connecting => WarmState::Connecting,
+            WarmStatus::Ready(_) => WarmState::Ready,
+            WarmStatus::Failed(_) => WarmState::Failed,
+        }
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl Drop for WarmConnection {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+struct WarmShared {
+    status: WarmStatus,
+    waiters: Vec<Waker>,
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl WarmShared {
+    fn set(&mut self, status: WarmStatus) {
+        self.status = status;
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+enum WarmStatus {
+    Connecting,
+    /// Owns the connection's drivers; users get handles which don't
+    Ready(Connection),
+    Failed(String),
+}
+
+#[cfg(feature = "runtime-tokio")]
+async fn warm(
+    client: Client,
+    addr: SocketAddr,
+    server_name: String,
+    config: WarmConfig,
+    shared: Arc<Mutex<WarmShared>>,
+) {
+    let mut failures = 0;
+    loop {
+        let conn = match client.connect_spawned(&addr, &server_name).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                failures += 1;
+                if failures > config.max_retries {
+                    shared
+                        .lock()
+                        .unwrap()
+                        .set(WarmStatus::Failed(e.to_string()));
+                    return;
+                }
+                trace!("connecting to {} failed, retrying: {}", addr, e);
+                delay_for(config.backoff(failures)).await;
+                continue;
+            }
+        };
+        failures = 0;
+        let quic = conn.conn.quic.clone();
+        shared.lock().unwrap().set(WarmStatus::Ready(conn));
+
+        let mut closed = quic.closed();
+        loop {
+            match future::select(&mut closed, delay_for(config.keep_alive)).await {
+                future::Either::Left((reason, _)) => {
+                    trace!("connection to {} lost: {}", addr, reason);
+                    break;
+                }
+                future::Either::Right(_) => {
+                    let _ = quic.send_ping();
+                }
+            }
+        }
+        shared.lock().unwrap().set(WarmStatus::Connecting);
+    }
+}
+
+/// Connections to any number of servers, established on demand and shared between requests
+///
+/// Connections are keyed by host and port. A connection is re-used for as long as it's open and
+/// the server hasn't sent GOAWAY; after that, the next request to the same authority establishes a
+/// new one, while requests in flight on the old connection complete. Connection drivers are
+/// spawned on the ambient tokio runtime.
+#[cfg(feature = "runtime-tokio")]
+#[derive(Clone)]
+pub struct Pool {
+    client: Client,
+    connections: Arc<Mutex<HashMap<(String, u16), Connection>>>,
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl Pool {
+    /// Pool connections established by `client`
+    ///
+    /// The client's `ClientDriver` must keep running, as it does for one built with
+    /// `build_spawned`.
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// A connection to `host` on `port`, established unless a pooled one can be re-used
+    ///
+    /// New connections go to the alternative service cached for the origin, if any, see
+    /// `Builder::alt_svc_cache`.
+    pub async fn connection(&self, host: &str, port: u16) -> Result<Connection, Error> {
+        let key = (host.to_owned(), port);
+        if let Some(conn) = self.reusable(&key) {
+            return Ok(conn);
+        }
+        let alternative = match self.client.alternative(host, port) {
+            Some((alt_host, alt_port)) => match self.connect(&alt_host, alt_port, host).await {
+                Ok(conn) => Some(conn),
+                Err(e) => {
+                    trace!("alternative {}:{} failed: {}", alt_host, alt_port, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let conn = match alternative {
+            Some(conn) => conn,
+            None => self.connect(host, port, host).await?,
+        };
+
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(&key) {
+            // Another request connected in the meantime
+            Some(existing) if existing.is_reusable() => return Ok(existing.handle()),
+            _ => (),
+        }
+        let handle = conn.handle();
+        if let Some(old) = connections.insert(key, conn) {
+            retire(old);
+        }
+        Ok(handle)
+    }
+
+    /// Send `request` on a connection to the authority of its URI, see `connection`
+    pub async fn request<T: Into<Body>>(
+        &self,
+        request: Request<T>,
+    ) -> Result<(RecvResponse, BodyWriter), Error> {
+        let (host, port) = authority(request.uri())?;
+        self.connection(&host, port)
+            .await?
+            .send_request(request)
+            .await
+    }
+
+    /// Establish a connection to `host` on `port`, authenticating the server as `server_name`
+    async fn connect(&self, host: &str, port: u16, server_name: &str) -> Result<Connection, Error> {
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found for host"))?;
+        trace!("connecting to {}:{} for the pool", host, port);
+        self.client.connect_spawned(&addr, server_name).await
+    }
+
+    /// Hand out the pooled connection for `key` if it's still usable, and retire it otherwise
+    fn reusable(&self, key: &(String, u16)) -> Option<Connection> {
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(key) {
+            None => return None,
+            Some(conn) if conn.is_reusable() => return Some(conn.handle()),
+            Some(_) => (),
+        }
+        retire(connections.remove(key).unwrap());
+        None
+    }
+}
+
+/// Keep driving a connection dropped from a `Pool` until it's closed
+///
+/// Requests still in flight on it can complete, and the server closes it once they have.
+#[cfg(feature = "runtime-tokio")]
+fn retire(conn: Connection) {
+    let closed = conn.conn.quic.closed();
+    tokio::spawn(closed.map(move |_| drop(conn)));
+}
+
+/// Host and port of the authority `uri` refers to, the port defaulting to 443
+#[cfg(any(feature = "blocking", feature = "runtime-tokio"))]
+fn authority(uri: &http::Uri) -> Result<(String, u16), Error> {
+    let authority = uri.authority().ok_or(Error::InvalidRequest(
+        crate::proto::headers::Error::MissingAuthority,
+    ))?;
+    // IPv6 literals are bracketed in URIs
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    Ok((host, authority.port_u16().unwrap_or(443)))
+}
+
+#[cfg(feature = "blocking")]
+fn resolve(host: &str, port: u16) -> Result<SocketAddr, Error> {
+    Ok((host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found for host"))?)
+}
+
+/// Synchronous facade over `Client`, for simple tools and scripts
+///
+/// Runs a single-threaded runtime that drives the endpoint and connections while a request is in
+/// flight, so no drivers need to be spawned. Each request is sent on a fresh connection to the
+/// authority of its URI, which is closed once the response has been read.
+#[cfg(feature = "blocking")]
+pub struct BlockingClient {
+    runtime: tokio::runtime::Runtime,
+    client: Client,
+}
+
+#[cfg(feature = "blocking")]
+impl BlockingClient {
+    /// Send `request` and wait for the response and its complete body
+    pub fn blocking_send_request<T: Into<Body>>(
+        &mut self,
+        request: Request<T>,
+    ) -> Result<(Response<()>, Vec<u8>), Error> {
+        let (host, port) = authority(request.uri())?;
+        let alternative = self.client.alternative(&host, port);
+
+        let client = &self.client;
+        self.runtime.block_on(async move {
+            let mut connected = None;
+            if let Some((alt_host, alt_port)) = alternative {
+                let attempt = match resolve(&alt_host, alt_port) {
+                    Ok(addr) => client.connect(&addr, &host)?.await,
+                    Err(e) => Err(e),
+                };
+                match attempt {
+                    Ok(x) => connected = Some(x),
+                    Err(e) => trace!("alternative {}:{} failed: {}", alt_host, alt_port, e),
+                }
+            }
+            let (quic_driver, driver, conn) = match connected {
+                Some(x) => x,
+                None => client.connect(&resolve(&host, port)?, &host)?.await?,
+            };
+            let quic_driver = tokio::spawn(quic_driver.map(|_| ()));
+            tokio::spawn(driver.map(|_| ()));
+
+            let (response, body_writer) = conn.send_request(request).await?;
+            body_writer.close().await?;
+            let (response, mut body_reader) = response.await?;
+            let mut body = Vec::new();
+            while let Some(data) = body_reader.data().await {
+                body.extend_from_slice(&data?);
+            }
+            drop(body_reader);
+            conn.close();
+            // Let the connection close cleanly before the runtime stops driving it
+            let _ = quic_driver.await;
+            Ok((response, body))
+        })
+    }
+}
+
+pub struct Connection {
+    conn: ConnectionRef,
+    /// Stops the spawned `ConnectionDriver`, if any, when the connection is dropped
+    driver: Option<AbortHandle>,
+    /// Statuses responses are restricted to, if any
+    allowed_statuses: Option<Arc<[StatusCode]>>,
+    /// Slots for requests in flight, if limited
+    requests: Option<Arc<Semaphore>>,
+    /// Where to record the alternative services advertised by responses
+    alt_svc: Option<Arc<Mutex<AltSvcCache>>>,
+}
 
 impl Connection {
     pub async fn send_request<T: Into<Body>>(
@@ -121,69 +983,418 @@ impl Connection {
             },
             body,
         ) = request.into_parts();
-        let (send, recv) = self.0.quic.open_bi().await?;
+        let content_length = headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok()?.parse::<u64>().ok());
+        let alt_svc = self.alt_svc.clone().map(|cache| (cache, uri.clone()));
+        let header = Header::request(method, uri, headers).map_err(Error::InvalidRequest)?;
+        let permit = self.request_permit().await;
+        let (send, recv) = self.conn.quic.open_bi().await?;
 
         let stream_id = send.id();
-        let send = SendHeaders::new(
-            Header::request(method, uri, headers),
-            &self.0,
-            send,
-            stream_id,
-        )?
-        .await?;
+        let send = SendHeaders::new(header, &self.conn, send, stream_id)?.await?;
 
-        let recv = RecvResponse::new(FrameDecoder::stream(recv), self.0.clone(), stream_id);
-        match body.into() {
+        let recv = RecvResponse::new(
+            FrameDecoder::stream(recv),
+            self.conn.clone(),
+            stream_id,
+            self.allowed_statuses.clone(),
+            permit,
+        )
+        .alt_svc(alt_svc);
+        let (send, written) = match body.into() {
             Body::Buf(payload) => {
-                let send = WriteFrame::new(send, DataFrame { payload }).await?;
-                Ok((
-                    recv,
-                    BodyWriter::new(send, self.0.clone(), stream_id, false),
-                ))
+                let written = payload.len() as u64;
+                (WriteFrame::new(send, DataFrame { payload }).await?, written)
+            }
+            Body::None => (send, 0),
+        };
+        Ok((
+            recv,
+            BodyWriter::new(send, self.conn.clone(), stream_id, false)
+                .content_length(content_length, written),
+        ))
+    }
+
+    /// Like `send_request`, but with both halves of the request owned by a single handle
+    pub async fn send_request_handle<T: Into<Body>>(
+        &self,
+        request: Request<T>,
+    ) -> Result<RequestHandle, Error> {
+        Ok(self.send_request(request).await?.into())
+    }
+
+    /// Send `request` and receive the whole response, body included
+    ///
+    /// The request body, if any, is sent in full before waiting for the response. Fails with
+    /// `Error::BodyTooLarge` and stops receiving if the response body exceeds `max_body` bytes.
+    /// Trailers are discarded.
+    pub async fn send_request_collect<T: Into<Body>>(
+        &self,
+        request: Request<T>,
+        max_body: usize,
+    ) -> Result<Response<Bytes>, Error> {
+        let (response, body_writer) = self.send_request(request).await?;
+        body_writer.close().await?;
+        let (response, mut body_reader) = response.await?;
+        let mut body = BytesMut::new();
+        while let Some(data) = body_reader.data().await {
+            let data = data?;
+            if body.len() + data.len() > max_body {
+                body_reader.cancel();
+                return Err(Error::BodyTooLarge { limit: max_body });
             }
-            Body::None => Ok((
-                recv,
-                BodyWriter::new(send, self.0.clone(), stream_id, false),
-            )),
+            body.extend_from_slice(&data);
         }
+        Ok(response.map(|()| body.freeze()))
+    }
+
+    /// What was negotiated while establishing the connection, in a single snapshot
+    ///
+    /// The server's SETTINGS may arrive shortly after the connection is established.
+    pub fn handshake_summary(&self) -> HandshakeSummary {
+        self.conn.handshake_summary()
+    }
+
+    /// Wait for the server's SETTINGS and seed the QPACK dynamic table with `fields`
+    ///
+    /// Resolves once the server has acknowledged the insertions, so that requests sent afterwards
+    /// can refer to these fields, such as an authorization token or user agent, by index without
+    /// risking being blocked. Fields that don't fit in the table the server allows are skipped.
+    pub async fn prewarm(&self, fields: &[HeaderField]) -> Result<(), Error> {
+        let mut closed = self.conn.quic.closed();
+        let mut required = None;
+        futures::future::poll_fn(|cx| {
+            if let Poll::Ready(reason) = Pin::new(&mut closed).poll(cx) {
+                return Poll::Ready(Err(reason.into()));
+            }
+            let mut conn = self.conn.h3.lock().unwrap();
+            conn.poll_prewarm(cx, fields, &mut required)
+        })
+        .await
+    }
+
+    /// Responses pushed by the server, as allowed by `Builder::max_pushes`
+    ///
+    /// A push resolves once its PUSH_PROMISE, which arrives on the stream of a request, and its
+    /// response headers have been received. Promises are read along with the responses they're
+    /// sent with, so those must be read as well.
+    pub fn incoming_pushes(&self) -> IncomingPushes {
+        IncomingPushes(self.conn.clone())
+    }
+
+    /// Whether the server accepts extended CONNECT requests
+    ///
+    /// `None` until the server's SETTINGS have been received.
+    pub fn connect_protocol_enabled(&self) -> Option<bool> {
+        let conn = self.conn.h3.lock().unwrap();
+        conn.inner
+            .remote_settings()
+            .as_ref()
+            .map(|s| s.enable_connect_protocol)
+    }
+
+    /// Open a stream speaking `protocol` through `authority`, with an extended CONNECT request
+    ///
+    /// Fails with `Error::ExtendedConnect` without sending anything unless the server's SETTINGS
+    /// have been received and enable the extended CONNECT protocol, see RFC 8441.
+    pub async fn extended_connect(
+        &self,
+        authority: Authority,
+        protocol: &str,
+    ) -> Result<(RecvResponse, BodyWriter), Error> {
+        match self.connect_protocol_enabled() {
+            None => return Err(Error::ExtendedConnect("server SETTINGS not received yet")),
+            Some(false) => return Err(Error::ExtendedConnect("not enabled by the server")),
+            Some(true) => (),
+        }
+        let protocol = protocol.parse().map_err(Error::InvalidRequest)?;
+        let header = Header::extended_connect(authority, protocol, HeaderMap::new());
+        let permit = self.request_permit().await;
+        let (send, recv) = self.conn.quic.open_bi().await?;
+
+        let stream_id = send.id();
+        let send = SendHeaders::new(header, &self.conn, send, stream_id)?.await?;
+        Ok((
+            RecvResponse::new(
+                FrameDecoder::stream(recv),
+                self.conn.clone(),
+                stream_id,
+                self.allowed_statuses.clone(),
+                permit,
+            ),
+            BodyWriter::new(send, self.conn.clone(), stream_id, false),
+        ))
+    }
+
+    /// Establish a WebTransport session through `authority`
+    ///
+    /// Sends an extended CONNECT request for the `webtransport` protocol, resolving once the
+    /// server has accepted it. Fails with `Error::WebTransport` without sending anything unless
+    /// `Settings::enable_webtransport` is enabled locally, and the server's SETTINGS have been
+    /// received and enable it too. Fails with `Error::UnexpectedStatus` if the server refuses the
+    /// session.
+    pub async fn webtransport(&self, authority: Authority) -> Result<WebTransportSession, Error> {
+        {
+            let conn = self.conn.h3.lock().unwrap();
+            if !conn.inner.local_settings().enable_webtransport {
+                return Err(Error::WebTransport("not enabled locally"));
+            }
+            match conn.inner.remote_settings() {
+                None => return Err(Error::WebTransport("server SETTINGS not received yet")),
+                Some(s) if !s.enable_webtransport => {
+                    return Err(Error::WebTransport("not enabled by the server"))
+                }
+                Some(_) => (),
+            }
+        }
+        let (response, writer) = self.extended_connect(authority, "webtransport").await?;
+        let stream_id = response.stream_id();
+        let (response, reader) = response.await?;
+        if !response.status().is_success() {
+            return Err(Error::UnexpectedStatus {
+                status: response.status(),
+            });
+        }
+        WebTransportSession::new(&self.conn, stream_id, reader, writer)
+    }
+
+    /// Origins the server advertised in ORIGIN frames, see RFC 8336
+    ///
+    /// `None` until an ORIGIN frame is received, in which case the connection is only known to be
+    /// authoritative for the origin it was established for. Frames received later add to the set.
+    pub fn origin_set(&self) -> Option<Vec<String>> {
+        self.conn.h3.lock().unwrap().origins.clone()
+    }
+
+    /// Current congestion control and round-trip time state of the QUIC connection
+    ///
+    /// See `quinn::Connection::path_stats`.
+    pub fn path_stats(&self) -> quinn::PathStats {
+        self.conn.quic.path_stats()
+    }
+
+    /// Snapshot of the connection's QUIC streams, for diagnostics
+    ///
+    /// Requests can be matched with their entries through `RecvResponse::stream_id`.
+    pub fn streams(&self) -> Vec<quinn::StreamInfo> {
+        self.conn.quic.streams()
+    }
+
+    /// Send a request whose body is produced by `body`
+    ///
+    /// Each chunk is sent in its own DATA frame as soon as it is yielded, waiting for the stream to
+    /// accept it before pulling the next one. If `body` yields an error, the request stream is
+    /// reset and the error is returned as `Error::Body`. Resolves once the whole body is sent.
+    pub async fn send_request_stream<S, E>(
+        &self,
+        request: Request<()>,
+        body: S,
+    ) -> Result<RecvResponse, Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let (recv, send) = self.send_request(request).await?;
+        send.write_stream(body).await?;
+        Ok(recv)
+    }
+
+    /// Coalesce requests sent until the returned guard is dropped into as few packets as possible
+    ///
+    /// See `quinn::Connection::batch`.
+    pub fn batch(&self) -> quinn::Batch {
+        self.conn.quic.batch()
     }
 
     pub fn close(self) {
+        self.close_with(ErrorCode::NO_ERROR, "Connection closed");
+    }
+
+    /// Wait for a slot under `Builder::max_local_concurrent_requests`, if set
+    async fn request_permit(&self) -> Option<OwnedSemaphorePermit> {
+        match self.requests {
+            Some(ref slots) => Some(slots.clone().acquire_owned().await),
+            None => None,
+        }
+    }
+
+    /// Close the connection immediately, sending `code` and `reason` to the server
+    ///
+    /// The server sees them through `Error::close_reason`.
+    pub fn close_with(self, code: ErrorCode, reason: &str) {
         trace!("connection closed by user");
-        self.0
-            .quic
-            .close(ErrorCode::NO_ERROR.into(), b"Connection closed");
+        self.conn.quic.close(code.into(), reason.as_bytes());
+    }
+}
+
+impl Connection {
+    /// Another handle to the connection, which doesn't own its drivers
+    #[cfg(feature = "runtime-tokio")]
+    fn handle(&self) -> Self {
+        Connection {
+            conn: self.conn.clone(),
+            driver: None,
+            allowed_statuses: self.allowed_statuses.clone(),
+            requests: self.requests.clone(),
+            alt_svc: self.alt_svc.clone(),
+        }
+    }
+
+    /// Resolves once the connection has been closed
+    #[cfg(test)]
+    pub(crate) async fn closed(&self) {
+        self.conn.quic.closed().await;
+    }
+
+    /// Whether new requests can be sent: the connection is open and the server hasn't sent GOAWAY
+    #[cfg(feature = "runtime-tokio")]
+    fn is_reusable(&self) -> bool {
+        self.conn.quic.closed().now_or_never().is_none()
+            && !self.conn.h3.lock().unwrap().inner.is_closing()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(driver) = self.driver.take() {
+            driver.abort();
+        }
     }
 }
 
 pub struct Connecting {
-    connecting: quinn::Connecting,
-    settings: Settings,
+    /// Only taken by `into_0rtt`, which consumes `self`
+    connecting: Option<quinn::Connecting>,
+    options: Arc<ConnectionOptions>,
 }
 
-impl Future for Connecting {
-    type Output = Result<(quinn::ConnectionDriver, ConnectionDriver, Connection), Error>;
+impl Connecting {
+    /// Whether a cached session ticket allows sending requests before the handshake completes
+    pub fn has_0rtt(&self) -> bool {
+        self.connecting.as_ref().unwrap().has_0rtt()
+    }
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    /// Use the connection before the handshake completes, sending requests as 0-RTT data
+    ///
+    /// Only possible when `has_0rtt`, `self` being returned unmodified otherwise. The connection is
+    /// then set up as when `Connecting` resolves, which fails if the HTTP/3 settings are invalid.
+    ///
+    /// `ZeroRttAccepted` resolves once the handshake completes. If it resolves to `false`, the
+    /// server discarded the early data: requests sent until then fail with
+    /// `Error::ZeroRttRejected`, and may be sent again on the same connection.
+    ///
+    /// # Security
+    ///
+    /// 0-RTT data can be replayed by an attacker, so only idempotent requests should be sent
+    /// before `ZeroRttAccepted` resolves. See `quinn::Connecting::into_0rtt`.
+    pub fn into_0rtt(mut self) -> Result<Result<ZeroRttConnection, Error>, Self> {
+        let (new_conn, accepted) = match self.connecting.take().unwrap().into_0rtt() {
+            Ok(x) => x,
+            Err(connecting) => {
+                self.connecting = Some(connecting);
+                return Err(self);
+            }
+        };
+        Ok(self
+            .establish(new_conn)
+            .map(|(quic_driver, driver, connection)| {
+                let accepted = ZeroRttAccepted(driver.0.enter_0rtt(accepted));
+                ZeroRttConnection {
+                    quic_driver,
+                    driver,
+                    connection,
+                    accepted,
+                }
+            }))
+    }
+
+    fn establish(
+        &self,
+        new_conn: quinn::NewConnection,
+    ) -> Result<(quinn::ConnectionDriver, ConnectionDriver, Connection), Error> {
         let quinn::NewConnection {
             driver,
             connection,
             uni_streams,
             bi_streams,
+            datagrams,
             ..
-        } = ready!(Pin::new(&mut self.connecting).poll(cx))?;
+        } = new_conn;
         let conn_ref = ConnectionRef::new(
             connection,
             Side::Client,
             uni_streams,
             bi_streams,
-            self.settings.clone(),
+            datagrams,
+            self.options.settings.clone(),
+            self.options.settings_grace,
+            None,
         )?;
-        Poll::Ready(Ok((
+        if self.options.max_pushes > 0 {
+            let mut conn = conn_ref.h3.lock().unwrap();
+            conn.inner.send_max_push_id(self.options.max_pushes - 1);
+        }
+        Ok((
             driver,
             ConnectionDriver(conn_ref.clone()),
-            Connection(conn_ref),
-        )))
+            Connection {
+                conn: conn_ref,
+                driver: None,
+                allowed_statuses: self.options.allowed_statuses.clone(),
+                requests: self
+                    .options
+                    .max_local_concurrent_requests
+                    .map(|max| Arc::new(Semaphore::new(max))),
+                alt_svc: self.options.alt_svc.clone(),
+            },
+        ))
+    }
+}
+
+/// A connection in use before its handshake completes, from `Connecting::into_0rtt`
+pub struct ZeroRttConnection {
+    /// The future handling I/O on the QUIC connection
+    pub quic_driver: quinn::ConnectionDriver,
+    /// The future handling HTTP/3 control streams on the connection
+    pub driver: ConnectionDriver,
+    /// Handle for sending requests
+    pub connection: Connection,
+    /// Resolves to whether the server accepted the requests sent so far
+    pub accepted: ZeroRttAccepted,
+}
+
+/// Future produced by `Connecting::into_0rtt`
+///
+/// Resolves to whether the server accepted the 0-RTT data, once the handshake completes.
+pub struct ZeroRttAccepted(oneshot::Receiver<bool>);
+
+impl Future for ZeroRttAccepted {
+    type Output = bool;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // The driver going away before the handshake completes means no data was accepted
+        Pin::new(&mut self.0).poll(cx).map(|x| x.unwrap_or(false))
+    }
+}
+
+impl Future for Connecting {
+    type Output = Result<(quinn::ConnectionDriver, ConnectionDriver, Connection), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let connecting = self.connecting.as_mut().unwrap();
+        let new_conn = match ready!(Pin::new(connecting).poll(cx)) {
+            Ok(x) => x,
+            Err(quinn::ConnectionError::ConnectionClosed(ref close))
+                if close.error_code == TransportErrorCode::NO_APPLICATION_PROTOCOL =>
+            {
+                return Poll::Ready(Err(Error::NoCommonAlpn {
+                    offered: vec![String::from_utf8_lossy(crate::ALPN).into()],
+                    reason: String::from_utf8_lossy(&close.reason).into(),
+                }));
+            }
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+        Poll::Ready(self.establish(new_conn))
     }
 }
 
@@ -192,6 +1403,11 @@ pub struct RecvResponse {
     conn: ConnectionRef,
     stream_id: StreamId,
     recv: Option<FrameStream>,
+    allowed_statuses: Option<Arc<[StatusCode]>>,
+    /// Slot under `Builder::max_local_concurrent_requests`, handed on to the response body
+    permit: Option<OwnedSemaphorePermit>,
+    /// Cache to record `Alt-Svc` in, and the request URI they're advertised for
+    alt_svc: Option<(Arc<Mutex<AltSvcCache>>, http::Uri)>,
 }
 
 enum RecvResponseState {
@@ -201,19 +1417,175 @@ enum RecvResponseState {
 }
 
 impl RecvResponse {
-    pub(crate) fn new(recv: FrameStream, conn: ConnectionRef, stream_id: StreamId) -> Self {
+    pub(crate) fn new(
+        recv: FrameStream,
+        conn: ConnectionRef,
+        stream_id: StreamId,
+        allowed_statuses: Option<Arc<[StatusCode]>>,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Self {
         Self {
             conn,
             stream_id,
             recv: None,
             state: RecvResponseState::Receiving(recv),
+            allowed_statuses,
+            permit,
+            alt_svc: None,
         }
     }
 
+    /// Record the `Alt-Svc` of the final response in `cache`, for the request URI
+    fn alt_svc(mut self, alt_svc: Option<(Arc<Mutex<AltSvcCache>>, http::Uri)>) -> Self {
+        self.alt_svc = alt_svc;
+        self
+    }
+
+    /// ID of the QUIC stream carrying the request
+    pub fn stream_id(&self) -> StreamId {
+        self.stream_id
+    }
+
+    /// Open the request's HTTP/3 datagram flow
+    ///
+    /// Fails with `Error::Datagram` unless both ends enabled `Settings::h3_datagram` and the
+    /// server's SETTINGS have been received, or if the flow is already open.
+    pub fn datagram_flow(&self) -> Result<DatagramFlow, Error> {
+        DatagramFlow::new(&self.conn, self.stream_id)
+    }
+
+    /// Ask the server to schedule the response with a different `priority`
+    ///
+    /// Sends a PRIORITY_UPDATE frame on the control stream; it overrides the `priority` header of
+    /// the request, and any previous update. Servers are free to ignore it.
+    pub fn set_priority(&self, priority: Priority) {
+        let mut conn = self.conn.h3.lock().unwrap();
+        conn.inner.send_priority_update(self.stream_id, priority);
+        conn.wake();
+    }
+
+    /// Abort the request, resetting both directions of its stream
+    ///
+    /// Dropping a `RecvResponse` before the response headers are received has the same effect.
     pub fn cancel(self) {
-        if let RecvResponseState::Receiving(recv) = self.state {
-            recv.reset(ErrorCode::REQUEST_CANCELLED);
+        drop(self);
+    }
+
+    /// Abort the request, resetting both directions of its stream with `code`
+    ///
+    /// Has no effect once the response headers have been received.
+    pub fn reset(mut self, code: ErrorCode) {
+        self.reset_with(code);
+    }
+
+    fn is_allowed(&self, status: StatusCode) -> bool {
+        match self.allowed_statuses {
+            Some(ref allowed) => allowed.contains(&status),
+            None => true,
+        }
+    }
+
+    fn reset_with(&mut self, code: ErrorCode) {
+        let recv = match mem::replace(&mut self.state, RecvResponseState::Finished) {
+            RecvResponseState::Finished => return,
+            RecvResponseState::Receiving(recv) => Some(recv),
+            RecvResponseState::Decoding(_) => self.recv.take(),
+        };
+        if let Some(recv) = recv {
+            recv.reset(code);
         }
+        // The request body may still be written through a `BodyWriter`, which holds the send side
+        self.conn.quic.reset_stream(self.stream_id, code.into());
+    }
+}
+
+impl Drop for RecvResponse {
+    fn drop(&mut self) {
+        self.reset_with(ErrorCode::REQUEST_CANCELLED);
+    }
+}
+
+/// Sole owner of both halves of a request in flight
+///
+/// Dropping the handle resets whatever hasn't completed: the stream is reset if the response
+/// headers haven't been received, and the request body is reset unless it was closed. Use `split`
+/// to manage the halves separately instead.
+pub struct RequestHandle {
+    // The response is only taken out by methods consuming the handle, the body also once finished
+    response: Option<RecvResponse>,
+    body: Option<BodyWriter>,
+}
+
+impl RequestHandle {
+    pub fn new(response: RecvResponse, body: BodyWriter) -> Self {
+        Self {
+            response: Some(response),
+            body: Some(body),
+        }
+    }
+
+    /// ID of the QUIC stream carrying the request
+    pub fn stream_id(&self) -> StreamId {
+        self.response.as_ref().unwrap().stream_id()
+    }
+
+    /// The future resolving to the response headers and body
+    pub fn response(&mut self) -> &mut RecvResponse {
+        self.response.as_mut().unwrap()
+    }
+
+    /// The writer for the request body
+    ///
+    /// Panics if the body has been finished with `close_body` or `send_trailers`.
+    pub fn body(&mut self) -> &mut BodyWriter {
+        self.body.as_mut().expect("request body already finished")
+    }
+
+    /// Finish the request body, as `BodyWriter::close` does
+    pub async fn close_body(&mut self) -> Result<(), Error> {
+        self.take_body()?.close().await
+    }
+
+    /// Finish the request body with trailing headers, as `BodyWriter::send_trailers` does
+    pub async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Error> {
+        self.take_body()?.send_trailers(trailers).await
+    }
+
+    fn take_body(&mut self) -> Result<BodyWriter, Error> {
+        self.body
+            .take()
+            .ok_or_else(|| Error::internal("request body already finished"))
+    }
+
+    /// Abort the request, resetting both directions of its stream with `code`
+    pub fn cancel(mut self, code: ErrorCode) {
+        if let Some(body) = self.body.take() {
+            body.reset(code);
+        }
+        self.response.take().unwrap().reset(code);
+    }
+
+    /// Give up ownership of the two halves, which then behave as if returned by `send_request`
+    ///
+    /// Panics if the body has already been finished.
+    pub fn split(mut self) -> (RecvResponse, BodyWriter) {
+        let body = self.body.take().expect("request body already finished");
+        (self.response.take().unwrap(), body)
+    }
+}
+
+impl Drop for RequestHandle {
+    fn drop(&mut self) {
+        if let Some(body) = self.body.take() {
+            body.reset(ErrorCode::REQUEST_CANCELLED);
+        }
+        // Dropping `response` resets the stream if the response hasn't been received
+    }
+}
+
+impl From<(RecvResponse, BodyWriter)> for RequestHandle {
+    fn from((response, body): (RecvResponse, BodyWriter)) -> Self {
+        Self::new(response, body)
     }
 }
 
@@ -234,6 +1606,11 @@ impl Future for RecvResponse {
                         Some(Err(e)) => return Poll::Ready(Err(e.into())),
                         Some(Ok(f)) => match f {
                             HttpFrame::Reserved => (),
+                            HttpFrame::PushPromise(p) => {
+                                if let Err(e) = self.conn.on_push_promise(self.stream_id, p) {
+                                    return Poll::Ready(Err(e));
+                                }
+                            }
                             HttpFrame::Headers(h) => {
                                 let decode =
                                     DecodeHeaders::new(h, self.conn.clone(), self.stream_id);
@@ -262,7 +1639,20 @@ impl Future for RecvResponse {
                     let response = build_response(headers);
                     match response {
                         Err(e) => return Poll::Ready(Err(e)),
+                        // Interim responses aren't surfaced, keep waiting for the final one
+                        Ok(ref r) if r.status().is_informational() => {
+                            let recv = self.recv.take().unwrap();
+                            self.state = RecvResponseState::Receiving(recv);
+                        }
                         Ok(r) => {
+                            if let Some((cache, uri)) = self.alt_svc.take() {
+                                cache.lock().unwrap().update(&uri, &r);
+                            }
+                            if !self.is_allowed(r.status()) {
+                                let status = r.status();
+                                self.reset_with(ErrorCode::REQUEST_CANCELLED);
+                                return Poll::Ready(Err(Error::UnexpectedStatus { status }));
+                            }
                             self.state = RecvResponseState::Finished;
                             return Poll::Ready(Ok((
                                 r,
@@ -271,7 +1661,8 @@ impl Future for RecvResponse {
                                     self.conn.clone(),
                                     self.stream_id,
                                     true,
-                                ),
+                                )
+                                .permit(self.permit.take()),
                             )));
                         }
                     }
@@ -281,6 +1672,149 @@ impl Future for RecvResponse {
     }
 }
 
+/// Stream of the responses pushed by the server on a connection
+///
+/// Ends once the connection is closed.
+pub struct IncomingPushes(ConnectionRef);
+
+impl Stream for IncomingPushes {
+    type Item = RecvPush;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let (push_id, recv) = match ready!(self.0.h3.lock().unwrap().poll_push(cx)) {
+            Some(x) => x,
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(RecvPush {
+            push_id,
+            stream_id: recv.get_ref().id(),
+            conn: self.0.clone(),
+            state: RecvPushState::Promise,
+            recv: Some(recv),
+            request: None,
+        }))
+    }
+}
+
+/// A pushed response, resolving to the promised request, the response and its body
+///
+/// Dropping it before it resolves cancels the push.
+pub struct RecvPush {
+    push_id: u64,
+    stream_id: StreamId,
+    conn: ConnectionRef,
+    state: RecvPushState,
+    recv: Option<FrameStream>,
+    request: Option<Request<()>>,
+}
+
+enum RecvPushState {
+    Promise,
+    DecodingPromise(DecodeHeaders),
+    Receiving,
+    Decoding(DecodeHeaders),
+    Finished,
+}
+
+impl RecvPush {
+    /// ID the server assigned to the push
+    pub fn push_id(&self) -> u64 {
+        self.push_id
+    }
+}
+
+impl Future for RecvPush {
+    type Output = Result<(Request<()>, Response<()>, BodyReader), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            match self.state {
+                RecvPushState::Finished => {
+                    return Poll::Ready(Err(Error::internal("recv push polled after finish")));
+                }
+                RecvPushState::Promise => {
+                    let push_id = self.push_id;
+                    let promise = ready!(self.conn.h3.lock().unwrap().poll_promise(cx, push_id));
+                    let (stream_id, frame) = match promise {
+                        Some(x) => x,
+                        None => {
+                            self.state = RecvPushState::Finished;
+                            return Poll::Ready(Err(Error::Aborted));
+                        }
+                    };
+                    let decode = DecodeHeaders::new(frame, self.conn.clone(), stream_id);
+                    self.state = RecvPushState::DecodingPromise(decode);
+                }
+                RecvPushState::DecodingPromise(ref mut decode) => {
+                    let headers = ready!(Pin::new(decode).poll(cx))?;
+                    self.request = Some(build_request(headers)?);
+                    self.state = RecvPushState::Receiving;
+                }
+                RecvPushState::Receiving => {
+                    match ready!(Pin::new(self.recv.as_mut().unwrap()).poll_next(cx)) {
+                        None => {
+                            self.state = RecvPushState::Finished;
+                            return Poll::Ready(Err(Error::peer("received an empty push")));
+                        }
+                        Some(Err(e)) => {
+                            self.state = RecvPushState::Finished;
+                            return Poll::Ready(Err(e.into()));
+                        }
+                        Some(Ok(HttpFrame::Reserved)) => (),
+                        Some(Ok(HttpFrame::Headers(h))) => {
+                            let decode = DecodeHeaders::new(h, self.conn.clone(), self.stream_id);
+                            self.state = RecvPushState::Decoding(decode);
+                        }
+                        Some(Ok(_)) => {
+                            self.state = RecvPushState::Finished;
+                            if let Some(recv) = self.recv.take() {
+                                recv.reset(ErrorCode::FRAME_UNEXPECTED);
+                            }
+                            return Poll::Ready(Err(Error::peer("first frame is not headers")));
+                        }
+                    }
+                }
+                RecvPushState::Decoding(ref mut decode) => {
+                    let headers = ready!(Pin::new(decode).poll(cx))?;
+                    let response = build_response(headers)?;
+                    if response.status().is_informational() {
+                        self.state = RecvPushState::Receiving;
+                        continue;
+                    }
+                    self.state = RecvPushState::Finished;
+                    let body = BodyReader::new(
+                        self.recv.take().unwrap(),
+                        self.conn.clone(),
+                        self.stream_id,
+                        false,
+                    );
+                    return Poll::Ready(Ok((self.request.take().unwrap(), response, body)));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RecvPush {
+    fn drop(&mut self) {
+        if let Some(recv) = self.recv.take() {
+            recv.reset(ErrorCode::REQUEST_CANCELLED);
+        }
+    }
+}
+
+fn build_request(header: Header) -> Result<Request<()>, Error> {
+    let (method, uri, headers) = header.into_request_parts()?;
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .version(http::version::Version::HTTP_3)
+        .body(())
+        .unwrap();
+    *request.headers_mut() = headers;
+    Ok(request)
+}
+
 fn build_response(header: Header) -> Result<Response<()>, Error> {
     let (status, headers) = header.into_response_parts()?;
     let mut response = Response::builder()
@@ -6,26 +6,30 @@ use std::{
     pin::Pin,
     sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
+    time::Duration,
 };
 
-use bytes::BytesMut;
-use futures::{io::AsyncRead, Stream};
+use bytes::{Bytes, BytesMut};
+use futures::{channel::oneshot, io::AsyncRead, Stream};
 use quinn::{IncomingBiStreams, IncomingUniStreams, RecvStream, SendStream};
-use quinn_proto::{Side, StreamId};
+use quinn_proto::{coding::BufExt, Dir, Side, StreamId};
+use tokio::time::{delay_for, Delay};
 use tracing::{trace, trace_span, warn};
 
 use crate::{
-    frame::{self, FrameStream},
+    frame::{self, FrameDecoder, FrameStream},
+    priority::Priority,
     proto::{
         self,
         connection::{
             Connection, DecodeResult, Error as ProtoError, Error as ConnectionError,
             PendingStreamType,
         },
-        frame::{HeadersFrame, HttpFrame},
+        frame::{HeadersFrame, HttpFrame, PriorityUpdateFrame, PushPromiseFrame},
         ErrorCode, StreamType,
     },
-    streams::{NewUni, RecvUni, SendUni},
+    qpack::HeaderField,
+    streams::{NewBi, NewUni, RecvBi, RecvUni, Reset, SendUni},
     Error, Settings,
 };
 
@@ -35,18 +39,81 @@ impl Future for ConnectionDriver {
     type Output = Result<(), Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let res = self.0.h3.lock().unwrap().drive(cx);
-        match res {
-            Ok(false) => Poll::Pending,
-            Ok(true) => Poll::Ready(Ok(())),
+        let mut h3 = self.0.h3.lock().unwrap();
+        let result = match h3.drive(cx) {
+            Ok(false) => return Poll::Pending,
+            Ok(true) => {
+                if let Some(reason) = h3.drain_reason() {
+                    self.0
+                        .quic
+                        .close(ErrorCode::NO_ERROR.into(), reason.as_bytes());
+                }
+                Ok(())
+            }
             Err(DriverError(err, code, msg)) => {
                 self.0.quic.close(code.into(), msg.as_bytes());
-                Poll::Ready(Err(err))
+                Err(err)
             }
+        };
+        h3.finished = true;
+        wake_all(&mut h3.finished_waiters);
+        for flow in h3.flows.values_mut() {
+            if let Some(w) = flow.waker.take() {
+                w.wake();
+            }
+        }
+        for session in h3.sessions.values_mut() {
+            session.wake();
+        }
+        if let Some(t) = h3.pushes_task.take() {
+            t.wake();
         }
+        wake_all(&mut h3.promise_waiters);
+        Poll::Ready(result)
     }
 }
 
+/// How long to wait for the peer's SETTINGS, and what to do if they don't arrive in time
+#[derive(Clone, Copy)]
+pub(crate) struct SettingsGrace {
+    pub period: Duration,
+    pub strict: bool,
+}
+
+/// How long a connection may live before it is shut down gracefully
+#[derive(Clone, Copy)]
+pub(crate) struct Lifetime {
+    pub max: Duration,
+    /// How long requests in flight are given to complete once the lifetime is reached
+    pub grace: Duration,
+}
+
+enum LifetimeState {
+    Open(Delay, Duration),
+    /// Waiting for requests in flight, until the delay elapses, before closing with the reason
+    Draining(Delay, &'static str),
+}
+
+/// What was negotiated while establishing a connection, for logging and debugging interop
+#[derive(Debug, Clone)]
+pub struct HandshakeSummary {
+    /// The application protocol negotiated through ALPN
+    pub alpn: Option<String>,
+    /// The TLS cipher suite securing the connection
+    pub cipher_suite: Option<String>,
+    /// The TLS version, always 1.3 once known
+    pub tls_version: Option<rustls::ProtocolVersion>,
+    /// The QUIC version in use
+    pub quic_version: u32,
+    /// The SETTINGS received on the peer's control stream, or `None` if they haven't arrived yet
+    pub peer_settings: Option<Settings>,
+    /// Whether 0-RTT was attempted, and if so whether it was accepted
+    ///
+    /// A 0-RTT attempt implies the TLS session was resumed. Resumption without early data can't be
+    /// observed separately.
+    pub early_data: quinn::EarlyDataStatus,
+}
+
 #[derive(Clone)]
 pub(crate) struct ConnectionRef {
     pub h3: Arc<Mutex<ConnectionInner>>,
@@ -54,12 +121,16 @@ pub(crate) struct ConnectionRef {
 }
 
 impl ConnectionRef {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         quic: quinn::Connection,
         side: Side,
         uni_streams: IncomingUniStreams,
         bi_streams: IncomingBiStreams,
+        datagrams: quinn::Datagrams,
         settings: Settings,
+        settings_grace: Option<SettingsGrace>,
+        lifetime: Option<Lifetime>,
     ) -> Result<Self, ProtoError> {
         Ok(Self {
             quic: quic.clone(),
@@ -69,6 +140,7 @@ impl ConnectionRef {
                 incoming_bi: bi_streams,
                 incoming_uni: uni_streams,
                 pending_uni: VecDeque::with_capacity(3),
+                pending_bi: Vec::new(),
                 inner: Connection::with_settings(settings)?,
                 requests: VecDeque::with_capacity(16),
                 requests_task: None,
@@ -81,40 +153,258 @@ impl ConnectionRef {
                     SendUni::new(StreamType::ENCODER, quic.open_uni()),
                     SendUni::new(StreamType::DECODER, quic.open_uni()),
                 ],
+                settings_grace: settings_grace.map(|x| (delay_for(x.period), x.strict)),
+                lifetime: lifetime.map(|x| LifetimeState::Open(delay_for(x.max), x.grace)),
+                finishing: Vec::new(),
+                origins: None,
+                prewarm_waiters: Vec::new(),
+                pushes: VecDeque::new(),
+                pushes_task: None,
+                promises: HashMap::new(),
+                promise_waiters: Vec::new(),
+                finished: false,
+                finished_waiters: Vec::new(),
+                datagrams,
+                flows: HashMap::new(),
+                sessions: HashMap::new(),
+                priorities: HashMap::new(),
+                quic: quic.clone(),
+                zero_rtt: None,
             })),
         })
     }
+
+    /// Record a PUSH_PROMISE frame received on the request stream `stream_id`
+    ///
+    /// Closes the connection if the server isn't allowed to send it.
+    pub fn on_push_promise(
+        &self,
+        stream_id: StreamId,
+        frame: PushPromiseFrame,
+    ) -> Result<(), Error> {
+        let mut conn = self.h3.lock().unwrap();
+        let (code, reason) = if conn.side == Side::Server {
+            (ErrorCode::FRAME_UNEXPECTED, "PUSH_PROMISE from client")
+        } else if !conn.inner.push_allowed(frame.push_id) {
+            (ErrorCode::ID_ERROR, "push ID exceeds MAX_PUSH_ID")
+        } else {
+            trace!("Got PushPromise({}) on {:?}", frame.push_id, stream_id);
+            let headers = HeadersFrame {
+                encoded: frame.encoded,
+            };
+            conn.promises.insert(frame.push_id, (stream_id, headers));
+            wake_all(&mut conn.promise_waiters);
+            return Ok(());
+        };
+        self.quic.close(code.into(), reason.as_bytes());
+        Err(Error::peer(reason))
+    }
+
+    /// Send on the connection before the handshake completes, `accepted` telling whether the
+    /// server accepts 0-RTT
+    ///
+    /// The returned receiver is told once the control and QPACK streams have been sent again, if
+    /// needed.
+    pub fn enter_0rtt(&self, accepted: quinn::ZeroRttAccepted) -> oneshot::Receiver<bool> {
+        let mut conn = self.h3.lock().unwrap();
+        for send in conn.send_unis.iter_mut() {
+            send.record();
+        }
+        let (send, recv) = oneshot::channel();
+        conn.zero_rtt = Some((accepted, send));
+        recv
+    }
+
+    pub fn handshake_summary(&self) -> HandshakeSummary {
+        HandshakeSummary {
+            alpn: self
+                .quic
+                .protocol()
+                .map(|x| String::from_utf8_lossy(&x).into_owned()),
+            cipher_suite: self.quic.cipher_suite(),
+            tls_version: self.quic.tls_version(),
+            quic_version: self.quic.quic_version(),
+            peer_settings: self.h3.lock().unwrap().inner.remote_settings().clone(),
+            early_data: self.quic.early_data_status(),
+        }
+    }
 }
 
 pub(crate) struct ConnectionInner {
     pub inner: Connection,
-    pub requests: VecDeque<(SendStream, RecvStream)>,
+    pub requests: VecDeque<(SendStream, FrameStream)>,
     pub requests_task: Option<Waker>,
     side: Side,
     driver: Option<Waker>,
     incoming_bi: IncomingBiStreams,
     incoming_uni: IncomingUniStreams,
     pending_uni: VecDeque<Option<RecvUni>>,
+    /// Bidirectional streams being told apart, when WebTransport is enabled
+    pending_bi: Vec<RecvBi>,
     recv_control: Option<FrameStream>,
     recv_encoder: Option<(RecvStream, BytesMut)>,
     recv_decoder: Option<(RecvStream, BytesMut)>,
     blocked_streams: BTreeMap<usize, HashMap<StreamId, Waker>>,
     send_unis: [SendUni; 3],
+    settings_grace: Option<(Delay, bool)>,
+    lifetime: Option<LifetimeState>,
+    /// Responses whose writer was dropped, kept until the peer has acknowledged them
+    finishing: Vec<SendStream>,
+    /// Origins advertised by the server in ORIGIN frames, once any was received
+    pub origins: Option<Vec<String>>,
+    /// Tasks waiting for the peer's SETTINGS or for it to acknowledge dynamic table insertions
+    prewarm_waiters: Vec<Waker>,
+    /// Push streams received by the client, not yet taken by the application
+    pushes: VecDeque<(u64, FrameStream)>,
+    pushes_task: Option<Waker>,
+    /// PUSH_PROMISE frames received by the client, with the stream they arrived on, by push ID
+    promises: HashMap<u64, (StreamId, HeadersFrame)>,
+    promise_waiters: Vec<Waker>,
+    /// Whether the driver has completed
+    finished: bool,
+    finished_waiters: Vec<Waker>,
+    datagrams: quinn::Datagrams,
+    /// Datagram flows open through a `DatagramFlow`, by flow ID
+    flows: HashMap<u64, Flow>,
+    /// WebTransport sessions, by session ID, including those streams arrived for before they
+    /// were established
+    sessions: HashMap<u64, Session>,
+    /// Priorities the client signalled in PRIORITY_UPDATE frames, by request stream
+    pub priorities: HashMap<StreamId, Priority>,
+    quic: quinn::Connection,
+    /// Whether the server accepts the 0-RTT data, pending, and who to tell once the critical
+    /// streams have been replayed if needed
+    zero_rtt: Option<(quinn::ZeroRttAccepted, oneshot::Sender<bool>)>,
+}
+
+#[derive(Default)]
+struct Flow {
+    received: VecDeque<Bytes>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct Session {
+    /// Whether a `WebTransportSession` was established, streams being buffered in the meantime
+    open: bool,
+    closed: bool,
+    bi: VecDeque<(SendStream, RecvStream)>,
+    uni: VecDeque<RecvStream>,
+    bi_waker: Option<Waker>,
+    uni_waker: Option<Waker>,
+}
+
+impl Session {
+    fn wake(&mut self) {
+        if let Some(w) = self.bi_waker.take() {
+            w.wake();
+        }
+        if let Some(w) = self.uni_waker.take() {
+            w.wake();
+        }
+    }
 }
 
 impl ConnectionInner {
     fn drive(&mut self, cx: &mut Context) -> Result<bool, DriverError> {
+        self.poll_zero_rtt(cx);
         self.poll_incoming_uni(cx)?;
         self.poll_send(cx)?;
         self.poll_recv_control(cx)?;
+        self.poll_settings_grace(cx)?;
+        let grace_elapsed = self.poll_lifetime(cx);
+        self.poll_finishing(cx);
         self.poll_recv_encoder(cx)?;
         self.poll_recv_decoder(cx)?;
         self.poll_incoming_bi(cx)?;
+        self.poll_resolve_bi(cx)?;
+        self.poll_datagrams(cx)?;
         self.poll_send(cx)?;
 
         self.reset_waker(cx);
 
-        Ok(self.inner.is_closing() && self.inner.requests_in_flight() == 0)
+        Ok(grace_elapsed
+            || self.inner.is_closing()
+                && self.inner.requests_in_flight() == 0
+                && self.finishing.is_empty())
+    }
+
+    /// Once the handshake completes, replay the critical streams if the server rejected 0-RTT
+    fn poll_zero_rtt(&mut self, cx: &mut Context) {
+        let accepted = match self.zero_rtt {
+            None => return,
+            Some((ref mut accepted, _)) => match Pin::new(accepted).poll(cx) {
+                Poll::Pending => return,
+                Poll::Ready(x) => x,
+            },
+        };
+        trace!("0-RTT accepted: {}", accepted);
+        for i in 0..self.send_unis.len() {
+            if accepted {
+                self.send_unis[i].confirm();
+            } else {
+                self.send_unis[i].replay(self.quic.open_uni());
+            }
+        }
+        let (_, send) = self.zero_rtt.take().unwrap();
+        let _ = send.send(accepted);
+    }
+
+    /// Track delivery of a response that is otherwise complete
+    ///
+    /// This keeps a graceful shutdown from closing the connection before the peer has received it.
+    pub fn finish_response(&mut self, send: SendStream) {
+        self.finishing.push(send);
+        self.wake();
+    }
+
+    fn poll_finishing(&mut self, cx: &mut Context) {
+        let mut i = 0;
+        while i < self.finishing.len() {
+            match Pin::new(&mut self.finishing[i].finish()).poll(cx) {
+                Poll::Pending => i += 1,
+                Poll::Ready(_) => {
+                    self.finishing.swap_remove(i);
+                }
+            }
+        }
+    }
+
+    /// Why the connection is being shut down gracefully, if it is
+    fn drain_reason(&self) -> Option<&'static str> {
+        match self.lifetime {
+            Some(LifetimeState::Draining(_, reason)) => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// Send GOAWAY, refusing new requests, and close once the requests in flight have completed
+    ///
+    /// The connection is closed after `grace` at the latest. Has no effect if the connection is
+    /// already shutting down.
+    pub fn shutdown(&mut self, grace: Duration) {
+        if self.drain_reason().is_some() {
+            return;
+        }
+        trace!("shutting down, going away");
+        self.inner.go_away();
+        self.lifetime = Some(LifetimeState::Draining(delay_for(grace), "server shutdown"));
+        self.wake();
+    }
+
+    /// Ready once the driver has completed
+    pub fn poll_finished(&mut self, cx: &mut Context) -> Poll<()> {
+        if self.finished {
+            return Poll::Ready(());
+        }
+        if !self
+            .finished_waiters
+            .iter()
+            .any(|w| w.will_wake(cx.waker()))
+        {
+            self.finished_waiters.push(cx.waker().clone());
+        }
+        Poll::Pending
     }
 
     pub fn wake(&mut self) {
@@ -123,6 +413,247 @@ impl ConnectionInner {
         }
     }
 
+    /// Seed the encoder's dynamic table with `fields` once the peer's SETTINGS are known
+    ///
+    /// Ready once the peer has acknowledged the insertions. `required` holds the insert count to
+    /// wait for between calls.
+    pub fn poll_prewarm(
+        &mut self,
+        cx: &mut Context,
+        fields: &[HeaderField],
+        required: &mut Option<usize>,
+    ) -> Poll<Result<(), Error>> {
+        if self.inner.remote_settings().is_some() {
+            let count = match *required {
+                Some(count) => count,
+                None => {
+                    let count = self.inner.prefill(fields)?;
+                    *required = Some(count);
+                    self.wake(); // flush the encoder stream
+                    count
+                }
+            };
+            if self.inner.inserts_acknowledged() >= count {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        if !self.prewarm_waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            self.prewarm_waiters.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// Take the next push stream received, or `None` once the connection is closed
+    pub fn poll_push(&mut self, cx: &mut Context) -> Poll<Option<(u64, FrameStream)>> {
+        if let Some(push) = self.pushes.pop_front() {
+            return Poll::Ready(Some(push));
+        }
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        self.pushes_task = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Take the PUSH_PROMISE for `push_id`, waiting for it to be received otherwise
+    ///
+    /// Resolves to `None` if the connection is closed first.
+    pub fn poll_promise(
+        &mut self,
+        cx: &mut Context,
+        push_id: u64,
+    ) -> Poll<Option<(StreamId, HeadersFrame)>> {
+        if let Some(promise) = self.promises.remove(&push_id) {
+            return Poll::Ready(Some(promise));
+        }
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        if !self.promise_waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            self.promise_waiters.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// Start buffering datagrams received on `flow_id`
+    pub fn open_flow(&mut self, flow_id: u64) -> Result<(), Error> {
+        if !self.inner.local_settings().h3_datagram {
+            return Err(Error::Datagram("not enabled locally"));
+        }
+        match self.inner.remote_settings() {
+            None => return Err(Error::Datagram("peer SETTINGS not received yet")),
+            Some(s) if !s.h3_datagram => return Err(Error::Datagram("not enabled by the peer")),
+            Some(_) => (),
+        }
+        if self.flows.contains_key(&flow_id) {
+            return Err(Error::Datagram("flow already open"));
+        }
+        self.flows.insert(flow_id, Flow::default());
+        Ok(())
+    }
+
+    pub fn close_flow(&mut self, flow_id: u64) {
+        self.flows.remove(&flow_id);
+    }
+
+    /// Take the next datagram received on `flow_id`, or `None` once the connection is closed
+    pub fn poll_flow(&mut self, cx: &mut Context, flow_id: u64) -> Poll<Option<Bytes>> {
+        let finished = self.finished;
+        let flow = match self.flows.get_mut(&flow_id) {
+            Some(flow) => flow,
+            None => return Poll::Ready(None),
+        };
+        if let Some(datagram) = flow.received.pop_front() {
+            return Poll::Ready(Some(datagram));
+        }
+        if finished {
+            return Poll::Ready(None);
+        }
+        flow.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Deliver the streams of the WebTransport session established on `session_id`
+    pub fn open_session(&mut self, session_id: u64) -> Result<(), Error> {
+        let session = self.sessions.entry(session_id).or_default();
+        if session.open || session.closed {
+            return Err(Error::WebTransport("session already established"));
+        }
+        session.open = true;
+        Ok(())
+    }
+
+    /// Stop accepting streams for `session_id`, rejecting those not accepted yet
+    pub fn close_session(&mut self, session_id: u64) {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(x) => x,
+            None => return,
+        };
+        session.closed = true;
+        let code = ErrorCode::WEBTRANSPORT_BUFFERED_STREAM_REJECTED.into();
+        for (mut send, mut recv) in session.bi.drain(..) {
+            send.reset(code);
+            let _ = recv.stop(code);
+        }
+        for mut recv in session.uni.drain(..) {
+            let _ = recv.stop(code);
+        }
+        session.wake();
+    }
+
+    /// Take the next bidirectional stream of `session_id`, or `None` once closed
+    pub fn poll_session_bi(
+        &mut self,
+        cx: &mut Context,
+        session_id: u64,
+    ) -> Poll<Option<(SendStream, RecvStream)>> {
+        let finished = self.finished;
+        let session = self.sessions.entry(session_id).or_default();
+        if let Some(x) = session.bi.pop_front() {
+            return Poll::Ready(Some(x));
+        }
+        if finished || session.closed {
+            return Poll::Ready(None);
+        }
+        session.bi_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Take the next unidirectional stream of `session_id`, or `None` once closed
+    pub fn poll_session_uni(
+        &mut self,
+        cx: &mut Context,
+        session_id: u64,
+    ) -> Poll<Option<RecvStream>> {
+        let finished = self.finished;
+        let session = self.sessions.entry(session_id).or_default();
+        if let Some(x) = session.uni.pop_front() {
+            return Poll::Ready(Some(x));
+        }
+        if finished || session.closed {
+            return Poll::Ready(None);
+        }
+        session.uni_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn on_session_stream(
+        &mut self,
+        session_id: u64,
+        send: Option<SendStream>,
+        mut recv: RecvStream,
+    ) -> Result<(), DriverError> {
+        if !self.inner.local_settings().enable_webtransport {
+            return Err(DriverError::peer(
+                ErrorCode::STREAM_CREATION_ERROR,
+                "WebTransport stream without ENABLE_WEBTRANSPORT",
+            ));
+        }
+        let id = StreamId(session_id);
+        if id.initiator() != Side::Client || id.dir() != Dir::Bi {
+            return Err(DriverError::peer(
+                ErrorCode::ID_ERROR,
+                format!("invalid WebTransport session ID {}", session_id),
+            ));
+        }
+        let session = self.sessions.entry(session_id).or_default();
+        if session.closed
+            || !session.open && session.bi.len() + session.uni.len() >= MAX_BUFFERED_STREAMS
+        {
+            trace!("rejecting stream of WebTransport session {}", session_id);
+            let code = ErrorCode::WEBTRANSPORT_BUFFERED_STREAM_REJECTED.into();
+            if let Some(mut send) = send {
+                send.reset(code);
+            }
+            let _ = recv.stop(code);
+            return Ok(());
+        }
+        match send {
+            Some(send) => {
+                session.bi.push_back((send, recv));
+                if let Some(w) = session.bi_waker.take() {
+                    w.wake();
+                }
+            }
+            None => {
+                session.uni.push_back(recv);
+                if let Some(w) = session.uni_waker.take() {
+                    w.wake();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_datagrams(&mut self, cx: &mut Context) -> Result<(), DriverError> {
+        loop {
+            let mut datagram = match Pin::new(&mut self.datagrams).poll_next(cx) {
+                Poll::Ready(Some(Ok(x))) => x,
+                // Connection errors are reported by the streams
+                _ => return Ok(()),
+            };
+            if !self.inner.local_settings().h3_datagram {
+                return Err(DriverError::peer(
+                    ErrorCode::GENERAL_PROTOCOL_ERROR,
+                    "datagram received without H3_DATAGRAM",
+                ));
+            }
+            let flow_id = datagram.get_var().map_err(|_| {
+                DriverError::peer(ErrorCode::GENERAL_PROTOCOL_ERROR, "malformed datagram")
+            })?;
+            match self.flows.get_mut(&flow_id) {
+                Some(flow) if flow.received.len() < MAX_BUFFERED_DATAGRAMS => {
+                    flow.received.push_back(datagram);
+                    if let Some(w) = flow.waker.take() {
+                        w.wake();
+                    }
+                }
+                Some(_) => trace!("datagram flow {} full, dropping datagram", flow_id),
+                None => trace!("no datagram flow {}, dropping datagram", flow_id),
+            }
+        }
+    }
+
     fn reset_waker(&mut self, cx: &mut Context) {
         if self.driver.is_none() {
             self.driver = Some(cx.waker().clone());
@@ -168,28 +699,71 @@ impl ConnectionInner {
                 Poll::Ready(None) => {
                     return Err(DriverError::internal("closed incoming bi"));
                 }
-                Poll::Ready(Some(Ok((mut send, mut recv)))) => match self.side {
-                    Side::Client => {
-                        return Err(DriverError::peer(
-                            ErrorCode::STREAM_CREATION_ERROR,
-                            "client does not accept bidirectional streams",
-                        ));
+                Poll::Ready(Some(Ok((mut send, mut recv)))) => {
+                    if self.inner.local_settings().enable_webtransport {
+                        // WebTransport streams can be opened by either side
+                        self.pending_bi.push(RecvBi::new(send, recv));
+                        continue;
                     }
-                    Side::Server => {
-                        if self.inner.is_closing() {
+                    match self.side {
+                        Side::Client => {
+                            return Err(DriverError::peer(
+                                ErrorCode::STREAM_CREATION_ERROR,
+                                "client does not accept bidirectional streams",
+                            ));
+                        }
+                        Side::Server if self.inner.is_closing() => {
                             send.reset(ErrorCode::REQUEST_REJECTED.into());
                             let _ = recv.stop(ErrorCode::REQUEST_REJECTED.into());
-                        } else {
-                            self.inner.request_initiated(send.id());
-                            self.requests.push_back((send, recv));
-                            if let Some(t) = self.requests_task.take() {
-                                t.wake();
-                            }
                         }
+                        Side::Server => self.on_request(send, FrameDecoder::stream(recv)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_resolve_bi(&mut self, cx: &mut Context) -> Result<(), DriverError> {
+        let mut i = 0;
+        while i < self.pending_bi.len() {
+            let resolved = match Pin::new(&mut self.pending_bi[i]).poll(cx) {
+                Poll::Pending => {
+                    i += 1;
+                    continue;
+                }
+                Poll::Ready(x) => x,
+            };
+            self.pending_bi.remove(i);
+            match resolved {
+                Err(e) => trace!("dropping bidirectional stream: {:?}", e),
+                Ok(NewBi::Request(_, _)) if self.side == Side::Client => {
+                    return Err(DriverError::peer(
+                        ErrorCode::STREAM_CREATION_ERROR,
+                        "client does not accept request streams",
+                    ));
+                }
+                Ok(NewBi::Request(mut send, recv)) => {
+                    if self.inner.is_closing() {
+                        send.reset(ErrorCode::REQUEST_REJECTED.into());
+                        recv.reset(ErrorCode::REQUEST_REJECTED);
+                    } else {
+                        self.on_request(send, recv);
                     }
-                },
+                }
+                Ok(NewBi::WebTransport(session_id, send, recv)) => {
+                    self.on_session_stream(session_id, Some(send), recv)?
+                }
             }
         }
+        Ok(())
+    }
+
+    fn on_request(&mut self, send: SendStream, recv: FrameStream) {
+        self.inner.request_initiated(send.id());
+        self.requests.push_back((send, recv));
+        if let Some(t) = self.requests_task.take() {
+            t.wake();
+        }
     }
 
     fn poll_incoming_uni(&mut self, cx: &mut Context) -> Result<(), DriverError> {
@@ -279,10 +853,25 @@ impl ConnectionInner {
                     "encoder stream already open",
                 )),
             },
-            NewUni::Push(_) => {
-                warn!("push stream ignored");
+            NewUni::Push(_, _) if self.side == Side::Server => Err(DriverError::peer(
+                ErrorCode::STREAM_CREATION_ERROR,
+                "client opened a push stream",
+            )),
+            NewUni::Push(id, _) if !self.inner.push_allowed(id) => Err(DriverError::peer(
+                ErrorCode::ID_ERROR,
+                format!("push ID {} exceeds MAX_PUSH_ID", id),
+            )),
+            NewUni::Push(id, stream) => {
+                trace!("Got push stream {}", id);
+                self.pushes.push_back((id, stream));
+                if let Some(t) = self.pushes_task.take() {
+                    t.wake();
+                }
                 Ok(())
             }
+            NewUni::WebTransport(session_id, recv) => {
+                self.on_session_stream(session_id, None, recv)
+            }
             NewUni::Reserved => Ok(()),
         }
     }
@@ -314,22 +903,48 @@ impl ConnectionInner {
                         (_, _, HttpFrame::Settings(s)) => {
                             trace!("Got Settings: {:#?}", s);
                             self.inner.set_remote_settings(s)?;
+                            wake_all(&mut self.prewarm_waiters);
                         }
                         (true, Side::Client, HttpFrame::Goaway(id)) => {
                             trace!("Got Goaway({:?})", id);
                             self.inner.leave(StreamId(id));
                         }
+                        (true, Side::Client, HttpFrame::CancelPush(id)) => {
+                            trace!("Got CancelPush({})", id);
+                            self.promises.remove(&id);
+                        }
+                        (true, Side::Client, HttpFrame::Origin(f)) => {
+                            trace!("Got Origin: {:?}", f.origins);
+                            let origins = self.origins.get_or_insert_with(Vec::new);
+                            for origin in f.origins {
+                                if !origins.contains(&origin) {
+                                    origins.push(origin);
+                                }
+                            }
+                        }
+                        (true, Side::Server, HttpFrame::PriorityUpdate(f)) => {
+                            Self::on_priority_update(&self.quic, &mut self.priorities, f)?;
+                        }
                         (true, Side::Server, HttpFrame::CancelPush(_)) => {
                             warn!("CANCEL_PUSH frame ignored");
                         }
-                        (true, Side::Server, HttpFrame::MaxPushId(_)) => {
-                            warn!("MAX_PUSH_ID frame ignored");
+                        (true, Side::Server, HttpFrame::MaxPushId(id)) => {
+                            trace!("Got MaxPushId({})", id);
+                            if !self.inner.on_max_push_id(id) {
+                                return Err(DriverError::peer(
+                                    ErrorCode::ID_ERROR,
+                                    "MAX_PUSH_ID reduced",
+                                ));
+                            }
                         }
                         (true, _, HttpFrame::Reserved) => (),
                         (false, Side::Server, HttpFrame::CancelPush(_))
                         | (false, Side::Server, HttpFrame::MaxPushId(_))
                         | (false, _, HttpFrame::Reserved)
-                        | (false, Side::Client, HttpFrame::Goaway(_)) => {
+                        | (false, Side::Client, HttpFrame::CancelPush(_))
+                        | (false, Side::Client, HttpFrame::Goaway(_))
+                        | (false, Side::Client, HttpFrame::Origin(_))
+                        | (false, Side::Server, HttpFrame::PriorityUpdate(_)) => {
                             return Err(DriverError::peer(
                                 ErrorCode::MISSING_SETTINGS,
                                 "missing settings",
@@ -347,6 +962,103 @@ impl ConnectionInner {
         }
     }
 
+    /// Reprioritize a request, which may not have been received yet
+    ///
+    /// Borrows only the fields it needs, as it runs while the control stream is borrowed.
+    fn on_priority_update(
+        quic: &quinn::Connection,
+        priorities: &mut HashMap<StreamId, Priority>,
+        frame: PriorityUpdateFrame,
+    ) -> Result<(), DriverError> {
+        trace!("Got PriorityUpdate({}): {:?}", frame.id, frame.priority);
+        if frame.push {
+            // Responses are pushed at the default priority
+            return Ok(());
+        }
+        let id = StreamId(frame.id);
+        if id.initiator() != Side::Client || id.dir() != Dir::Bi {
+            return Err(DriverError::peer(
+                ErrorCode::ID_ERROR,
+                "PRIORITY_UPDATE for a non-request stream",
+            ));
+        }
+        if id.index() >= quic.remote_stream_limit(Dir::Bi) {
+            return Err(DriverError::peer(
+                ErrorCode::ID_ERROR,
+                "PRIORITY_UPDATE beyond the stream limit",
+            ));
+        }
+        let priority = Priority::parse(&frame.priority);
+        let quic_priority = priority.quic_priority();
+        if quic.set_stream_priority(id, quic_priority).is_err() {
+            // The response was already sent
+            return Ok(());
+        }
+        // As RFC 9218 allows, bound the state kept for updates
+        if priorities.len() < MAX_PRIORITY_UPDATES || priorities.contains_key(&id) {
+            priorities.insert(id, priority);
+        }
+        Ok(())
+    }
+
+    fn poll_settings_grace(&mut self, cx: &mut Context) -> Result<(), DriverError> {
+        if self.inner.remote_settings().is_some() {
+            self.settings_grace = None;
+            return Ok(());
+        }
+        match self.settings_grace.as_mut() {
+            None => return Ok(()),
+            Some((ref mut delay, _)) => {
+                if Pin::new(delay).poll(cx).is_pending() {
+                    return Ok(());
+                }
+            }
+        }
+
+        let (_, strict) = self.settings_grace.take().unwrap();
+        if strict {
+            return Err(DriverError::peer(
+                ErrorCode::MISSING_SETTINGS,
+                "settings not received in time",
+            ));
+        }
+        warn!("peer settings not received in time, assuming protocol defaults");
+        // As if the peer sent an empty SETTINGS frame: no dynamic table, unlimited field sections
+        self.inner.set_remote_settings(Settings {
+            max_header_list_size: u64::max_value(),
+            qpack_max_table_capacity: 0,
+            qpack_blocked_streams: 0,
+            ..Settings::default()
+        })?;
+        wake_all(&mut self.prewarm_waiters);
+        Ok(())
+    }
+
+    /// Send GOAWAY once the connection reaches its maximum lifetime
+    ///
+    /// Returns true when the grace period for requests in flight has elapsed as well.
+    fn poll_lifetime(&mut self, cx: &mut Context) -> bool {
+        loop {
+            match self.lifetime {
+                None => return false,
+                Some(LifetimeState::Draining(ref mut delay, _)) => {
+                    return Pin::new(delay).poll(cx).is_ready();
+                }
+                Some(LifetimeState::Open(ref mut delay, grace)) => {
+                    if Pin::new(delay).poll(cx).is_pending() {
+                        return false;
+                    }
+                    trace!("maximum connection lifetime reached, going away");
+                    self.inner.go_away();
+                    self.lifetime = Some(LifetimeState::Draining(
+                        delay_for(grace),
+                        "maximum connection lifetime reached",
+                    ));
+                }
+            }
+        }
+    }
+
     fn poll_recv_encoder(&mut self, cx: &mut Context) -> Result<(), DriverError> {
         let (mut recv_encoder, mut buffer) = match self.recv_encoder.as_mut() {
             None => return Ok(()),
@@ -415,6 +1127,7 @@ impl ConnectionInner {
                         self.inner.on_recv_decoder(&mut cur)?;
                         cur.position() as usize
                     };
+                    wake_all(&mut self.prewarm_waiters);
                     buffer.split_to(pos);
                     buffer.reserve(RECV_DECODER_INITIAL_CAPACITY);
                     trace!("decoded {} bytes, buf capacity: {}", pos, buffer.capacity());
@@ -430,6 +1143,10 @@ impl ConnectionInner {
                 self.send_unis[ty as usize].push(data);
             }
             match Pin::new(&mut self.send_unis[ty as usize]).poll(cx) {
+                // Sent again on a new stream by `poll_zero_rtt`
+                Poll::Ready(Err(Error::ZeroRttRejected)) if self.zero_rtt.is_some() => {
+                    return Ok(())
+                }
                 Poll::Ready(Err(err)) => {
                     return Err(DriverError::peer(
                         ErrorCode::CLOSED_CRITICAL_STREAM,
@@ -446,6 +1163,12 @@ impl ConnectionInner {
     }
 }
 
+fn wake_all(waiters: &mut Vec<Waker>) {
+    for waker in waiters.drain(..) {
+        waker.wake();
+    }
+}
+
 struct DriverError(Error, ErrorCode, String);
 
 impl DriverError {
@@ -469,13 +1192,13 @@ impl DriverError {
 
 impl From<quinn::ConnectionError> for DriverError {
     fn from(err: quinn::ConnectionError) -> DriverError {
-        DriverError::new(Error::Quic(err), ErrorCode::INTERNAL_ERROR, "")
+        DriverError::new(err, ErrorCode::INTERNAL_ERROR, "")
     }
 }
 
 impl From<io::Error> for DriverError {
     fn from(err: io::Error) -> DriverError {
-        DriverError::new(Error::Io(err), ErrorCode::INTERNAL_ERROR, "")
+        DriverError::new(err, ErrorCode::INTERNAL_ERROR, "")
     }
 }
 
@@ -520,3 +1243,9 @@ impl From<ConnectionError> for DriverError {
 
 const RECV_ENCODER_INITIAL_CAPACITY: usize = 20480;
 const RECV_DECODER_INITIAL_CAPACITY: usize = 2048;
+/// Datagrams buffered on each flow before more are dropped
+const MAX_BUFFERED_DATAGRAMS: usize = 64;
+/// Streams buffered for a WebTransport session before it is established
+const MAX_BUFFERED_STREAMS: usize = 16;
+/// Requests whose priority updates are remembered
+const MAX_PRIORITY_UPDATES: usize = 256;
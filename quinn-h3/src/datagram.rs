@@ -0,0 +1,106 @@
+//! HTTP/3 datagrams, associated with requests by flow ID
+//!
+//! Built on the QUIC DATAGRAM extension, and available once both ends have enabled
+//! `Settings::h3_datagram`. The flow ID of a request is the quarter stream ID of the stream carrying
+//! it, so no further negotiation is needed to use it. A `DatagramFlow` is obtained from
+//! `client::RecvResponse::datagram_flow` or `server::Sender::datagram_flow`.
+
+use std::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use quinn_proto::{coding::BufMutExt, StreamId, VarInt};
+
+use crate::{connection::ConnectionRef, Error};
+
+/// Unreliable, unordered datagrams exchanged alongside a request
+///
+/// Datagrams received on the flow are buffered until read, and dropped while no `DatagramFlow` is
+/// open for it. The stream of received datagrams ends when the connection is closed.
+pub struct DatagramFlow {
+    conn: ConnectionRef,
+    flow_id: u64,
+}
+
+impl DatagramFlow {
+    pub(crate) fn new(conn: &ConnectionRef, stream_id: StreamId) -> Result<Self, Error> {
+        let flow_id = stream_id.index();
+        conn.h3.lock().unwrap().open_flow(flow_id)?;
+        Ok(Self {
+            conn: conn.clone(),
+            flow_id,
+        })
+    }
+
+    /// Flow ID prefixed to the datagrams, the quarter stream ID of the request
+    pub fn flow_id(&self) -> u64 {
+        self.flow_id
+    }
+
+    /// Largest payload `send` accepts at the moment
+    ///
+    /// `None` if the peer doesn't accept QUIC datagrams.
+    pub fn max_size(&self) -> Option<usize> {
+        let overhead = VarInt::from_u64(self.flow_id).unwrap().size();
+        self.conn
+            .quic
+            .max_datagram_size()
+            .map(|x| x.saturating_sub(overhead))
+    }
+
+    /// Send `data` in a datagram on this flow
+    ///
+    /// Only waits if the connection is congested. Fails with `Error::Datagram` if `data` exceeds
+    /// `max_size`.
+    pub async fn send(&self, data: Bytes) -> Result<(), Error> {
+        let mut buf = BytesMut::with_capacity(8 + data.len());
+        buf.write_var(self.flow_id);
+        buf.extend_from_slice(&data);
+        self.conn
+            .quic
+            .send_datagram(buf.freeze())
+            .await
+            .map_err(|e| match e {
+                quinn::SendDatagramError::ConnectionClosed(e) => e.into(),
+                quinn::SendDatagramError::TooLarge => Error::Datagram("datagram too large"),
+                quinn::SendDatagramError::UnsupportedByPeer => {
+                    Error::Datagram("QUIC datagrams not supported by the peer")
+                }
+                quinn::SendDatagramError::Disabled => {
+                    Error::Datagram("QUIC datagrams disabled locally")
+                }
+            })
+    }
+
+    /// Receive the next datagram on this flow, or `None` once the connection is closed
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.next().await
+    }
+}
+
+impl Stream for DatagramFlow {
+    type Item = Bytes;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let flow_id = self.flow_id;
+        self.conn.h3.lock().unwrap().poll_flow(cx, flow_id)
+    }
+}
+
+impl fmt::Debug for DatagramFlow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DatagramFlow")
+            .field("flow_id", &self.flow_id)
+            .finish()
+    }
+}
+
+impl Drop for DatagramFlow {
+    fn drop(&mut self) {
+        self.conn.h3.lock().unwrap().close_flow(self.flow_id);
+    }
+}
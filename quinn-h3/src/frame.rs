@@ -18,7 +18,7 @@ pub type FrameStream = FramedRead<RecvStream, FrameDecoder>;
 
 impl Reset for FrameStream {
     fn reset(self, error_code: ErrorCode) {
-        let _ = self.into_inner().stop(error_code.0.into());
+        let _ = self.into_inner().stop(error_code.into());
     }
 }
 
@@ -26,6 +26,8 @@ impl Reset for FrameStream {
 pub struct FrameDecoder {
     partial: Option<PartialData>,
     expected: Option<usize>,
+    /// Bytes already read from the stream by the caller, to be decoded first
+    prefix: Option<Bytes>,
 }
 
 impl FrameDecoder {
@@ -35,9 +37,36 @@ impl FrameDecoder {
             FrameDecoder {
                 expected: None,
                 partial: None,
+                prefix: None,
             },
         )
     }
+
+    /// Decode `prefix`, the start of the stream, before what remains to be read from `stream`
+    pub fn stream_with_prefix<T: AsyncRead>(stream: T, prefix: Bytes) -> FramedRead<T, Self> {
+        FramedRead::new(
+            stream,
+            FrameDecoder {
+                expected: None,
+                partial: None,
+                prefix: Some(prefix),
+            },
+        )
+    }
+
+    /// Payload bytes of the current DATA frame that have yet to be decoded
+    pub fn data_remaining(&self) -> usize {
+        self.partial.as_ref().map_or(0, |p| p.remaining())
+    }
+
+    /// Account for `len` bytes of DATA payload read from the stream by the caller
+    pub fn data_read(&mut self, len: usize) {
+        let partial = self.partial.as_mut().expect("not within a DATA frame");
+        partial.skip(len);
+        if partial.remaining() == 0 {
+            self.partial = None;
+        }
+    }
 }
 
 macro_rules! decode {
@@ -53,6 +82,11 @@ impl Decoder for FrameDecoder {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(prefix) = self.prefix.take() {
+            let rest = mem::replace(src, BytesMut::from(&prefix[..]));
+            src.extend_from_slice(&rest);
+        }
+
         if src.is_empty() {
             return Ok(None);
         }
@@ -156,8 +190,11 @@ impl Future for WriteFrame {
                     }
                 }
                 WriteFrameState::Payload(ref mut send, ref mut p) => {
-                    let wrote = ready!(Pin::new(send).poll_write(cx, p))?;
-                    p.advance(wrote);
+                    // Flow control may only admit part of the payload at a time
+                    while !p.is_empty() {
+                        let wrote = ready!(Pin::new(&mut *send).poll_write(cx, p))?;
+                        p.advance(wrote);
+                    }
                     let send = match mem::replace(&mut self.state, WriteFrameState::Finished) {
                         WriteFrameState::Payload(s, _) => s,
                         _ => unreachable!(),
@@ -256,4 +293,22 @@ mod tests {
         assert_matches!(decoder.decode(&mut buf), Ok(Some(HttpFrame::Data(_))));
         assert_matches!(decoder.decode(&mut buf), Ok(None));
     }
+
+    #[test]
+    fn prefixed_frame() {
+        let frame = frame::HeadersFrame {
+            encoded: b"salut"[..].into(),
+        };
+
+        let mut buf = BytesMut::with_capacity(16);
+        frame.encode(&mut buf);
+        let mut rest = buf.split_off(1);
+
+        let mut decoder = FrameDecoder {
+            prefix: Some(buf.freeze()),
+            ..FrameDecoder::default()
+        };
+        assert_matches!(decoder.decode(&mut rest), Ok(Some(HttpFrame::Headers(_))));
+        assert!(rest.is_empty());
+    }
 }
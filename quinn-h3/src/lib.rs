@@ -11,17 +11,24 @@ extern crate assert_matches;
 
 pub use body::Body;
 
+pub mod alt_svc;
 pub mod body;
 pub mod client;
 pub mod connection;
+pub mod datagram;
 pub mod headers;
+pub mod priority;
 pub mod proto;
 pub mod qpack;
 pub mod server;
+pub mod webtransport;
 
 mod frame;
 mod streams;
 
+#[cfg(test)]
+mod tests;
+
 use err_derive::Error;
 
 use proto::{frame::SettingsFrame, ErrorCode};
@@ -36,6 +43,8 @@ pub enum Error {
     Proto(proto::connection::Error),
     #[error(display = "QUIC protocol error: {}", _0)]
     Quic(quinn::ConnectionError),
+    #[error(display = "QUIC connect error: {}", _0)]
+    Connect(quinn::ConnectError),
     #[error(display = "QUIC write error: {}", _0)] // TODO to be refined
     Write(quinn::WriteError),
     #[error(display = "Internal error: {}", _0)]
@@ -50,6 +59,61 @@ pub enum Error {
     Overflow,
     #[error(display = "Polled after finished")]
     Poll,
+    #[error(display = "Invalid request: {:?}", _0)]
+    InvalidRequest(proto::headers::Error),
+    #[error(display = "Body stream error: {}", _0)]
+    Body(Box<dyn std::error::Error + Send + Sync>),
+    #[error(display = "Extended CONNECT unavailable: {}", _0)]
+    ExtendedConnect(&'static str),
+    /// HTTP/3 datagrams can't be used on the flow, see `datagram::DatagramFlow`
+    #[error(display = "H3 datagram unavailable: {}", _0)]
+    Datagram(&'static str),
+    /// A WebTransport session can't be established, see `webtransport::WebTransportSession`
+    #[error(display = "WebTransport unavailable: {}", _0)]
+    WebTransport(&'static str),
+    #[error(display = "Connection unavailable: {}", _0)]
+    Unavailable(String),
+    #[error(
+        display = "Body length {} doesn't match content-length {}",
+        written,
+        declared
+    )]
+    ContentLength { declared: u64, written: u64 },
+    #[error(display = "Unexpected response status: {}", status)]
+    UnexpectedStatus { status: http::StatusCode },
+    #[error(display = "Body exceeds the {} byte limit", limit)]
+    BodyTooLarge { limit: usize },
+    /// Trailers were asked for before the body was read to its end, see `BodyReader::trailers`
+    #[error(display = "Body data not read yet")]
+    UnreadBody,
+    /// The server supports none of the application protocols offered by the client
+    ///
+    /// `reason` is the server's explanation, which may name the protocols it saw offered.
+    #[error(
+        display = "No application protocol in common with the server, offered {:?}: {}",
+        offered,
+        reason
+    )]
+    NoCommonAlpn {
+        offered: Vec<String>,
+        reason: String,
+    },
+    /// The peer closed the connection or the stream with `H3_EXCESSIVE_LOAD` to shed load
+    ///
+    /// Carries the underlying error. The peer is expected to recover, so the request may be retried
+    /// after backing off, preferably against another server.
+    #[error(display = "Peer under excessive load: {}", _0)]
+    ExcessiveLoad(Box<Error>),
+    #[error(display = "Timed out")]
+    TimedOut,
+    /// The client allows no more pushes on the connection, see `client::Builder::max_pushes`
+    #[error(display = "Push not allowed by the client")]
+    PushRefused,
+    /// The server rejected the 0-RTT data the request was sent in, see `client::Connecting::into_0rtt`
+    ///
+    /// The request was not processed, and may be sent again on the same connection.
+    #[error(display = "0-RTT rejected")]
+    ZeroRttRejected,
 }
 
 impl Error {
@@ -60,6 +124,71 @@ impl Error {
     pub fn internal<T: Into<String>>(msg: T) -> Self {
         Error::Internal(msg.into())
     }
+
+    /// Code and reason the peer closed the connection with, if that's what caused this error
+    pub fn close_reason(&self) -> Option<(ErrorCode, String)> {
+        let err = match self {
+            Error::ExcessiveLoad(e) => return e.close_reason(),
+            Error::Quic(e) | Error::Write(quinn::WriteError::ConnectionClosed(e)) => e,
+            Error::Io(e) => e.get_ref()?.downcast_ref()?,
+            _ => return None,
+        };
+        match err {
+            quinn::ConnectionError::ApplicationClosed(close) => Some((
+                close.error_code.into(),
+                String::from_utf8_lossy(&close.reason).into_owned(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Code the peer reset or stopped the stream with, if that's what caused this error
+    pub fn reset_code(&self) -> Option<ErrorCode> {
+        let code = match self {
+            Error::ExcessiveLoad(e) => return e.reset_code(),
+            Error::Write(quinn::WriteError::Stopped(code)) => code,
+            Error::Io(e) => {
+                let e = e.get_ref()?;
+                match (e.downcast_ref(), e.downcast_ref()) {
+                    (Some(quinn::ReadError::Reset(code)), _) => code,
+                    (_, Some(quinn::WriteError::Stopped(code))) => code,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        Some((*code).into())
+    }
+
+    /// Whether the stream was discarded because the server rejected 0-RTT
+    fn is_0rtt_rejected(&self) -> bool {
+        let e = match self {
+            Error::Write(e) => return *e == quinn::WriteError::ZeroRttRejected,
+            Error::Io(e) => e,
+            _ => return false,
+        };
+        match e.get_ref() {
+            Some(e) => {
+                e.downcast_ref() == Some(&quinn::ReadError::ZeroRttRejected)
+                    || e.downcast_ref() == Some(&quinn::WriteError::ZeroRttRejected)
+            }
+            None => false,
+        }
+    }
+
+    /// Surface errors caused by the peer shedding load as `ExcessiveLoad`, and streams discarded
+    /// along with rejected 0-RTT data as `ZeroRttRejected`
+    fn classify(self) -> Self {
+        if self.is_0rtt_rejected() {
+            return Error::ZeroRttRejected;
+        }
+        let code = self.close_reason().map(|(code, _)| code);
+        if code.or_else(|| self.reset_code()) == Some(ErrorCode::EXCESSIVE_LOAD) {
+            Error::ExcessiveLoad(Box::new(self))
+        } else {
+            self
+        }
+    }
 }
 
 impl From<proto::connection::Error> for Error {
@@ -70,26 +199,32 @@ impl From<proto::connection::Error> for Error {
 
 impl From<quinn::ConnectionError> for Error {
     fn from(err: quinn::ConnectionError) -> Error {
-        Error::Quic(err)
+        Error::Quic(err).classify()
+    }
+}
+
+impl From<quinn::ConnectError> for Error {
+    fn from(err: quinn::ConnectError) -> Error {
+        Error::Connect(err)
     }
 }
 
 impl From<quinn::WriteError> for Error {
     fn from(err: quinn::WriteError) -> Error {
-        Error::Write(err)
+        Error::Write(err).classify()
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
-        Error::Io(err)
+        Error::Io(err).classify()
     }
 }
 
 impl From<frame::Error> for Error {
     fn from(err: frame::Error) -> Error {
         match err {
-            frame::Error::Io(e) => Error::Io(e),
+            frame::Error::Io(e) => e.into(),
             e => Error::Peer(format!("received an invalid frame: {:?}", e)),
         }
     }
@@ -0,0 +1,194 @@
+//! Extensible prioritization scheme for HTTP (RFC 9218)
+//!
+//! Clients signal how a response should be scheduled relative to others on the connection through
+//! the `priority` request header, and may change their mind later with `RecvResponse::set_priority`,
+//! which sends a PRIORITY_UPDATE frame. Servers find the resulting `Priority` in the extensions of
+//! the request, or through `Sender::priority` to observe updates, and schedule the QUIC stream
+//! carrying the response accordingly.
+
+use std::fmt;
+
+use http::{HeaderMap, HeaderValue};
+
+/// Name of the header carrying the priority of a request
+pub const PRIORITY: &str = "priority";
+
+/// Urgency of responses for which no other was signalled
+pub const DEFAULT_URGENCY: u8 = 3;
+
+/// Largest, and so least urgent, urgency
+pub const MAX_URGENCY: u8 = 7;
+
+/// Priority parameters of a request's response
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Priority {
+    /// From 0, the most urgent, to `MAX_URGENCY`
+    pub urgency: u8,
+    /// Whether the response can be used as its parts arrive, and so share bandwidth with others
+    pub incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: DEFAULT_URGENCY,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// The priority signalled by the `priority` header fields in `headers`
+    ///
+    /// Parameters which are missing or can't be understood take their default value.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut priority = Self::default();
+        for value in headers.get_all(PRIORITY) {
+            if let Ok(value) = value.to_str() {
+                priority.merge(value);
+            }
+        }
+        priority
+    }
+
+    /// Parse a priority field value, such as `u=1, i`
+    ///
+    /// Like `from_headers`, ignores parameters which can't be understood.
+    pub fn parse(value: &str) -> Self {
+        let mut priority = Self::default();
+        priority.merge(value);
+        priority
+    }
+
+    /// Encode as a `priority` header value, omitting parameters at their default
+    pub fn to_header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.to_string()).expect("valid header value")
+    }
+
+    /// Priority of the QUIC stream carrying the response
+    ///
+    /// The default urgency maps to the default stream priority, so that responses sent without a
+    /// priority signal compete evenly with other streams. The QUIC scheduler sends data of equal
+    /// priority in the order it was written, so non-incremental responses are served one after the
+    /// other, as RFC 9218 recommends; incremental ones don't share bandwidth any more finely.
+    pub fn quic_priority(&self) -> i32 {
+        i32::from(DEFAULT_URGENCY) - i32::from(self.urgency)
+    }
+
+    /// Apply the parameters of a structured field dictionary, the last occurrence winning
+    fn merge(&mut self, value: &str) {
+        for member in value.split(',') {
+            // Parameters of dictionary members carry no meaning here
+            let member = member.split(';').next().unwrap().trim();
+            let (key, value) = match member.find('=') {
+                Some(i) => (member[..i].trim(), Some(member[i + 1..].trim())),
+                None => (member, None),
+            };
+            match key {
+                "u" => {
+                    if let Some(urgency) = value.and_then(|x| x.parse::<u8>().ok()) {
+                        if urgency <= MAX_URGENCY {
+                            self.urgency = urgency;
+                        }
+                    }
+                }
+                "i" => match value {
+                    None | Some("?1") => self.incremental = true,
+                    Some("?0") => self.incremental = false,
+                    Some(_) => {}
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.urgency != DEFAULT_URGENCY, self.incremental) {
+            (true, true) => write!(f, "u={}, i", self.urgency),
+            (true, false) => write!(f, "u={}", self.urgency),
+            (false, true) => write!(f, "i"),
+            (false, false) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Priority::parse(""), Priority::default());
+        assert_eq!(
+            Priority::parse("u=1, i"),
+            Priority {
+                urgency: 1,
+                incremental: true
+            }
+        );
+        assert_eq!(
+            Priority::parse("i=?0, u=7"),
+            Priority {
+                urgency: 7,
+                incremental: false
+            }
+        );
+        assert_eq!(
+            Priority::parse("u=2;foo=bar, i=?1;x"),
+            Priority {
+                urgency: 2,
+                incremental: true
+            }
+        );
+    }
+
+    #[test]
+    fn parse_ignores_invalid() {
+        assert_eq!(Priority::parse("u=8, i=1"), Priority::default());
+        assert_eq!(Priority::parse("u=-1, x=5, u"), Priority::default());
+        assert_eq!(Priority::parse("u=0, u=9").urgency, 0);
+    }
+
+    #[test]
+    fn from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Priority::from_headers(&headers), Priority::default());
+        headers.append(PRIORITY, HeaderValue::from_static("u=5"));
+        headers.append(PRIORITY, HeaderValue::from_static("i"));
+        assert_eq!(
+            Priority::from_headers(&headers),
+            Priority {
+                urgency: 5,
+                incremental: true
+            }
+        );
+    }
+
+    #[test]
+    fn display() {
+        for &(urgency, incremental, value) in &[
+            (3, false, ""),
+            (3, true, "i"),
+            (0, false, "u=0"),
+            (6, true, "u=6, i"),
+        ] {
+            let priority = Priority {
+                urgency,
+                incremental,
+            };
+            assert_eq!(priority.to_string(), value);
+            assert_eq!(Priority::parse(value), priority);
+        }
+    }
+
+    #[test]
+    fn quic_priority() {
+        let urgent = Priority::parse("u=0").quic_priority();
+        let default = Priority::default().quic_priority();
+        let background = Priority::parse("u=7").quic_priority();
+        assert_eq!(default, 0);
+        assert!(urgent > default && default > background);
+    }
+}
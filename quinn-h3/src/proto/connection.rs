@@ -6,8 +6,9 @@ use std::convert::TryFrom;
 use tracing::trace;
 
 use crate::{
+    priority::Priority,
     proto::{
-        frame::{HeadersFrame, HttpFrame},
+        frame::{HeadersFrame, HttpFrame, OriginFrame, PriorityUpdateFrame, PushPromiseFrame},
         headers::{self, Header},
     },
     qpack::{self, DecoderError, DynamicTable, EncoderError, HeaderField},
@@ -40,12 +41,16 @@ impl PendingStreamType {
 }
 
 pub struct Connection {
+    local_settings: Settings,
     remote_settings: Option<Settings>,
     decoder_table: DynamicTable,
     encoder_table: DynamicTable,
     pending_streams: [BytesMut; 3],
     requests_in_flight: VecDeque<StreamId>,
     go_away: bool,
+    /// Largest push ID the client allows, once it sent MAX_PUSH_ID
+    max_push_id: Option<u64>,
+    next_push_id: u64,
 }
 
 impl Connection {
@@ -65,10 +70,13 @@ impl Connection {
         Ok(Self {
             decoder_table,
             pending_streams,
+            local_settings: settings,
             remote_settings: None,
             encoder_table: DynamicTable::new(),
             requests_in_flight: VecDeque::with_capacity(32),
             go_away: false,
+            max_push_id: None,
+            next_push_id: 0,
         })
     }
 
@@ -135,6 +143,82 @@ impl Connection {
         Ok(qpack::on_decoder_recv(&mut self.encoder_table, read)?)
     }
 
+    /// Encode the PUSH_PROMISE frame for the request `headers`
+    ///
+    /// Only the static table is used, so the block needs no acknowledgement that could be
+    /// confused with those of the headers of the request stream carrying it.
+    pub fn encode_push_promise(
+        &mut self,
+        push_id: u64,
+        headers: Header,
+    ) -> Result<PushPromiseFrame> {
+        let mut block = BytesMut::with_capacity(512);
+        qpack::encode(
+            &mut DynamicTable::new().encoder(0),
+            &mut block,
+            &mut BytesMut::new(),
+            headers,
+        )?;
+        Ok(PushPromiseFrame {
+            push_id,
+            encoded: block.freeze(),
+        })
+    }
+
+    /// Allow the server to push responses with IDs up to `max_push_id`
+    pub fn send_max_push_id(&mut self, max_push_id: u64) {
+        self.max_push_id = Some(max_push_id);
+        HttpFrame::MaxPushId(max_push_id)
+            .encode(&mut self.pending_streams[PendingStreamType::Control as usize]);
+    }
+
+    /// Tell the client that the response promised for `push_id` won't be sent
+    pub fn send_cancel_push(&mut self, push_id: u64) {
+        HttpFrame::CancelPush(push_id)
+            .encode(&mut self.pending_streams[PendingStreamType::Control as usize]);
+    }
+
+    /// Record the client's MAX_PUSH_ID, returning false if it was reduced
+    pub fn on_max_push_id(&mut self, max_push_id: u64) -> bool {
+        if self.max_push_id.map_or(false, |x| max_push_id < x) {
+            return false;
+        }
+        self.max_push_id = Some(max_push_id);
+        true
+    }
+
+    /// Whether `push_id` is within the limit set by the client
+    pub fn push_allowed(&self, push_id: u64) -> bool {
+        self.max_push_id.map_or(false, |x| push_id <= x)
+    }
+
+    /// Allocate the ID for a new push, unless the client doesn't allow more
+    pub fn next_push_id(&mut self) -> Option<u64> {
+        if !self.push_allowed(self.next_push_id) {
+            return None;
+        }
+        self.next_push_id += 1;
+        Some(self.next_push_id - 1)
+    }
+
+    /// Insert `fields` in the encoder's dynamic table, returning the insert count to wait for
+    pub fn prefill(&mut self, fields: &[HeaderField]) -> Result<usize> {
+        Ok(qpack::prefill(
+            &mut self.encoder_table,
+            &mut self.pending_streams[PendingStreamType::Encoder as usize],
+            fields,
+        )?)
+    }
+
+    /// Number of dynamic table insertions acknowledged by the peer's decoder
+    pub fn inserts_acknowledged(&self) -> usize {
+        self.encoder_table.largest_known_received()
+    }
+
+    pub fn local_settings(&self) -> &Settings {
+        &self.local_settings
+    }
+
     pub fn remote_settings(&self) -> &Option<Settings> {
         &self.remote_settings
     }
@@ -167,15 +251,29 @@ impl Connection {
     }
 
     pub fn request_finished(&mut self, id: StreamId) {
-        if !self.go_away {
-            self.requests_in_flight.push_back(id);
-        }
+        self.requests_in_flight.retain(|i| *i != id);
     }
 
     pub fn requests_in_flight(&self) -> usize {
         self.requests_in_flight.len()
     }
 
+    /// Advertise the origins this server is authoritative for
+    pub fn send_origin(&mut self, origins: Vec<String>) {
+        HttpFrame::Origin(OriginFrame { origins })
+            .encode(&mut self.pending_streams[PendingStreamType::Control as usize]);
+    }
+
+    /// Ask the server to schedule the response to the request on `stream_id` with `priority`
+    pub fn send_priority_update(&mut self, stream_id: StreamId, priority: Priority) {
+        HttpFrame::PriorityUpdate(PriorityUpdateFrame {
+            push: false,
+            id: stream_id.0,
+            priority: priority.to_string(),
+        })
+        .encode(&mut self.pending_streams[PendingStreamType::Control as usize]);
+    }
+
     pub fn go_away(&mut self) {
         if !self.go_away {
             self.go_away = true;
@@ -237,6 +335,18 @@ impl From<headers::Error> for Error {
             headers::Error::InvalidRequest(e) => Error::InvalidRequest(format!("{:?}", e)),
             headers::Error::MissingMethod => Error::InvalidRequest("missing method".into()),
             headers::Error::MissingStatus => Error::InvalidResponse("missing status".into()),
+            headers::Error::MissingAuthority => Error::InvalidRequest("missing authority".into()),
+            headers::Error::AuthorityMismatch => {
+                Error::InvalidRequest("authority does not match host".into())
+            }
+            headers::Error::InvalidAuthority => {
+                Error::InvalidRequest("authority with userinfo".into())
+            }
+            headers::Error::InvalidScheme => Error::InvalidRequest("scheme is not http(s)".into()),
+            headers::Error::InvalidPath => Error::InvalidRequest("'*' path without OPTIONS".into()),
+            headers::Error::InvalidConnect => {
+                Error::InvalidRequest("CONNECT with scheme or path".into())
+            }
         }
     }
 }
@@ -253,6 +363,7 @@ mod tests {
     impl Default for Connection {
         fn default() -> Self {
             Self {
+                local_settings: Settings::default(),
                 remote_settings: None,
                 decoder_table: DynamicTable::new(),
                 encoder_table: DynamicTable::new(),
@@ -263,15 +374,51 @@ mod tests {
                 ],
                 requests_in_flight: VecDeque::with_capacity(32),
                 go_away: false,
+                max_push_id: None,
+                next_push_id: 0,
             }
         }
     }
 
+    #[test]
+    fn requests_drain_after_go_away() {
+        let mut conn = Connection::default();
+        conn.request_initiated(StreamId(0));
+        conn.request_initiated(StreamId(4));
+        conn.request_finished(StreamId(0));
+        assert_eq!(conn.requests_in_flight(), 1);
+
+        conn.go_away();
+        conn.request_initiated(StreamId(8));
+        assert_eq!(conn.requests_in_flight(), 1);
+        conn.request_finished(StreamId(4));
+        assert_eq!(conn.requests_in_flight(), 0);
+        assert!(conn.is_closing());
+    }
+
+    #[test]
+    fn push_ids_within_max_push_id() {
+        let mut conn = Connection::default();
+        assert_eq!(conn.next_push_id(), None);
+        assert!(conn.on_max_push_id(1));
+        assert_eq!(conn.next_push_id(), Some(0));
+        assert_eq!(conn.next_push_id(), Some(1));
+        assert_eq!(conn.next_push_id(), None);
+        assert!(!conn.on_max_push_id(0));
+        assert!(conn.on_max_push_id(2));
+        assert_eq!(conn.next_push_id(), Some(2));
+    }
+
     #[test]
     fn encode_no_dynamic() {
         let mut header_map = HeaderMap::new();
         header_map.append("hello", HeaderValue::from_static("text/html"));
-        let header = Header::request(Method::GET, Uri::default(), header_map);
+        let header = Header::request(
+            Method::GET,
+            Uri::from_static("https://example.com/"),
+            header_map,
+        )
+        .expect("valid request");
 
         let mut conn = Connection::default();
         assert_matches!(conn.encode_header(StreamId(1), header), Ok(_));
@@ -282,7 +429,12 @@ mod tests {
     fn encode_with_dynamic() {
         let mut header_map = HeaderMap::new();
         header_map.append("hello", HeaderValue::from_static("text/html"));
-        let header = Header::request(Method::GET, Uri::default(), header_map);
+        let header = Header::request(
+            Method::GET,
+            Uri::from_static("https://example.com/"),
+            header_map,
+        )
+        .expect("valid request");
 
         let mut conn = Connection::default();
         conn.encoder_table
@@ -301,7 +453,12 @@ mod tests {
         for _ in 0..5 {
             header_map.append("hello", HeaderValue::from_static("text/html"));
         }
-        let header = Header::request(Method::GET, Uri::default(), header_map);
+        let header = Header::request(
+            Method::GET,
+            Uri::from_static("https://example.com/"),
+            header_map,
+        )
+        .expect("valid request");
 
         let mut conn = Connection::default();
         conn.remote_settings = Some(Settings {
@@ -318,7 +475,12 @@ mod tests {
     fn decode_header() {
         let mut header_map = HeaderMap::new();
         header_map.append("hello", HeaderValue::from_static("text/html"));
-        let header = Header::request(Method::GET, Uri::default(), header_map);
+        let header = Header::request(
+            Method::GET,
+            Uri::from_static("https://example.com/"),
+            header_map,
+        )
+        .expect("valid request");
 
         let mut client = Connection::default();
         let encoded = client
@@ -339,7 +501,12 @@ mod tests {
     fn decode_blocked() {
         let mut header_map = HeaderMap::new();
         header_map.append("hello", HeaderValue::from_static("text/html"));
-        let header = Header::request(Method::GET, Uri::default(), header_map);
+        let header = Header::request(
+            Method::GET,
+            Uri::from_static("https://example.com/"),
+            header_map,
+        )
+        .expect("valid request");
 
         let mut client = Connection::default();
         client
@@ -365,7 +532,7 @@ mod tests {
 
         assert_matches!(
             server.decode_header(StreamId(1), &encoded),
-            Ok(DecodeResult::MissingRefs(1))
+            Ok(DecodeResult::MissingRefs(2))
         );
         assert!(server.pending_streams[PendingStreamType::Decoder as usize].is_empty());
     }
@@ -26,6 +26,8 @@ pub enum HttpFrame {
     Goaway(u64),
     MaxPushId(u64),
     DuplicatePush(u64),
+    Origin(OriginFrame),
+    PriorityUpdate(PriorityUpdateFrame),
     Reserved,
 }
 
@@ -40,6 +42,8 @@ impl HttpFrame {
             HttpFrame::Goaway(id) => simple_frame_encode(Type::GOAWAY, *id, buf),
             HttpFrame::MaxPushId(id) => simple_frame_encode(Type::MAX_PUSH_ID, *id, buf),
             HttpFrame::DuplicatePush(id) => simple_frame_encode(Type::DUPLICATE_PUSH, *id, buf),
+            HttpFrame::Origin(f) => f.encode(buf),
+            HttpFrame::PriorityUpdate(f) => f.encode(buf),
             HttpFrame::Reserved => (),
         }
     }
@@ -69,6 +73,10 @@ impl HttpFrame {
             Type::GOAWAY => Ok(HttpFrame::Goaway(payload.get_var()?)),
             Type::MAX_PUSH_ID => Ok(HttpFrame::MaxPushId(payload.get_var()?)),
             Type::DUPLICATE_PUSH => Ok(HttpFrame::DuplicatePush(payload.get_var()?)),
+            Type::ORIGIN => Ok(HttpFrame::Origin(OriginFrame::decode(&mut payload)?)),
+            Type::PRIORITY_UPDATE_REQUEST | Type::PRIORITY_UPDATE_PUSH => Ok(
+                HttpFrame::PriorityUpdate(PriorityUpdateFrame::decode(ty, &mut payload)?),
+            ),
             Type::H2_PRIORITY | Type::H2_PING | Type::H2_WINDOW_UPDATE | Type::H2_CONTINUATION => {
                 Err(Error::UnsupportedFrame)
             }
@@ -97,8 +105,12 @@ frame_types! {
     GOAWAY = 0x7,
     H2_WINDOW_UPDATE = 0x8,
     H2_CONTINUATION = 0x9,
+    ORIGIN = 0xC,
     MAX_PUSH_ID = 0xD,
     DUPLICATE_PUSH = 0xE,
+    // RFC 9218
+    PRIORITY_UPDATE_REQUEST = 0xF0700,
+    PRIORITY_UPDATE_PUSH = 0xF0701,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -181,6 +193,11 @@ impl PartialData {
     pub fn remaining(&self) -> usize {
         self.remaining
     }
+
+    /// Account for `len` bytes of payload read without going through `decode_data`
+    pub fn skip(&mut self, len: usize) {
+        self.remaining -= len;
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -216,8 +233,9 @@ impl IntoPayload for HeadersFrame {
 
 #[derive(Debug, PartialEq)]
 pub struct PushPromiseFrame {
-    push_id: u64,
-    encoded: Bytes,
+    pub push_id: u64,
+    /// Header block of the promised request
+    pub encoded: Bytes,
 }
 
 impl FrameHeader for PushPromiseFrame {
@@ -241,12 +259,112 @@ impl PushPromiseFrame {
     }
 }
 
+impl IntoPayload for PushPromiseFrame {
+    fn into_payload(self) -> Bytes {
+        let mut buf = Vec::with_capacity(VarInt::MAX_SIZE + self.encoded.len());
+        buf.write_var(self.push_id);
+        buf.extend_from_slice(&self.encoded);
+        buf.into()
+    }
+}
+
+/// Origins the server is authoritative for, see RFC 8336
+#[derive(Debug, PartialEq, Clone)]
+pub struct OriginFrame {
+    /// Serialized ASCII origins, such as `https://example.com`
+    pub origins: Vec<String>,
+}
+
+impl FrameHeader for OriginFrame {
+    const TYPE: Type = Type::ORIGIN;
+    fn len(&self) -> usize {
+        self.origins.iter().map(|x| 2 + x.len()).sum()
+    }
+}
+
+impl OriginFrame {
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.encode_header(buf);
+        for origin in &self.origins {
+            buf.put_u16(origin.len() as u16);
+            buf.put_slice(origin.as_bytes());
+        }
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Result<Self, Error> {
+        let mut origins = Vec::new();
+        while buf.has_remaining() {
+            if buf.remaining() < 2 {
+                return Err(Error::Malformed);
+            }
+            let len = buf.get_u16() as usize;
+            if buf.remaining() < len {
+                return Err(Error::Malformed);
+            }
+            let mut origin = vec![0; len];
+            buf.copy_to_slice(&mut origin);
+            if !origin.is_ascii() {
+                return Err(Error::InvalidFrameValue);
+            }
+            origins.push(String::from_utf8(origin).unwrap());
+        }
+        Ok(OriginFrame { origins })
+    }
+}
+
+/// New priority of a request's response or of a push, see RFC 9218
+#[derive(Debug, PartialEq, Clone)]
+pub struct PriorityUpdateFrame {
+    /// Whether `id` is a push ID rather than the ID of a request stream
+    pub push: bool,
+    pub id: u64,
+    /// Priority field value, in the syntax of the `priority` header
+    pub priority: String,
+}
+
+impl PriorityUpdateFrame {
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.ty().encode(buf);
+        buf.write_var((VarInt::from_u64(self.id).unwrap().size() + self.priority.len()) as u64);
+        buf.write_var(self.id);
+        buf.put_slice(self.priority.as_bytes());
+    }
+
+    fn decode<B: Buf>(ty: Type, buf: &mut B) -> Result<Self, Error> {
+        let id = buf.get_var()?;
+        let mut priority = vec![0; buf.remaining()];
+        buf.copy_to_slice(&mut priority);
+        if !priority.is_ascii() {
+            return Err(Error::InvalidFrameValue);
+        }
+        Ok(PriorityUpdateFrame {
+            push: ty == Type::PRIORITY_UPDATE_PUSH,
+            id,
+            priority: String::from_utf8(priority).unwrap(),
+        })
+    }
+
+    fn ty(&self) -> Type {
+        if self.push {
+            Type::PRIORITY_UPDATE_PUSH
+        } else {
+            Type::PRIORITY_UPDATE_REQUEST
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct SettingsFrame {
     pub num_placeholders: u64,
     pub max_header_list_size: u64,
     pub qpack_max_table_capacity: u64,
     pub qpack_blocked_streams: u64,
+    /// Whether extended CONNECT requests, carrying a `:protocol` pseudo-header, are accepted
+    pub enable_connect_protocol: bool,
+    /// Whether HTTP/3 datagrams, associated with requests by flow ID, are accepted
+    pub h3_datagram: bool,
+    /// Whether WebTransport sessions may be established, see `webtransport`
+    pub enable_webtransport: bool,
 }
 
 impl Default for SettingsFrame {
@@ -256,6 +374,9 @@ impl Default for SettingsFrame {
             max_header_list_size: DEFAULT_MAX_HEADER_LIST_SIZE,
             qpack_max_table_capacity: DEFAULT_QPACK_MAX_TABLE_CAPACITY,
             qpack_blocked_streams: DEFAULT_QPACK_BLOCKED_STREAMS,
+            enable_connect_protocol: false,
+            h3_datagram: false,
+            enable_webtransport: false,
         }
     }
 }
@@ -276,6 +397,18 @@ impl SettingsFrame {
         buf.write_var(self.qpack_max_table_capacity);
         SettingId::QPACK_BLOCKED_STREAMS.encode(buf);
         buf.write_var(self.qpack_blocked_streams);
+        if self.enable_connect_protocol {
+            SettingId::ENABLE_CONNECT_PROTOCOL.encode(buf);
+            buf.write_var(1);
+        }
+        if self.h3_datagram {
+            SettingId::H3_DATAGRAM.encode(buf);
+            buf.write_var(1);
+        }
+        if self.enable_webtransport {
+            SettingId::ENABLE_WEBTRANSPORT.encode(buf);
+            buf.write_var(1);
+        }
     }
 
     fn decode<T: Buf>(buf: &mut T) -> Result<SettingsFrame, Error> {
@@ -310,6 +443,39 @@ impl SettingsFrame {
                 SettingId::QPACK_BLOCKED_STREAMS => {
                     settings.qpack_blocked_streams = value;
                 }
+                SettingId::ENABLE_CONNECT_PROTOCOL => {
+                    settings.enable_connect_protocol = match value {
+                        0 => false,
+                        1 => true,
+                        x => {
+                            return Err(Error::Settings(format!(
+                                "invalid ENABLE_CONNECT_PROTOCOL value {}",
+                                x
+                            )))
+                        }
+                    };
+                }
+                SettingId::H3_DATAGRAM => {
+                    settings.h3_datagram = match value {
+                        0 => false,
+                        1 => true,
+                        x => {
+                            return Err(Error::Settings(format!("invalid H3_DATAGRAM value {}", x)))
+                        }
+                    };
+                }
+                SettingId::ENABLE_WEBTRANSPORT => {
+                    settings.enable_webtransport = match value {
+                        0 => false,
+                        1 => true,
+                        x => {
+                            return Err(Error::Settings(format!(
+                                "invalid ENABLE_WEBTRANSPORT value {}",
+                                x
+                            )))
+                        }
+                    };
+                }
                 _ => continue,
             }
         }
@@ -331,6 +497,21 @@ impl FrameHeader for SettingsFrame {
             + sz(self.qpack_max_table_capacity)
             + sz(SettingId::QPACK_BLOCKED_STREAMS.0)
             + sz(self.qpack_blocked_streams)
+            + if self.enable_connect_protocol {
+                sz(SettingId::ENABLE_CONNECT_PROTOCOL.0) + 1
+            } else {
+                0
+            }
+            + if self.h3_datagram {
+                sz(SettingId::H3_DATAGRAM.0) + 1
+            } else {
+                0
+            }
+            + if self.enable_webtransport {
+                sz(SettingId::ENABLE_WEBTRANSPORT.0) + 1
+            } else {
+                0
+            }
     }
 }
 
@@ -359,6 +540,12 @@ setting_identifiers! {
     QPACK_BLOCKED_STREAMS = 0x7,
     NUM_PLACEHOLDERS = 0x8,
     MAX_HEADER_LIST_SIZE = 0x6,
+    // RFC 8441 registers 0x8, which this draft still uses for NUM_PLACEHOLDERS
+    ENABLE_CONNECT_PROTOCOL = 0x2c,
+    // draft-ietf-masque-h3-datagram-00
+    H3_DATAGRAM = 0x276,
+    // draft-ietf-webtrans-http3-02
+    ENABLE_WEBTRANSPORT = 0x2b60_3742,
 }
 
 fn simple_frame_encode<B: BufMut>(ty: Type, id: u64, buf: &mut B) {
@@ -448,6 +635,59 @@ mod tests {
         );
     }
 
+    #[test]
+    fn origin_frame() {
+        let mut wire = vec![0xc, 43, 0, 17];
+        wire.extend_from_slice(b"https://a.example");
+        wire.extend_from_slice(&[0, 22]);
+        wire.extend_from_slice(b"https://b.example:8443");
+        codec_frame_check(
+            HttpFrame::Origin(OriginFrame {
+                origins: vec!["https://a.example".into(), "https://b.example:8443".into()],
+            }),
+            &wire,
+        );
+    }
+
+    #[test]
+    fn origin_frame_truncated_entry() {
+        let mut buf = Cursor::new(&[0xc, 4, 0, 3, b'a', b'b']);
+        let decoded = HttpFrame::decode(&mut buf);
+        assert_eq!(decoded, Err(Error::Malformed));
+    }
+
+    #[test]
+    fn priority_update_frames() {
+        let mut wire = vec![0x80, 0x0f, 0x07, 0x00, 7, 4];
+        wire.extend_from_slice(b"u=1, i");
+        codec_frame_check(
+            HttpFrame::PriorityUpdate(PriorityUpdateFrame {
+                push: false,
+                id: 4,
+                priority: "u=1, i".into(),
+            }),
+            &wire,
+        );
+        codec_frame_check(
+            HttpFrame::PriorityUpdate(PriorityUpdateFrame {
+                push: true,
+                id: 64,
+                priority: "".into(),
+            }),
+            &[0x80, 0x0f, 0x07, 0x01, 2, 0x40, 64],
+        );
+    }
+
+    #[test]
+    fn priority_update_frame_invalid() {
+        let mut buf = Cursor::new(&[0x80, 0x0f, 0x07, 0x00, 2, 0, 0xff]);
+        let decoded = HttpFrame::decode(&mut buf);
+        assert_eq!(decoded, Err(Error::InvalidFrameValue));
+        let mut buf = Cursor::new(&[0x80, 0x0f, 0x07, 0x00, 0]);
+        let decoded = HttpFrame::decode(&mut buf);
+        assert_eq!(decoded, Err(Error::UnexpectedEnd));
+    }
+
     fn codec_frame_check(frame: HttpFrame, wire: &[u8]) {
         let mut buf = Vec::new();
         frame.encode(&mut buf);
@@ -467,6 +707,9 @@ mod tests {
                 max_header_list_size: 0xfad1,
                 qpack_max_table_capacity: 0xfad2,
                 qpack_blocked_streams: 0xfad3,
+                enable_connect_protocol: false,
+                h3_datagram: false,
+                enable_webtransport: false,
             }),
             &[
                 4, 20, 8, 128, 0, 250, 218, 6, 128, 0, 250, 209, 1, 128, 0, 250, 210, 7, 128, 0,
@@ -475,6 +718,97 @@ mod tests {
         );
     }
 
+    #[test]
+    fn settings_frame_connect_protocol() {
+        codec_frame_check(
+            HttpFrame::Settings(SettingsFrame {
+                num_placeholders: 0xfada,
+                max_header_list_size: 0xfad1,
+                qpack_max_table_capacity: 0xfad2,
+                qpack_blocked_streams: 0xfad3,
+                enable_connect_protocol: true,
+                h3_datagram: false,
+                enable_webtransport: false,
+            }),
+            &[
+                4, 22, 8, 128, 0, 250, 218, 6, 128, 0, 250, 209, 1, 128, 0, 250, 210, 7, 128, 0,
+                250, 211, 0x2c, 1,
+            ],
+        );
+    }
+
+    #[test]
+    fn settings_frame_invalid_connect_protocol() {
+        let mut buf = Cursor::new(&[4, 2, 0x2c, 2]);
+        let decoded = HttpFrame::decode(&mut buf);
+        assert_eq!(
+            decoded,
+            Err(Error::Settings(
+                "invalid ENABLE_CONNECT_PROTOCOL value 2".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn settings_frame_h3_datagram() {
+        codec_frame_check(
+            HttpFrame::Settings(SettingsFrame {
+                num_placeholders: 0xfada,
+                max_header_list_size: 0xfad1,
+                qpack_max_table_capacity: 0xfad2,
+                qpack_blocked_streams: 0xfad3,
+                enable_connect_protocol: false,
+                h3_datagram: true,
+                enable_webtransport: false,
+            }),
+            &[
+                4, 23, 8, 128, 0, 250, 218, 6, 128, 0, 250, 209, 1, 128, 0, 250, 210, 7, 128, 0,
+                250, 211, 0x42, 0x76, 1,
+            ],
+        );
+    }
+
+    #[test]
+    fn settings_frame_invalid_h3_datagram() {
+        let mut buf = Cursor::new(&[4, 3, 0x42, 0x76, 2]);
+        let decoded = HttpFrame::decode(&mut buf);
+        assert_eq!(
+            decoded,
+            Err(Error::Settings("invalid H3_DATAGRAM value 2".to_string()))
+        );
+    }
+
+    #[test]
+    fn settings_frame_webtransport() {
+        codec_frame_check(
+            HttpFrame::Settings(SettingsFrame {
+                num_placeholders: 0xfada,
+                max_header_list_size: 0xfad1,
+                qpack_max_table_capacity: 0xfad2,
+                qpack_blocked_streams: 0xfad3,
+                enable_connect_protocol: false,
+                h3_datagram: false,
+                enable_webtransport: true,
+            }),
+            &[
+                4, 25, 8, 128, 0, 250, 218, 6, 128, 0, 250, 209, 1, 128, 0, 250, 210, 7, 128, 0,
+                250, 211, 0xab, 0x60, 0x37, 0x42, 1,
+            ],
+        );
+    }
+
+    #[test]
+    fn settings_frame_invalid_webtransport() {
+        let mut buf = Cursor::new(&[4, 5, 0xab, 0x60, 0x37, 0x42, 2]);
+        let decoded = HttpFrame::decode(&mut buf);
+        assert_eq!(
+            decoded,
+            Err(Error::Settings(
+                "invalid ENABLE_WEBTRANSPORT value 2".to_string()
+            ))
+        );
+    }
+
     #[test]
     fn data_frame() {
         codec_frame_check(
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use quinn_proto::{
     coding::{BufExt, BufMutExt, UnexpectedEnd},
@@ -24,6 +26,10 @@ stream_types! {
     PUSH = 0x01,
     ENCODER = 0x02,
     DECODER = 0x03,
+    // draft-ietf-webtrans-http3-02
+    WEBTRANSPORT_UNI = 0x54,
+    // Not a stream type as such, precedes the session ID on bidirectional WebTransport streams
+    WEBTRANSPORT_BI = 0x41,
 }
 
 impl StreamType {
@@ -43,13 +49,24 @@ impl StreamType {
     }
 }
 
+/// HTTP/3 error code, carried by stream resets and connection closes
+///
+/// Values other than the constants below can be used for application-specific errors.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-pub struct ErrorCode(pub(super) u32);
+pub struct ErrorCode(pub(super) u64);
 
 macro_rules! error_codes {
-    {$($name:ident = $val:expr,)*} => {
+    {$($name:ident = $val:literal,)*} => {
         impl ErrorCode {
             $(pub const $name: ErrorCode = ErrorCode($val);)*
+
+            /// Name of the constant for this code, unless it's application-specific
+            pub fn name(self) -> Option<&'static str> {
+                match self.0 {
+                    $($val => Some(stringify!($name)),)*
+                    _ => None,
+                }
+            }
         }
     }
 }
@@ -75,10 +92,49 @@ error_codes! {
     QPACK_DECOMPRESSION_FAILED = 0x200,
     QPACK_ENCODER_STREAM_ERROR = 0x201,
     QPACK_DECODER_STREAM_ERROR = 0x202,
+    WEBTRANSPORT_BUFFERED_STREAM_REJECTED = 0x3994_bd84,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> ErrorCode {
+        ErrorCode(code.into())
+    }
+}
+
+impl From<VarInt> for ErrorCode {
+    fn from(code: VarInt) -> ErrorCode {
+        ErrorCode(code.into_inner())
+    }
 }
 
 impl From<ErrorCode> for VarInt {
     fn from(error: ErrorCode) -> VarInt {
-        error.0.into()
+        // Only ever constructed from a `u32` or a `VarInt`
+        VarInt::from_u64(error.0).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_code_display() {
+        assert_eq!(ErrorCode::EXCESSIVE_LOAD.to_string(), "EXCESSIVE_LOAD");
+        assert_eq!(
+            ErrorCode::QPACK_DECOMPRESSION_FAILED.to_string(),
+            "QPACK_DECOMPRESSION_FAILED"
+        );
+        assert_eq!(ErrorCode::from(0x1337).to_string(), "0x1337");
+        assert_eq!(ErrorCode::from(0x1337).name(), None);
     }
 }
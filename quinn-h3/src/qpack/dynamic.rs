@@ -571,6 +571,11 @@ impl DynamicTable {
         }
     }
 
+    /// Number of insertions the decoder is known to have received
+    pub fn largest_known_received(&self) -> usize {
+        self.largest_known_received
+    }
+
     pub fn update_largest_received(&mut self, increment: usize) {
         self.largest_known_received += increment;
 
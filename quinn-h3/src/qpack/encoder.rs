@@ -141,6 +141,45 @@ fn encode_field<W: BufMut>(
     Ok(reference)
 }
 
+/// Insert `fields` in the dynamic table ahead of the header blocks that will reference them
+///
+/// Fields already in the static or dynamic table, and those that don't fit, are skipped. Returns
+/// the insert count the decoder must acknowledge for all of them to be usable without blocking.
+pub fn prefill<W: BufMut>(
+    table: &mut DynamicTable,
+    encoder: &mut W,
+    fields: &[HeaderField],
+) -> Result<usize, Error> {
+    // Not committed, so no references are tracked once this is dropped
+    let mut table = table.encoder(0);
+    for field in fields {
+        if StaticTable::find(field).is_some() {
+            continue;
+        }
+        match table.find(field) {
+            DynamicLookupResult::NotFound => (),
+            _ => continue,
+        }
+        match table.insert(field)? {
+            DynamicInsertionResult::Duplicated { relative, .. } => {
+                Duplicate(relative).encode(encoder);
+            }
+            DynamicInsertionResult::Inserted { .. } => {
+                InsertWithoutNameRef::new(field.name.clone(), field.value.clone())
+                    .encode(encoder)?;
+            }
+            DynamicInsertionResult::InsertedWithStaticNameRef { index, .. } => {
+                InsertWithNameRef::new_static(index, field.value.clone()).encode(encoder)?;
+            }
+            DynamicInsertionResult::InsertedWithNameRef { relative, .. } => {
+                InsertWithNameRef::new_dynamic(relative, field.value.clone()).encode(encoder)?;
+            }
+            DynamicInsertionResult::NotInserted(_) => (),
+        }
+    }
+    Ok(table.total_inserted())
+}
+
 pub fn on_decoder_recv<R: Buf>(table: &mut DynamicTable, read: &mut R) -> Result<(), Error> {
     while let Some(instruction) = parse_instruction(read)? {
         match instruction {
@@ -1,5 +1,5 @@
 use crate::qpack::{
-    decode_header, encode, on_decoder_recv, on_encoder_recv, DecoderError, DynamicTable,
+    decode_header, encode, on_decoder_recv, on_encoder_recv, prefill, DecoderError, DynamicTable,
     HeaderField,
 };
 use std::io::Cursor;
@@ -166,3 +166,46 @@ fn codec_table_full() {
     let mut dec_cur = Cursor::new(&mut dec_buf);
     on_decoder_recv(&mut enc_table, &mut dec_cur).unwrap();
 }
+
+#[test]
+fn codec_prefilled() {
+    let mut enc_table = DynamicTable::new();
+    enc_table.set_max_size(TABLE_SIZE).unwrap();
+    enc_table.set_max_blocked(100).unwrap();
+    let mut dec_table = DynamicTable::new();
+    dec_table.set_max_size(TABLE_SIZE).unwrap();
+    dec_table.set_max_blocked(100).unwrap();
+
+    let mut enc_buf = vec![];
+    let mut dec_buf = vec![];
+    let common = [
+        HeaderField::new(":method", "GET"),
+        HeaderField::new("user-agent", "quinn"),
+        HeaderField::new("x-token", "secret"),
+    ];
+    // The static table entry is skipped
+    assert_eq!(prefill(&mut enc_table, &mut enc_buf, &common), Ok(2));
+
+    let mut enc_cur = Cursor::new(&mut enc_buf);
+    on_encoder_recv(&mut dec_table.inserter(), &mut enc_cur, &mut dec_buf).unwrap();
+    let mut dec_cur = Cursor::new(&mut dec_buf);
+    on_decoder_recv(&mut enc_table, &mut dec_cur).unwrap();
+    assert_eq!(enc_table.largest_known_received(), 2);
+
+    let mut block_buf = vec![];
+    let mut enc_buf = vec![];
+    encode(
+        &mut enc_table.encoder(42),
+        &mut block_buf,
+        &mut enc_buf,
+        common.iter(),
+    )
+    .unwrap();
+    // Prefix followed by one index per field, with nothing more to insert
+    assert!(enc_buf.is_empty());
+    assert_eq!(block_buf.len(), 2 + common.len());
+
+    let mut block_cur = Cursor::new(&mut block_buf);
+    let (decoded, _) = decode_header(&dec_table, &mut block_cur).unwrap();
+    assert_eq!(decoded, common);
+}
@@ -3,33 +3,62 @@ use std::{
     io, mem,
     net::{SocketAddr, ToSocketAddrs},
     pin::Pin,
-    task::{Context, Poll},
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+    time::Duration,
 };
 
-use futures::{ready, Stream};
-use http::{response, Request, Response};
-use quinn::{CertificateChain, EndpointBuilder, PrivateKey, RecvStream, SendStream};
-use quinn_proto::{Side, StreamId};
+use bytes::BytesMut;
+use futures::{future, ready, Stream};
+use http::{request, response, Request, Response, StatusCode, Uri};
+use quinn::{CertificateChain, EndpointBuilder, PrivateKey, SendStream};
+use quinn_proto::{coding::BufMutExt, Side, StreamId, VarInt};
 use rustls::TLSError;
 
 use crate::{
     body::{Body, BodyReader, BodyWriter},
-    connection::{ConnectionDriver, ConnectionRef},
-    frame::{FrameDecoder, FrameStream, WriteFrame},
+    connection::{ConnectionDriver, ConnectionInner, ConnectionRef, HandshakeSummary, Lifetime},
+    datagram::DatagramFlow,
+    frame::{FrameStream, WriteFrame},
     headers::{DecodeHeaders, SendHeaders},
+    priority::Priority,
     proto::{
         frame::{DataFrame, HttpFrame},
         headers::Header,
-        ErrorCode,
+        ErrorCode, StreamType,
     },
     streams::Reset,
-    try_take, Error, Settings,
+    try_take,
+    webtransport::WebTransportSession,
+    Error, Settings,
 };
 
+/// Validate `origin` and normalize it to the form sent in an ORIGIN frame
+fn parse_origin(origin: &str) -> Result<String, InvalidOrigin> {
+    let invalid = || InvalidOrigin(origin.into());
+    // Only ASCII URIs up to 65534 bytes long parse, so the result fits an ORIGIN frame entry
+    let uri = origin.parse::<Uri>().map_err(|_| invalid())?;
+    match (uri.scheme(), uri.authority()) {
+        (Some(scheme), Some(authority))
+            if uri.path() == "/" && uri.query().is_none() && !authority.as_str().contains('@') =>
+        {
+            Ok(format!("{}://{}", scheme, authority))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// An origin passed to `Builder::origins` which can't be advertised
+#[derive(Debug, err_derive::Error)]
+#[error(display = "invalid origin {:?}", _0)]
+pub struct InvalidOrigin(pub String);
+
 pub struct Builder {
     config: quinn::ServerConfigBuilder,
     listen: Option<SocketAddr>,
     settings: Settings,
+    lifetime: Option<Lifetime>,
+    origins: Option<Arc<[String]>>,
 }
 
 impl Default for Builder {
@@ -41,6 +70,8 @@ impl Default for Builder {
             config,
             listen: None,
             settings: Settings::default(),
+            lifetime: None,
+            origins: None,
         }
     }
 }
@@ -52,6 +83,8 @@ impl Builder {
             config,
             listen: None,
             settings: Settings::default(),
+            lifetime: None,
+            origins: None,
         }
     }
 
@@ -79,6 +112,38 @@ impl Builder {
         self
     }
 
+    /// Shut connections down gracefully once they have been established for `lifetime`
+    ///
+    /// When the lifetime is reached, the server sends GOAWAY and refuses new requests. The
+    /// connection is closed with `H3_NO_ERROR` as soon as the requests in flight have completed, or
+    /// after `grace` at the latest. By default, connections may live indefinitely.
+    pub fn max_connection_lifetime(&mut self, lifetime: Duration, grace: Duration) -> &mut Self {
+        self.lifetime = Some(Lifetime {
+            max: lifetime,
+            grace,
+        });
+        self
+    }
+
+    /// Advertise the origins the server is authoritative for in an ORIGIN frame, see RFC 8336
+    ///
+    /// Each origin is serialized as in `https://example.com` or `https://example.com:8443`. Clients
+    /// can use the set to decide whether a connection may be reused for requests to other origins.
+    /// No ORIGIN frame is sent by default.
+    ///
+    /// Fails if an origin isn't a URI made of only a scheme and an authority.
+    pub fn origins(
+        &mut self,
+        origins: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<&mut Self, InvalidOrigin> {
+        let origins = origins
+            .into_iter()
+            .map(|x| parse_origin(x.as_ref()))
+            .collect::<Result<_, _>>()?;
+        self.origins = Some(origins);
+        Ok(self)
+    }
+
     pub fn endpoint(
         self,
         endpoint: EndpointBuilder,
@@ -87,15 +152,9 @@ impl Builder {
             .listen
             .unwrap_or_else(|| "[::]:4433".parse().expect("valid listen address"));
         let (endpoint_driver, _, incoming) = endpoint.bind(&listen)?;
-
-        Ok((
-            endpoint_driver,
-            Server,
-            IncomingConnection {
-                incoming,
-                settings: self.settings,
-            },
-        ))
+        let (server, incoming) =
+            IncomingConnection::new(incoming, self.settings, self.lifetime, self.origins);
+        Ok((endpoint_driver, server, incoming))
     }
 
     pub fn build(
@@ -108,33 +167,113 @@ impl Builder {
             .listen
             .unwrap_or_else(|| "[::]:4433".parse().expect("valid listen address"));
         let (endpoint_driver, _, incoming) = endpoint_builder.bind(&listen)?;
+        let (server, incoming) =
+            IncomingConnection::new(incoming, self.settings, self.lifetime, self.origins);
+        Ok((endpoint_driver, server, incoming))
+    }
+}
 
-        Ok((
-            endpoint_driver,
-            Server,
-            IncomingConnection {
-                incoming,
-                settings: self.settings,
-            },
-        ))
+/// Handle to the connections accepted through an `IncomingConnection`
+#[derive(Clone)]
+pub struct Server(Arc<Mutex<ServerState>>);
+
+impl Server {
+    /// Drain all connections, then close them
+    ///
+    /// Each connection sends GOAWAY and refuses new requests, and is closed with `H3_NO_ERROR`
+    /// once its requests in flight have completed, or after `timeout` at the latest.
+    /// `IncomingConnection` yields no more connections, and connections still being established
+    /// are shut down as soon as they are. Resolves once every connection has been closed, which
+    /// requires their `ConnectionDriver`s to keep running.
+    pub async fn graceful_shutdown(&self, timeout: Duration) {
+        let connections = {
+            let mut state = self.0.lock().unwrap();
+            state.shutdown = Some(timeout);
+            if let Some(t) = state.incoming_task.take() {
+                t.wake();
+            }
+            state
+                .connections
+                .drain(..)
+                .filter_map(|c| c.upgrade())
+                .collect::<Vec<_>>()
+        };
+        for conn in &connections {
+            conn.lock().unwrap().shutdown(timeout);
+        }
+        future::join_all(
+            connections
+                .iter()
+                .map(|conn| future::poll_fn(move |cx| conn.lock().unwrap().poll_finished(cx))),
+        )
+        .await;
     }
 }
 
-pub struct Server;
+#[derive(Default)]
+struct ServerState {
+    connections: Vec<Weak<Mutex<ConnectionInner>>>,
+    /// The grace period requests were given, once shutting down
+    shutdown: Option<Duration>,
+    incoming_task: Option<Waker>,
+}
+
+impl ServerState {
+    fn register(&mut self, conn: &ConnectionRef) {
+        if let Some(grace) = self.shutdown {
+            conn.h3.lock().unwrap().shutdown(grace);
+            return;
+        }
+        self.connections.retain(|c| c.strong_count() > 0);
+        self.connections.push(Arc::downgrade(&conn.h3));
+    }
+}
 
 pub struct IncomingConnection {
     incoming: quinn::Incoming,
     settings: Settings,
+    lifetime: Option<Lifetime>,
+    origins: Option<Arc<[String]>>,
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl IncomingConnection {
+    fn new(
+        incoming: quinn::Incoming,
+        settings: Settings,
+        lifetime: Option<Lifetime>,
+        origins: Option<Arc<[String]>>,
+    ) -> (Server, Self) {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let incoming = Self {
+            incoming,
+            settings,
+            lifetime,
+            origins,
+            state: state.clone(),
+        };
+        (Server(state), incoming)
+    }
 }
 
 impl Stream for IncomingConnection {
     type Item = Connecting;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.shutdown.is_some() {
+                return Poll::Ready(None);
+            }
+            state.incoming_task = Some(cx.waker().clone());
+        }
         Poll::Ready(
             ready!(Pin::new(&mut self.incoming).poll_next(cx)).map(|c| Connecting {
                 connecting: c,
                 settings: self.settings.clone(),
+                lifetime: self.lifetime,
+                origins: self.origins.clone(),
+                state: self.state.clone(),
             }),
         )
     }
@@ -143,6 +282,9 @@ impl Stream for IncomingConnection {
 pub struct Connecting {
     connecting: quinn::Connecting,
     settings: Settings,
+    lifetime: Option<Lifetime>,
+    origins: Option<Arc<[String]>>,
+    state: Arc<Mutex<ServerState>>,
 }
 
 impl Future for Connecting {
@@ -154,6 +296,7 @@ impl Future for Connecting {
             connection,
             bi_streams,
             uni_streams,
+            datagrams,
             ..
         } = ready!(Pin::new(&mut self.connecting).poll(cx))?;
         let conn_ref = ConnectionRef::new(
@@ -161,8 +304,20 @@ impl Future for Connecting {
             Side::Server,
             uni_streams,
             bi_streams,
+            datagrams,
             self.settings.clone(),
+            None,
+            self.lifetime,
         )?;
+        if let Some(ref origins) = self.origins {
+            conn_ref
+                .h3
+                .lock()
+                .unwrap()
+                .inner
+                .send_origin(origins.to_vec());
+        }
+        self.state.lock().unwrap().register(&conn_ref);
         Poll::Ready(Ok((
             driver,
             ConnectionDriver(conn_ref.clone()),
@@ -173,6 +328,34 @@ impl Future for Connecting {
 
 pub struct IncomingRequest(ConnectionRef);
 
+impl IncomingRequest {
+    /// Current congestion control and round-trip time state of the QUIC connection
+    ///
+    /// See `quinn::Connection::path_stats`.
+    pub fn path_stats(&self) -> quinn::PathStats {
+        self.0.quic.path_stats()
+    }
+
+    /// Snapshot of the connection's QUIC streams, for diagnostics
+    ///
+    /// Requests can be matched with their entries through `RecvRequest::stream_id`.
+    pub fn streams(&self) -> Vec<quinn::StreamInfo> {
+        self.0.quic.streams()
+    }
+
+    /// What was negotiated while establishing the connection, in a single snapshot
+    pub fn handshake_summary(&self) -> HandshakeSummary {
+        self.0.handshake_summary()
+    }
+
+    /// Close the connection immediately, sending `code` and `reason` to the client
+    ///
+    /// The client sees them through `Error::close_reason`.
+    pub fn close_with(self, code: ErrorCode, reason: &str) {
+        self.0.quic.close(code.into(), reason.as_bytes());
+    }
+}
+
 impl Stream for IncomingRequest {
     type Item = RecvRequest;
 
@@ -202,19 +385,30 @@ pub struct RecvRequest {
     conn: ConnectionRef,
     stream_id: StreamId,
     streams: Option<(FrameStream, SendStream)>,
+    priority_updates: Option<PriorityUpdates>,
 }
 
 impl RecvRequest {
-    fn new(recv: RecvStream, send: SendStream, conn: ConnectionRef) -> Self {
+    fn new(recv: FrameStream, send: SendStream, conn: ConnectionRef) -> Self {
         Self {
+            priority_updates: Some(PriorityUpdates {
+                conn: conn.clone(),
+                stream_id: send.id(),
+            }),
             conn,
             stream_id: send.id(),
             streams: None,
-            state: RecvRequestState::Receiving(FrameDecoder::stream(recv), send),
+            state: RecvRequestState::Receiving(recv, send),
         }
     }
 
+    /// ID of the QUIC stream carrying the request
+    pub fn stream_id(&self) -> StreamId {
+        self.stream_id
+    }
+
     fn build_request(&self, headers: Header) -> Result<Request<()>, Error> {
+        let protocol = headers.protocol().cloned();
         let (method, uri, headers) = headers.into_request_parts()?;
         let mut request = Request::builder()
             .method(method)
@@ -223,6 +417,9 @@ impl RecvRequest {
             .body(())
             .unwrap();
         *request.headers_mut() = headers;
+        if let Some(protocol) = protocol {
+            request.extensions_mut().insert(protocol);
+        }
         Ok(request)
     }
 
@@ -273,13 +470,31 @@ impl Future for RecvRequest {
                     let header = ready!(Pin::new(decode).poll(cx))?;
                     self.state = RecvRequestState::Finished;
                     let (recv, send) = try_take(&mut self.streams, "Recv request invalid state")?;
+                    let priority_updates =
+                        try_take(&mut self.priority_updates, "Recv request invalid state")?;
+                    let mut request = self.build_request(header)?;
+                    // An update received ahead of the request overrides its header
+                    let update = self
+                        .conn
+                        .h3
+                        .lock()
+                        .unwrap()
+                        .priorities
+                        .get(&self.stream_id)
+                        .copied();
+                    let priority =
+                        update.unwrap_or_else(|| Priority::from_headers(request.headers()));
+                    let _ = send.set_priority(priority.quic_priority());
+                    request.extensions_mut().insert(priority);
                     return Poll::Ready(Ok((
-                        self.build_request(header)?,
+                        request,
                         BodyReader::new(recv, self.conn.clone(), self.stream_id, false),
                         Sender {
                             send,
                             stream_id: self.stream_id,
                             conn: self.conn.clone(),
+                            priority,
+                            _priority_updates: priority_updates,
                         },
                     )));
                 }
@@ -295,9 +510,115 @@ pub struct Sender {
     send: SendStream,
     conn: ConnectionRef,
     stream_id: StreamId,
+    /// Priority signalled with the request
+    priority: Priority,
+    _priority_updates: PriorityUpdates,
 }
 
 impl Sender {
+    /// Current priority of the response, which the client may update while it is being sent
+    ///
+    /// The request's own signal is also found in its extensions. The QUIC stream carrying the
+    /// response is scheduled according to the latest one.
+    pub fn priority(&self) -> Priority {
+        let conn = self.conn.h3.lock().unwrap();
+        conn.priorities
+            .get(&self.stream_id)
+            .copied()
+            .unwrap_or(self.priority)
+    }
+
+    /// Open the request's HTTP/3 datagram flow
+    ///
+    /// The flow outlives the `Sender`, so it may be opened before sending the response. Fails with
+    /// `Error::Datagram` unless both ends enabled `Settings::h3_datagram`, or if the flow is
+    /// already open.
+    pub fn datagram_flow(&self) -> Result<DatagramFlow, Error> {
+        DatagramFlow::new(&self.conn, self.stream_id)
+    }
+
+    /// Accept the WebTransport session requested by this extended CONNECT request
+    ///
+    /// Sends a `200 OK` response. `body` is the reader of the request body, the CONNECT stream
+    /// lasting as long as the session. Fails with `Error::WebTransport` unless
+    /// `Settings::enable_webtransport` is enabled locally.
+    pub async fn accept_webtransport(self, body: BodyReader) -> Result<WebTransportSession, Error> {
+        let enabled = {
+            let conn = self.conn.h3.lock().unwrap();
+            conn.inner.local_settings().enable_webtransport
+        };
+        if !enabled {
+            return Err(Error::WebTransport("not enabled locally"));
+        }
+        let (conn, stream_id) = (self.conn.clone(), self.stream_id);
+        let writer = self
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await?;
+        WebTransportSession::new(&conn, stream_id, body, writer)
+    }
+
+    /// Send an informational (1xx) response ahead of the final one
+    ///
+    /// May be called any number of times before `send_response`, e.g. to send `103 Early Hints`.
+    /// Fails with `Error::UnexpectedStatus` for anything but a 1xx status other than
+    /// `101 Switching Protocols`, which HTTP/3 doesn't allow.
+    pub async fn send_interim(self, response: Response<()>) -> Result<Self, Error> {
+        let (
+            response::Parts {
+                status, headers, ..
+            },
+            _,
+        ) = response.into_parts();
+        if !status.is_informational() || status == StatusCode::SWITCHING_PROTOCOLS {
+            return Err(Error::UnexpectedStatus { status });
+        }
+
+        let send = SendHeaders::new(
+            Header::response(status, headers),
+            &self.conn,
+            self.send,
+            self.stream_id,
+        )?
+        .await?;
+        Ok(Self { send, ..self })
+    }
+
+    /// Promise the response to `request`, which is then sent through the returned `Push`
+    ///
+    /// Sends a PUSH_PROMISE frame on the request stream, which must precede the final response to
+    /// let the client know not to request the resource itself. Pushed requests should be safe and
+    /// cacheable, such as GETs. Fails with `Error::PushRefused` unless the client allows another
+    /// push on the connection, in which case the response can still be sent.
+    pub async fn push(&mut self, request: Request<()>) -> Result<Push, Error> {
+        let (
+            request::Parts {
+                method,
+                uri,
+                headers,
+                ..
+            },
+            _,
+        ) = request.into_parts();
+        let header = Header::request(method, uri, headers).map_err(Error::InvalidRequest)?;
+        let (push_id, frame) = {
+            let conn = &mut self.conn.h3.lock().unwrap();
+            let push_id = conn.inner.next_push_id().ok_or(Error::PushRefused)?;
+            (push_id, conn.inner.encode_push_promise(push_id, header)?)
+        };
+        let mut buf = BytesMut::new();
+        HttpFrame::PushPromise(frame).encode(&mut buf);
+        self.send.write_all(&buf).await?;
+        Ok(Push {
+            push_id,
+            conn: self.conn.clone(),
+            opened: false,
+        })
+    }
+
+    /// Send the final response, returning a writer for its body
+    ///
+    /// Fails with `Error::UnexpectedStatus` if the status is informational; those are sent with
+    /// `send_interim`.
     pub async fn send_response<T: Into<Body>>(
         self,
         response: Response<T>,
@@ -308,6 +629,9 @@ impl Sender {
             },
             body,
         ) = response.into_parts();
+        if status.is_informational() {
+            return Err(Error::UnexpectedStatus { status });
+        }
 
         let send = SendHeaders::new(
             Header::response(status, headers),
@@ -327,3 +651,86 @@ impl Sender {
         self.send.reset(ErrorCode::REQUEST_REJECTED.into());
     }
 }
+
+/// Forgets the priority updates received for a request once its response won't be sent
+///
+/// Held by the request until it's received, then by its `Sender`. Updates received while the
+/// response body is sent are forgotten by the `BodyWriter`.
+struct PriorityUpdates {
+    conn: ConnectionRef,
+    stream_id: StreamId,
+}
+
+impl Drop for PriorityUpdates {
+    fn drop(&mut self) {
+        let mut conn = self.conn.h3.lock().unwrap();
+        conn.priorities.remove(&self.stream_id);
+    }
+}
+
+/// A response promised with `Sender::push`
+///
+/// Dropping it before `send_response` has opened the push stream sends CANCEL_PUSH, so the client
+/// stops waiting for the response.
+pub struct Push {
+    push_id: u64,
+    conn: ConnectionRef,
+    /// Whether the push stream was opened, the push being cancelled by resetting it from then on
+    opened: bool,
+}
+
+impl Push {
+    /// ID of the push, as seen by the client
+    pub fn push_id(&self) -> u64 {
+        self.push_id
+    }
+
+    /// Open the push stream and send the response, returning a writer for its body
+    ///
+    /// Fails with `Error::UnexpectedStatus` if the status is informational.
+    pub async fn send_response<T: Into<Body>>(
+        mut self,
+        response: Response<T>,
+    ) -> Result<BodyWriter, Error> {
+        let (
+            response::Parts {
+                status, headers, ..
+            },
+            body,
+        ) = response.into_parts();
+        if status.is_informational() {
+            return Err(Error::UnexpectedStatus { status });
+        }
+
+        let mut send = self.conn.quic.open_uni().await?;
+        self.opened = true;
+        let mut prefix = Vec::with_capacity(2 * VarInt::MAX_SIZE);
+        StreamType::PUSH.encode(&mut prefix);
+        prefix.write_var(self.push_id);
+        send.write_all(&prefix).await?;
+
+        let stream_id = send.id();
+        let send = SendHeaders::new(
+            Header::response(status, headers),
+            &self.conn,
+            send,
+            stream_id,
+        )?
+        .await?;
+        let send = match body.into() {
+            Body::None => send,
+            Body::Buf(payload) => WriteFrame::new(send, DataFrame { payload }).await?,
+        };
+        Ok(BodyWriter::new(send, self.conn.clone(), stream_id, true))
+    }
+}
+
+impl Drop for Push {
+    fn drop(&mut self) {
+        if !self.opened {
+            let mut conn = self.conn.h3.lock().unwrap();
+            conn.inner.send_cancel_push(self.push_id);
+            conn.wake();
+        }
+    }
+}
@@ -13,7 +13,7 @@ use futures::{
     ready,
 };
 use quinn::{OpenUni, RecvStream, SendStream};
-use quinn_proto::VarInt;
+use quinn_proto::{coding::BufExt, VarInt};
 
 use crate::{
     frame::{FrameDecoder, FrameStream},
@@ -23,9 +23,12 @@ use crate::{
 
 pub enum NewUni {
     Control(FrameStream),
-    Push(PushStream),
+    /// Push stream, with the push ID following the stream type
+    Push(u64, FrameStream),
     Encoder(RecvStream),
     Decoder(RecvStream),
+    /// WebTransport stream, with the session ID following the stream type
+    WebTransport(u64, RecvStream),
     Reserved,
 }
 
@@ -35,7 +38,6 @@ impl TryFrom<(StreamType, RecvStream)> for NewUni {
         let (ty, recv) = value;
         Ok(match ty {
             StreamType::CONTROL => NewUni::Control(FrameDecoder::stream(recv)),
-            StreamType::PUSH => NewUni::Push(PushStream(FrameDecoder::stream(recv))),
             StreamType::ENCODER => NewUni::Encoder(recv),
             StreamType::DECODER => NewUni::Decoder(recv),
             t if t.0 > 0x21 && (t.0 - 0x21) % 0x1f == 0 => NewUni::Reserved,
@@ -46,12 +48,15 @@ impl TryFrom<(StreamType, RecvStream)> for NewUni {
 
 pub struct RecvUni {
     inner: Option<(RecvStream, [u8; VarInt::MAX_SIZE], usize, usize)>,
+    /// The stream type, once read if it's followed by an ID, such as PUSH and its push ID
+    ty: Option<StreamType>,
 }
 
 impl RecvUni {
     pub fn new(recv: RecvStream) -> Self {
         Self {
             inner: Some((recv, [0u8; VarInt::MAX_SIZE], 1, 0)),
+            ty: None,
         }
     }
 }
@@ -60,8 +65,9 @@ impl Future for RecvUni {
     type Output = Result<NewUni, Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
         loop {
-            match self.inner {
+            match this.inner {
                 None => panic!("polled after resolved"),
                 Some((ref mut recv, ref mut buf, ref mut expected, ref mut len)) => {
                     match ready!(Pin::new(recv).poll_read(cx, &mut buf[*len..*expected]))? {
@@ -77,14 +83,30 @@ impl Future for RecvUni {
                             }
                             if len == expected {
                                 let mut cur = io::Cursor::new(&buf);
-                                let ty = StreamType::decode(&mut cur)
+                                let value = cur
+                                    .get_var()
                                     .map_err(|_| Error::internal("stream type decode"))?;
-                                match mem::replace(&mut self.inner, None) {
-                                    Some((recv, _, _, _)) => {
-                                        return Poll::Ready(NewUni::try_from((ty, recv)))
-                                    }
+                                let ty = StreamType(value);
+                                if this.ty.is_none()
+                                    && (ty == StreamType::PUSH
+                                        || ty == StreamType::WEBTRANSPORT_UNI)
+                                {
+                                    this.ty = Some(ty);
+                                    *expected = 1;
+                                    *len = 0;
+                                    continue;
+                                }
+                                let recv = match this.inner.take() {
+                                    Some((recv, _, _, _)) => recv,
                                     _ => unreachable!(),
                                 };
+                                return Poll::Ready(match this.ty {
+                                    Some(StreamType::PUSH) => {
+                                        Ok(NewUni::Push(value, FrameDecoder::stream(recv)))
+                                    }
+                                    Some(_) => Ok(NewUni::WebTransport(value, recv)),
+                                    None => NewUni::try_from((ty, recv)),
+                                });
                             }
                         }
                     }
@@ -94,12 +116,80 @@ impl Future for RecvUni {
     }
 }
 
-pub struct PushStream(FrameStream);
+pub enum NewBi {
+    /// Request stream, decoding the bytes read to tell it apart first
+    Request(SendStream, FrameStream),
+    /// WebTransport stream, with the ID of its session
+    WebTransport(u64, SendStream, RecvStream),
+}
+
+/// Tell request streams and WebTransport streams apart, reading the session ID of the latter
+pub struct RecvBi {
+    inner: Option<(SendStream, RecvStream)>,
+    buf: [u8; VarInt::MAX_SIZE],
+    expected: usize,
+    len: usize,
+    /// Whether the stream is a WebTransport stream, and the session ID is being read
+    webtransport: bool,
+}
+
+impl RecvBi {
+    pub fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self {
+            inner: Some((send, recv)),
+            buf: [0u8; VarInt::MAX_SIZE],
+            expected: 1,
+            len: 0,
+            webtransport: false,
+        }
+    }
+}
+
+impl Future for RecvBi {
+    type Output = Result<NewBi, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            let (_, recv) = this.inner.as_mut().expect("polled after resolved");
+            let read =
+                ready!(Pin::new(recv).poll_read(cx, &mut this.buf[this.len..this.expected]))?;
+            if read == 0 {
+                return Poll::Ready(Err(Error::peer("Bi stream closed before type received")));
+            }
+            this.len += read;
+            if this.len == 1 {
+                this.expected = VarInt::encoded_size(this.buf[0]);
+            }
+            if this.len < this.expected {
+                continue;
+            }
+            let value = io::Cursor::new(&this.buf[..this.len])
+                .get_var()
+                .map_err(|_| Error::internal("stream type decode"))?;
+            if !this.webtransport && StreamType(value) == StreamType::WEBTRANSPORT_BI {
+                this.webtransport = true;
+                this.expected = 1;
+                this.len = 0;
+                continue;
+            }
+            let (send, recv) = this.inner.take().unwrap();
+            return Poll::Ready(Ok(if this.webtransport {
+                NewBi::WebTransport(value, send, recv)
+            } else {
+                let prefix = Bytes::copy_from_slice(&this.buf[..this.len]);
+                NewBi::Request(send, FrameDecoder::stream_with_prefix(recv, prefix))
+            }));
+        }
+    }
+}
 
 pub struct SendUni {
     ty: StreamType,
     state: SendUniState,
     data: VecDeque<Bytes>,
+    /// Data sent so far, kept while it may have to be sent again after 0-RTT is rejected
+    sent: Option<Vec<Bytes>>,
 }
 
 impl SendUni {
@@ -108,7 +198,34 @@ impl SendUni {
             ty,
             state: SendUniState::Opening(open_uni),
             data: VecDeque::with_capacity(2),
+            sent: None,
+        }
+    }
+
+    /// Keep the data sent until `confirm` or `replay`, as the stream is opened in 0-RTT
+    pub fn record(&mut self) {
+        self.sent = Some(Vec::new());
+    }
+
+    /// Forget the data sent, 0-RTT having been accepted
+    pub fn confirm(&mut self) {
+        self.sent = None;
+    }
+
+    /// Send everything again on a new stream, 0-RTT having been rejected
+    pub fn replay(&mut self, open_uni: quinn::OpenUni) {
+        for data in self.sent.take().into_iter().flatten().rev() {
+            self.data.push_front(data);
+        }
+        self.state = SendUniState::Opening(open_uni);
+    }
+
+    fn next(&mut self) -> Option<Bytes> {
+        let data = self.data.pop_front()?;
+        if let Some(ref mut sent) = self.sent {
+            sent.push(data.clone());
         }
+        Some(data)
     }
 }
 
@@ -139,7 +256,7 @@ impl Future for SendUni {
                     let send = ready!(Pin::new(o).poll(cx))?;
                     self.state = SendUniState::Sending(send, self.ty.encoded());
                 }
-                SendUniState::Idle(_) => match self.data.pop_front() {
+                SendUniState::Idle(_) => match self.next() {
                     Some(d) => match mem::replace(&mut self.state, SendUniState::Transitive) {
                         SendUniState::Idle(s) => self.state = SendUniState::Sending(s, d),
                         _ => unreachable!(),
@@ -151,7 +268,7 @@ impl Future for SendUni {
                     data.advance(wrote);
                     if data.is_empty() {
                         self.state = match mem::replace(&mut self.state, SendUniState::Transitive) {
-                            SendUniState::Sending(s, _) => match self.data.pop_front() {
+                            SendUniState::Sending(s, _) => match self.next() {
                                 Some(d) => SendUniState::Sending(s, d),
                                 None => SendUniState::Idle(s),
                             },
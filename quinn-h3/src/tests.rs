@@ -0,0 +1,2547 @@
+use std::{
+    future::Future,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::{stream, AsyncReadExt, AsyncWriteExt, StreamExt, TryFutureExt};
+use http::{HeaderMap, Request, Response, StatusCode};
+use quinn::{Certificate, CertificateChain, PrivateKey};
+
+use crate::{
+    alt_svc::AltSvcCache,
+    client,
+    datagram::DatagramFlow,
+    priority::{Priority, PRIORITY},
+    proto::{headers::Protocol, ErrorCode},
+    qpack::HeaderField,
+    server, Error, Settings,
+};
+
+/// Sets up a server listening on localhost and clients trusting its self-signed certificate
+struct Helper {
+    port: u16,
+    cert: Certificate,
+    cert_chain: CertificateChain,
+    key: PrivateKey,
+}
+
+impl Helper {
+    fn new() -> Self {
+        let port = free_port();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+        let cert = Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+        let cert_chain = CertificateChain::from_certs(vec![cert.clone()]);
+        Self {
+            port,
+            cert,
+            cert_chain,
+            key,
+        }
+    }
+
+    /// Another helper, using the same certificate on a different port
+    fn sibling(&self) -> Self {
+        Self {
+            port: free_port(),
+            cert: self.cert.clone(),
+            cert_chain: self.cert_chain.clone(),
+            key: self.key.clone(),
+        }
+    }
+
+    fn addr(&self) -> SocketAddr {
+        SocketAddr::new(Ipv6Addr::LOCALHOST.into(), self.port)
+    }
+
+    fn make_server(&self) -> server::IncomingConnection {
+        self.make_server_with(quinn::ServerConfigBuilder::default())
+    }
+
+    fn make_server_with(&self, config: quinn::ServerConfigBuilder) -> server::IncomingConnection {
+        self.serve(server::Builder::with_quic_config(config))
+    }
+
+    fn serve(&self, server: server::Builder) -> server::IncomingConnection {
+        self.serve_with_handle(server).1
+    }
+
+    fn serve_with_handle(
+        &self,
+        mut server: server::Builder,
+    ) -> (server::Server, server::IncomingConnection) {
+        server
+            .certificate(self.cert_chain.clone(), self.key.clone())
+            .unwrap();
+        server.listen(self.addr()).unwrap();
+        let (driver, server, incoming) = server.build().unwrap();
+        tokio::spawn(driver.unwrap_or_else(|e| panic!("server endpoint failed: {}", e)));
+        (server, incoming)
+    }
+
+    async fn make_connection(&self) -> client::Connection {
+        self.connect_to(self.addr()).await
+    }
+
+    async fn connect_to(&self, addr: SocketAddr) -> client::Connection {
+        let mut client = client::Builder::default();
+        client.add_certificate_authority(self.cert.clone()).unwrap();
+        let (driver, client) = client.build().unwrap();
+        tokio::spawn(driver.unwrap_or_else(|e| panic!("client endpoint failed: {}", e)));
+        let (quic_driver, driver, conn) = client
+            .connect(&addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        conn
+    }
+}
+
+fn free_port() -> u16 {
+    UdpSocket::bind("[::1]:0")
+        .and_then(|s| s.local_addr())
+        .expect("find a free port")
+        .port()
+}
+
+/// Forwards datagrams between a single client and the server, counting those sent by the client
+struct Relay {
+    addr: SocketAddr,
+    sent: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Relay {
+    fn new(server: SocketAddr) -> Self {
+        let front = UdpSocket::bind("[::1]:0").unwrap();
+        let back = UdpSocket::bind("[::1]:0").unwrap();
+        let addr = front.local_addr().unwrap();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let client = Arc::new(Mutex::new(None));
+        for socket in &[&front, &back] {
+            socket
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+        }
+
+        let (front2, back2) = (front.try_clone().unwrap(), back.try_clone().unwrap());
+        let (sent2, stop2, client2) = (sent.clone(), stop.clone(), client.clone());
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            while !stop2.load(Ordering::Relaxed) {
+                if let Ok((n, from)) = front2.recv_from(&mut buf) {
+                    *client2.lock().unwrap() = Some(from);
+                    sent2.fetch_add(1, Ordering::Relaxed);
+                    back2.send_to(&buf[..n], server).unwrap();
+                }
+            }
+        });
+        let stop2 = stop.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            while !stop2.load(Ordering::Relaxed) {
+                if let Ok(n) = back.recv(&mut buf) {
+                    if let Some(client) = *client.lock().unwrap() {
+                        front.send_to(&buf[..n], client).unwrap();
+                    }
+                }
+            }
+        });
+
+        Self { addr, sent, stop }
+    }
+
+    /// Number of datagrams forwarded from the client so far
+    fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Accept the first incoming connection
+async fn accept_connection(incoming: &mut server::IncomingConnection) -> server::IncomingRequest {
+    let (quic_driver, driver, requests) = incoming
+        .next()
+        .await
+        .expect("incoming connection")
+        .await
+        .expect("accept");
+    tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    requests
+}
+
+/// Accept the first request of the first incoming connection
+async fn accept_request(
+    incoming: &mut server::IncomingConnection,
+) -> (
+    http::Request<()>,
+    crate::body::BodyReader,
+    server::Sender,
+    server::IncomingRequest,
+) {
+    let mut requests = accept_connection(incoming).await;
+    let (request, body, sender) = requests
+        .next()
+        .await
+        .expect("incoming request")
+        .await
+        .expect("receive request");
+    (request, body, sender, requests)
+}
+
+fn get(helper: &Helper) -> Request<()> {
+    Request::get(format!("https://localhost:{}/", helper.port))
+        .body(())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn drop_body_reader_stops_sending() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    let server = tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        let mut body = sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        let chunk = [0xAB; 1024];
+        loop {
+            if let Err(e) = body.write_all(&chunk).await {
+                return e;
+            }
+        }
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (_, mut body) = response.await.expect("response");
+    let mut buf = [0; 16];
+    body.read_exact(&mut buf).await.expect("read");
+    drop(body);
+
+    let err: io::Error = server.await.unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    assert_matches!(
+        err.get_ref().and_then(|e| e.downcast_ref::<quinn::WriteError>()),
+        Some(quinn::WriteError::Stopped(code)) if *code == ErrorCode::REQUEST_CANCELLED.into()
+    );
+}
+
+#[tokio::test]
+async fn drop_recv_response_resets_request() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    let server = tokio::spawn(async move {
+        let (_, mut body, sender, _requests) = accept_request(&mut incoming).await;
+        let read = body.data().await;
+        // Give STOP_SENDING, sent along with RESET_STREAM, a chance to be processed
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        let write = sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await;
+        (read, write.map(|_| ()))
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _body_writer) = conn.send_request(get(&helper)).await.expect("request");
+    // Let the server receive the request headers before cancelling
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    drop(response);
+
+    let (read, write) = server.await.unwrap();
+    match read {
+        Some(Err(Error::Io(e))) => assert_matches!(
+            e.get_ref().and_then(|e| e.downcast_ref::<quinn::ReadError>()),
+            Some(quinn::ReadError::Reset(code)) if *code == ErrorCode::REQUEST_CANCELLED.into()
+        ),
+        x => panic!(
+            "unexpected body read result: {:?}",
+            x.map(|x| x.map(|_| ()))
+        ),
+    }
+    match write {
+        Err(Error::Io(e)) => assert_matches!(
+            e.get_ref().and_then(|e| e.downcast_ref::<quinn::WriteError>()),
+            Some(quinn::WriteError::Stopped(code)) if *code == ErrorCode::REQUEST_CANCELLED.into()
+        ),
+        x => panic!("unexpected response write result: {:?}", x),
+    }
+}
+
+#[tokio::test]
+async fn send_request_stream() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    let server = tokio::spawn(async move {
+        let (_, body, sender, _requests) = accept_request(&mut incoming).await;
+        let chunks = body
+            .map(|x| x.expect("read body"))
+            .collect::<Vec<_>>()
+            .await;
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        chunks
+    });
+
+    let conn = helper.make_connection().await;
+    let body = stream::iter(vec![
+        Ok::<_, io::Error>(Bytes::from_static(b"hello")),
+        Ok(Bytes::new()),
+        Ok(Bytes::from_static(b" world")),
+    ]);
+    let response = conn
+        .send_request_stream(get(&helper), body)
+        .await
+        .expect("request");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.await.unwrap().concat(), b"hello world");
+}
+
+#[tokio::test]
+async fn send_request_stream_error() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    let server = tokio::spawn(async move {
+        let mut requests = accept_connection(&mut incoming).await;
+        // The reset may arrive before or after the request headers are decoded
+        match requests.next().await.expect("incoming request").await {
+            Err(e) => e,
+            Ok((_, mut body, _)) => loop {
+                match body.data().await {
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => break e,
+                    None => panic!("body finished despite reset"),
+                }
+            },
+        }
+    });
+
+    let conn = helper.make_connection().await;
+    let body = stream::iter(vec![
+        Ok(Bytes::from_static(b"hello")),
+        Err(io::Error::new(io::ErrorKind::Other, "source failed")),
+    ]);
+    assert_matches!(
+        conn.send_request_stream(get(&helper), body).await.err(),
+        Some(Error::Body(_))
+    );
+    assert_matches!(
+        server.await.unwrap(),
+        Error::Io(ref e) if e.kind() == io::ErrorKind::ConnectionReset
+    );
+}
+
+#[tokio::test]
+async fn trailers_only_response() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        let body = sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .header("content-type", "application/grpc")
+                    .body(())
+                    .unwrap(),
+            )
+            .await
+            .expect("send response");
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        body.send_trailers(trailers).await.expect("send trailers");
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (response, mut body) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/grpc");
+    let trailers = body
+        .trailers()
+        .await
+        .expect("missing trailers")
+        .expect("decode trailers");
+    assert_eq!(trailers["grpc-status"], "0");
+    assert!(body.data().await.is_none());
+}
+
+#[tokio::test]
+async fn trailers_before_body() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        let body = sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .body("data")
+                    .unwrap(),
+            )
+            .await
+            .expect("send response");
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        body.send_trailers(trailers).await.expect("send trailers");
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (_, mut body) = response.await.expect("response");
+    assert_matches!(body.trailers().await, Some(Err(Error::UnreadBody)));
+    assert_eq!(body.data().await.expect("body").expect("body"), "data");
+    let trailers = body
+        .trailers()
+        .await
+        .expect("missing trailers")
+        .expect("decode trailers");
+    assert_eq!(trailers["grpc-status"], "0");
+}
+
+#[tokio::test]
+async fn request_trailers() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    let server = tokio::spawn(async move {
+        let (_, mut body, sender, _requests) = accept_request(&mut incoming).await;
+        assert_eq!(body.data().await.expect("body").expect("body"), "message");
+        let trailers = body
+            .trailers()
+            .await
+            .expect("missing trailers")
+            .expect("decode trailers");
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        trailers
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, mut body) = conn.send_request(get(&helper)).await.expect("request");
+    body.write_all(b"message").await.expect("write body");
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-timeout", "1S".parse().unwrap());
+    body.send_trailers(trailers).await.expect("send trailers");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.await.unwrap()["grpc-timeout"], "1S");
+}
+
+#[tokio::test]
+async fn headers_only_response() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        let body = sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .header("grpc-status", "14")
+                    .body(())
+                    .unwrap(),
+            )
+            .await
+            .expect("send response");
+        body.close().await.expect("close");
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (response, mut body) = response.await.expect("response");
+    assert_eq!(response.headers()["grpc-status"], "14");
+    assert!(body.data().await.is_none());
+    assert!(body.trailers().await.is_none());
+}
+
+#[tokio::test]
+async fn read_body_into_buffer() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let expected = (0..80_000).map(|i| i as u8).collect::<Vec<u8>>();
+    let sent = expected.clone();
+
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        let mut body = sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        for chunk in sent.chunks(20_000) {
+            body.write_all(chunk).await.expect("write body");
+        }
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        body.send_trailers(trailers).await.expect("send trailers");
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (_, mut body) = response.await.expect("response");
+    let mut buf = [0; 4096];
+    let mut received = Vec::new();
+    while let Some(data) = body.data_into(&mut buf).await {
+        received.extend_from_slice(data.expect("read body"));
+    }
+    assert_eq!(received, expected);
+    let trailers = body
+        .trailers()
+        .await
+        .expect("missing trailers")
+        .expect("decode trailers");
+    assert_eq!(trailers["grpc-status"], "0");
+}
+
+#[tokio::test]
+async fn batch_requests() {
+    const REQUESTS: usize = 100;
+    let helper = Helper::new();
+    let mut config = quinn::ServerConfig::default();
+    Arc::get_mut(&mut config.transport)
+        .unwrap()
+        .stream_window_bidi = REQUESTS as u64;
+    let mut incoming = helper.make_server_with(quinn::ServerConfigBuilder::new(config));
+
+    tokio::spawn(async move {
+        let mut requests = accept_connection(&mut incoming).await;
+        while let Some(request) = requests.next().await {
+            tokio::spawn(async move {
+                let (_, _, sender) = request.await.expect("receive request");
+                sender
+                    .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+                    .await
+                    .expect("send response");
+            });
+        }
+    });
+
+    let relay = Relay::new(helper.addr());
+    let conn = helper.connect_to(relay.addr).await;
+    // Let the handshake and control stream traffic settle
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    let batch = conn.batch();
+    let before = relay.sent();
+    let mut responses = Vec::with_capacity(REQUESTS);
+    for _ in 0..REQUESTS {
+        let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+        responses.push(response);
+    }
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(relay.sent(), before, "packets sent during batch");
+    drop(batch);
+
+    for response in responses {
+        let (response, _) = response.await.expect("response");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let sent = relay.sent() - before;
+    assert!(
+        sent < REQUESTS / 10,
+        "{} datagrams sent for {} requests",
+        sent,
+        REQUESTS
+    );
+}
+
+/// Accept a QUIC connection for HTTP/3 without ever opening the control stream
+fn make_silent_server(helper: &Helper) -> impl Future<Output = quinn::NewConnection> {
+    let mut config = quinn::ServerConfigBuilder::default();
+    config.protocols(&[crate::ALPN]);
+    config
+        .certificate(helper.cert_chain.clone(), helper.key.clone())
+        .unwrap();
+    let mut endpoint = quinn::Endpoint::builder();
+    endpoint.listen(config.build());
+    let (driver, _, mut incoming) = endpoint.bind(&helper.addr()).unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("server endpoint failed: {}", e)));
+    async move {
+        let conn = incoming
+            .next()
+            .await
+            .expect("incoming connection")
+            .await
+            .expect("accept");
+        conn
+    }
+}
+
+#[tokio::test]
+async fn no_common_alpn() {
+    let helper = Helper::new();
+    let mut config = quinn::ServerConfigBuilder::default();
+    config.protocols(&[b"h3-20"]).require_protocol(true);
+    config
+        .certificate(helper.cert_chain.clone(), helper.key.clone())
+        .unwrap();
+    let mut endpoint = quinn::Endpoint::builder();
+    endpoint.listen(config.build());
+    let (driver, _, mut incoming) = endpoint.bind(&helper.addr()).unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("server endpoint failed: {}", e)));
+    tokio::spawn(async move {
+        let connecting = incoming.next().await.expect("incoming connection");
+        assert_matches!(
+            connecting.await,
+            Err(quinn::ConnectionError::TransportError(_))
+        );
+    });
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap();
+    let (driver, client) = client.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("client endpoint failed: {}", e)));
+    match client.connect(&helper.addr(), "localhost").unwrap().await {
+        Err(Error::NoCommonAlpn { offered, reason }) => {
+            assert_eq!(offered, vec!["h3-24".to_string()]);
+            assert_eq!(
+                reason,
+                "no application protocol negotiated, client offered h3-24"
+            );
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected without a common protocol"),
+    }
+}
+
+async fn connect_with_grace(
+    helper: &Helper,
+    strict: bool,
+) -> (crate::connection::ConnectionDriver, client::Connection) {
+    let mut client = client::Builder::default();
+    client.settings_grace_period(Duration::from_millis(50), strict);
+    connect_with(helper, client).await
+}
+
+async fn connect_with(
+    helper: &Helper,
+    mut client: client::Builder,
+) -> (crate::connection::ConnectionDriver, client::Connection) {
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap();
+    let (driver, client) = client.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("client endpoint failed: {}", e)));
+    let (quic_driver, driver, conn) = client
+        .connect(&helper.addr(), "localhost")
+        .unwrap()
+        .await
+        .expect("connect");
+    tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+    (driver, conn)
+}
+
+#[tokio::test]
+async fn missing_settings_defaults() {
+    let helper = Helper::new();
+    let server = tokio::spawn(make_silent_server(&helper));
+
+    let (driver, _conn) = connect_with_grace(&helper, false).await;
+    let conn_ref = driver.0.clone();
+    assert!(conn_ref
+        .h3
+        .lock()
+        .unwrap()
+        .inner
+        .remote_settings()
+        .is_none());
+    let _server = server.await.unwrap();
+    let driver = tokio::time::timeout(Duration::from_millis(200), driver).await;
+    assert!(driver.is_err(), "driver finished: {:?}", driver);
+    assert_matches!(
+        conn_ref.h3.lock().unwrap().inner.remote_settings(),
+        Some(settings) if settings.qpack_max_table_capacity == 0
+    );
+}
+
+#[tokio::test]
+async fn missing_settings_strict() {
+    let helper = Helper::new();
+    let server = tokio::spawn(make_silent_server(&helper));
+
+    let (driver, _conn) = connect_with_grace(&helper, true).await;
+    let quinn::NewConnection {
+        driver: server_driver,
+        connection: _server_conn,
+        ..
+    } = server.await.unwrap();
+    assert_matches!(
+        tokio::time::timeout(Duration::from_millis(200), driver).await,
+        Ok(Err(Error::Peer(_)))
+    );
+    assert_matches!(
+        server_driver.await,
+        Err(quinn::ConnectionError::ApplicationClosed(close))
+            if close.error_code == ErrorCode::MISSING_SETTINGS.into()
+    );
+}
+
+/// Accept connections that reach their maximum lifetime after 100ms, responding to each request
+/// after `delay`
+fn make_short_lived_server(helper: &Helper, delay: Duration) {
+    let mut server = server::Builder::default();
+    server.max_connection_lifetime(Duration::from_millis(100), Duration::from_millis(500));
+    let mut incoming = helper.serve(server);
+    tokio::spawn(async move {
+        let mut requests = accept_connection(&mut incoming).await;
+        while let Some(request) = requests.next().await {
+            tokio::spawn(async move {
+                let (_, _, sender) = request.await.expect("receive request");
+                tokio::time::delay_for(delay).await;
+                let _ = sender
+                    .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+                    .await;
+            });
+        }
+    });
+}
+
+#[tokio::test]
+async fn max_lifetime_closes_idle_connection() {
+    let helper = Helper::new();
+    make_short_lived_server(&helper, Duration::from_millis(0));
+
+    let (driver, _conn) = connect_with(&helper, client::Builder::default()).await;
+    assert_matches!(
+        tokio::time::timeout(Duration::from_millis(400), driver).await,
+        Ok(Err(Error::Quic(quinn::ConnectionError::ApplicationClosed(close))))
+            if close.error_code == ErrorCode::NO_ERROR.into()
+    );
+}
+
+#[tokio::test]
+async fn max_lifetime_drains_requests() {
+    let helper = Helper::new();
+    make_short_lived_server(&helper, Duration::from_millis(200));
+
+    let (driver, conn) = connect_with(&helper, client::Builder::default()).await;
+    let driver = tokio::spawn(driver);
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    // The client stops driving the connection once it has seen GOAWAY
+    assert_matches!(
+        tokio::time::timeout(Duration::from_millis(400), driver).await,
+        Ok(Ok(Ok(())))
+    );
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_matches!(
+        conn.send_request(get(&helper)).await.map(|_| ()),
+        Err(Error::Quic(quinn::ConnectionError::ApplicationClosed(close)))
+            if close.error_code == ErrorCode::NO_ERROR.into()
+    );
+}
+
+#[tokio::test]
+async fn max_lifetime_grace_period() {
+    let helper = Helper::new();
+    make_short_lived_server(&helper, Duration::from_secs(10));
+
+    let (driver, conn) = connect_with(&helper, client::Builder::default()).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    assert_matches!(
+        tokio::time::timeout(Duration::from_secs(1), response.map_ok(|_| ())).await,
+        Ok(Err(Error::Io(e))) if e.kind() == io::ErrorKind::ConnectionAborted
+    );
+}
+
+#[tokio::test]
+async fn graceful_shutdown_drains_requests() {
+    let helper = Helper::new();
+    let (server, mut incoming) = helper.serve_with_handle(server::Builder::default());
+    let (received_send, received) = futures::channel::oneshot::channel::<()>();
+    let serving = tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        received_send.send(()).unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .body("done")
+                    .unwrap(),
+            )
+            .await
+            .expect("send response")
+            .close()
+            .await
+            .expect("close body");
+        incoming
+    });
+
+    let (driver, conn) = connect_with(&helper, client::Builder::default()).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    received.await.unwrap();
+    let shutdown =
+        tokio::spawn(async move { server.graceful_shutdown(Duration::from_secs(5)).await });
+
+    let (response, mut body) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body.data().await.expect("body").expect("body"), "done");
+    tokio::time::timeout(Duration::from_secs(1), shutdown)
+        .await
+        .expect("shutdown timed out")
+        .unwrap();
+    let mut incoming = serving.await.unwrap();
+    assert!(incoming.next().await.is_none());
+}
+
+#[tokio::test]
+async fn graceful_shutdown_timeout() {
+    let helper = Helper::new();
+    let (server, mut incoming) = helper.serve_with_handle(server::Builder::default());
+    let (received_send, received) = futures::channel::oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        received_send.send(()).unwrap();
+        // Never respond
+        tokio::time::delay_for(Duration::from_secs(10)).await;
+        drop(sender);
+    });
+
+    let (driver, conn) = connect_with(&helper, client::Builder::default()).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    received.await.unwrap();
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        server.graceful_shutdown(Duration::from_millis(100)),
+    )
+    .await
+    .expect("shutdown timed out");
+    assert_matches!(
+        response.await.map(|_| ()),
+        Err(Error::Io(e)) if e.kind() == io::ErrorKind::ConnectionAborted
+    );
+}
+
+/// Wait for the server's SETTINGS, returning whether extended CONNECT is enabled
+async fn connect_protocol_enabled(conn: &client::Connection) -> bool {
+    loop {
+        if let Some(enabled) = conn.connect_protocol_enabled() {
+            return enabled;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn extended_connect() {
+    let helper = Helper::new();
+    let mut server = server::Builder::default();
+    server.settings(Settings {
+        enable_connect_protocol: true,
+        ..Settings::default()
+    });
+    let mut incoming = helper.serve(server);
+
+    tokio::spawn(async move {
+        let (request, _, sender, _requests) = accept_request(&mut incoming).await;
+        assert_eq!(request.method(), http::Method::CONNECT);
+        assert_eq!(
+            request.extensions().get::<Protocol>().map(Protocol::as_str),
+            Some("websocket")
+        );
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+    });
+
+    let conn = helper.make_connection().await;
+    assert!(connect_protocol_enabled(&conn).await);
+    let authority = format!("localhost:{}", helper.port).parse().unwrap();
+    let (response, _) = conn
+        .extended_connect(authority, "websocket")
+        .await
+        .expect("extended connect");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn priority_header() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    tokio::spawn(async move {
+        let (request, _, sender, _requests) = accept_request(&mut incoming).await;
+        let priority = *request.extensions().get::<Priority>().unwrap();
+        assert_eq!(sender.priority(), priority);
+        sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .header(PRIORITY, priority.to_header_value())
+                    .body(())
+                    .unwrap(),
+            )
+            .await
+            .expect("send response");
+    });
+
+    let conn = helper.make_connection().await;
+    let mut request = get(&helper);
+    request
+        .headers_mut()
+        .insert(PRIORITY, "u=1, i".parse().unwrap());
+    let (response, _) = conn.send_request(request).await.expect("request");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(
+        Priority::from_headers(response.headers()),
+        Priority {
+            urgency: 1,
+            incremental: true
+        }
+    );
+}
+
+#[tokio::test]
+async fn priority_update() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        while sender.priority() == Priority::default() {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        let priority = sender.priority();
+        sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .header(PRIORITY, priority.to_header_value())
+                    .body(())
+                    .unwrap(),
+            )
+            .await
+            .expect("send response");
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    response.set_priority(Priority::parse("u=6"));
+    let (response, _) = response.await.expect("response");
+    assert_eq!(Priority::from_headers(response.headers()).urgency, 6);
+}
+
+/// Open a datagram flow, waiting for the peer's SETTINGS if needed
+async fn open_flow(open: impl Fn() -> Result<DatagramFlow, Error>) -> DatagramFlow {
+    loop {
+        match open() {
+            Err(Error::Datagram("peer SETTINGS not received yet")) => {
+                tokio::time::delay_for(Duration::from_millis(10)).await
+            }
+            x => return x.expect("datagram flow"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn h3_datagrams() {
+    let helper = Helper::new();
+    let settings = Settings {
+        h3_datagram: true,
+        ..Settings::default()
+    };
+    let mut server = server::Builder::default();
+    server.settings(settings.clone());
+    let mut incoming = helper.serve(server);
+
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        let mut flow = open_flow(|| sender.datagram_flow()).await;
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        // Echo datagrams back on the request's flow
+        while let Some(datagram) = flow.recv().await {
+            flow.send(datagram).await.expect("send datagram");
+        }
+    });
+
+    let mut client = client::Builder::default();
+    client.settings(settings);
+    let (driver, conn) = connect_with(&helper, client).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let request = Request::get(format!("https://localhost:{}/", helper.port))
+        .body(())
+        .unwrap();
+    let (response, _) = conn.send_request(request).await.expect("request");
+    let mut flow = open_flow(|| response.datagram_flow()).await;
+    assert_eq!(flow.flow_id(), response.stream_id().index());
+    assert_matches!(
+        response.datagram_flow(),
+        Err(Error::Datagram("flow already open"))
+    );
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    flow.send(Bytes::from_static(b"ping"))
+        .await
+        .expect("send datagram");
+    assert_eq!(flow.recv().await, Some(Bytes::from_static(b"ping")));
+}
+
+#[tokio::test]
+async fn h3_datagrams_disabled_by_peer() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+    });
+
+    let mut client = client::Builder::default();
+    client.settings(Settings {
+        h3_datagram: true,
+        ..Settings::default()
+    });
+    let (driver, conn) = connect_with(&helper, client).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let request = Request::get(format!("https://localhost:{}/", helper.port))
+        .body(())
+        .unwrap();
+    let (response, _) = conn.send_request(request).await.expect("request");
+    while conn.handshake_summary().peer_settings.is_none() {
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_matches!(
+        response.datagram_flow(),
+        Err(Error::Datagram("not enabled by the peer"))
+    );
+}
+
+#[tokio::test]
+async fn webtransport() {
+    let helper = Helper::new();
+    let mut server = server::Builder::default();
+    server.settings(Settings {
+        enable_connect_protocol: true,
+        enable_webtransport: true,
+        h3_datagram: true,
+        ..Settings::default()
+    });
+    let mut incoming = helper.serve(server);
+
+    tokio::spawn(async move {
+        let (request, body, sender, _requests) = accept_request(&mut incoming).await;
+        assert_eq!(
+            request.extensions().get::<Protocol>().map(Protocol::as_str),
+            Some("webtransport")
+        );
+        let mut session = sender.accept_webtransport(body).await.expect("accept");
+        let mut flow = session.datagram_flow().expect("datagram flow");
+
+        // Echo a bidirectional stream, then a datagram, then send a unidirectional stream
+        let (mut send, recv) = session.accept_bi().await.expect("accept bi");
+        let data = recv.read_to_end(1024).await.expect("read bi");
+        send.write_all(&data).await.expect("write bi");
+        send.finish().await.expect("finish bi");
+        let datagram = flow.recv().await.expect("recv datagram");
+        flow.send(datagram).await.expect("send datagram");
+        let mut send = session.open_uni().await.expect("open uni");
+        send.write_all(b"uni").await.expect("write uni");
+        send.finish().await.expect("finish uni");
+        session.closed().await.expect("closed");
+    });
+
+    let mut client = client::Builder::default();
+    client.settings(Settings {
+        enable_webtransport: true,
+        h3_datagram: true,
+        ..Settings::default()
+    });
+    let (driver, conn) = connect_with(&helper, client).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    assert!(connect_protocol_enabled(&conn).await);
+    let authority = format!("localhost:{}", helper.port).parse().unwrap();
+    let session = conn.webtransport(authority).await.expect("webtransport");
+    let mut flow = session.datagram_flow().expect("datagram flow");
+    assert_eq!(flow.flow_id(), session.session_id().index());
+
+    let (mut send, recv) = session.open_bi().await.expect("open bi");
+    send.write_all(b"bi").await.expect("write bi");
+    send.finish().await.expect("finish bi");
+    assert_eq!(recv.read_to_end(1024).await.expect("read bi"), b"bi");
+    flow.send(Bytes::from_static(b"datagram"))
+        .await
+        .expect("send datagram");
+    assert_eq!(flow.recv().await, Some(Bytes::from_static(b"datagram")));
+    let recv = session.accept_uni().await.expect("accept uni");
+    assert_eq!(recv.read_to_end(1024).await.expect("read uni"), b"uni");
+    session.close().await.expect("close");
+}
+
+#[tokio::test]
+async fn webtransport_disabled_by_server() {
+    let helper = Helper::new();
+    let mut server = server::Builder::default();
+    server.settings(Settings {
+        enable_connect_protocol: true,
+        ..Settings::default()
+    });
+    let mut incoming = helper.serve(server);
+    tokio::spawn(async move {
+        let _requests = accept_connection(&mut incoming).await;
+        futures::future::pending::<()>().await;
+    });
+
+    let mut client = client::Builder::default();
+    client.settings(Settings {
+        enable_webtransport: true,
+        ..Settings::default()
+    });
+    let (driver, conn) = connect_with(&helper, client).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    assert!(connect_protocol_enabled(&conn).await);
+    let authority = format!("localhost:{}", helper.port).parse().unwrap();
+    assert_matches!(
+        conn.webtransport(authority).await,
+        Err(Error::WebTransport("not enabled by the server"))
+    );
+}
+
+#[tokio::test]
+async fn prewarm() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let (request, _, sender, _requests) = accept_request(&mut incoming).await;
+        assert_eq!(request.headers()["user-agent"], "quinn");
+        assert_eq!(request.headers()["authorization"], "Bearer token");
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+    });
+
+    let conn = helper.make_connection().await;
+    conn.prewarm(&[
+        HeaderField::new("user-agent", "quinn"),
+        HeaderField::new("authorization", "Bearer token"),
+    ])
+    .await
+    .expect("prewarm");
+
+    let request = Request::get(format!("https://localhost:{}/", helper.port))
+        .header("user-agent", "quinn")
+        .header("authorization", "Bearer token")
+        .body(())
+        .unwrap();
+    let (response, _) = conn.send_request(request).await.expect("request");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn dynamic_table_across_requests() {
+    let helper = Helper::new();
+    let settings = Settings {
+        qpack_max_table_capacity: 1024,
+        qpack_blocked_streams: 4,
+        ..Settings::default()
+    };
+    let mut server = server::Builder::default();
+    server.settings(settings.clone());
+    let mut incoming = helper.serve(server);
+    tokio::spawn(async move {
+        let mut requests = accept_connection(&mut incoming).await;
+        while let Some(request) = requests.next().await {
+            let (request, _, sender) = request.await.expect("receive request");
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header("x-trace", request.headers()["x-trace"].clone())
+                .body(())
+                .unwrap();
+            sender.send_response(response).await.expect("send response");
+        }
+    });
+
+    let mut client = client::Builder::default();
+    client.settings(settings);
+    let (driver, conn) = connect_with(&helper, client).await;
+    let conn_ref = driver.0.clone();
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+
+    for _ in 0..3 {
+        let request = Request::get(format!("https://localhost:{}/", helper.port))
+            .header("x-trace", "0123456789abcdef")
+            .body(())
+            .unwrap();
+        let (response, _) = conn.send_request(request).await.expect("request");
+        let (response, _) = response.await.expect("response");
+        assert_eq!(response.headers()["x-trace"], "0123456789abcdef");
+    }
+
+    // The server acknowledges the insertions on its decoder stream
+    for _ in 0..100 {
+        if conn_ref.h3.lock().unwrap().inner.inserts_acknowledged() > 0 {
+            return;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    panic!("no insertion acknowledged");
+}
+
+#[tokio::test]
+async fn server_push() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let port = helper.port;
+    tokio::spawn(async move {
+        let (_, _, mut sender, _requests) = accept_request(&mut incoming).await;
+        let promised = Request::get(format!("https://localhost:{}/style.css", port))
+            .body(())
+            .unwrap();
+        let push = sender.push(promised).await.expect("push");
+        assert_eq!(push.push_id(), 0);
+        sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .body("page")
+                    .unwrap(),
+            )
+            .await
+            .expect("send response")
+            .close()
+            .await
+            .expect("close body");
+        push.send_response(
+            Response::builder()
+                .status(StatusCode::OK)
+                .body("style")
+                .unwrap(),
+        )
+        .await
+        .expect("send push")
+        .close()
+        .await
+        .expect("close push body");
+    });
+
+    let mut client = client::Builder::default();
+    client.max_pushes(1);
+    let (driver, conn) = connect_with(&helper, client).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let mut pushes = conn.incoming_pushes();
+
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (_, mut body) = response.await.expect("response");
+    assert_eq!(body.data().await.expect("page").expect("page"), "page");
+
+    let push = pushes.next().await.expect("push");
+    assert_eq!(push.push_id(), 0);
+    let (request, response, mut body) = push.await.expect("receive push");
+    assert_eq!(request.uri().path(), "/style.css");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body.data().await.expect("style").expect("style"), "style");
+}
+
+#[tokio::test]
+async fn server_push_cancelled() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let port = helper.port;
+    tokio::spawn(async move {
+        let (_, _, mut sender, mut requests) = accept_request(&mut incoming).await;
+        let promised = Request::get(format!("https://localhost:{}/style.css", port))
+            .body(())
+            .unwrap();
+        let push = sender.push(promised).await.expect("push");
+        // Sends CANCEL_PUSH
+        drop(push);
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        // The connection outlives the cancellation
+        let request = requests.next().await.expect("second request");
+        let (_, _, sender) = request.await.expect("receive request");
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        futures::future::pending::<()>().await;
+    });
+
+    let mut client = client::Builder::default();
+    client.max_pushes(1);
+    let (driver, conn) = connect_with(&helper, client).await;
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let mut pushes = conn.incoming_pushes();
+
+    for _ in 0..2 {
+        let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+        let (response, _) = response.await.expect("response");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    conn.close();
+    assert!(pushes.next().await.is_none());
+}
+
+#[tokio::test]
+async fn server_push_refused() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let port = helper.port;
+    tokio::spawn(async move {
+        let (_, _, mut sender, _requests) = accept_request(&mut incoming).await;
+        let promised = Request::get(format!("https://localhost:{}/style.css", port))
+            .body(())
+            .unwrap();
+        // The client didn't allow any push
+        match sender.push(promised).await {
+            Err(Error::PushRefused) => (),
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("push accepted"),
+        }
+        // The response can still be sent
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        futures::future::pending::<()>().await;
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn origin_set() {
+    let helper = Helper::new();
+    let mut server = server::Builder::default();
+    server
+        .origins(vec!["https://localhost", "https://alt.localhost"])
+        .unwrap();
+    let mut incoming = helper.serve(server);
+    tokio::spawn(async move {
+        let _requests = accept_connection(&mut incoming).await;
+        futures::future::pending::<()>().await;
+    });
+
+    let conn = helper.make_connection().await;
+    let origins = loop {
+        if let Some(origins) = conn.origin_set() {
+            break origins;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    };
+    assert_eq!(origins, ["https://localhost", "https://alt.localhost"]);
+}
+
+#[test]
+fn invalid_origins() {
+    let mut server = server::Builder::default();
+    for origin in &[
+        "localhost",
+        "/index.html",
+        "https://localhost/index.html",
+        "https://localhost?q",
+        "https://user@localhost",
+        "https://lôcalhost",
+    ] {
+        match server.origins(vec![origin]) {
+            Err(server::InvalidOrigin(x)) => assert_eq!(x, *origin),
+            Ok(_) => panic!("accepted origin {:?}", origin),
+        }
+    }
+    server.origins(vec!["https://localhost:4433"]).unwrap();
+}
+
+#[tokio::test]
+async fn extended_connect_disabled() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let _requests = accept_connection(&mut incoming).await;
+        futures::future::pending::<()>().await;
+    });
+
+    let conn = helper.make_connection().await;
+    assert!(!connect_protocol_enabled(&conn).await);
+    let authority = format!("localhost:{}", helper.port).parse().unwrap();
+    match conn.extended_connect(authority, "websocket").await {
+        Err(Error::ExtendedConnect(_)) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("extended CONNECT sent to a server not enabling it"),
+    }
+}
+
+#[tokio::test]
+async fn handshake_summary() {
+    let helper = Helper::new();
+    let mut server = server::Builder::default();
+    server.settings(Settings {
+        enable_connect_protocol: true,
+        ..Settings::default()
+    });
+    let mut incoming = helper.serve(server);
+    tokio::spawn(async move {
+        let _requests = accept_connection(&mut incoming).await;
+        futures::future::pending::<()>().await;
+    });
+
+    let conn = helper.make_connection().await;
+    connect_protocol_enabled(&conn).await;
+    let summary = conn.handshake_summary();
+    assert_eq!(summary.alpn.as_deref(), Some("h3-24"));
+    assert_eq!(summary.tls_version, Some(rustls::ProtocolVersion::TLSv1_3));
+    assert!(summary.cipher_suite.is_some());
+    assert_eq!(summary.quic_version, 0xff00_0018);
+    assert!(summary.peer_settings.unwrap().enable_connect_protocol);
+    assert_eq!(summary.early_data, quinn::EarlyDataStatus::NoTicket);
+}
+
+/// Serve every request with the client's `h3_datagram` setting, waiting for its SETTINGS
+fn make_settings_echo_server(helper: &Helper) {
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        while let Some(connecting) = incoming.next().await {
+            tokio::spawn(async move {
+                let (quic_driver, driver, mut requests) = connecting.await.expect("accept");
+                tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+                tokio::spawn(driver.unwrap_or_else(|_| ()));
+                while let Some(request) = requests.next().await {
+                    let (_, _, sender) = request.await.expect("receive request");
+                    let settings = loop {
+                        match requests.handshake_summary().peer_settings {
+                            Some(x) => break x,
+                            None => tokio::time::delay_for(Duration::from_millis(10)).await,
+                        }
+                    };
+                    let response = Response::builder()
+                        .status(StatusCode::OK)
+                        .header("h3-datagram", settings.h3_datagram.to_string())
+                        .body(())
+                        .unwrap();
+                    let _ = sender.send_response(response).await;
+                }
+            });
+        }
+    });
+}
+
+/// Send a request to the settings echo server, checking it received the client's SETTINGS
+async fn echo_settings(helper: &Helper, conn: &client::Connection) -> Result<(), Error> {
+    let (response, _) = conn.send_request(get(helper)).await?;
+    let (response, _) = response.await?;
+    assert_eq!(response.headers()["h3-datagram"], "true");
+    Ok(())
+}
+
+fn make_0rtt_client(helper: &Helper) -> client::Client {
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap()
+        .settings(Settings {
+            h3_datagram: true,
+            ..Settings::default()
+        });
+    let (driver, client) = client.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("client endpoint failed: {}", e)));
+    client
+}
+
+/// Connect with a full handshake, so that the client caches a session ticket for `localhost`
+async fn connect_for_ticket(helper: &Helper, client: &client::Client) {
+    let connecting = client.connect(&helper.addr(), "localhost").unwrap();
+    assert!(!connecting.has_0rtt());
+    let (quic_driver, driver, conn) = connecting.await.expect("connect");
+    tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    echo_settings(helper, &conn).await.expect("request");
+}
+
+#[tokio::test]
+async fn zero_rtt_accepted() {
+    let helper = Helper::new();
+    make_settings_echo_server(&helper);
+    let client = make_0rtt_client(&helper);
+    connect_for_ticket(&helper, &client).await;
+
+    let connecting = client.connect(&helper.addr(), "localhost").unwrap();
+    assert!(connecting.has_0rtt());
+    let client::ZeroRttConnection {
+        quic_driver,
+        driver,
+        connection: conn,
+        accepted,
+    } = connecting
+        .into_0rtt()
+        .ok()
+        .expect("0-RTT keys")
+        .expect("connect");
+    tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let request = echo_settings(&helper, &conn);
+    let (accepted, request) = futures::future::join(accepted, request).await;
+    assert!(accepted);
+    request.expect("0-RTT request");
+    assert_eq!(
+        conn.handshake_summary().early_data,
+        quinn::EarlyDataStatus::Attempted {
+            accepted: Some(true)
+        }
+    );
+}
+
+#[tokio::test]
+async fn zero_rtt_rejected() {
+    // A server with the same name and certificate, which can't resume sessions of the first one
+    let helper = Helper::new();
+    let other = helper.sibling();
+    make_settings_echo_server(&helper);
+    make_settings_echo_server(&other);
+    let client = make_0rtt_client(&helper);
+    connect_for_ticket(&helper, &client).await;
+
+    let connecting = client.connect(&other.addr(), "localhost").unwrap();
+    assert!(connecting.has_0rtt());
+    let client::ZeroRttConnection {
+        quic_driver,
+        driver,
+        connection: conn,
+        accepted,
+    } = connecting
+        .into_0rtt()
+        .ok()
+        .expect("0-RTT keys")
+        .expect("connect");
+    tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+    let request = echo_settings(&other, &conn);
+    let (accepted, request) = futures::future::join(accepted, request).await;
+    assert!(!accepted);
+    assert_matches!(request, Err(Error::ZeroRttRejected));
+    // The control stream was sent again, so the server gets the client's SETTINGS
+    echo_settings(&other, &conn)
+        .await
+        .expect("request sent again");
+}
+
+#[tokio::test]
+async fn client_close_reason() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let server = tokio::spawn(async move {
+        let (quic_driver, driver, _requests) = incoming
+            .next()
+            .await
+            .expect("incoming connection")
+            .await
+            .expect("accept");
+        tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+        driver.await
+    });
+
+    let conn = helper.make_connection().await;
+    conn.close_with(ErrorCode::from(0x1337), "going away");
+    let err = server.await.unwrap().expect_err("driver error");
+    assert_eq!(
+        err.close_reason(),
+        Some((ErrorCode::from(0x1337), "going away".into()))
+    );
+}
+
+#[tokio::test]
+async fn server_close_reason() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let requests = accept_connection(&mut incoming).await;
+        requests.close_with(ErrorCode::EXCESSIVE_LOAD, "too many clients");
+    });
+
+    let conn = helper.make_connection().await;
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let err = conn
+        .send_request(get(&helper))
+        .await
+        .map(|_| ())
+        .expect_err("request on closed connection");
+    assert_eq!(
+        err.close_reason(),
+        Some((ErrorCode::EXCESSIVE_LOAD, "too many clients".into()))
+    );
+    assert_matches!(err, Error::ExcessiveLoad(_));
+}
+
+#[tokio::test]
+async fn excessive_load_reset() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        let body_writer = sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        body_writer.reset(ErrorCode::EXCESSIVE_LOAD);
+        futures::future::pending::<()>().await;
+    });
+
+    let conn = helper.make_connection().await;
+    let (response, _body_writer) = conn.send_request(get(&helper)).await.expect("request");
+    // The reset may overtake the response headers
+    let err = match response.await {
+        Err(e) => e,
+        Ok((_, mut body)) => loop {
+            match body.data().await {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break e,
+                None => panic!("body finished despite reset"),
+            }
+        },
+    };
+    assert_eq!(err.reset_code(), Some(ErrorCode::EXCESSIVE_LOAD));
+    assert_matches!(err, Error::ExcessiveLoad(_));
+}
+
+#[tokio::test]
+async fn reset_request_body_with_code() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let server = tokio::spawn(async move {
+        let (_, mut body, _, _requests) = accept_request(&mut incoming).await;
+        loop {
+            match body.data().await {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return e.reset_code(),
+                None => return None,
+            }
+        }
+    });
+
+    let conn = helper.make_connection().await;
+    let (_response, body_writer) = conn.send_request(get(&helper)).await.expect("request");
+    // Let the server receive the request headers before resetting
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    body_writer.reset(ErrorCode::from(0x42));
+    assert_eq!(server.await.unwrap(), Some(ErrorCode::from(0x42)));
+}
+
+#[tokio::test]
+async fn request_handle_cancel() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let server = tokio::spawn(async move {
+        let (_, mut body, _, _requests) = accept_request(&mut incoming).await;
+        loop {
+            match body.data().await {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return e.reset_code(),
+                None => return None,
+            }
+        }
+    });
+
+    let conn = helper.make_connection().await;
+    let handle = conn
+        .send_request_handle(get(&helper))
+        .await
+        .expect("request");
+    // Let the server receive the request headers before resetting
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    handle.cancel(ErrorCode::from(0x42));
+    assert_eq!(server.await.unwrap(), Some(ErrorCode::from(0x42)));
+}
+
+#[tokio::test]
+async fn drop_request_handle_after_close_body() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let server = tokio::spawn(async move {
+        let (_, mut body, sender, _requests) = accept_request(&mut incoming).await;
+        let _body_writer = sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        loop {
+            match body.data().await {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return e.reset_code(),
+                None => return None,
+            }
+        }
+    });
+
+    let conn = helper.make_connection().await;
+    let mut handle = conn
+        .send_request_handle(get(&helper))
+        .await
+        .expect("request");
+    handle.body().write_all(b"complete").await.expect("write");
+    handle.close_body().await.expect("close body");
+    let (response, _) = handle.response().await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    // The finished body isn't reset along with the handle
+    drop(handle);
+    assert_eq!(server.await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn unexpected_status_resets_stream() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let server = tokio::spawn(async move {
+        let (_, _, sender, _requests) = accept_request(&mut incoming).await;
+        let mut body = sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(())
+                    .unwrap(),
+            )
+            .await
+            .expect("send response");
+        let chunk = [0xAB; 1024];
+        loop {
+            if let Err(e) = body.write_all(&chunk).await {
+                return e;
+            }
+        }
+    });
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap()
+        .allowed_statuses(vec![StatusCode::OK, StatusCode::NO_CONTENT]);
+    let (driver, client) = client.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("client endpoint failed: {}", e)));
+    let (quic_driver, driver, conn) = client
+        .connect(&helper.addr(), "localhost")
+        .unwrap()
+        .await
+        .expect("connect");
+    tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    match response.await {
+        Err(Error::UnexpectedStatus { status }) => assert_eq!(status, StatusCode::NOT_FOUND),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("response with unexpected status returned"),
+    }
+    let err: io::Error = server.await.unwrap();
+    assert_matches!(
+        err.get_ref().and_then(|e| e.downcast_ref::<quinn::WriteError>()),
+        Some(quinn::WriteError::Stopped(code)) if *code == ErrorCode::REQUEST_CANCELLED.into()
+    );
+}
+
+#[tokio::test]
+async fn send_request_collect() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let mut requests = accept_connection(&mut incoming).await;
+        while let Some(recv_request) = requests.next().await {
+            let (_, _, sender) = recv_request.await.expect("receive request");
+            sender
+                .send_response(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header("x-greeting", "1")
+                        .body("hello world")
+                        .unwrap(),
+                )
+                .await
+                .expect("send response")
+                .close()
+                .await
+                .expect("close");
+        }
+    });
+
+    let conn = helper.make_connection().await;
+    let response = conn
+        .send_request_collect(get(&helper), 64)
+        .await
+        .expect("request");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-greeting"], "1");
+    assert_eq!(&response.body()[..], b"hello world");
+
+    match conn.send_request_collect(get(&helper), 5).await {
+        Err(Error::BodyTooLarge { limit }) => assert_eq!(limit, 5),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("oversized body collected"),
+    }
+}
+
+#[tokio::test]
+async fn body_writer_credit() {
+    const TOTAL: usize = 5000;
+    let helper = Helper::new();
+    let mut config = quinn::ServerConfig::default();
+    Arc::get_mut(&mut config.transport)
+        .unwrap()
+        .stream_receive_window = 1000;
+    let mut incoming = helper.make_server_with(quinn::ServerConfigBuilder::new(config));
+    tokio::spawn(async move {
+        let (_, mut body, sender, _requests) = accept_request(&mut incoming).await;
+        let mut received = 0;
+        // Read slowly, so that credit is granted a little at a time
+        while let Some(data) = body.data().await {
+            received += data.expect("read body").len();
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+        }
+        assert_eq!(received, TOTAL);
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+    });
+
+    let conn = helper.make_connection().await;
+    let request = Request::post(format!("https://localhost:{}/", helper.port))
+        .body(())
+        .unwrap();
+    let (response, mut body) = conn.send_request(request).await.expect("request");
+    let mut sent = 0;
+    let mut grants = 0;
+    while sent < TOTAL {
+        if body.available_credit() == 0 {
+            grants += 1;
+        }
+        let credit = body.credit_available().await.expect("credit") as usize;
+        assert!(credit > 3);
+        // Leave room for the DATA frame header
+        let header = if credit - 2 < 64 { 2 } else { 3 };
+        let len = (credit - header).min(TOTAL - sent);
+        body.write_all(&vec![0xAB; len]).await.expect("write");
+        sent += len;
+        if sent < TOTAL {
+            assert_eq!(body.available_credit(), 0);
+        }
+    }
+    assert!(grants >= 4, "only waited for {} grants", grants);
+    body.close().await.expect("close");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn request_body_backpressure() {
+    const TOTAL: usize = 5000;
+    let helper = Helper::new();
+    let mut config = quinn::ServerConfig::default();
+    Arc::get_mut(&mut config.transport)
+        .unwrap()
+        .stream_receive_window = 1000;
+    let mut incoming = helper.make_server_with(quinn::ServerConfigBuilder::new(config));
+    let (resume_send, resume) = futures::channel::oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let (_, mut body, sender, _requests) = accept_request(&mut incoming).await;
+        // Don't read until told to, which should stall the upload
+        resume.await.unwrap();
+        let mut received = Vec::new();
+        body.read_to_end(&mut received).await.expect("read body");
+        assert_eq!(received.len(), TOTAL);
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+    });
+
+    let conn = helper.make_connection().await;
+    let request = Request::post(format!("https://localhost:{}/", helper.port))
+        .body(())
+        .unwrap();
+    let (response, mut body) = conn.send_request(request).await.expect("request");
+    let mut upload = tokio::spawn(async move {
+        body.write_all(&[0xAB; TOTAL]).await.expect("write");
+        body.close().await.expect("close");
+    });
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), &mut upload)
+            .await
+            .is_err(),
+        "upload completed without the server reading"
+    );
+    resume_send.send(()).unwrap();
+    upload.await.unwrap();
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// Connect to an IPv6 and an IPv4 server from a single client bound according to `mode`
+async fn connect_both_families(mode: client::BindMode) {
+    let helper = Helper::new();
+    let incoming_v6 = helper.make_server();
+    let mut server = server::Builder::default();
+    server
+        .certificate(helper.cert_chain.clone(), helper.key.clone())
+        .unwrap();
+    let addr_v4 = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), helper.port);
+    server.listen(addr_v4).unwrap();
+    let (driver, _, incoming_v4) = server.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("server endpoint failed: {}", e)));
+    for mut incoming in [incoming_v6, incoming_v4] {
+        tokio::spawn(async move {
+            let _requests = accept_connection(&mut incoming).await;
+            futures::future::pending::<()>().await;
+        });
+    }
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap()
+        .bind_mode(mode);
+    let (driver, client) = client.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("client endpoint failed: {}", e)));
+    for addr in &[helper.addr(), addr_v4] {
+        let (quic_driver, _, _conn) = client
+            .connect(addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap_or_else(|e| panic!("connecting to {} failed: {}", addr, e));
+        tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+    }
+}
+
+#[tokio::test]
+async fn bind_dual_stack() {
+    connect_both_families(client::BindMode::DualStack).await;
+}
+
+#[tokio::test]
+async fn bind_separate() {
+    connect_both_families(client::BindMode::Separate).await;
+}
+
+#[tokio::test]
+async fn max_local_concurrent_requests() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let mut requests = accept_connection(&mut incoming).await;
+        while let Some(request) = requests.next().await {
+            tokio::spawn(async move {
+                let (_, _, sender) = request.await.expect("receive request");
+                sender
+                    .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+                    .await
+                    .expect("send response");
+            });
+        }
+    });
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap()
+        .max_local_concurrent_requests(2);
+    let (driver, client) = client.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("client endpoint failed: {}", e)));
+    let (quic_driver, driver, conn) = client
+        .connect(&helper.addr(), "localhost")
+        .unwrap()
+        .await
+        .expect("connect");
+    tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+    tokio::spawn(driver.unwrap_or_else(|_| ()));
+
+    let (first, _first_body) = conn.send_request(get(&helper)).await.expect("request");
+    let (_second, _second_body) = conn.send_request(get(&helper)).await.expect("request");
+    let mut third = Box::pin(conn.send_request(get(&helper)));
+    assert!(tokio::time::timeout(Duration::from_millis(100), &mut third)
+        .await
+        .is_err());
+    // Completing the first request admits the third
+    let (_, body) = first.await.expect("response");
+    drop(body);
+    tokio::time::timeout(Duration::from_secs(1), third)
+        .await
+        .expect("third request admitted")
+        .expect("request");
+}
+
+#[tokio::test]
+async fn per_connection_transport() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let _first = accept_connection(&mut incoming).await;
+        let _second = accept_connection(&mut incoming).await;
+        futures::future::pending::<()>().await;
+    });
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap();
+    let (driver, client) = client.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("client endpoint failed: {}", e)));
+    let mut drivers = Vec::new();
+    for &idle_timeout in &[200, 5_000] {
+        let transport = quinn::TransportConfig {
+            idle_timeout,
+            ..Default::default()
+        };
+        let (quic_driver, driver, conn) = client
+            .connect_with_transport(Arc::new(transport), &helper.addr(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        drivers.push((quic_driver, conn));
+    }
+
+    let (long, _long_conn) = drivers.pop().unwrap();
+    let (short, _short_conn) = drivers.pop().unwrap();
+    match tokio::time::timeout(Duration::from_secs(2), short).await {
+        Ok(Err(quinn::ConnectionError::TimedOut)) => {}
+        Ok(x) => panic!("unexpected driver result: {:?}", x),
+        Err(_) => panic!("connection with a short idle timeout stayed open"),
+    }
+    assert!(tokio::time::timeout(Duration::from_millis(500), long)
+        .await
+        .is_err());
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[tokio::test]
+async fn spawned_drivers() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let server = tokio::spawn(async move {
+        let (quic_driver, driver, mut requests) = incoming
+            .next()
+            .await
+            .expect("incoming connection")
+            .await
+            .expect("accept");
+        let quic_driver = tokio::spawn(quic_driver);
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        let (_, _, sender) = requests
+            .next()
+            .await
+            .expect("incoming request")
+            .await
+            .expect("receive request");
+        sender
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .expect("send response");
+        quic_driver.await.unwrap()
+    });
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap();
+    let client = client.build_spawned().unwrap();
+    let conn = client
+        .connect_spawned(&helper.addr(), "localhost")
+        .await
+        .expect("connect");
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Dropping the connection stops its drivers, which closes it
+    drop(conn);
+    assert_matches!(
+        tokio::time::timeout(Duration::from_secs(1), server).await,
+        Ok(Ok(Err(quinn::ConnectionError::ApplicationClosed(_))))
+    );
+}
+
+/// Serve each request on every connection, counting connections
+#[cfg(feature = "runtime-tokio")]
+fn make_counting_server(helper: &Helper, mut server: server::Builder) -> Arc<AtomicUsize> {
+    server
+        .certificate(helper.cert_chain.clone(), helper.key.clone())
+        .unwrap();
+    // Dual-stack, as `localhost` may resolve to either address family
+    server
+        .listen(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), helper.port))
+        .unwrap();
+    let (driver, _, mut incoming) = server.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("server endpoint failed: {}", e)));
+    let connections = Arc::new(AtomicUsize::new(0));
+    let count = connections.clone();
+    tokio::spawn(async move {
+        while let Some(connecting) = incoming.next().await {
+            count.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                let (quic_driver, driver, mut requests) = connecting.await.expect("accept");
+                tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+                tokio::spawn(driver.unwrap_or_else(|_| ()));
+                while let Some(request) = requests.next().await {
+                    let (_, _, sender) = request.await.expect("receive request");
+                    let _ = sender
+                        .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+                        .await;
+                }
+            });
+        }
+    });
+    connections
+}
+
+#[cfg(feature = "runtime-tokio")]
+fn make_pool(helper: &Helper) -> client::Pool {
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap();
+    client::Pool::new(client.build_spawned().unwrap())
+}
+
+#[cfg(feature = "runtime-tokio")]
+async fn pool_get(pool: &client::Pool, helper: &Helper) {
+    let request = Request::get(format!("https://localhost:{}/", helper.port))
+        .body(())
+        .unwrap();
+    let (response, _) = pool.request(request).await.expect("request");
+    let (response, _) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[tokio::test]
+async fn pool_reuses_connection() {
+    let helper = Helper::new();
+    let connections = make_counting_server(&helper, server::Builder::default());
+    let pool = make_pool(&helper);
+    for _ in 0..3 {
+        pool_get(&pool, &helper).await;
+    }
+    assert_eq!(connections.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[tokio::test]
+async fn pool_reconnects_after_goaway() {
+    let helper = Helper::new();
+    let mut server = server::Builder::default();
+    server.max_connection_lifetime(Duration::from_millis(100), Duration::from_millis(500));
+    let connections = make_counting_server(&helper, server);
+    let pool = make_pool(&helper);
+    let conn = pool
+        .connection("localhost", helper.port)
+        .await
+        .expect("connection");
+    pool_get(&pool, &helper).await;
+    // Once past its lifetime, the server goes away and closes the idle connection
+    conn.closed().await;
+    pool_get(&pool, &helper).await;
+    assert_eq!(connections.load(Ordering::Relaxed), 2);
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[tokio::test]
+async fn pool_uses_alt_svc() {
+    let helper = Helper::new();
+    let alternative = helper.sibling();
+    let alt_connections = make_counting_server(&alternative, server::Builder::default());
+    // Advertises `alternative` in its responses
+    let mut server = server::Builder::default();
+    server
+        .certificate(helper.cert_chain.clone(), helper.key.clone())
+        .unwrap();
+    server
+        .listen(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), helper.port))
+        .unwrap();
+    let (driver, _, mut incoming) = server.build().unwrap();
+    tokio::spawn(driver.unwrap_or_else(|e| panic!("server endpoint failed: {}", e)));
+    let alt_svc = format!(
+        "{}=\":{}\"",
+        String::from_utf8_lossy(crate::ALPN),
+        alternative.port
+    );
+    tokio::spawn(async move {
+        while let Some(connecting) = incoming.next().await {
+            let (quic_driver, driver, mut requests) = connecting.await.expect("accept");
+            tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+            tokio::spawn(driver.unwrap_or_else(|_| ()));
+            let alt_svc = alt_svc.clone();
+            tokio::spawn(async move {
+                while let Some(request) = requests.next().await {
+                    let (_, _, sender) = request.await.expect("receive request");
+                    let response = Response::builder()
+                        .status(StatusCode::OK)
+                        .header(http::header::ALT_SVC, alt_svc.as_str())
+                        .body(())
+                        .unwrap();
+                    let _ = sender.send_response(response).await;
+                }
+            });
+        }
+    });
+
+    let cache = Arc::new(Mutex::new(AltSvcCache::new()));
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap()
+        .alt_svc_cache(cache.clone());
+    let client = client.build_spawned().unwrap();
+    pool_get(&client::Pool::new(client.clone()), &helper).await;
+    let origin = format!("https://localhost:{}/", helper.port)
+        .parse()
+        .unwrap();
+    assert_eq!(
+        cache.lock().unwrap().get(&origin).map(|x| x.port),
+        Some(alternative.port)
+    );
+    assert_eq!(alt_connections.load(Ordering::Relaxed), 0);
+
+    // New connections to the origin go to the alternative
+    pool_get(&client::Pool::new(client), &helper).await;
+    assert_eq!(alt_connections.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[tokio::test]
+async fn warm_connection_reconnects() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let accepted2 = accepted.clone();
+    tokio::spawn(async move {
+        while let Some(connecting) = incoming.next().await {
+            let (quic_driver, driver, mut requests) = connecting.await.expect("accept");
+            accepted2.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+            tokio::spawn(driver.unwrap_or_else(|_| ()));
+            tokio::spawn(async move {
+                while let Some(request) = requests.next().await {
+                    let (_, _, sender) = request.await.expect("receive request");
+                    sender
+                        .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+                        .await
+                        .expect("send response");
+                }
+            });
+        }
+    });
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap();
+    let client = client.build_spawned().unwrap();
+    let warm = client.warm_connection(helper.addr(), "localhost", client::WarmConfig::default());
+    let conn = warm.connection().await.expect("connection");
+    assert_eq!(warm.state(), client::WarmState::Ready);
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    response.await.expect("response");
+
+    conn.close();
+    // The closed connection may still be handed out until the loss is noticed
+    let mut attempts = 0;
+    loop {
+        let conn = warm.connection().await.expect("connection");
+        if let Ok((response, _)) = conn.send_request(get(&helper)).await {
+            if response.await.is_ok() {
+                break;
+            }
+        }
+        attempts += 1;
+        assert!(attempts < 100, "connection not re-established");
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert_eq!(accepted.load(Ordering::Relaxed), 2);
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[tokio::test]
+async fn warm_connection_fails() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move { while incoming.next().await.is_some() {} });
+
+    // The server's certificate isn't trusted, so every attempt fails
+    let client = client::Builder::default().build_spawned().unwrap();
+    let warm = client.warm_connection(
+        helper.addr(),
+        "localhost",
+        client::WarmConfig {
+            max_retries: 2,
+            retry_delay: Duration::from_millis(10),
+            ..client::WarmConfig::default()
+        },
+    );
+    match warm.connection().await {
+        Err(Error::Unavailable(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected to an untrusted server"),
+    }
+    assert_eq!(warm.state(), client::WarmState::Failed);
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[test]
+fn warm_connection_backoff_capped() {
+    let config = client::WarmConfig {
+        retry_delay: Duration::from_millis(100),
+        max_retry_delay: Duration::from_secs(1),
+        ..client::WarmConfig::default()
+    };
+    assert_eq!(config.backoff(1), Duration::from_millis(100));
+    assert_eq!(config.backoff(3), Duration::from_millis(400));
+    assert_eq!(config.backoff(5), Duration::from_secs(1));
+    assert_eq!(config.backoff(u32::max_value()), Duration::from_secs(1));
+
+    let config = client::WarmConfig {
+        retry_delay: Duration::from_secs(u64::max_value()),
+        ..client::WarmConfig::default()
+    };
+    assert_eq!(config.backoff(2), config.max_retry_delay);
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[tokio::test]
+async fn connect_racing() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        while let Some(connecting) = incoming.next().await {
+            if let Ok((quic_driver, driver, _)) = connecting.await {
+                tokio::spawn(quic_driver.unwrap_or_else(|_| ()));
+                tokio::spawn(driver.unwrap_or_else(|_| ()));
+            }
+        }
+    });
+    // Bound but never read, so handshakes with it never complete
+    let black_hole = UdpSocket::bind("[::1]:0").unwrap();
+    let dead = black_hole.local_addr().unwrap();
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap();
+    let client = client.build_spawned().unwrap();
+    let config = client::RacingConfig {
+        stagger_delay: Duration::from_millis(50),
+        attempt_timeout: Duration::from_millis(200),
+        overall_timeout: Duration::from_secs(5),
+    };
+
+    let (_, _, _conn) = client
+        .connect_racing(vec![dead, helper.addr()], "localhost", config.clone())
+        .await
+        .expect("connect");
+
+    match client
+        .connect_racing(vec![dead, dead], "localhost", config.clone())
+        .await
+    {
+        Err(Error::TimedOut) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected to a black hole"),
+    }
+
+    let config = client::RacingConfig {
+        overall_timeout: Duration::from_millis(100),
+        ..config
+    };
+    match client.connect_racing(vec![dead], "localhost", config).await {
+        Err(Error::TimedOut) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected to a black hole"),
+    }
+
+    match client
+        .connect_racing(Vec::new(), "localhost", client::RacingConfig::default())
+        .await
+    {
+        Err(Error::Unavailable(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected without an address"),
+    }
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn blocking_send_request() {
+    let helper = Helper::new();
+    let mut server = server::Builder::default();
+    server
+        .certificate(helper.cert_chain.clone(), helper.key.clone())
+        .unwrap();
+    // Dual-stack, as `localhost` may resolve to either address family
+    server
+        .listen(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), helper.port))
+        .unwrap();
+    let (done_send, done_recv) = futures::channel::oneshot::channel::<()>();
+    let server = thread::spawn(move || {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let (driver, _, mut incoming) = server.build().unwrap();
+            tokio::spawn(driver.unwrap_or_else(|e| panic!("server endpoint failed: {}", e)));
+            let (request, _, sender, _requests) = accept_request(&mut incoming).await;
+            assert_eq!(request.uri().path(), "/hello");
+            sender
+                .send_response(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body("world")
+                        .unwrap(),
+                )
+                .await
+                .expect("send response")
+                .close()
+                .await
+                .expect("close");
+            // Keep the connection open until the client is done with it
+            let _ = done_recv.await;
+        });
+    });
+
+    let mut client = client::Builder::default();
+    client
+        .add_certificate_authority(helper.cert.clone())
+        .unwrap();
+    let mut client = client.build_blocking().unwrap();
+    let request = Request::get(format!("https://localhost:{}/hello", helper.port))
+        .body(())
+        .unwrap();
+    let (response, body) = client.blocking_send_request(request).expect("request");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body, b"world");
+    drop(done_send);
+    server.join().unwrap();
+}
+
+#[tokio::test]
+async fn content_length_mismatch_fails_on_close() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    tokio::spawn(async move {
+        let _requests = accept_connection(&mut incoming).await;
+        futures::future::pending::<()>().await;
+    });
+    let conn = helper.make_connection().await;
+
+    let post = |len: &str, body: &'static str| {
+        Request::post(format!("https://localhost:{}/", helper.port))
+            .header(http::header::CONTENT_LENGTH, len)
+            .body(body)
+            .unwrap()
+    };
+
+    let (_response, mut body) = conn.send_request(post("10", "")).await.expect("request");
+    body.write_all(b"hello").await.expect("write body");
+    match body.close().await {
+        Err(Error::ContentLength { declared, written }) => assert_eq!((declared, written), (10, 5)),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(()) => panic!("short body finished"),
+    }
+
+    let (_response, body) = conn
+        .send_request(post("3", "hello"))
+        .await
+        .expect("request");
+    match body.close().await {
+        Err(Error::ContentLength { declared, written }) => assert_eq!((declared, written), (3, 5)),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(()) => panic!("long body finished"),
+    }
+
+    let (_response, mut body) = conn.send_request(post("5", "")).await.expect("request");
+    body.write_all(b"hello").await.expect("write body");
+    body.close().await.expect("matching body");
+}
+
+#[tokio::test]
+async fn interim_responses() {
+    let helper = Helper::new();
+    let mut incoming = helper.make_server();
+    let server = tokio::spawn(async move {
+        let (_, _, sender, mut requests) = accept_request(&mut incoming).await;
+        match sender
+            .send_interim(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+        {
+            Err(Error::UnexpectedStatus { status }) => assert_eq!(status, StatusCode::OK),
+            _ => panic!("final status sent as interim"),
+        }
+
+        let (_, _, mut sender) = requests
+            .next()
+            .await
+            .expect("incoming request")
+            .await
+            .expect("receive request");
+        for status in &[StatusCode::CONTINUE, StatusCode::from_u16(103).unwrap()] {
+            sender = sender
+                .send_interim(Response::builder().status(*status).body(()).unwrap())
+                .await
+                .expect("send interim response");
+        }
+        let body = sender
+            .send_response(
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .body("final")
+                    .unwrap(),
+            )
+            .await
+            .expect("send response");
+        body.close().await.expect("close body");
+    });
+    let conn = helper.make_connection().await;
+
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    assert!(response.await.is_err());
+
+    let (response, _) = conn.send_request(get(&helper)).await.expect("request");
+    let (response, mut body) = response.await.expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut buf = Vec::new();
+    body.read_to_end(&mut buf).await.expect("read body");
+    assert_eq!(&buf[..], b"final");
+    server.await.unwrap();
+}
@@ -0,0 +1,139 @@
+//! WebTransport sessions, as in draft-ietf-webtrans-http3-02
+//!
+//! A session is established by an extended CONNECT request for the `webtransport` protocol, once
+//! both ends have enabled `Settings::enable_webtransport` and the server has enabled
+//! `Settings::enable_connect_protocol`. Streams opened within the session are QUIC streams
+//! prefixed with its ID, the ID of the CONNECT stream, and its datagrams are the HTTP/3 datagrams
+//! of the CONNECT request, which also require `Settings::h3_datagram`. A `WebTransportSession` is
+//! obtained from `client::Connection::webtransport` or `server::Sender::accept_webtransport`.
+
+use std::fmt;
+
+use futures::{future, io::AsyncWriteExt, StreamExt};
+use quinn::{RecvStream, SendStream};
+use quinn_proto::{coding::BufMutExt, StreamId, VarInt};
+
+use crate::{
+    body::{BodyReader, BodyWriter},
+    connection::ConnectionRef,
+    datagram::DatagramFlow,
+    proto::StreamType,
+    Error,
+};
+
+/// Streams and datagrams exchanged within a WebTransport session
+///
+/// Streams the peer opens within the session are buffered until accepted. Dropping the session
+/// closes it, finishing the CONNECT stream and rejecting the streams not accepted yet.
+pub struct WebTransportSession {
+    conn: ConnectionRef,
+    session_id: StreamId,
+    /// Both halves of the CONNECT stream, which the session lasts as long as
+    reader: BodyReader,
+    writer: BodyWriter,
+}
+
+impl WebTransportSession {
+    pub(crate) fn new(
+        conn: &ConnectionRef,
+        session_id: StreamId,
+        reader: BodyReader,
+        writer: BodyWriter,
+    ) -> Result<Self, Error> {
+        conn.h3.lock().unwrap().open_session(session_id.0)?;
+        Ok(Self {
+            conn: conn.clone(),
+            session_id,
+            reader,
+            writer,
+        })
+    }
+
+    /// ID of the session, that of the CONNECT stream establishing it
+    pub fn session_id(&self) -> StreamId {
+        self.session_id
+    }
+
+    /// Open a bidirectional stream within the session
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream), Error> {
+        let (mut send, recv) = self.conn.quic.open_bi().await?;
+        send.write_all(&self.prefix(StreamType::WEBTRANSPORT_BI))
+            .await?;
+        Ok((send, recv))
+    }
+
+    /// Open a unidirectional stream within the session
+    pub async fn open_uni(&self) -> Result<SendStream, Error> {
+        let mut send = self.conn.quic.open_uni().await?;
+        send.write_all(&self.prefix(StreamType::WEBTRANSPORT_UNI))
+            .await?;
+        Ok(send)
+    }
+
+    /// Accept the next bidirectional stream opened by the peer, or `None` once closed
+    pub async fn accept_bi(&self) -> Option<(SendStream, RecvStream)> {
+        let session_id = self.session_id.0;
+        future::poll_fn(|cx| self.conn.h3.lock().unwrap().poll_session_bi(cx, session_id)).await
+    }
+
+    /// Accept the next unidirectional stream opened by the peer, or `None` once closed
+    pub async fn accept_uni(&self) -> Option<RecvStream> {
+        let session_id = self.session_id.0;
+        future::poll_fn(|cx| {
+            self.conn
+                .h3
+                .lock()
+                .unwrap()
+                .poll_session_uni(cx, session_id)
+        })
+        .await
+    }
+
+    /// Open the session's datagram flow
+    ///
+    /// Fails with `Error::Datagram` unless both ends enabled `Settings::h3_datagram`, or if the
+    /// flow is already open.
+    pub fn datagram_flow(&self) -> Result<DatagramFlow, Error> {
+        DatagramFlow::new(&self.conn, self.session_id)
+    }
+
+    /// Wait for the peer to close the session, by finishing the CONNECT stream
+    pub async fn closed(&mut self) -> Result<(), Error> {
+        // Capsules are not supported, so anything sent on the CONNECT stream is ignored
+        while let Some(x) = self.reader.next().await {
+            x?;
+        }
+        Ok(())
+    }
+
+    /// Close the session, finishing the CONNECT stream
+    pub async fn close(mut self) -> Result<(), Error> {
+        AsyncWriteExt::close(&mut self.writer).await?;
+        Ok(())
+    }
+
+    fn prefix(&self, ty: StreamType) -> Vec<u8> {
+        let mut prefix = Vec::with_capacity(2 * VarInt::MAX_SIZE);
+        ty.encode(&mut prefix);
+        prefix.write_var(self.session_id.0);
+        prefix
+    }
+}
+
+impl fmt::Debug for WebTransportSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebTransportSession")
+            .field("session_id", &self.session_id)
+            .finish()
+    }
+}
+
+impl Drop for WebTransportSession {
+    fn drop(&mut self) {
+        self.conn
+            .h3
+            .lock()
+            .unwrap()
+            .close_session(self.session_id.0);
+    }
+}
@@ -1,4 +1,7 @@
-use std::{cmp::Ordering, collections::BinaryHeap};
+use std::{
+    cmp::Ordering,
+    collections::{binary_heap::PeekMut, BinaryHeap},
+};
 
 use bytes::{Buf, Bytes};
 
@@ -71,6 +74,34 @@ impl Assembler {
         }
     }
 
+    /// Take up to `max_length` contiguous bytes from the current position, without copying
+    pub(crate) fn read_chunk(&mut self, max_length: usize) -> Option<Bytes> {
+        loop {
+            let mut chunk = self.data.peek_mut()?;
+            if chunk.offset > self.offset {
+                return None;
+            } else if (chunk.offset + chunk.bytes.len() as u64) <= self.offset {
+                // Already delivered
+                PeekMut::pop(chunk);
+                continue;
+            }
+
+            let start = (self.offset - chunk.offset) as usize;
+            let len = max_length.min(chunk.bytes.len() - start);
+            if len == 0 {
+                return None;
+            }
+            chunk.bytes.advance(start);
+            let bytes = chunk.bytes.split_to(len);
+            chunk.offset = self.offset + len as u64;
+            self.offset += len as u64;
+            if chunk.bytes.is_empty() {
+                PeekMut::pop(chunk);
+            }
+            return Some(bytes);
+        }
+    }
+
     #[cfg(test)]
     fn next(&mut self, size: usize) -> Option<Box<[u8]>> {
         let mut buf = vec![0; size];
@@ -96,6 +127,11 @@ impl Assembler {
         self.offset
     }
 
+    /// Number of bytes buffered, including any duplicated by overlapping chunks
+    pub(crate) fn buffered(&self) -> usize {
+        self.data.iter().map(|x| x.bytes.len()).sum()
+    }
+
     /// Discard all buffered data
     pub(crate) fn clear(&mut self) {
         self.data.clear();
@@ -208,6 +244,21 @@ mod test {
         assert_matches!(x.next(32), None);
     }
 
+    #[test]
+    fn read_chunks() {
+        let mut x = Assembler::new();
+        x.insert(0, Bytes::from_static(b"123"));
+        x.insert(2, Bytes::from_static(b"3456"));
+        x.insert(7, Bytes::from_static(b"8"));
+        assert_matches!(x.read_chunk(2), Some(ref y) if &y[..] == b"12");
+        assert_matches!(x.read_chunk(32), Some(ref y) if &y[..] == b"3456");
+        assert_matches!(x.read_chunk(32), None);
+        x.insert(6, Bytes::from_static(b"7"));
+        assert_matches!(x.read_chunk(32), Some(ref y) if &y[..] == b"7");
+        assert_matches!(x.read_chunk(32), Some(ref y) if &y[..] == b"8");
+        assert_matches!(x.read_chunk(32), None);
+    }
+
     #[test]
     fn assemble_old() {
         let mut x = Assembler::new();
@@ -0,0 +1,86 @@
+//! Logic for controlling the rate at which data is sent
+//!
+//! Connections consult a `Controller` to decide how much data may be in flight on their path.
+//! `NewReno` is used unless `TransportConfig::congestion_controller_factory` supplies another
+//! implementation, such as `Cubic` or `Bbr`. Packets are spread out over time rather than sent in
+//! bursts, at the controller's `pacing_rate` or otherwise at a rate derived from its window.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+mod bbr;
+mod cubic;
+mod new_reno;
+mod pacing;
+
+pub use bbr::{Bbr, BbrConfig};
+pub use cubic::{Cubic, CubicConfig};
+pub use new_reno::NewReno;
+pub(crate) use pacing::{window_rate, Pacer};
+
+/// Common interface for congestion control algorithms
+pub trait Controller: Send {
+    /// A packet counting towards the window was sent
+    ///
+    /// `bytes` is its size and `in_flight` the number of bytes in flight including it.
+    fn on_sent(&mut self, now: Instant, bytes: u64, in_flight: u64) {
+        let _ = (now, bytes, in_flight);
+    }
+
+    /// Packets were acknowledged
+    ///
+    /// `sent` is when the acknowledged packet was sent, and `bytes` its size. `app_limited`
+    /// indicates that the connection wasn't using the whole window when the acknowledgement was
+    /// received, so that it says little about the capacity of the path.
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: u64, app_limited: bool);
+
+    /// Packets were deemed lost
+    ///
+    /// `sent` is when the most recently sent of those packets was sent. `persistent_congestion`
+    /// indicates that every packet sent over a long enough period was lost, as defined by
+    /// `TransportConfig::persistent_congestion_threshold`.
+    fn on_loss(&mut self, now: Instant, sent: Instant, persistent_congestion: bool);
+
+    /// The peer reported `count` more packets marked congestion experienced (ECN-CE) by the network
+    ///
+    /// `sent` is when the largest newly acknowledged packet was sent. Treated as a loss by default.
+    fn on_congestion_experienced(&mut self, now: Instant, sent: Instant, count: u64) {
+        let _ = count;
+        self.on_loss(now, sent, false);
+    }
+
+    /// Number of bytes which may be in flight
+    fn window(&self) -> u64;
+
+    /// Slow start threshold, below which the window grows exponentially
+    ///
+    /// Only reported through `PathStats`. `u64::max_value()` for algorithms without one.
+    fn ssthresh(&self) -> u64 {
+        0xffff_ffff_ffff_ffff
+    }
+
+    /// Rate at which packets should be paced, in bytes per second
+    ///
+    /// Ack-eliciting packets are delayed as needed to keep to this rate. `None`, the default, paces
+    /// packets at 5/4 of the window per smoothed round-trip time.
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+
+    /// Duplicate the controller's state
+    ///
+    /// Used when migrating to what is probably the same path, after a NAT rebinding.
+    fn clone_box(&self) -> Box<dyn Controller>;
+}
+
+/// Constructs a `Controller` for each connection, and for each new path a connection migrates to
+pub trait ControllerFactory: fmt::Debug {
+    /// Construct a fresh `Controller`
+    fn build(&self, now: Instant) -> Box<dyn Controller>;
+}
+
+fn duration_secs(x: Duration) -> f64 {
+    x.as_secs() as f64 + f64::from(x.subsec_nanos()) * 1e-9
+}
@@ -0,0 +1,371 @@
+use std::{
+    cmp,
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::{duration_secs, Controller, ControllerFactory};
+use crate::TransportConfig;
+
+/// Model-based congestion control, after BBR version 1
+///
+/// Rather than reacting to loss, `Bbr` estimates the bottleneck bandwidth and round-trip
+/// propagation delay of the path, and paces packets out at the estimated bandwidth with roughly
+/// one bandwidth-delay product in flight. See `draft-cardwell-iccrg-bbr-congestion-control-00`.
+///
+/// The `Drain` phase is left once the bytes in flight reported by `on_sent` fall to the estimated
+/// bandwidth-delay product, and the `ProbeBW` gain cycle always starts from its first cruising
+/// phase rather than a random one.
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    max_datagram_size: u64,
+    initial_window: u64,
+    initial_rtt: Duration,
+    mode: Mode,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    window: u64,
+    /// Recent delivery rate samples in bytes per second, by round, in decreasing order of rate
+    bandwidth_samples: VecDeque<(u64, u64)>,
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Instant,
+    /// Total bytes acknowledged
+    delivered: u64,
+    /// When `delivered` last increased
+    delivered_time: Instant,
+    /// `delivered` and `delivered_time` at the time each unacknowledged packet was sent
+    sent: VecDeque<SendState>,
+    round_count: u64,
+    next_round_delivered: u64,
+    /// Bandwidth estimate that `Startup` must grow on to continue
+    full_bandwidth: u64,
+    /// Rounds in a row for which the bandwidth estimate didn't grow on `full_bandwidth`
+    full_bandwidth_count: u32,
+    filled_pipe: bool,
+    cycle_index: usize,
+    cycle_stamp: Instant,
+    probe_rtt_done: Option<Instant>,
+    probe_rtt_round_done: bool,
+    /// Window to return to after `ProbeRTT`
+    prior_window: u64,
+}
+
+impl Bbr {
+    /// Construct a controller in the `Startup` phase
+    pub fn new(config: &BbrConfig, now: Instant) -> Self {
+        Self {
+            max_datagram_size: config.max_datagram_size,
+            initial_window: config.initial_window,
+            initial_rtt: config.initial_rtt,
+            mode: Mode::Startup,
+            pacing_gain: HIGH_GAIN,
+            cwnd_gain: HIGH_GAIN,
+            window: config.initial_window,
+            bandwidth_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: now,
+            delivered: 0,
+            delivered_time: now,
+            sent: VecDeque::new(),
+            round_count: 0,
+            next_round_delivered: 0,
+            full_bandwidth: 0,
+            full_bandwidth_count: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_stamp: now,
+            probe_rtt_done: None,
+            probe_rtt_round_done: false,
+            prior_window: config.initial_window,
+        }
+    }
+
+    /// Estimated bottleneck bandwidth in bytes per second, if any delivery has been measured
+    fn bandwidth(&self) -> Option<u64> {
+        self.bandwidth_samples.front().map(|&(_, rate)| rate)
+    }
+
+    /// Estimated bandwidth-delay product scaled by `gain`, if both have been measured
+    fn bdp(&self, gain: f64) -> Option<u64> {
+        let bandwidth = self.bandwidth()?;
+        let min_rtt = self.min_rtt?;
+        let bdp = bandwidth as f64 * duration_secs(min_rtt);
+        Some((bdp * gain) as u64)
+    }
+
+    fn minimum_window(&self) -> u64 {
+        MIN_PIPE_PACKETS * self.max_datagram_size
+    }
+
+    fn update_bandwidth(&mut self, rate: u64, app_limited: bool) {
+        // Application-limited samples underestimate the path, so they can only raise the estimate
+        if app_limited && rate < self.bandwidth().unwrap_or(0) {
+            return;
+        }
+        let round = self.round_count;
+        while let Some(&(r, _)) = self.bandwidth_samples.front() {
+            if r + BANDWIDTH_WINDOW_ROUNDS > round {
+                break;
+            }
+            self.bandwidth_samples.pop_front();
+        }
+        while let Some(&(_, x)) = self.bandwidth_samples.back() {
+            if x > rate {
+                break;
+            }
+            self.bandwidth_samples.pop_back();
+        }
+        self.bandwidth_samples.push_back((round, rate));
+    }
+
+    fn check_full_pipe(&mut self, round_start: bool, app_limited: bool) {
+        if self.filled_pipe || !round_start || app_limited {
+            return;
+        }
+        let bandwidth = self.bandwidth().unwrap_or(0);
+        if bandwidth as f64 >= self.full_bandwidth as f64 * 1.25 {
+            self.full_bandwidth = bandwidth;
+            self.full_bandwidth_count = 0;
+            return;
+        }
+        self.full_bandwidth_count += 1;
+        if self.full_bandwidth_count >= 3 {
+            self.filled_pipe = true;
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.mode = Mode::ProbeBw;
+        self.cwnd_gain = 2.0;
+        // The first two phases probe up then drain; start cruising instead
+        self.cycle_index = 2;
+        self.cycle_stamp = now;
+        self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+    }
+
+    fn advance_cycle(&mut self, now: Instant) {
+        let min_rtt = self.min_rtt.unwrap_or(self.initial_rtt);
+        if now - self.cycle_stamp > min_rtt {
+            self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+            self.cycle_stamp = now;
+            self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        }
+    }
+
+    fn enter_probe_rtt(&mut self) {
+        self.mode = Mode::ProbeRtt;
+        self.pacing_gain = 1.0;
+        self.cwnd_gain = 1.0;
+        self.prior_window = self.window;
+        self.probe_rtt_done = None;
+        self.window = self.minimum_window();
+    }
+
+    fn check_probe_rtt(&mut self, now: Instant, round_start: bool) {
+        if self.mode != Mode::ProbeRtt {
+            return;
+        }
+        match self.probe_rtt_done {
+            None => {
+                self.probe_rtt_done = Some(now + PROBE_RTT_DURATION);
+                self.probe_rtt_round_done = false;
+                self.next_round_delivered = self.delivered;
+            }
+            Some(done) => {
+                if round_start {
+                    self.probe_rtt_round_done = true;
+                }
+                if self.probe_rtt_round_done && now >= done {
+                    self.min_rtt_stamp = now;
+                    self.window = cmp::max(self.window, self.prior_window);
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.mode = Mode::Startup;
+                        self.pacing_gain = HIGH_GAIN;
+                        self.cwnd_gain = HIGH_GAIN;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Discard the state of packets sent up to `sent`, returning that of the last one
+    fn forget_sent(&mut self, sent: Instant) -> Option<SendState> {
+        // Packets are sent in order, so anything sent no later than an acknowledged or lost
+        // packet is either acknowledged or lost by now too
+        let mut last = None;
+        while let Some(&state) = self.sent.front() {
+            if state.time > sent {
+                break;
+            }
+            last = self.sent.pop_front();
+        }
+        last
+    }
+
+    fn update_window(&mut self, bytes: u64) {
+        if self.mode == Mode::ProbeRtt {
+            return;
+        }
+        let target = match self.bdp(self.cwnd_gain) {
+            Some(bdp) => cmp::max(bdp, self.minimum_window()),
+            None => self.initial_window,
+        };
+        if self.filled_pipe {
+            self.window = cmp::min(self.window + bytes, target);
+        } else if self.window < target || self.delivered < self.initial_window {
+            self.window += bytes;
+        }
+        self.window = cmp::max(self.window, self.minimum_window());
+    }
+}
+
+impl Controller for Bbr {
+    fn on_sent(&mut self, now: Instant, _bytes: u64, in_flight: u64) {
+        if self.sent.is_empty() {
+            // Don't count idle periods towards the delivery interval
+            self.delivered_time = now;
+        }
+        self.sent.push_back(SendState {
+            time: now,
+            delivered: self.delivered,
+            delivered_time: self.delivered_time,
+        });
+        if self.mode == Mode::Drain && in_flight <= self.bdp(1.0).unwrap_or(0) {
+            self.enter_probe_bw(now);
+        }
+    }
+
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: u64, app_limited: bool) {
+        let state = self.forget_sent(sent);
+        self.delivered += bytes;
+        self.delivered_time = now;
+
+        let mut round_start = false;
+        if let Some(state) = state {
+            if state.delivered >= self.next_round_delivered {
+                self.next_round_delivered = self.delivered;
+                self.round_count += 1;
+                round_start = true;
+            }
+            let interval = now - state.delivered_time;
+            if interval > Duration::from_secs(0) {
+                let rate = (self.delivered - state.delivered) as f64 / duration_secs(interval);
+                self.update_bandwidth(rate as u64, app_limited);
+            }
+        }
+
+        let rtt = now - sent;
+        let min_rtt_expired = now - self.min_rtt_stamp > MIN_RTT_WINDOW;
+        if self.min_rtt.map(|x| rtt <= x).unwrap_or(true) || min_rtt_expired {
+            self.min_rtt = Some(rtt);
+            self.min_rtt_stamp = now;
+        }
+
+        self.check_full_pipe(round_start, app_limited);
+        match self.mode {
+            Mode::Startup if self.filled_pipe => {
+                self.mode = Mode::Drain;
+                self.pacing_gain = 1.0 / HIGH_GAIN;
+                self.cwnd_gain = HIGH_GAIN;
+            }
+            Mode::ProbeBw => self.advance_cycle(now),
+            _ => {}
+        }
+        if min_rtt_expired && self.mode != Mode::ProbeRtt {
+            self.enter_probe_rtt();
+        }
+        self.check_probe_rtt(now, round_start);
+        self.update_window(bytes);
+    }
+
+    fn on_loss(&mut self, _now: Instant, sent: Instant, persistent_congestion: bool) {
+        // Loss doesn't factor into the model, but the state of lost packets is no longer useful
+        self.forget_sent(sent);
+        if persistent_congestion {
+            self.prior_window = cmp::max(self.prior_window, self.window);
+            self.window = self.minimum_window();
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        let rate = match self.bandwidth() {
+            Some(bandwidth) => bandwidth as f64 * self.pacing_gain,
+            None => {
+                let rtt = self.min_rtt.unwrap_or(self.initial_rtt);
+                self.initial_window as f64 * HIGH_GAIN / duration_secs(rtt)
+            }
+        };
+        Some(cmp::max(rate as u64, self.max_datagram_size))
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
+
+/// Selects `Bbr` through `TransportConfig::congestion_controller_factory`
+#[derive(Debug, Clone)]
+pub struct BbrConfig {
+    /// Size of the largest packets sent, bounding the smallest window
+    pub max_datagram_size: u64,
+    /// Window used before the bandwidth-delay product has been measured
+    pub initial_window: u64,
+    /// RTT used to derive the initial pacing rate before an RTT sample is taken
+    pub initial_rtt: Duration,
+}
+
+impl BbrConfig {
+    /// Take the initial parameters from `config`
+    pub fn new(config: &TransportConfig) -> Self {
+        Self {
+            max_datagram_size: config.max_datagram_size,
+            initial_window: config.initial_window,
+            initial_rtt: Duration::from_micros(config.initial_rtt),
+        }
+    }
+}
+
+impl Default for BbrConfig {
+    fn default() -> Self {
+        Self::new(&TransportConfig::default())
+    }
+}
+
+impl ControllerFactory for BbrConfig {
+    fn build(&self, now: Instant) -> Box<dyn Controller> {
+        Box::new(Bbr::new(self, now))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Mode {
+    /// Grow the sending rate exponentially until the bandwidth estimate stops growing
+    Startup,
+    /// Drain the queue built up during `Startup`
+    Drain,
+    /// Cycle the pacing rate around the bandwidth estimate to discover newly available bandwidth
+    ProbeBw,
+    /// Briefly reduce the window to drain the queue and refresh the RTT estimate
+    ProbeRtt,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct SendState {
+    time: Instant,
+    delivered: u64,
+    delivered_time: Instant,
+}
+
+/// 2/ln(2), the smallest gain that doubles the sending rate every round
+const HIGH_GAIN: f64 = 2.885;
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const BANDWIDTH_WINDOW_ROUNDS: u64 = 10;
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+const MIN_PIPE_PACKETS: u64 = 4;
@@ -0,0 +1,253 @@
+use std::{
+    cmp,
+    time::{Duration, Instant},
+};
+
+use super::{duration_secs, Controller, ControllerFactory};
+use crate::TransportConfig;
+
+/// CUBIC congestion control, as described in RFC 8312
+///
+/// After a loss the window grows along a cubic function of the time since, quickly returning to
+/// the size at which the loss occurred, cautiously probing around it, then accelerating away. The
+/// window never grows slower than a standard TCP flow's would on the same path. Slow start is
+/// left early on signs of queueing, as measured by HyStart++ (RFC 9406).
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    max_datagram_size: u64,
+    minimum_window: u64,
+    c: f64,
+    hystart: bool,
+    window: u64,
+    ssthresh: u64,
+    /// When QUIC entered recovery. Acknowledgements of packets sent before this don't grow the
+    /// window.
+    recovery_start_time: Instant,
+    /// Start of the current congestion avoidance epoch, if any
+    epoch_start: Option<Instant>,
+    /// Window size before the last reduction, in segments
+    w_max: f64,
+    /// `w_max` before the last reduction, for fast convergence
+    w_last_max: f64,
+    /// Time for the window to grow back to `w_max`, in seconds
+    k: f64,
+    /// Window a standard TCP flow would have reached during this epoch, in segments
+    w_est: f64,
+    min_rtt: Option<Duration>,
+    /// When the most recent packet was sent
+    last_sent: Option<Instant>,
+    /// A slow start round ends once a packet sent at or after this time is acknowledged
+    round_end: Option<Instant>,
+    last_round_min_rtt: Option<Duration>,
+    current_round_min_rtt: Option<Duration>,
+    rtt_sample_count: u32,
+}
+
+impl Cubic {
+    /// Construct a controller in slow start
+    pub fn new(config: &CubicConfig, now: Instant) -> Self {
+        Self {
+            max_datagram_size: config.max_datagram_size,
+            minimum_window: config.minimum_window,
+            c: config.c,
+            hystart: config.hystart,
+            window: config.initial_window,
+            ssthresh: 0xffff_ffff_ffff_ffff,
+            recovery_start_time: now,
+            epoch_start: None,
+            w_max: 0.0,
+            w_last_max: 0.0,
+            k: 0.0,
+            w_est: 0.0,
+            min_rtt: None,
+            last_sent: None,
+            round_end: None,
+            last_round_min_rtt: None,
+            current_round_min_rtt: None,
+            rtt_sample_count: 0,
+        }
+    }
+
+    fn in_recovery(&self, sent: Instant) -> bool {
+        sent <= self.recovery_start_time
+    }
+
+    /// Window in segments
+    fn segments(&self) -> f64 {
+        self.window as f64 / self.max_datagram_size as f64
+    }
+
+    /// Track the RTT of each slow start round, leaving slow start if it rises significantly
+    fn hystart(&mut self, sent: Instant, rtt: Duration) {
+        if self.round_end.map(|end| sent >= end).unwrap_or(true) {
+            self.round_end = self.last_sent;
+            self.last_round_min_rtt = self.current_round_min_rtt.take();
+            self.rtt_sample_count = 0;
+        }
+        let current = cmp::min(rtt, self.current_round_min_rtt.unwrap_or(rtt));
+        self.current_round_min_rtt = Some(current);
+        self.rtt_sample_count += 1;
+        if self.rtt_sample_count < HYSTART_RTT_SAMPLES {
+            return;
+        }
+        if let Some(last) = self.last_round_min_rtt {
+            let threshold = cmp::min(
+                cmp::max(last / 8, HYSTART_MIN_RTT_THRESHOLD),
+                HYSTART_MAX_RTT_THRESHOLD,
+            );
+            if current >= last + threshold {
+                self.ssthresh = self.window;
+            }
+        }
+    }
+
+    fn congestion_avoidance(&mut self, now: Instant, bytes: u64) {
+        let rtt = duration_secs(self.min_rtt.unwrap_or_else(|| Duration::from_millis(100)));
+        let epoch_start = match self.epoch_start {
+            Some(x) => x,
+            None => {
+                // First acknowledgement since a reduction, or since leaving slow start
+                let cwnd = self.segments();
+                if self.w_max < cwnd {
+                    self.k = 0.0;
+                    self.w_max = cwnd;
+                } else {
+                    self.k = ((self.w_max - cwnd) / self.c).cbrt();
+                }
+                self.w_est = cwnd;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+
+        let segment = self.max_datagram_size as f64;
+        let acked = bytes as f64 / segment;
+        let cwnd = self.segments();
+        self.w_est += 3.0 * (1.0 - BETA) / (1.0 + BETA) * acked / cwnd;
+
+        let t = duration_secs(now - epoch_start) + rtt;
+        let target = self.c * (t - self.k).powi(3) + self.w_max;
+        let increase = if target > cwnd {
+            (target - cwnd) / cwnd * acked
+        } else {
+            // Grow very slowly while the cubic function sits below the current window
+            acked / (100.0 * cwnd)
+        };
+        let w_cubic = cwnd + increase;
+        let next = if w_cubic < self.w_est {
+            // TCP-friendly region
+            self.w_est
+        } else {
+            w_cubic
+        };
+        self.window = cmp::max(self.window, (next * segment) as u64);
+    }
+}
+
+impl Controller for Cubic {
+    fn on_sent(&mut self, now: Instant, _bytes: u64, _in_flight: u64) {
+        self.last_sent = Some(now);
+    }
+
+    fn on_ack(&mut self, now: Instant, sent: Instant, bytes: u64, app_limited: bool) {
+        let rtt = now - sent;
+        self.min_rtt = Some(cmp::min(rtt, self.min_rtt.unwrap_or(rtt)));
+        // Do not increase congestion window in recovery period, or if we weren't sending at max
+        // rate.
+        if app_limited || self.in_recovery(sent) {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+            if self.hystart {
+                self.hystart(sent, rtt);
+            }
+        } else {
+            self.congestion_avoidance(now, bytes);
+        }
+    }
+
+    fn on_loss(&mut self, now: Instant, sent: Instant, persistent_congestion: bool) {
+        // Start a new recovery epoch if the lost packet is larger than the end of the
+        // previous recovery epoch.
+        if !self.in_recovery(sent) {
+            self.recovery_start_time = now;
+            self.epoch_start = None;
+            let cwnd = self.segments();
+            // Fast convergence: release bandwidth to flows which have joined since the last loss
+            self.w_max = if cwnd < self.w_last_max {
+                cwnd * (1.0 + BETA) / 2.0
+            } else {
+                cwnd
+            };
+            self.w_last_max = cwnd;
+            self.window = cmp::max((self.window as f64 * BETA) as u64, self.minimum_window);
+            self.ssthresh = self.window;
+        }
+        if persistent_congestion {
+            self.window = self.minimum_window;
+            self.w_last_max = 0.0;
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn ssthresh(&self) -> u64 {
+        self.ssthresh
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
+
+/// Selects `Cubic` through `TransportConfig::congestion_controller_factory`
+#[derive(Debug, Clone)]
+pub struct CubicConfig {
+    /// Size of the largest packets sent, the unit in which the cubic function is evaluated
+    pub max_datagram_size: u64,
+    /// Window used before any loss is detected
+    pub initial_window: u64,
+    /// Smallest the window may be reduced to
+    pub minimum_window: u64,
+    /// Scaling constant `C` of the cubic function, governing its aggressiveness
+    ///
+    /// RFC 8312 recommends 0.4, the default.
+    pub c: f64,
+    /// Whether to leave slow start early when the RTT rises, according to HyStart++
+    pub hystart: bool,
+}
+
+impl CubicConfig {
+    /// Take the window parameters from `config`
+    pub fn new(config: &TransportConfig) -> Self {
+        Self {
+            max_datagram_size: config.max_datagram_size,
+            initial_window: config.initial_window,
+            minimum_window: config.minimum_window,
+            c: 0.4,
+            hystart: true,
+        }
+    }
+}
+
+impl Default for CubicConfig {
+    fn default() -> Self {
+        Self::new(&TransportConfig::default())
+    }
+}
+
+impl ControllerFactory for CubicConfig {
+    fn build(&self, now: Instant) -> Box<dyn Controller> {
+        Box::new(Cubic::new(self, now))
+    }
+}
+
+/// Multiplicative decrease factor
+const BETA: f64 = 0.7;
+const HYSTART_RTT_SAMPLES: u32 = 8;
+const HYSTART_MIN_RTT_THRESHOLD: Duration = Duration::from_millis(4);
+const HYSTART_MAX_RTT_THRESHOLD: Duration = Duration::from_millis(16);
@@ -0,0 +1,85 @@
+use std::{cmp, time::Instant};
+
+use super::Controller;
+use crate::TransportConfig;
+
+/// A simple, standard congestion controller, as described in RFC 6582
+///
+/// Parameterized by `TransportConfig::max_datagram_size`, `initial_window`, `minimum_window` and
+/// `loss_reduction_factor`.
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    max_datagram_size: u64,
+    minimum_window: u64,
+    loss_reduction_factor: u16,
+    /// Maximum number of bytes in flight that may be sent
+    window: u64,
+    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is
+    /// slow start and the window grows by the number of bytes acknowledged.
+    ssthresh: u64,
+    /// The time when QUIC first detects a loss, causing it to enter recovery. When a packet sent
+    /// after this time is acknowledged, QUIC exits recovery.
+    recovery_start_time: Instant,
+}
+
+impl NewReno {
+    /// Construct a controller in slow start, using the window parameters of `config`
+    pub fn new(config: &TransportConfig, now: Instant) -> Self {
+        Self {
+            max_datagram_size: config.max_datagram_size,
+            minimum_window: config.minimum_window,
+            loss_reduction_factor: config.loss_reduction_factor,
+            window: config.initial_window,
+            ssthresh: u64::max_value(),
+            recovery_start_time: now,
+        }
+    }
+
+    fn in_recovery(&self, sent: Instant) -> bool {
+        sent <= self.recovery_start_time
+    }
+}
+
+impl Controller for NewReno {
+    fn on_ack(&mut self, _now: Instant, sent: Instant, bytes: u64, app_limited: bool) {
+        // Do not increase congestion window in recovery period, or if we weren't sending at max
+        // rate.
+        if app_limited || self.in_recovery(sent) {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+        } else {
+            // Congestion avoidance.
+            self.window += self.max_datagram_size * bytes / self.window;
+        }
+    }
+
+    fn on_loss(&mut self, now: Instant, sent: Instant, persistent_congestion: bool) {
+        // Start a new recovery epoch if the lost packet is larger than the end of the
+        // previous recovery epoch.
+        if !self.in_recovery(sent) {
+            self.recovery_start_time = now;
+            // *= factor
+            self.window = (self.window * u64::from(self.loss_reduction_factor)) >> 16;
+            self.window = cmp::max(self.window, self.minimum_window);
+            self.ssthresh = self.window;
+        }
+        if persistent_congestion {
+            self.window = self.minimum_window;
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn ssthresh(&self) -> u64 {
+        self.ssthresh
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    cmp,
+    time::{Duration, Instant},
+};
+
+/// Token bucket spreading packets out at a `Controller`'s pacing rate, or at `window_rate`
+///
+/// Up to `BURST_PACKETS` packets may be sent back to back after a pause.
+#[derive(Debug, Clone)]
+pub(crate) struct Pacer {
+    tokens: u64,
+    prev: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            // Clamped to the burst size on first use
+            tokens: 0xffff_ffff_ffff_ffff,
+            prev: now,
+        }
+    }
+
+    /// When a packet of `size` bytes may be sent at `rate` bytes per second, if not immediately
+    pub(crate) fn delay(&mut self, now: Instant, rate: u64, size: u64) -> Option<Instant> {
+        let capacity = BURST_PACKETS * size;
+        let elapsed = now.saturating_duration_since(self.prev);
+        let refill = u128::from(rate) * elapsed.as_nanos() / 1_000_000_000;
+        self.tokens = cmp::min(capacity as u128, u128::from(self.tokens) + refill) as u64;
+        self.prev = now;
+        if self.tokens >= size {
+            return None;
+        }
+        // Round up, so that the tokens have been refilled by the time we're woken
+        let wait =
+            u128::from(size - self.tokens) * 1_000_000_000 / u128::from(cmp::max(rate, 1)) + 1;
+        Some(now + Duration::from_nanos(wait as u64))
+    }
+
+    /// A packet of `size` bytes was sent
+    pub(crate) fn on_sent(&mut self, size: u64) {
+        self.tokens = self.tokens.saturating_sub(size);
+    }
+}
+
+/// Pacing rate for controllers which don't report one, in bytes per second
+///
+/// Spreads 5/4 of the congestion `window` over a round trip, so that pacing doesn't keep the
+/// window from filling up as it grows. `None` until the round-trip time is known.
+pub(crate) fn window_rate(window: u64, rtt: Duration) -> Option<u64> {
+    let nanos = rtt.as_nanos();
+    if nanos == 0 {
+        return None;
+    }
+    let rate = u128::from(window) * 5 / 4 * 1_000_000_000 / nanos;
+    Some(cmp::min(rate, 0xffff_ffff_ffff_ffff) as u64)
+}
+
+const BURST_PACKETS: u64 = 10;
@@ -9,27 +9,30 @@ use std::{
 
 use bytes::{Bytes, BytesMut};
 use err_derive::Error;
-use rand::{rngs::StdRng, Rng, SeedableRng};
-use tracing::{debug, error, info, trace, trace_span, warn};
+use rand::{rngs::StdRng, Rng};
+use tracing::{debug, error, field, info, info_span, trace, trace_span, warn, Span};
 
 use crate::{
     coding::BufMutExt,
+    congestion,
     crypto::{self, HeaderKeys, Keys},
     frame,
     frame::{Close, Datagram, FrameStruct},
     packet::{Header, LongType, Packet, PacketNumber, PartialDecode, SpaceId},
+    qlog::{Metrics, QlogTrace},
     range_set::RangeSet,
     shared::{
         ConnectionEvent, ConnectionEventInner, ConnectionId, EcnCodepoint, EndpointConfig,
         EndpointEvent, EndpointEventInner, IssuedCid, ServerConfig, TransportConfig,
     },
     spaces::{CryptoSpace, PacketSpace, Retransmits, SentPacket},
-    streams::{self, FinishError, ReadError, Streams, UnknownStream, WriteError},
+    streams::{
+        self, FinishError, ReadError, StreamInfo, Streams, UnknownStream, WriteError, Written,
+    },
     timer::{Timer, TimerKind, TimerTable},
     transport_parameters::{self, TransportParameters},
     Dir, Frame, Side, StreamId, Transmit, TransportError, TransportErrorCode, VarInt,
-    MAX_STREAM_COUNT, MIN_INITIAL_SIZE, MIN_MTU, REM_CID_COUNT, RESET_TOKEN_SIZE,
-    TIMER_GRANULARITY,
+    MAX_STREAM_COUNT, MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE, TIMER_GRANULARITY, VERSION,
 };
 
 /// Protocol state and logic for a single QUIC connection
@@ -65,6 +68,8 @@ where
     key_phase: bool,
     /// Transport parameters set by the peer
     params: TransportParameters,
+    /// Exponent we told the peer to scale our ACK delays by
+    local_ack_delay_exponent: u64,
     /// Streams on which writing was blocked on *connection-level* flow or congestion control
     blocked_streams: HashSet<StreamId>,
     /// Limit on outgoing data, dictated by peer
@@ -75,12 +80,21 @@ where
     data_recvd: u64,
     /// Limit on incoming data
     local_max_data: u64,
+    /// Sum of offsets up to which all receive streams have been read or discarded
+    data_consumed: u64,
     /// Stream data we're sending that hasn't been acknowledged or reset yet
     unacked_data: u64,
-    /// ConnectionId sent by this client on the first Initial, if a Retry was received.
+    /// ConnectionId sent by the client on its first Initial, if a Retry was received (clients) or
+    /// sent (servers).
     orig_rem_cid: Option<ConnectionId>,
     /// Total number of outgoing packets that have been deemed lost
     lost_packets: u64,
+    /// Total size of the outgoing packets that have been deemed lost
+    lost_bytes: u64,
+    /// Number of 1-RTT key updates, initiated by either side
+    key_updates: u64,
+    /// Number of outgoing packets the peer reported as ECN-CE marked
+    congestion_experienced: u64,
     io: IoQueue,
     events: VecDeque<Event>,
     endpoint_events: VecDeque<EndpointEventInner>,
@@ -103,10 +117,15 @@ where
     next_crypto: Option<S::Keys>,
     /// Latest PATH_CHALLENGE token issued to the peer along the current path
     path_challenge: Option<u64>,
+    /// Wait imposed after the most recent migration
+    migration_backoff: Duration,
+    /// Earliest time at which another migration will be followed
+    next_migration: Option<Instant>,
     /// Whether the remote endpoint has opened any streams the application doesn't know about yet,
     /// per directionality
     stream_opened: [bool; 2],
     accepted_0rtt: bool,
+    early_data: EarlyDataStatus,
     /// Whether the idle timer should be reset the next time an ack-eliciting packet is transmitted.
     permit_idle_reset: bool,
     /// Negotiated idle timeout
@@ -136,23 +155,38 @@ where
     //
     /// Summary statistics of packets that have been sent, but not yet acked or deemed lost
     in_flight: InFlight,
-    /// The time when QUIC first detects a loss, causing it to enter recovery. When a packet sent
-    /// after this time is acknowledged, QUIC exits recovery.
-    recovery_start_time: Instant,
     /// Explicit congestion notification (ECN) counters
     ecn_counters: frame::EcnCounts,
     /// Whether the most recently received packet had an ECN codepoint set
     receiving_ecn: bool,
     remote_validated: bool,
+    /// Address validation token to send the client once the handshake completes (servers only)
+    new_token: Option<Bytes>,
+    /// Where tokens from NEW_TOKEN frames are kept, and the server name they're kept by (clients
+    /// only)
+    token_storage: Option<(Arc<dyn crypto::SessionStorage>, String)>,
     /// Total UDP datagram bytes received, tracked for handshake anti-amplification
     total_recvd: u64,
     total_sent: u64,
+    received_datagrams: u64,
+    sent_datagrams: u64,
+    /// Authenticated packets received, excluding duplicates
+    received_packets: u64,
+    sent_packets: u64,
+    /// DSCP marking applied to outgoing packets
+    dscp: u8,
 
     streams: Streams,
     /// Surplus remote CIDs for future use on new paths
     rem_cids: Vec<IssuedCid>,
     /// State of the unreliable datagram extension
     datagrams: DatagramState,
+    /// State of the ACK frequency extension
+    ack_frequency: AckFrequencyState,
+    /// Entered while handshaking, and closed once the handshake completes
+    handshake_span: Option<Span>,
+    /// Trace of events in the qlog format, if enabled by `TransportConfig::qlog_writer`
+    qlog: Option<QlogTrace>,
 }
 
 impl<S> Connection<S>
@@ -163,20 +197,26 @@ where
         endpoint_config: Arc<EndpointConfig>,
         server_config: Option<Arc<ServerConfig<S>>>,
         config: Arc<TransportConfig>,
-        init_cid: ConnectionId,
-        loc_cid: ConnectionId,
-        rem_cid: ConnectionId,
+        cids: InitialCids,
         remote: SocketAddr,
         tls: S,
         now: Instant,
+        mut rng: StdRng,
     ) -> Self {
+        let InitialCids {
+            init: init_cid,
+            local: loc_cid,
+            remote: rem_cid,
+        } = cids;
         let side = if server_config.is_some() {
             Side::Server
         } else {
             Side::Client
         };
         let initial_space = PacketSpace {
-            crypto: Some(CryptoSpace::new(S::Keys::new_initial(&init_cid, side))),
+            crypto: Some(CryptoSpace::new(S::Keys::new_initial(
+                &init_cid, side, VERSION,
+            ))),
             ..PacketSpace::new(now)
         };
         let state = State::Handshake(state::Handshake {
@@ -184,10 +224,20 @@ where
             token: None,
             client_hello: None,
         });
-        let mut rng = StdRng::from_entropy();
         let remote_validated = server_config
             .as_ref()
             .map_or(false, |c| c.use_stateless_retry);
+        // Named after the destination CID of the client's Initial, which both sides know, or of its
+        // second Initial if the server sent a Retry
+        let handshake_span = info_span!(
+            "handshake",
+            ?side,
+            cid = %init_cid,
+            alpn = field::Empty,
+            cipher_suite = field::Empty,
+            zero_rtt = field::Empty,
+            retry = field::Empty,
+        );
         let mut this = Self {
             endpoint_config,
             server_config,
@@ -199,8 +249,8 @@ where
             path: PathData {
                 remote,
                 rtt: RttEstimator::new(),
-                congestion_window: config.initial_window,
-                ssthresh: u64::max_value(),
+                congestion: new_controller(&config, now),
+                pacing: congestion::Pacer::new(now),
                 sending_ecn: true,
             },
             prev_path: None,
@@ -211,27 +261,37 @@ where
             zero_rtt_crypto: None,
             key_phase: false,
             params: TransportParameters::default(),
+            local_ack_delay_exponent: TransportParameters::default().ack_delay_exponent,
             blocked_streams: HashSet::new(),
             max_data: 0,
             data_sent: 0,
             data_recvd: 0,
             local_max_data: config.receive_window as u64,
+            data_consumed: 0,
             unacked_data: 0,
             orig_rem_cid: None,
             lost_packets: 0,
+            lost_bytes: 0,
+            key_updates: 0,
+            congestion_experienced: 0,
             io: IoQueue::new(),
             events: VecDeque::new(),
             endpoint_events: VecDeque::new(),
             cids_issued: 0,
-            spin_enabled: config.allow_spin && rng.gen_ratio(7, 8),
+            spin_enabled: config.allow_spin
+                && !config.deterministic_header_bits
+                && rng.gen_ratio(7, 8),
             spin: false,
             spaces: [initial_space, PacketSpace::new(now), PacketSpace::new(now)],
             highest_space: SpaceId::Initial,
             prev_crypto: None,
             next_crypto: None,
             path_challenge: None,
+            migration_backoff: Duration::from_micros(0),
+            next_migration: None,
             stream_opened: [false, false],
             accepted_0rtt: false,
+            early_data: EarlyDataStatus::NotAttempted,
             permit_idle_reset: true,
             idle_timeout: config.idle_timeout,
             first_1rtt_sent: None,
@@ -244,15 +304,27 @@ where
             pto_count: 0,
 
             in_flight: InFlight::new(),
-            recovery_start_time: now,
             ecn_counters: frame::EcnCounts::ZERO,
             receiving_ecn: false,
             remote_validated,
+            new_token: None,
+            token_storage: None,
             total_recvd: 0,
             total_sent: 0,
+            received_datagrams: 0,
+            sent_datagrams: 0,
+            received_packets: 0,
+            sent_packets: 0,
+            dscp: 0,
 
             streams: Streams::new(side, config.stream_window_uni, config.stream_window_bidi),
             datagrams: DatagramState::new(),
+            ack_frequency: AckFrequencyState::new(),
+            handshake_span: Some(handshake_span),
+            qlog: config
+                .qlog
+                .as_ref()
+                .map(|sink| QlogTrace::new(sink.clone(), side, &init_cid)),
             config,
             rem_cids: Vec::new(),
             rng,
@@ -319,6 +391,14 @@ where
         } = packet;
 
         self.in_flight.insert(&packet);
+        if size != 0 {
+            self.path
+                .congestion
+                .on_sent(now, u64::from(size), self.in_flight.bytes);
+            if ack_eliciting {
+                self.path.pacing.on_sent(u64::from(size));
+            }
+        }
         self.space_mut(space)
             .sent_packets
             .insert(packet_number, packet);
@@ -378,7 +458,12 @@ where
             if let Some(info) = self.space_mut(space).sent_packets.remove(&packet) {
                 self.space_mut(space).pending_acks.subtract(&info.acks);
                 ack_eliciting_acked |= info.ack_eliciting;
-                self.on_packet_acked(info);
+                if info.retransmits.ping {
+                    self.events.push_back(Event::PingAcknowledged {
+                        rtt: instant_saturating_sub(now, info.time_sent),
+                    });
+                }
+                self.on_packet_acked(now, info);
             }
         }
 
@@ -401,6 +486,9 @@ where
         {
             // Received first acknowledgment of 1-RTT packet
             self.discard_space(SpaceId::Handshake);
+            if self.side.is_client() {
+                self.migrate_to_preferred_address(now);
+            }
         }
 
         // Must be called before crypto/pto_count are clobbered
@@ -428,6 +516,8 @@ where
         }
 
         self.set_loss_detection_timer();
+        self.trace_metrics(now);
+        self.update_max_data();
         if was_blocked && !self.blocked() {
             for stream in self.blocked_streams.drain() {
                 self.events.push_back(Event::StreamWritable { stream });
@@ -453,33 +543,29 @@ where
                 // future attempts to use ECN on new paths.
                 self.space_mut(space).ecn_feedback = frame::EcnCounts::ZERO;
             }
-            Ok(false) => {}
-            Ok(true) => {
-                self.congestion_event(now, largest_sent_time);
+            Ok(0) => {}
+            Ok(ce_increase) => {
+                self.congestion_experienced += ce_increase;
+                self.path
+                    .congestion
+                    .on_congestion_experienced(now, largest_sent_time, ce_increase);
             }
         }
     }
 
     // Not timing-aware, so it's safe to call this for inferred acks, such as arise from
     // high-latency handshakes
-    fn on_packet_acked(&mut self, info: SentPacket) {
+    fn on_packet_acked(&mut self, now: Instant, info: SentPacket) {
         let was_congestion_blocked = self.congestion_blocked();
         self.in_flight.remove(&info);
-        if info.ack_eliciting {
-            // Congestion control
-            // Do not increase congestion window in recovery period or while migrating, or if we
-            // weren't sending at max rate.
-            if !self.in_recovery(info.time_sent) && !self.migrating() && was_congestion_blocked {
-                if self.path.congestion_window < self.path.ssthresh {
-                    // Slow start.
-                    self.path.congestion_window += u64::from(info.size);
-                } else {
-                    // Congestion avoidance.
-                    self.path.congestion_window += self.config.max_datagram_size
-                        * u64::from(info.size)
-                        / self.path.congestion_window;
-                }
-            }
+        // Do not increase congestion window while migrating
+        if info.ack_eliciting && !self.migrating() {
+            self.path.congestion.on_ack(
+                now,
+                info.time_sent,
+                u64::from(info.size),
+                !was_congestion_blocked,
+            );
         }
 
         // Update state for confirmed delivery of frames
@@ -499,7 +585,7 @@ where
             }
         }
 
-        for frame in info.retransmits.stream {
+        for frame in info.retransmits.stream.into_frames() {
             let ss = match self.streams.send_mut(frame.id) {
                 Some(x) => x,
                 None => continue,
@@ -523,6 +609,8 @@ where
     /// `EndpointEvent`s and outgoing datagrams) that should be extracted through the relevant
     /// methods.
     pub fn handle_timeout(&mut self, now: Instant, timer: Timer) {
+        let span = self.handshake_span();
+        let _guard = span.enter();
         match timer.0 {
             TimerKind::Close => {
                 self.state = State::Drained;
@@ -542,7 +630,9 @@ where
                 self.on_loss_detection_timeout(now);
             }
             TimerKind::KeyDiscard => {
-                self.zero_rtt_crypto = None;
+                if self.zero_rtt_crypto.take().is_some() {
+                    debug!("0-RTT keys discarded");
+                }
                 self.prev_crypto = None;
             }
             TimerKind::PathValidation => {
@@ -553,6 +643,13 @@ where
                     self.path = prev;
                 }
             }
+            TimerKind::MaxAckDelay => {
+                trace!("max ack delay reached");
+                self.space_mut(SpaceId::Data).permit_ack_only = true;
+            }
+            TimerKind::Pacing => {
+                trace!("pacing delay elapsed");
+            }
         }
     }
 
@@ -619,6 +716,8 @@ where
     fn ensure_probe_queued(&mut self, space: SpaceId) {
         // Retransmit the data of the oldest in-flight packet
         let space = self.space_mut(space);
+        // Peers that delay acks at our request should still answer probes promptly
+        space.immediate_ack_pending = true;
         if !space.pending.is_empty() {
             // There's real data to send here, no need to make something up
             return;
@@ -677,12 +776,16 @@ where
             self.lost_packets += lost_packets.len() as u64;
             trace!("packets lost: {:?}", lost_packets);
             for packet in &lost_packets {
+                if let Some(ref qlog) = self.qlog {
+                    qlog.packet_lost(now, pn_space, *packet);
+                }
                 let info = self
                     .space_mut(pn_space)
                     .sent_packets
                     .remove(&packet)
                     .unwrap(); // safe: lost_packets is populated just above
                 self.in_flight.remove(&info);
+                self.lost_bytes += u64::from(info.size);
                 self.space_mut(pn_space).pending += info.retransmits;
             }
             // Don't apply congestion penalty for lost ack-only packets
@@ -695,31 +798,43 @@ where
                 < largest_lost_sent - congestion_period;
 
             if lost_ack_eliciting {
-                self.congestion_event(now, largest_lost_sent);
-                if in_persistent_congestion {
-                    self.path.congestion_window = self.config.minimum_window;
-                }
+                self.path
+                    .congestion
+                    .on_loss(now, largest_lost_sent, in_persistent_congestion);
             }
+            self.trace_metrics(now);
         }
     }
 
-    fn congestion_event(&mut self, now: Instant, sent_time: Instant) {
-        // Start a new recovery epoch if the lost packet is larger than the end of the
-        // previous recovery epoch.
-        if self.in_recovery(sent_time) {
-            return;
+    /// Report congestion and RTT metrics to the qlog trace, if any
+    fn trace_metrics(&mut self, now: Instant) {
+        if let Some(ref mut qlog) = self.qlog {
+            qlog.metrics_updated(
+                now,
+                Metrics {
+                    congestion_window: self.path.congestion.window(),
+                    bytes_in_flight: self.in_flight.bytes,
+                    smoothed_rtt: self.path.rtt.smoothed,
+                    min_rtt: self.path.rtt.min,
+                    latest_rtt: self.path.rtt.latest,
+                },
+            );
         }
-        self.recovery_start_time = now;
-        // *= factor
-        self.path.congestion_window =
-            (self.path.congestion_window * u64::from(self.config.loss_reduction_factor)) >> 16;
-        self.path.congestion_window =
-            cmp::max(self.path.congestion_window, self.config.minimum_window);
-        self.path.ssthresh = self.path.congestion_window;
     }
 
-    fn in_recovery(&self, sent_time: Instant) -> bool {
-        sent_time <= self.recovery_start_time
+    /// Account for the transport parameters sent to the peer
+    pub(crate) fn local_params_set(&mut self, now: Instant, params: &TransportParameters) {
+        self.local_ack_delay_exponent = params.ack_delay_exponent;
+        if self.side.is_server() {
+            self.orig_rem_cid = params.original_connection_id;
+        }
+        if params.preferred_address.is_some() {
+            // Issued the CID of sequence number 1 along with it
+            self.cids_issued = 1;
+        }
+        if let Some(ref qlog) = self.qlog {
+            qlog.parameters_set(now, true, params);
+        }
     }
 
     fn earliest_time_and_space(
@@ -815,6 +930,9 @@ where
         if space.pending_acks.len() > MAX_ACK_BLOCKS {
             space.pending_acks.pop_min();
         }
+        if space_id == SpaceId::Data {
+            self.ack_frequency.reordered = packet < space.rx_packet || packet > space.rx_packet + 1;
+        }
         if packet >= space.rx_packet {
             space.rx_packet = packet;
             space.rx_packet_time = now;
@@ -856,15 +974,16 @@ where
         ss.bytes_in_flight += data.len() as u64;
         self.data_sent += data.len() as u64;
         self.unacked_data += data.len() as u64;
-        self.space_mut(SpaceId::Data)
-            .pending
-            .stream
-            .push_back(frame::Stream {
+        let priority = ss.priority;
+        self.space_mut(SpaceId::Data).pending.stream.push_back(
+            priority,
+            frame::Stream {
                 offset,
                 fin: false,
                 data,
                 id: stream,
-            });
+            },
+        );
         Ok(())
     }
 
@@ -937,11 +1056,23 @@ where
         packet: Packet,
         remaining: Option<BytesMut>,
     ) -> Result<(), ConnectionError> {
+        let handshake_span = self.handshake_span();
+        let _handshake_guard = handshake_span.enter();
         let span = trace_span!("first recv");
         let _guard = span.enter();
         debug_assert!(self.side.is_server());
-        let len = packet.header_data.len() + packet.payload.len();
+        // The payload was stripped of its authentication tag by decryption
+        let tag_len = self
+            .space(SpaceId::Initial)
+            .crypto
+            .as_ref()
+            .unwrap()
+            .packet
+            .tag_len();
+        let len = packet.header_data.len() + packet.payload.len() + tag_len;
         self.total_recvd = len as u64;
+        self.received_datagrams = 1;
+        self.received_packets = 1;
 
         self.on_packet_authenticated(
             now,
@@ -961,7 +1092,12 @@ where
     fn init_0rtt(&mut self) {
         let packet = match self.tls.early_crypto() {
             Some(x) => x,
-            None => return,
+            None => {
+                if self.side.is_client() {
+                    self.early_data = EarlyDataStatus::NoTicket;
+                }
+                return;
+            }
         };
         if self.side.is_client() {
             match self.tls.transport_parameters() {
@@ -981,12 +1117,21 @@ where
                 }
                 Err(e) => {
                     error!("session ticket has malformed transport parameters: {}", e);
+                    self.early_data = EarlyDataStatus::NoTicket;
                     return;
                 }
             }
         }
-        trace!("0-RTT enabled");
+        debug!("0-RTT keys ready");
         self.zero_rtt_enabled = true;
+        self.early_data = EarlyDataStatus::Attempted {
+            // Servers accept 0-RTT whenever they derive keys for it
+            accepted: if self.side.is_client() {
+                None
+            } else {
+                Some(true)
+            },
+        };
         self.zero_rtt_crypto = Some(CryptoSpace {
             header: packet.header_keys(),
             packet,
@@ -1012,7 +1157,17 @@ where
             ));
         }
 
+        if let Some(limit) = self.config.max_handshake_data {
+            if end > limit {
+                return Err(TransportError::CRYPTO_BUFFER_EXCEEDED(format!(
+                    "peer sent {} bytes of {:?} CRYPTO data, exceeding the limit of {}",
+                    end, space, limit
+                )));
+            }
+        }
+
         let space = &mut self.spaces[space as usize];
+        space.crypto_received = cmp::max(space.crypto_received, end);
         let max = space.crypto_stream.offset() + self.config.crypto_buffer_size as u64;
         if end > max {
             return Err(TransportError::CRYPTO_BUFFER_EXCEEDED(""));
@@ -1075,7 +1230,7 @@ where
             "already reached packet space {:?}",
             space
         );
-        trace!("{:?} keys ready", space);
+        debug!(?space, "keys ready");
         if space == SpaceId::Data {
             // Precompute the first key update
             self.next_crypto = Some(self.tls.update_keys(&crypto));
@@ -1083,14 +1238,15 @@ where
         self.spaces[space as usize].crypto = Some(CryptoSpace::new(crypto));
         debug_assert!(space as usize > self.highest_space as usize);
         self.highest_space = space;
-        if space == SpaceId::Data && self.side.is_client() {
+        if space == SpaceId::Data && self.side.is_client() && self.zero_rtt_crypto.is_some() {
             // Discard 0-RTT keys because 1-RTT keys are available.
+            debug!("0-RTT keys discarded");
             self.zero_rtt_crypto = None;
         }
     }
 
     fn discard_space(&mut self, space: SpaceId) {
-        trace!("discarding {:?} keys", space);
+        debug!(?space, "keys discarded");
         let space = self.space_mut(space);
         space.crypto = None;
         space.time_of_last_sent_ack_eliciting_packet = None;
@@ -1108,6 +1264,8 @@ where
     /// extracted through the relevant methods.
     pub fn handle_event(&mut self, event: ConnectionEvent) {
         use self::ConnectionEventInner::*;
+        let span = self.handshake_span();
+        let _guard = span.enter();
         match event.0 {
             Datagram {
                 now,
@@ -1127,6 +1285,7 @@ where
                 }
 
                 self.total_recvd = self.total_recvd.wrapping_add(first_decode.len() as u64);
+                self.received_datagrams += 1;
 
                 self.handle_decode(now, remote, ecn, first_decode);
                 if let Some(data) = remaining {
@@ -1278,7 +1437,18 @@ where
                             packet.header.is_1rtt(),
                         );
                     }
+                    self.received_packets += 1;
+                    if let Some(ref qlog) = self.qlog {
+                        qlog.packet_received(
+                            now,
+                            &packet.header,
+                            number,
+                            packet.header_data.len() + packet.payload.len(),
+                            &packet.payload,
+                        );
+                    }
                     self.process_decrypted_packet(now, remote, number, packet)
+                        .and_then(|()| self.check_memory())
                 }
             }
         };
@@ -1355,7 +1525,7 @@ where
                             // Destination Connection ID field of its first Initial packet.
                             return Ok(());
                         }
-                        trace!("retrying with CID {}", rem_cid);
+                        debug!("retrying with CID {}", rem_cid);
                         let client_hello = state.client_hello.take().unwrap();
                         self.orig_rem_cid = Some(self.rem_cid);
                         self.rem_cid = rem_cid;
@@ -1364,13 +1534,15 @@ where
                         let space = self.space_mut(SpaceId::Initial);
                         if let Some(info) = space.sent_packets.remove(&0) {
                             space.pending_acks.subtract(&info.acks);
-                            self.on_packet_acked(info);
+                            self.on_packet_acked(now, info);
                         };
 
                         self.discard_space(SpaceId::Initial); // Make sure we clean up after any retransmitted Initials
                         self.spaces[0] = PacketSpace {
                             crypto: Some(CryptoSpace::new(S::Keys::new_initial(
-                                &rem_cid, self.side,
+                                &rem_cid,
+                                self.side,
+                                self.version(),
                             ))),
                             next_packet_number: self.spaces[0].next_packet_number,
                             crypto_offset: client_hello.len() as u64,
@@ -1443,6 +1615,9 @@ where
                                     self.reject_0rtt();
                                 } else {
                                     self.accepted_0rtt = true;
+                                    self.early_data = EarlyDataStatus::Attempted {
+                                        accepted: Some(true),
+                                    };
                                     params.validate_0rtt(&self.params)?;
                                 }
                             }
@@ -1455,6 +1630,9 @@ where
                             }
                             self.validate_params(&params)?;
                             self.set_params(params);
+                            if let Some(ref qlog) = self.qlog {
+                                qlog.parameters_set(now, false, &params);
+                            }
                             if params.active_connection_id_limit != 0 {
                                 self.endpoint_events.push_back(
                                     EndpointEventInner::NeedIdentifiers(
@@ -1464,9 +1642,13 @@ where
                             }
                         }
 
+                        self.request_ack_frequency();
+                        if let Some(token) = self.new_token.take() {
+                            self.space_mut(SpaceId::Data).pending.new_tokens.push(token);
+                        }
                         self.events.push_back(Event::Connected);
                         self.state = State::Established;
-                        trace!("established");
+                        self.finish_handshake_span();
                         Ok(())
                     }
                     Header::Initial {
@@ -1494,6 +1676,8 @@ where
                             && starting_space == SpaceId::Initial
                             && self.highest_space != SpaceId::Initial
                         {
+                            // The client's first handshake message has been read
+                            check_alpn(&self.tls, self.server_config.as_ref().unwrap())?;
                             let params =
                                 self.tls
                                     .transport_parameters()?
@@ -1504,6 +1688,9 @@ where
                                     })?;
                             self.validate_params(&params)?;
                             self.set_params(params);
+                            if let Some(ref qlog) = self.qlog {
+                                qlog.parameters_set(now, false, &params);
+                            }
                             if params.active_connection_id_limit != 0 {
                                 self.endpoint_events.push_back(
                                     EndpointEventInner::NeedIdentifiers(
@@ -1564,9 +1751,23 @@ where
         &mut self,
         now: Instant,
         packet: Packet,
+    ) -> Result<(), TransportError> {
+        let mut ty = None;
+        self.process_early_frames(now, packet, &mut ty)
+            .map_err(|e| e.or_frame(ty))
+    }
+
+    /// Process the frames of an Initial or Handshake packet, recording the type of each in `ty` as
+    /// it's processed so errors can be attributed
+    fn process_early_frames(
+        &mut self,
+        now: Instant,
+        packet: Packet,
+        ty: &mut Option<frame::Type>,
     ) -> Result<(), TransportError> {
         debug_assert_ne!(packet.header.space(), SpaceId::Data);
         for frame in frame::Iter::new(packet.payload.freeze()) {
+            *ty = Some(frame.ty());
             let span = match frame {
                 Frame::Padding => None,
                 _ => Some(trace_span!("frame", ty = %frame.ty())),
@@ -1617,10 +1818,28 @@ where
         remote: SocketAddr,
         number: u64,
         payload: Bytes,
+    ) -> Result<(), TransportError> {
+        let mut ty = None;
+        self.process_frames(now, remote, number, payload, &mut ty)
+            .map_err(|e| e.or_frame(ty))
+    }
+
+    /// Process the frames of a 0-RTT or 1-RTT packet, recording the type of each in `ty` as it's
+    /// processed so errors can be attributed
+    fn process_frames(
+        &mut self,
+        now: Instant,
+        remote: SocketAddr,
+        number: u64,
+        payload: Bytes,
+        ty: &mut Option<frame::Type>,
     ) -> Result<(), TransportError> {
         let is_0rtt = self.space(SpaceId::Data).crypto.is_none();
         let mut is_probing_packet = true;
+        let mut ack_eliciting = false;
+        let mut immediate_ack = false;
         for frame in frame::Iter::new(payload) {
+            *ty = Some(frame.ty());
             let span = match frame {
                 Frame::Padding => None,
                 _ => Some(trace_span!("frame", ty = %frame.ty())),
@@ -1640,7 +1859,7 @@ where
             match frame {
                 Frame::Ack(_) | Frame::Padding | Frame::Close(_) => {}
                 _ => {
-                    self.space_mut(SpaceId::Data).permit_ack_only = true;
+                    ack_eliciting = true;
                 }
             }
             // Check whether this could be a probing packet
@@ -1677,6 +1896,9 @@ where
                         Ok(Some(rs)) => rs,
                     };
                     if rs.is_finished() {
+                        // Retransmissions and data overtaken by a RESET_STREAM are harmless, but
+                        // the final size must still be respected
+                        rs.check_final_offset(&frame)?;
                         trace!("dropping frame for finished stream");
                         continue;
                     }
@@ -1723,6 +1945,15 @@ where
                     trace!("path validated");
                     self.io.timer_stop(TimerKind::PathValidation);
                     self.path_challenge = None;
+                    if let Some(prev) = self.prev_path.take() {
+                        // Nothing to report if only the local address changed
+                        if prev.remote != remote {
+                            self.events.push_back(Event::RemoteAddressChanged {
+                                old: prev.remote,
+                                new: remote,
+                            });
+                        }
+                    }
                 }
                 Frame::MaxData(bytes) => {
                     let was_blocked = self.blocked();
@@ -1798,6 +2029,12 @@ where
                         ));
                     }
 
+                    if rs.is_reset() {
+                        // Retransmitted; flow control has already been updated
+                        trace!("dropping duplicate RST_STREAM");
+                        continue;
+                    }
+
                     // State transition
                     rs.reset(error_code, final_offset);
 
@@ -1805,8 +2042,8 @@ where
                     if rs.bytes_read != final_offset {
                         self.data_recvd += final_offset - limit;
                         // bytes_read is always <= limit, so this won't underflow.
-                        self.local_max_data += final_offset - rs.bytes_read;
-                        self.space_mut(SpaceId::Data).pending.max_data = true;
+                        self.data_consumed += final_offset - rs.bytes_read;
+                        self.update_max_data();
                     }
 
                     // Notify application
@@ -1905,11 +2142,18 @@ where
                         // switch immediately to enable clientside stateless resets.
                         debug_assert_eq!(self.rem_cid_seq, 0);
                         self.update_rem_cid(issued);
-                    } else {
-                        // Reasonable limit to bound memory use
-                        if (self.rem_cids.len() as u64) < REM_CID_COUNT {
-                            self.rem_cids.push(issued);
+                    } else if frame.sequence >= self.first_unretired_cid
+                        && frame.sequence != self.rem_cid_seq
+                        && !self.rem_cids.iter().any(|x| x.sequence == frame.sequence)
+                    {
+                        // The CID in use only counts against the limit if it's not about to be
+                        // replaced by one of the cached CIDs.
+                        let active = self.rem_cids.len() as u64
+                            + (self.rem_cid_seq >= self.first_unretired_cid) as u64;
+                        if active >= self.config.active_connection_id_limit {
+                            return Err(TransportError::CONNECTION_ID_LIMIT_ERROR(""));
                         }
+                        self.rem_cids.push(issued);
                     }
 
                     if self.rem_cid_seq < self.first_unretired_cid {
@@ -1928,7 +2172,9 @@ where
                         return Err(TransportError::FRAME_ENCODING_ERROR("empty token"));
                     }
                     trace!("got new token");
-                    // TODO: Cache, or perhaps forward to user?
+                    if let Some((ref storage, ref server_name)) = self.token_storage {
+                        storage.put_token(server_name, token.to_vec());
+                    }
                 }
                 Frame::Datagram(datagram) => {
                     let window = match self.config.datagram_receive_buffer_size {
@@ -1952,12 +2198,63 @@ where
                     self.datagrams.recv_buffered += datagram.data.len();
                     self.datagrams.incoming.push_back(datagram);
                 }
+                Frame::AckFrequency(frame) => {
+                    let min_ack_delay = match self.config.min_ack_delay {
+                        None => {
+                            return Err(TransportError::PROTOCOL_VIOLATION(
+                                "unexpected ACK_FREQUENCY frame",
+                            ));
+                        }
+                        Some(x) => x,
+                    };
+                    if frame.packet_tolerance == 0 {
+                        return Err(TransportError::PROTOCOL_VIOLATION(
+                            "ACK_FREQUENCY with zero packet tolerance",
+                        ));
+                    }
+                    if frame.update_max_ack_delay < min_ack_delay {
+                        return Err(TransportError::PROTOCOL_VIOLATION(
+                            "ACK_FREQUENCY delay below min_ack_delay",
+                        ));
+                    }
+                    let state = &mut self.ack_frequency;
+                    if state.last_sequence.map_or(false, |x| frame.sequence <= x) {
+                        trace!(sequence = frame.sequence, "ignoring stale ACK_FREQUENCY");
+                        continue;
+                    }
+                    trace!(
+                        sequence = frame.sequence,
+                        packet_tolerance = frame.packet_tolerance,
+                        max_ack_delay = frame.update_max_ack_delay,
+                        ignore_order = frame.ignore_order,
+                        "peer updated ack frequency"
+                    );
+                    state.last_sequence = Some(frame.sequence);
+                    state.packet_tolerance = frame.packet_tolerance;
+                    state.max_ack_delay = Duration::from_micros(frame.update_max_ack_delay);
+                    state.ignore_order = frame.ignore_order;
+                }
+                Frame::ImmediateAck => {
+                    if self.config.min_ack_delay.is_none() {
+                        return Err(TransportError::PROTOCOL_VIOLATION(
+                            "unexpected IMMEDIATE_ACK frame",
+                        ));
+                    }
+                    immediate_ack = true;
+                }
             }
         }
 
-        if remote != self.path.remote
+        if ack_eliciting {
+            self.on_ack_eliciting_packet(now, immediate_ack);
+        }
+
+        // Clients only see packets from elsewhere while moving to the server's preferred address
+        if self.side.is_server()
+            && remote != self.path.remote
             && !is_probing_packet
             && number == self.space(SpaceId::Data).rx_packet
+            && self.migration_permitted(now, remote)
         {
             debug_assert!(
                 self.server_config
@@ -1966,7 +2263,9 @@ where
                     .migration,
                 "migration-initiating packets should have been dropped immediately"
             );
-            self.migrate(now, remote);
+            // Reset rtt/congestion state for new path unless it looks like a NAT rebinding.
+            let maybe_rebinding = remote.is_ipv4() && remote.ip() == self.path.remote.ip();
+            self.migrate(now, remote, maybe_rebinding);
             // Break linkability, if possible
             if let Some(cid) = self.rem_cids.pop() {
                 self.update_rem_cid(cid);
@@ -1999,10 +2298,59 @@ where
         self.path_challenge.is_some()
     }
 
-    fn migrate(&mut self, now: Instant, remote: SocketAddr) {
+    /// Whether a migration to `remote` should be followed now, updating the backoff if so
+    fn migration_permitted(&mut self, now: Instant, remote: SocketAddr) -> bool {
+        let (base, max) = match self.server_config {
+            Some(ref config) if config.migration_backoff != 0 => (
+                Duration::from_micros(config.migration_backoff),
+                Duration::from_micros(config.max_migration_backoff),
+            ),
+            _ => return true,
+        };
+        self.migration_backoff = match self.next_migration {
+            Some(t) if now < t => {
+                trace!(%remote, "ignoring migration during backoff");
+                return false;
+            }
+            // Another change soon after the last wait ended suggests the path is flapping
+            Some(t) if now < t + self.migration_backoff => {
+                cmp::max(cmp::min(self.migration_backoff * 2, max), base)
+            }
+            _ => base,
+        };
+        self.next_migration = Some(now + self.migration_backoff);
+        true
+    }
+
+    /// Switch to the server's preferred address, if it has one in a usable address family
+    ///
+    /// Where both families are usable, `TransportConfig::ip_preference` picks one. Only done once
+    /// the handshake is confirmed. Should validation fail, the connection falls back to the address
+    /// it was established on.
+    fn migrate_to_preferred_address(&mut self, now: Instant) {
+        let preferred = match self.params.preferred_address {
+            Some(x) => x,
+            None => return,
+        };
+        let remote = match preferred.select(self.path.remote, self.config.ip_preference) {
+            Some(x) => x,
+            None => {
+                debug!("ignoring preferred address of another address family");
+                return;
+            }
+        };
+        debug!(%remote, "migrating to preferred address");
+        self.migrate(now, remote, false);
+        self.update_rem_cid(IssuedCid {
+            sequence: 1,
+            id: preferred.connection_id,
+            reset_token: preferred.stateless_reset_token.into(),
+        });
+    }
+
+    /// Switch to a new path to `remote`, keeping rtt/congestion state if `maybe_rebinding`
+    fn migrate(&mut self, now: Instant, remote: SocketAddr, maybe_rebinding: bool) {
         trace!(%remote, "migration initiated");
-        // Reset rtt/congestion state for new path unless it looks like a NAT rebinding.
-        let maybe_rebinding = remote.is_ipv4() && remote.ip() == self.path.remote.ip();
         // Note that the congestion window will not grow until validation terminates. Helps mitigate
         // amplification attacks performed by spoofing source addresses.
         let new_path = PathData {
@@ -2012,16 +2360,12 @@ where
             } else {
                 RttEstimator::new()
             },
-            congestion_window: if maybe_rebinding {
-                self.path.congestion_window
-            } else {
-                self.config.initial_window
-            },
-            ssthresh: if maybe_rebinding {
-                self.path.ssthresh
+            congestion: if maybe_rebinding {
+                self.path.congestion.clone_box()
             } else {
-                u64::max_value()
+                new_controller(&self.config, now)
             },
+            pacing: congestion::Pacer::new(now),
             // Try ECN on the new path if it's probably not the same as an old broken path.
             sending_ecn: self.path.sending_ecn || !maybe_rebinding,
         };
@@ -2069,6 +2413,8 @@ where
     /// - an incoming packet is handled
     /// - the LossDetection timer expires
     pub fn poll_transmit(&mut self, now: Instant) -> Option<Transmit> {
+        let span = self.handshake_span();
+        let _guard = span.enter();
         if self.state.is_handshake()
             && !self.remote_validated
             && self.side.is_server()
@@ -2133,6 +2479,15 @@ where
                 {
                     continue;
                 }
+                if ack_eliciting && self.space(space_id).loss_probes == 0 {
+                    if let Some(rate) = self.pacing_rate() {
+                        let mtu = u64::from(self.mtu);
+                        if let Some(time) = self.path.pacing.delay(now, rate, mtu) {
+                            self.io.timer_start(TimerKind::Pacing, time);
+                            continue;
+                        }
+                    }
+                }
             }
 
             //
@@ -2156,13 +2511,15 @@ where
             let exact_number = space.get_tx_number();
             let span = trace_span!("send", space = ?space_id, pn = exact_number);
             let _guard = span.enter();
-            let number = PacketNumber::new(exact_number, space.largest_acked_packet.unwrap_or(0));
+            let number = PacketNumber::new(exact_number, space.largest_acked_packet);
             let header = match space_id {
                 SpaceId::Data if space.crypto.is_some() => Header::Short {
                     dst_cid: self.rem_cid,
                     number,
                     spin: if self.spin_enabled {
                         self.spin
+                    } else if self.config.deterministic_header_bits {
+                        false
                     } else {
                         self.rng.gen()
                     },
@@ -2216,7 +2573,7 @@ where
                 coalesce = false;
                 None
             } else {
-                Some(self.populate_packet(space_id, &mut buf))
+                Some(self.populate_packet(now, space_id, &mut buf))
             };
 
             let space = &mut self.spaces[space_id as usize];
@@ -2257,8 +2614,20 @@ where
                 buf.resize(buf.len() + padding, 0);
             }
 
+            if let Some(ref qlog) = self.qlog {
+                let payload_start = partial_encode.start + partial_encode.header_len;
+                qlog.packet_sent(
+                    now,
+                    &header,
+                    exact_number,
+                    buf.len() + crypto.packet.tag_len() - partial_encode.start,
+                    &buf[payload_start..],
+                );
+            }
+
             buf.resize(buf.len() + crypto.packet.tag_len(), 0);
             debug_assert!(buf.len() < self.mtu as usize);
+            self.sent_packets += 1;
             let packet_buf = &mut buf[partial_encode.start..];
             partial_encode.finish(
                 packet_buf,
@@ -2271,6 +2640,10 @@ where
                 // false needlessly prevents us from ACKing the next packet if it's ACK-only, but saves
                 // the need for subtler logic to avoid double-transmitting acks all the time.
                 space.permit_ack_only &= acks.is_empty();
+                if space_id == SpaceId::Data && !acks.is_empty() {
+                    self.ack_frequency.unacked = 0;
+                    self.io.timer_stop(TimerKind::MaxAckDelay);
+                }
 
                 self.on_packet_sent(
                     now,
@@ -2301,6 +2674,7 @@ where
 
         trace!("sending {} byte datagram", buf.len());
         self.total_sent = self.total_sent.wrapping_add(buf.len() as u64);
+        self.sent_datagrams += 1;
 
         Some(Transmit {
             destination: self.path.remote,
@@ -2310,10 +2684,16 @@ where
             } else {
                 None
             },
+            dscp: self.dscp,
         })
     }
 
-    fn populate_packet(&mut self, space_id: SpaceId, buf: &mut Vec<u8>) -> (Retransmits, RangeSet) {
+    fn populate_packet(
+        &mut self,
+        now: Instant,
+        space_id: SpaceId,
+        buf: &mut Vec<u8>,
+    ) -> (Retransmits, RangeSet) {
         let space = &mut self.spaces[space_id as usize];
         let mut sent = Retransmits::default();
         let zero_rtt_crypto = self.zero_rtt_crypto.as_ref();
@@ -2335,11 +2715,21 @@ where
         let is_0rtt = space_id == SpaceId::Data && space.crypto.is_none();
 
         // PING
-        if mem::replace(&mut space.ping_pending, false) {
+        sent.ping = mem::replace(&mut space.pending.ping, false);
+        if mem::replace(&mut space.ping_pending, false) || sent.ping {
             trace!("PING");
             buf.write(frame::Type::PING);
         }
 
+        // IMMEDIATE_ACK
+        if mem::replace(&mut space.immediate_ack_pending, false)
+            && space_id == SpaceId::Data
+            && self.params.min_ack_delay.is_some()
+        {
+            trace!("IMMEDIATE_ACK");
+            buf.write(frame::Type::IMMEDIATE_ACK);
+        }
+
         // ACK
         // 0-RTT packets must never carry acks (which would have to be of handshake packets)
         let acks = if !space.pending_acks.is_empty() {
@@ -2350,7 +2740,15 @@ where
             } else {
                 None
             };
-            frame::Ack::encode(0, &space.pending_acks, ecn, buf);
+            // Only application data acks may be deliberately delayed, so only their delay is
+            // meaningful to the peer
+            let delay = if space_id == SpaceId::Data {
+                let micros = (now - space.rx_packet_time).as_micros() as u64;
+                micros >> self.local_ack_delay_exponent
+            } else {
+                0
+            };
+            frame::Ack::encode(delay, &space.pending_acks, ecn, buf);
             space.pending_acks.clone()
         } else {
             RangeSet::new()
@@ -2467,6 +2865,7 @@ where
                 continue;
             }
             sent.max_stream_data.insert(id);
+            rs.bytes_read_credited = rs.bytes_read;
             let max = rs.bytes_read + self.config.stream_receive_window;
             trace!(stream = %id, max = max, "MAX_STREAM_DATA");
             buf.write(frame::Type::MAX_STREAM_DATA);
@@ -2498,6 +2897,32 @@ where
             buf.write_var(self.streams.max_remote[Dir::Bi as usize]);
         }
 
+        // ACK_FREQUENCY
+        if space.pending.ack_frequency
+            && buf.len() + frame::AckFrequency::SIZE_BOUND < max_size
+            && space_id == SpaceId::Data
+        {
+            space.pending.ack_frequency = false;
+            sent.ack_frequency = true;
+            let min_ack_delay = self.params.min_ack_delay.map_or(0, |x| x.into_inner());
+            let frame = frame::AckFrequency {
+                sequence: self.ack_frequency.next_sequence,
+                packet_tolerance: self.config.ack_packet_tolerance,
+                update_max_ack_delay: cmp::max(self.config.delayed_ack_timeout, min_ack_delay),
+                ignore_order: false,
+            };
+            trace!(
+                sequence = frame.sequence,
+                packet_tolerance = frame.packet_tolerance,
+                max_ack_delay = frame.update_max_ack_delay,
+                "ACK_FREQUENCY"
+            );
+            self.ack_frequency.next_sequence += 1;
+            self.ack_frequency.peer_max_ack_delay =
+                Some(Duration::from_micros(frame.update_max_ack_delay));
+            frame.encode(buf);
+        }
+
         // NEW_CONNECTION_ID
         while buf.len() + 44 < max_size {
             let issued = match space.pending.new_cids.pop() {
@@ -2531,6 +2956,19 @@ where
             sent.retire_cids.push(seq);
         }
 
+        // NEW_TOKEN
+        while let Some(token) = space.pending.new_tokens.pop() {
+            if buf.len() + 1 + 8 + token.len() >= max_size {
+                space.pending.new_tokens.push(token);
+                break;
+            }
+            trace!("NEW_TOKEN");
+            buf.write(frame::Type::NEW_TOKEN);
+            buf.write_var(token.len() as u64);
+            buf.extend_from_slice(&token);
+            sent.new_tokens.push(token);
+        }
+
         // DATAGRAM
         while buf.len() + Datagram::SIZE_BOUND < max_size && space_id == SpaceId::Data {
             let datagram = match self.datagrams.outgoing.pop_front() {
@@ -2552,7 +2990,7 @@ where
 
         // STREAM
         while buf.len() + frame::Stream::SIZE_BOUND < max_size {
-            let mut stream = match space.pending.stream.pop_front() {
+            let (priority, mut stream) = match space.pending.stream.pop() {
                 Some(x) => x,
                 None => break,
             };
@@ -2578,10 +3016,10 @@ where
                 data,
             };
             frame.encode(true, buf);
-            sent.stream.push_back(frame);
+            sent.stream.push_back(priority, frame);
             if !stream.data.is_empty() {
                 stream.offset += len as u64;
-                space.pending.stream.push_front(stream);
+                space.pending.stream.push_front(priority, stream);
             }
         }
 
@@ -2633,6 +3071,13 @@ where
                 "unrepresentable initial stream limit",
             ));
         }
+        if let Some(ref preferred) = params.preferred_address {
+            if preferred.connection_id.is_empty() || self.rem_cid.is_empty() {
+                return Err(TransportError::TRANSPORT_PARAMETER_ERROR(
+                    "preferred address without connection IDs",
+                ));
+            }
+        }
 
         Ok(())
     }
@@ -2677,6 +3122,29 @@ where
         self.spaces[self.highest_space as usize].ping_pending = true;
     }
 
+    /// Ping the remote endpoint and measure the round-trip time
+    ///
+    /// Like `ping`, but the PING is retransmitted if lost, and an `Event::PingAcknowledged` carrying
+    /// the time elapsed between transmission and acknowledgement is emitted once the peer
+    /// acknowledges it. Calls made before the PING is transmitted are coalesced into a single
+    /// frame.
+    pub fn probe_rtt(&mut self) {
+        let space = &mut self.spaces[self.highest_space as usize];
+        space.pending.ping = true;
+        // Don't let a delayed ack inflate the measurement
+        space.immediate_ack_pending = true;
+    }
+
+    /// Mark subsequently transmitted packets with the differentiated services code point `dscp`
+    ///
+    /// Lets networks that honor DiffServ prioritize the connection's traffic, e.g. for real-time
+    /// media. Applies to every packet sent by the connection from now on, including
+    /// retransmissions. Panics if `dscp` doesn't fit in six bits.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        assert!(dscp < 64, "DSCP values are six bits");
+        self.dscp = dscp;
+    }
+
     /// Permit an additional remote `ty` stream.
     fn alloc_remote_stream(&mut self, dir: Dir) {
         let space = &mut self.spaces[SpaceId::Data as usize];
@@ -2707,7 +3175,7 @@ where
             .send_mut(id)
             .ok_or(FinishError::UnknownStream)?;
         ss.finish()?;
-        self.spaces[SpaceId::Data as usize].finish_stream(id, ss.offset);
+        self.spaces[SpaceId::Data as usize].finish_stream(id, ss.offset, ss.priority);
         // We no longer need to notify the application of capacity for additional writes.
         self.blocked_streams.remove(&id);
         Ok(())
@@ -2727,6 +3195,21 @@ where
         }))
     }
 
+    /// Read the next contiguous segment of the given recv stream, without copying
+    ///
+    /// Yields at most `max_length` bytes, or `None` if the stream was finished. Like `read()`, data
+    /// is returned in order, but as slices of the buffers it was received in.
+    pub fn read_chunk(
+        &mut self,
+        id: StreamId,
+        max_length: usize,
+    ) -> Result<Option<Bytes>, ReadError> {
+        Ok(self.streams.read_chunk(id, max_length)?.map(|(buf, more)| {
+            self.add_read_credits(id, buf.len() as u64, more);
+            buf
+        }))
+    }
+
     /// Read from the given recv stream
     pub fn read(&mut self, id: StreamId, buf: &mut [u8]) -> Result<Option<usize>, ReadError> {
         Ok(self.streams.read(id, buf)?.map(|(len, more)| {
@@ -2736,12 +3219,95 @@ where
     }
 
     fn add_read_credits(&mut self, id: StreamId, len: u64, more: bool) {
-        self.local_max_data += len;
-        let space = &mut self.spaces[SpaceId::Data as usize];
-        space.pending.max_data = true;
-        if more {
-            // Only bother issuing stream credit if the peer wants to send more
-            space.pending.max_stream_data.insert(id);
+        self.data_consumed += len;
+        self.memory_released();
+        // Only bother issuing stream credit if the peer wants to send more
+        let threshold = self.window_update_threshold(self.config.stream_receive_window);
+        let rs = match self.streams.recv_mut(id) {
+            Some(x) if more => x,
+            _ => return,
+        };
+        if rs.bytes_read - rs.bytes_read_credited >= threshold {
+            self.spaces[SpaceId::Data as usize]
+                .pending
+                .max_stream_data
+                .insert(id);
+        }
+    }
+
+    /// Amount of a `window` that must be consumed before the peer is issued more credit
+    fn window_update_threshold(&self, window: u64) -> u64 {
+        let threshold =
+            (u128::from(window) * u128::from(self.config.window_update_threshold)) >> 16;
+        cmp::max(threshold as u64, 1)
+    }
+
+    /// Extend the peer's connection-level flow control credit, as far as memory limits permit
+    fn update_max_data(&mut self) {
+        let wanted = self.data_consumed + self.config.receive_window;
+        let max_data = cmp::min(
+            wanted,
+            self.local_max_data.saturating_add(self.unreserved_memory()),
+        );
+        // Credit held back by the memory limit is issued as soon as it's available
+        let threshold = self.window_update_threshold(self.config.receive_window);
+        if max_data > self.local_max_data
+            && (max_data - self.local_max_data >= threshold || max_data < wanted)
+        {
+            self.local_max_data = max_data;
+            self.space_mut(SpaceId::Data).pending.max_data = true;
+        } else if max_data < wanted {
+            trace!(
+                max_data,
+                "withholding flow control credit due to memory limit"
+            );
+        }
+    }
+
+    /// Resume issuing credit and accepting writes that were held back by the memory limit
+    fn memory_released(&mut self) {
+        self.update_max_data();
+        if self.config.max_connection_memory.is_some() && !self.blocked() {
+            for stream in self.blocked_streams.drain() {
+                self.events.push_back(Event::StreamWritable { stream });
+            }
+        }
+    }
+
+    /// Approximate number of bytes buffered by this connection
+    fn memory_usage(&self) -> u64 {
+        let crypto = self
+            .spaces
+            .iter()
+            .map(|x| x.crypto_stream.buffered() as u64)
+            .sum::<u64>();
+        (self.data_recvd - self.data_consumed)
+            + self.unacked_data
+            + (self.datagrams.recv_buffered + self.datagrams.outgoing_total) as u64
+            + crypto
+    }
+
+    /// Memory available under `max_connection_memory` that isn't in use or promised to the peer as
+    /// flow control credit
+    fn unreserved_memory(&self) -> u64 {
+        let max = match self.config.max_connection_memory {
+            Some(x) => x,
+            None => return u64::max_value(),
+        };
+        let credit = self.local_max_data.saturating_sub(self.data_recvd);
+        max.saturating_sub(self.memory_usage() + credit)
+    }
+
+    /// Close the connection if buffered data has grown beyond `max_connection_memory`
+    fn check_memory(&self) -> Result<(), ConnectionError> {
+        match self.config.max_connection_memory {
+            Some(max) if self.memory_usage() > max => Err(TransportError {
+                code: TransportErrorCode::INTERNAL_ERROR,
+                frame: None,
+                reason: "connection memory limit exceeded".into(),
+            }
+            .into()),
+            _ => Ok(()),
         }
     }
 
@@ -2767,13 +3333,22 @@ where
     }
 
     fn congestion_blocked(&self) -> bool {
-        self.in_flight.bytes + u64::from(self.mtu) >= self.path.congestion_window
+        self.in_flight.bytes + u64::from(self.mtu) >= self.path.congestion.window()
+    }
+
+    /// Rate at which to pace packets, in bytes per second, once it can be determined
+    fn pacing_rate(&self) -> Option<u64> {
+        self.path.congestion.pacing_rate().or_else(|| {
+            let rtt = self.path.rtt.smoothed?;
+            congestion::window_rate(self.path.congestion.window(), rtt)
+        })
     }
 
     fn blocked(&self) -> bool {
         self.data_sent >= self.max_data
             || self.congestion_blocked()
             || self.unacked_data >= self.config.send_window
+            || self.unreserved_memory() == 0
     }
 
     fn decrypt_packet(
@@ -2855,15 +3430,118 @@ where
         Ok(Some(number))
     }
 
+    /// Notify the connection that it's now sending from a different local address
+    ///
+    /// Validates the new path, on which congestion control and RTT estimation start over, and
+    /// switches to a connection ID the peer issued but that wasn't used yet, if any, so that
+    /// observers can't link the old and new paths. Has no effect while handshaking, as migration
+    /// is only permitted once the handshake completes.
+    pub fn local_address_changed(&mut self, now: Instant) {
+        if self.state.is_handshake() || self.state.is_closed() {
+            return;
+        }
+        debug!("local address changed");
+        let remote = self.path.remote;
+        self.migrate(now, remote, false);
+        if let Some(cid) = self.rem_cids.pop() {
+            self.update_rem_cid(cid);
+        }
+    }
+
     #[doc(hidden)]
     pub fn initiate_key_update(&mut self) {
         self.update_keys(None, false);
     }
 
+    /// Set the priority of a send stream
+    ///
+    /// When congestion control or packet size limits what can be sent, pending data of streams
+    /// with higher priority is transmitted first. Streams of equal priority share the link in the
+    /// order their data was written, so concurrent writers interleave at the granularity of their
+    /// writes rather than strictly round-robin. Defaults to 0.
+    pub fn set_priority(&mut self, id: StreamId, priority: i32) -> Result<(), UnknownStream> {
+        self.streams.set_priority(id, priority)?;
+        self.space_mut(SpaceId::Data)
+            .pending
+            .stream
+            .set_priority(id, priority);
+        Ok(())
+    }
+
+    /// Number of streams in `dir` the peer may open, counting those it already did
+    pub fn remote_stream_limit(&self, dir: Dir) -> u64 {
+        self.streams.max_remote[dir as usize]
+    }
+
+    /// Priority of a send stream, as set by `set_priority`
+    pub fn priority(&self, id: StreamId) -> Result<i32, UnknownStream> {
+        self.streams
+            .send(id)
+            .map(|ss| ss.priority)
+            .ok_or(UnknownStream { _private: () })
+    }
+
+    /// Bytes that may be written to `stream` before reaching the flow control limit set by the peer
+    ///
+    /// Connection-level flow control and congestion control may hold writes back sooner. Once the
+    /// credit is exhausted, `Event::StreamWritable` is emitted when the peer grants more.
+    pub fn write_credit(&self, stream: StreamId) -> Result<u64, WriteError> {
+        self.streams
+            .send(stream)
+            .ok_or(WriteError::UnknownStream)?
+            .credit()
+    }
+
     /// Send data on the given stream
     ///
     /// Returns the number of bytes successfully written.
     pub fn write(&mut self, stream: StreamId, data: &[u8]) -> Result<usize, WriteError> {
+        let n = self.write_limit(stream)?.min(data.len() as u64) as usize;
+        self.queue_stream_data(stream, Bytes::copy_from_slice(&data[0..n]))?;
+        trace!(%stream, "wrote {} bytes", n);
+        Ok(n)
+    }
+
+    /// Send owned chunks of data on the given stream, without copying
+    ///
+    /// Chunks are written in order until flow or congestion control intervene. The prefix of a
+    /// chunk that could only be written in part is split off, leaving the remainder in `data`.
+    pub fn write_chunks(
+        &mut self,
+        stream: StreamId,
+        data: &mut [Bytes],
+    ) -> Result<Written, WriteError> {
+        let mut limit = self.write_limit(stream)?;
+        let mut written = Written::default();
+        for chunk in data.iter_mut() {
+            if chunk.is_empty() {
+                written.chunks += 1;
+                continue;
+            }
+            if limit == 0 {
+                break;
+            }
+            let n = limit.min(chunk.len() as u64) as usize;
+            let partial = n < chunk.len();
+            let bytes = if partial {
+                chunk.split_to(n)
+            } else {
+                mem::replace(chunk, Bytes::new())
+            };
+            self.queue_stream_data(stream, bytes)?;
+            written.bytes += n;
+            if partial {
+                break;
+            }
+            written.chunks += 1;
+            limit -= n as u64;
+        }
+        trace!(%stream, "wrote {} bytes", written.bytes);
+        Ok(written)
+    }
+
+    /// Number of bytes that may currently be written to `stream`
+    fn write_limit(&mut self, stream: StreamId) -> Result<u64, WriteError> {
         assert!(stream.dir() == Dir::Bi || stream.initiator() == self.side);
         if self.state.is_closed() {
             trace!(%stream, "write blocked; connection draining");
@@ -2873,6 +3551,8 @@ where
         if self.blocked() {
             if self.congestion_blocked() {
                 trace!(%stream, "write blocked by congestion");
+            } else if self.unreserved_memory() == 0 {
+                trace!(%stream, "write blocked by memory limit");
             } else {
                 trace!(%stream, "write blocked by connection-level flow control");
             }
@@ -2900,13 +3580,13 @@ where
         };
 
         let conn_budget = cmp::min(
-            self.max_data - self.data_sent,
-            self.config.send_window - self.unacked_data,
+            cmp::min(
+                self.max_data - self.data_sent,
+                self.config.send_window - self.unacked_data,
+            ),
+            self.unreserved_memory(),
         );
-        let n = conn_budget.min(stream_budget).min(data.len() as u64) as usize;
-        self.queue_stream_data(stream, Bytes::copy_from_slice(&data[0..n]))?;
-        trace!(%stream, "wrote {} bytes", n);
-        Ok(n)
+        Ok(conn_budget.min(stream_budget))
     }
 
     /// Prepare to transmit an unreliable, unordered datagram
@@ -2926,7 +3606,9 @@ where
         let max = self
             .max_datagram_size()
             .ok_or(SendDatagramError::UnsupportedByPeer)?;
-        if self.datagrams.outgoing_total >= self.config.datagram_send_buffer_size {
+        if self.datagrams.outgoing_total >= self.config.datagram_send_buffer_size
+            || self.unreserved_memory() < max as u64
+        {
             return Err(SendDatagramError::Blocked);
         }
         Ok(DatagramSender { max, conn: self })
@@ -2936,6 +3618,7 @@ where
     pub fn recv_datagram(&mut self) -> Option<Bytes> {
         let x = self.datagrams.incoming.pop_front()?.data;
         self.datagrams.recv_buffered -= x.len();
+        self.memory_released();
         Some(x)
     }
 
@@ -2980,6 +3663,32 @@ where
             update_unacked: remote,
         });
         self.key_phase = !self.key_phase;
+        self.key_updates += 1;
+        debug!(key_phase = self.key_phase, remote, "1-RTT keys updated");
+    }
+
+    /// The span to enter while processing, which is disabled once the handshake is over
+    fn handshake_span(&self) -> Span {
+        self.handshake_span.clone().unwrap_or_else(Span::none)
+    }
+
+    /// Record the outcome of the handshake and close its span
+    fn finish_handshake_span(&mut self) {
+        let span = match self.handshake_span.take() {
+            Some(span) => span,
+            None => return,
+        };
+        if !span.is_disabled() {
+            if let Some(alpn) = self.protocol() {
+                span.record("alpn", field::display(String::from_utf8_lossy(alpn)));
+            }
+            if let Some(suite) = self.tls.cipher_suite() {
+                span.record("cipher_suite", &suite);
+            }
+            span.record("zero_rtt", field::debug(self.early_data));
+            span.record("retry", self.orig_rem_cid.is_some());
+        }
+        debug!(parent: &span, "established");
     }
 
     /// If the connection is currently handshaking
@@ -3009,6 +3718,92 @@ where
         self.zero_rtt_enabled
     }
 
+    /// Whether 0-RTT was attempted on this connection, and if not, why
+    pub fn early_data_status(&self) -> EarlyDataStatus {
+        self.early_data
+    }
+
+    /// Quantity of crypto layer data received from the peer in each packet number space
+    pub fn crypto_stats(&self) -> CryptoStats {
+        CryptoStats {
+            initial: self.spaces[SpaceId::Initial as usize].crypto_received,
+            handshake: self.spaces[SpaceId::Handshake as usize].crypto_received,
+            data: self.spaces[SpaceId::Data as usize].crypto_received,
+        }
+    }
+
+    /// Current congestion control and round-trip time state of the connection's path
+    pub fn path_stats(&self) -> PathStats {
+        let rtt = &self.path.rtt;
+        PathStats {
+            congestion_window: self.path.congestion.window(),
+            ssthresh: self.path.congestion.ssthresh(),
+            pacing_rate: self.pacing_rate(),
+            bytes_in_flight: self.in_flight.bytes,
+            smoothed_rtt: rtt.smoothed,
+            rtt_var: rtt.var,
+            min_rtt: rtt.smoothed.map(|_| rtt.min),
+            latest_rtt: rtt.smoothed.map(|_| rtt.latest),
+            lost_packets: self.lost_packets,
+            mtu: self.mtu,
+        }
+    }
+
+    /// Totals of the traffic exchanged over the connection so far, along with `path_stats`
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            path: self.path_stats(),
+            sent_datagrams: self.sent_datagrams,
+            sent_packets: self.sent_packets,
+            sent_bytes: self.total_sent,
+            received_datagrams: self.received_datagrams,
+            received_packets: self.received_packets,
+            received_bytes: self.total_recvd,
+            lost_bytes: self.lost_bytes,
+            key_updates: self.key_updates,
+            congestion_experienced: self.congestion_experienced,
+        }
+    }
+
+    /// Snapshot of the connection's streams, for diagnostics
+    ///
+    /// Lists every stream which was opened and hasn't been fully closed yet, in order of ID.
+    pub fn streams(&self) -> Vec<StreamInfo> {
+        self.streams.info(
+            self.side,
+            self.config.stream_receive_window,
+            &self.blocked_streams,
+        )
+    }
+
+    /// Record that the client configuration doesn't permit 0-RTT
+    pub(crate) fn early_data_disabled(&mut self) {
+        debug_assert!(!self.zero_rtt_enabled);
+        self.early_data = EarlyDataStatus::NotAttempted;
+    }
+
+    /// Keep tokens from NEW_TOKEN frames in `storage`, and present one kept by an earlier connection
+    pub(crate) fn set_token_storage(
+        &mut self,
+        storage: Arc<dyn crypto::SessionStorage>,
+        server_name: String,
+    ) {
+        if let State::Handshake(ref mut state) = self.state {
+            state.token = storage.take_token(&server_name).map(Bytes::from);
+        }
+        self.token_storage = Some((storage, server_name));
+    }
+
+    /// Record that the client presented a valid token from a NEW_TOKEN frame
+    pub(crate) fn address_validated(&mut self) {
+        self.remote_validated = true;
+    }
+
+    /// Send the client `token` for use in future connections once the handshake completes
+    pub(crate) fn issue_token(&mut self, token: Bytes) {
+        self.new_token = Some(token);
+    }
+
     /// Look up whether we're the client or server of this Connection
     pub fn side(&self) -> Side {
         self.side
@@ -3025,8 +3820,25 @@ where
     }
 
     /// The ALPN protocol negotiated during this connection's handshake
+    ///
+    /// For servers, this is `ServerConfig::default_protocol` if none was negotiated.
     pub fn protocol(&self) -> Option<&[u8]> {
-        self.tls.alpn_protocol()
+        self.tls.alpn_protocol().or_else(|| {
+            let config = self.server_config.as_ref()?;
+            config.default_protocol.as_ref().map(|x| &x[..])
+        })
+    }
+
+    /// The cryptographic session securing this connection
+    pub fn crypto_session(&self) -> &S {
+        &self.tls
+    }
+
+    /// The QUIC version in use
+    ///
+    /// Only a single draft version is implemented, so this never varies between connections.
+    pub fn version(&self) -> u32 {
+        VERSION
     }
 
     /// The number of bytes of packets containing retransmittable frames that have not been
@@ -3040,7 +3852,8 @@ where
     #[cfg(test)]
     pub(crate) fn congestion_state(&self) -> u64 {
         self.path
-            .congestion_window
+            .congestion
+            .window()
             .saturating_sub(self.in_flight.bytes)
     }
 
@@ -3051,12 +3864,38 @@ where
         self.tls.sni_hostname()
     }
 
+    /// Whether a Retry packet was sent or received during the handshake
+    #[cfg(test)]
+    pub(crate) fn retried(&self) -> bool {
+        self.orig_rem_cid.is_some()
+    }
+
     /// Total number of outgoing packets that have been deemed lost
     #[cfg(test)]
     pub(crate) fn lost_packets(&self) -> u64 {
         self.lost_packets
     }
 
+    /// Queue a STREAM frame regardless of the state of its stream, as a misbehaving peer might
+    #[cfg(test)]
+    pub(crate) fn inject_stream_frame(&mut self, frame: frame::Stream) {
+        let len = frame.data.len() as u64;
+        self.unacked_data += len;
+        if let Some(ss) = self.streams.send_mut(frame.id) {
+            ss.bytes_in_flight += len;
+        }
+        self.space_mut(SpaceId::Data)
+            .pending
+            .stream
+            .push_back(0, frame);
+    }
+
+    /// Whether the current path was validated, i.e. no migration is in progress
+    #[cfg(test)]
+    pub(crate) fn is_path_validated(&self) -> bool {
+        !self.migrating()
+    }
+
     /// Whether explicit congestion notification is in use on outgoing packets.
     #[cfg(test)]
     pub(crate) fn using_ecn(&self) -> bool {
@@ -3064,7 +3903,36 @@ where
     }
 
     fn max_ack_delay(&self) -> Duration {
-        Duration::from_micros(self.params.max_ack_delay * 1000)
+        let advertised = Duration::from_micros(self.params.max_ack_delay * 1000);
+        self.ack_frequency
+            .peer_max_ack_delay
+            .map_or(advertised, |x| cmp::max(x, advertised))
+    }
+
+    /// Ask the peer to acknowledge packets less often, if configured to and the peer supports it
+    fn request_ack_frequency(&mut self) {
+        if self.params.min_ack_delay.is_none() || self.config.ack_packet_tolerance <= 1 {
+            return;
+        }
+        self.space_mut(SpaceId::Data).pending.ack_frequency = true;
+    }
+
+    /// Decide whether an ack-eliciting application data packet must be acknowledged immediately,
+    /// or whether the ack may be delayed as requested by the peer's ACK_FREQUENCY frames
+    fn on_ack_eliciting_packet(&mut self, now: Instant, immediate: bool) {
+        let state = &mut self.ack_frequency;
+        state.unacked += 1;
+        if immediate
+            || state.unacked >= state.packet_tolerance
+            || (state.reordered && !state.ignore_order)
+        {
+            self.space_mut(SpaceId::Data).permit_ack_only = true;
+            return;
+        }
+        if state.unacked == 1 {
+            self.io
+                .timer_start(TimerKind::MaxAckDelay, now + state.max_ack_delay);
+        }
     }
 
     fn space(&self, id: SpaceId) -> &PacketSpace<S::Keys> {
@@ -3089,6 +3957,9 @@ where
         debug_assert!(self.side.is_client());
         debug!("0-RTT rejected");
         self.accepted_0rtt = false;
+        self.early_data = EarlyDataStatus::Attempted {
+            accepted: Some(false),
+        };
         self.streams.zero_rtt_rejected(self.side);
         // Discard already-queued frames
         self.space_mut(SpaceId::Data).pending = Retransmits::default();
@@ -3149,6 +4020,111 @@ where
     buf.into()
 }
 
+/// Whether a connection used 0-RTT, as reported by `Connection::early_data_status`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EarlyDataStatus {
+    /// 0-RTT was not attempted
+    ///
+    /// Either the client's configuration doesn't enable early data, or this is a server
+    /// connection whose client didn't offer any.
+    NotAttempted,
+    /// The client wanted to send 0-RTT data but had no usable session ticket for the server
+    ///
+    /// The TLS session cache doesn't distinguish a server that was never contacted from one whose
+    /// ticket expired, was evicted, or didn't permit early data; all of these are reported here.
+    NoTicket,
+    /// 0-RTT keys were derived from a session ticket
+    Attempted {
+        /// Whether the server accepted the 0-RTT data, or `None` until the client learns this
+        /// at handshake completion
+        ///
+        /// Data sent in rejected 0-RTT packets is retransmitted automatically, but streams opened
+        /// in the meantime are reset.
+        accepted: Option<bool>,
+    },
+}
+
+/// Congestion control and round-trip time state of a path, as reported by
+/// `Connection::path_stats`
+///
+/// Unlike totals accumulated over the connection's lifetime, these values follow the congestion
+/// controller as it runs, so applications can adapt the rate at which they produce data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PathStats {
+    /// Maximum number of bytes which may be in flight
+    pub congestion_window: u64,
+    /// Slow start threshold, below which the congestion window grows exponentially
+    ///
+    /// `u64::max_value()` until the first congestion event, or if the congestion controller doesn't
+    /// use one.
+    pub ssthresh: u64,
+    /// Rate at which packets are paced, in bytes per second, once the round-trip time is known
+    ///
+    /// Reported by the congestion controller, or derived from its window otherwise.
+    pub pacing_rate: Option<u64>,
+    /// Bytes sent but neither acknowledged nor deemed lost
+    pub bytes_in_flight: u64,
+    /// Smoothed round-trip time, computed as described in RFC6298, once a sample was taken
+    pub smoothed_rtt: Option<Duration>,
+    /// Variation of the round-trip time
+    pub rtt_var: Duration,
+    /// Smallest round-trip time sample, ignoring ack delay
+    pub min_rtt: Option<Duration>,
+    /// Most recent round-trip time sample
+    pub latest_rtt: Option<Duration>,
+    /// Total number of packets deemed lost
+    pub lost_packets: u64,
+    /// Maximum size of the UDP payloads sent
+    pub mtu: u16,
+}
+
+/// Statistics about a connection, as reported by `Connection::stats`
+///
+/// Totals cover the connection's lifetime. Byte counts are of UDP payloads, so include packet
+/// headers, authentication tags and padding.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Current state of the path, as reported by `Connection::path_stats`
+    pub path: PathStats,
+    /// UDP datagrams sent
+    pub sent_datagrams: u64,
+    /// QUIC packets sent, several of which may be coalesced into a datagram during the handshake
+    pub sent_packets: u64,
+    /// Bytes sent
+    pub sent_bytes: u64,
+    /// UDP datagrams received
+    pub received_datagrams: u64,
+    /// QUIC packets received and authenticated, excluding duplicates
+    pub received_packets: u64,
+    /// Bytes received, including packets that couldn't be authenticated
+    pub received_bytes: u64,
+    /// Size of the packets deemed lost, of which there are `path.lost_packets`
+    ///
+    /// Unpadded packets carrying only acknowledgements count for nothing, as they aren't tracked
+    /// for congestion control.
+    pub lost_bytes: u64,
+    /// Number of times the 1-RTT keys were updated, at the initiative of either side
+    pub key_updates: u64,
+    /// Sent packets which the peer reported as marked congestion experienced (ECN-CE) by the
+    /// network
+    pub congestion_experienced: u64,
+}
+
+/// Quantity of crypto layer data received from the peer, as reported by
+/// `Connection::crypto_stats`
+///
+/// Each value is the highest offset of CRYPTO frame data seen in its packet number space, which is
+/// what `TransportConfig::max_handshake_data` limits.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CryptoStats {
+    /// Data received in Initial packets, i.e. the ClientHello or ServerHello
+    pub initial: u64,
+    /// Data received in Handshake packets, including the peer's certificate chain
+    pub handshake: u64,
+    /// Data received after the handshake, such as session tickets
+    pub data: u64,
+}
+
 /// Reasons why a connection might be lost.
 #[derive(Debug, Error, Clone, PartialEq, Eq)]
 pub enum ConnectionError {
@@ -3262,7 +4238,8 @@ mod state {
         ///
         /// Always set for servers
         pub rem_cid_set: bool,
-        /// Stateless retry token, if the peer has provided one
+        /// Address validation token, from a Retry or a NEW_TOKEN frame received by an earlier
+        /// connection
         ///
         /// Only set for clients
         pub token: Option<Bytes>,
@@ -3472,6 +4449,21 @@ pub enum Event {
     ///
     /// Emitted after `send_datagram` returns `Err(SendDatagramError::Blocked)`
     DatagramSendUnblocked,
+    /// A PING requested with `probe_rtt` was acknowledged by the peer
+    PingAcknowledged {
+        /// Time elapsed between transmission of the PING and receipt of its acknowledgement
+        rtt: Duration,
+    },
+    /// The peer was observed at a new address, which has since been validated
+    ///
+    /// Only emitted by servers, which are the only side to follow a peer to a new address, e.g.
+    /// after a NAT rebinding.
+    RemoteAddressChanged {
+        /// The last validated address of the peer
+        old: SocketAddr,
+        /// The address now in use
+        new: SocketAddr,
+    },
 }
 
 impl From<ConnectionError> for Event {
@@ -3480,6 +4472,16 @@ impl From<ConnectionError> for Event {
     }
 }
 
+/// Connection IDs a connection is established with
+pub(crate) struct InitialCids {
+    /// Destination CID of the client's first Initial packet
+    pub init: ConnectionId,
+    /// CID the peer addresses us by
+    pub local: ConnectionId,
+    /// CID we address the peer by
+    pub remote: ConnectionId,
+}
+
 struct PathResponse {
     /// The packet number the corresponding PATH_CHALLENGE was received in
     packet: u64,
@@ -3494,6 +4496,43 @@ fn instant_saturating_sub(x: Instant, y: Instant) -> Duration {
     }
 }
 
+/// Reject clients with which ALPN negotiated no protocol, if the server requires one
+fn check_alpn<S: crypto::Session>(tls: &S, config: &ServerConfig<S>) -> Result<(), TransportError> {
+    if !config.require_protocol
+        || config.default_protocol.is_some()
+        || tls.alpn_protocol().is_some()
+    {
+        return Ok(());
+    }
+    let reason = match tls.offered_protocols() {
+        None => "no application protocol negotiated".into(),
+        Some(offered) if offered.is_empty() => {
+            "no application protocol negotiated, client offered none".into()
+        }
+        Some(offered) => format!(
+            "no application protocol negotiated, client offered {}",
+            offered
+                .iter()
+                .map(|x| String::from_utf8_lossy(x))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    Err(TransportError {
+        code: TransportErrorCode::NO_APPLICATION_PROTOCOL,
+        frame: None,
+        reason,
+    })
+}
+
+/// The congestion controller configured for a new path
+fn new_controller(config: &TransportConfig, now: Instant) -> Box<dyn congestion::Controller> {
+    match config.congestion_controller_factory {
+        Some(ref factory) => factory.build(now),
+        None => Box::new(congestion::NewReno::new(config, now)),
+    }
+}
+
 // Prevents overflow and improves behavior in extreme circumstances
 const MAX_BACKOFF_EXPONENT: u32 = 16;
 // Minimal remaining size to allow packet coalescing
@@ -3503,11 +4542,10 @@ const MIN_PACKET_SPACE: usize = 40;
 struct PathData {
     remote: SocketAddr,
     rtt: RttEstimator,
-    /// Maximum number of bytes in flight that may be sent.
-    congestion_window: u64,
-    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is
-    /// slow start and the window grows by the number of bytes acknowledged.
-    ssthresh: u64,
+    /// Decides how many bytes may be in flight
+    congestion: Box<dyn congestion::Controller>,
+    /// Delays packets to keep to the controller's pacing rate, if any
+    pacing: congestion::Pacer,
     /// Whether we're enabling ECN on outgoing packets
     sending_ecn: bool,
 }
@@ -3571,3 +4609,38 @@ impl DatagramState {
         }
     }
 }
+
+struct AckFrequencyState {
+    /// Sequence number of the next ACK_FREQUENCY frame we send
+    next_sequence: u64,
+    /// Longest the peer has been asked to delay its acks, if we've sent it an ACK_FREQUENCY frame
+    peer_max_ack_delay: Option<Duration>,
+    /// Sequence number of the most recent ACK_FREQUENCY frame received from the peer
+    last_sequence: Option<u64>,
+    /// Number of ack-eliciting packets we may receive before acknowledging them
+    packet_tolerance: u64,
+    /// Longest we may delay acknowledging an ack-eliciting packet
+    max_ack_delay: Duration,
+    /// Whether out-of-order packets may be acknowledged like any other
+    ignore_order: bool,
+    /// Number of ack-eliciting packets received since we last sent an ACK
+    unacked: u64,
+    /// Whether the most recently authenticated application data packet arrived out of order
+    reordered: bool,
+}
+
+impl AckFrequencyState {
+    fn new() -> Self {
+        Self {
+            next_sequence: 0,
+            peer_max_ack_delay: None,
+            last_sequence: None,
+            // Until the peer says otherwise, every ack-eliciting packet is acknowledged promptly
+            packet_tolerance: 1,
+            max_ack_delay: Duration::from_micros(0),
+            ignore_order: false,
+            unacked: 0,
+            reordered: false,
+        }
+    }
+}
@@ -8,7 +8,12 @@
 //! Note that usage of any protocol (version) other than TLS 1.3 does not conform to any
 //! published versions of the specification, and will not be supported in QUIC v1.
 
-use std::str;
+use std::{
+    collections::{HashSet, VecDeque},
+    str,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use bytes::BytesMut;
 
@@ -42,6 +47,22 @@ pub trait Session: Sized {
     /// has been negotiated.
     fn alpn_protocol(&self) -> Option<&[u8]>;
 
+    /// Get the name of the negotiated cipher suite
+    ///
+    /// Returns `None` if the handshake has not advanced sufficiently, or if the implementation
+    /// can't tell.
+    fn cipher_suite(&self) -> Option<String> {
+        None
+    }
+
+    /// Get the application protocols the client offered through ALPN (servers only)
+    ///
+    /// Returns `None` if the client's first handshake message has not been read yet, or if the
+    /// implementation can't tell.
+    fn offered_protocols(&self) -> Option<&[Vec<u8>]> {
+        None
+    }
+
     /// Get the 0-RTT keys if available (clients only)
     ///
     /// On the client side, this method can be used to see if 0-RTT key material is available
@@ -64,6 +85,21 @@ pub trait Session: Sized {
     /// to send to the peer.
     fn read_handshake(&mut self, buf: &[u8]) -> Result<(), TransportError>;
 
+    /// Refuse the client's 0-RTT data in some circumstances (servers only)
+    ///
+    /// Early data is refused if `anti_replay` has seen the random value of the client's first
+    /// handshake message before, or if the negotiated ALPN protocol is one of `refused_protocols`.
+    /// `anti_replay` is consulted as of `now`, the time the connection was accepted. The handshake
+    /// proceeds regardless, the client retransmitting its early data in 1-RTT packets. Must be
+    /// called before any handshake data is read. By default, early data is never refused.
+    fn set_early_data_policy(
+        &mut self,
+        _anti_replay: Option<Arc<dyn AntiReplay>>,
+        _refused_protocols: Vec<Vec<u8>>,
+        _now: Instant,
+    ) {
+    }
+
     /// The SNI hostname sent by the client (server only)
     fn sni_hostname(&self) -> Option<&str>;
 
@@ -98,6 +134,16 @@ where
         server_name: &str,
         params: &TransportParameters,
     ) -> Result<S, ConnectError>;
+
+    /// Whether sessions started with this configuration may send 0-RTT data
+    fn early_data_enabled(&self) -> bool {
+        true
+    }
+
+    /// Check that this configuration is permissible for QUIC
+    fn validate(&self) -> Result<(), ConfigError> {
+        Ok(())
+    }
 }
 
 /// Server-side configuration for the crypto protocol
@@ -112,15 +158,164 @@ where
 
     /// Start a server session with this configuration
     fn start_session(&self, params: &TransportParameters) -> S;
+
+    /// Check that this configuration is permissible for QUIC
+    fn validate(&self) -> Result<(), ConfigError> {
+        Ok(())
+    }
+}
+
+/// Cache of the handshakes that offered 0-RTT data, used by servers to detect replays
+///
+/// 0-RTT data has no protection against replay: an attacker can capture a client's first flight
+/// and send it again, to the same server or another sharing its session ticket keys. Servers given
+/// an `AntiReplay` refuse early data from handshakes it has seen before, so the data is only
+/// processed once it is retransmitted under 1-RTT keys. Sharing the cache between servers that
+/// accept each other's tickets extends the protection across them.
+pub trait AntiReplay: Send + Sync {
+    /// Record the random value of a client's first handshake message, received at `now`
+    ///
+    /// Returns whether the value is fresh, i.e. early data offered with it may be accepted.
+    fn check(&self, random: &[u8; 32], now: Instant) -> bool;
+}
+
+/// An in-memory `AntiReplay` remembering handshakes for a fixed time window
+///
+/// Replays arriving after their original has been forgotten aren't detected, so the window should
+/// cover the lifetime of the session tickets 0-RTT data is sent with. While `capacity` handshakes
+/// are remembered, early data is refused rather than forgetting any of them early.
+pub struct StrikeRegister {
+    window: Duration,
+    capacity: usize,
+    strikes: Mutex<Strikes>,
+}
+
+#[derive(Default)]
+struct Strikes {
+    seen: HashSet<[u8; 32]>,
+    /// `seen`, in the order the values were recorded
+    expiry: VecDeque<(Instant, [u8; 32])>,
+}
+
+impl StrikeRegister {
+    /// Remember up to `capacity` handshakes for `window` each
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            strikes: Mutex::new(Strikes::default()),
+        }
+    }
+}
+
+impl AntiReplay for StrikeRegister {
+    fn check(&self, random: &[u8; 32], now: Instant) -> bool {
+        let strikes = &mut *self.strikes.lock().unwrap();
+        while let Some(&(time, ref old)) = strikes.expiry.front() {
+            if now < time + self.window {
+                break;
+            }
+            strikes.seen.remove(old);
+            strikes.expiry.pop_front();
+        }
+        if strikes.seen.len() >= self.capacity || !strikes.seen.insert(*random) {
+            return false;
+        }
+        strikes.expiry.push_back((now, *random));
+        true
+    }
 }
 
+/// Storage for the session tickets clients resume TLS sessions, and send 0-RTT data, with
+///
+/// Tickets are kept by the name of the server they were received from, as opaque values holding
+/// everything needed to resume. Storing them on disk lets sessions be resumed after the process
+/// restarts; they must then be protected like other secrets.
+pub trait SessionStorage: Send + Sync {
+    /// Remember `value` for `server_name`, replacing any previous value
+    fn put(&self, server_name: &str, value: Vec<u8>);
+    /// The value last put for `server_name`, if any
+    fn get(&self, server_name: &str) -> Option<Vec<u8>>;
+
+    /// Remember an address validation token sent by `server_name` in a NEW_TOKEN frame
+    ///
+    /// Presenting the token when connecting again saves the server validating the client's address
+    /// with a stateless retry. Tokens aren't secret, but each should only be used once, so that
+    /// connections can't be linked through them. By default, tokens are discarded.
+    fn put_token(&self, _server_name: &str, _token: Vec<u8>) {}
+    /// Remove and return a token put for `server_name`, if any
+    fn take_token(&self, _server_name: &str) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// A `SessionStorage` keeping tickets in memory, evicting the least recently used beyond a limit
+///
+/// The latest address validation token of each server is kept along with its tickets.
+pub struct MemorySessionStorage {
+    /// Least recently used first
+    entries: Mutex<VecDeque<(String, Vec<u8>)>>,
+    tokens: Mutex<VecDeque<(String, Vec<u8>)>>,
+    capacity: usize,
+}
+
+impl MemorySessionStorage {
+    /// Keep tickets for up to `capacity` servers
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            tokens: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    fn insert(&self, entries: &Mutex<VecDeque<(String, Vec<u8>)>>, name: &str, value: Vec<u8>) {
+        let mut entries = entries.lock().unwrap();
+        if let Some(i) = entries.iter().position(|x| x.0 == name) {
+            entries.remove(i);
+        }
+        entries.push_back((name.into(), value));
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+}
+
+impl SessionStorage for MemorySessionStorage {
+    fn put(&self, server_name: &str, value: Vec<u8>) {
+        self.insert(&self.entries, server_name, value);
+    }
+
+    fn get(&self, server_name: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let i = entries.iter().position(|x| x.0 == server_name)?;
+        let entry = entries.remove(i).unwrap();
+        let value = entry.1.clone();
+        entries.push_back(entry);
+        Some(value)
+    }
+
+    fn put_token(&self, server_name: &str, token: Vec<u8>) {
+        self.insert(&self.tokens, server_name, token);
+    }
+
+    fn take_token(&self, server_name: &str) -> Option<Vec<u8>> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let i = tokens.iter().position(|x| x.0 == server_name)?;
+        tokens.remove(i).map(|x| x.1)
+    }
+}
+
+/// Servers whose tickets and tokens clients remember by default
+pub(crate) const DEFAULT_SESSION_CAPACITY: usize = 32;
+
 /// Keys used to protect packet payloads
 pub trait Keys {
     /// Type used for header protection keys
     type HeaderKeys: HeaderKeys + Sized;
 
-    /// Create the initial set of keys given the initial ConnectionId
-    fn new_initial(id: &ConnectionId, side: Side) -> Self;
+    /// Create the initial set of keys given the initial ConnectionId and QUIC version
+    fn new_initial(id: &ConnectionId, side: Side, version: u32) -> Self;
     /// Encrypt the packet payload with the given packet number
     fn encrypt(&self, packet: u64, buf: &mut [u8], header_len: usize);
     /// Decrypt the packet payload with the given packet number
@@ -155,3 +350,46 @@ pub trait HmacKey: Sized {
     /// Method for verifying a message
     fn verify(&self, data: &[u8], signature: &[u8]) -> Result<(), ()>;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn strike_register() {
+        let register = StrikeRegister::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        assert!(register.check(&[1; 32], now));
+        assert!(!register.check(&[1; 32], now + Duration::from_secs(9)));
+        assert!(register.check(&[2; 32], now + Duration::from_secs(5)));
+        // Full
+        assert!(!register.check(&[3; 32], now + Duration::from_secs(5)));
+        // The first value expires, making room
+        assert!(register.check(&[3; 32], now + Duration::from_secs(10)));
+        assert!(register.check(&[1; 32], now + Duration::from_secs(16)));
+        assert!(!register.check(&[3; 32], now + Duration::from_secs(16)));
+    }
+
+    #[test]
+    fn memory_session_storage_evicts_lru() {
+        let storage = MemorySessionStorage::new(2);
+        storage.put("a", vec![1]);
+        storage.put("b", vec![2]);
+        assert_eq!(storage.get("a"), Some(vec![1]));
+        storage.put("c", vec![3]);
+        assert_eq!(storage.get("b"), None);
+        assert_eq!(storage.get("a"), Some(vec![1]));
+        storage.put("c", vec![4]);
+        assert_eq!(storage.get("c"), Some(vec![4]));
+    }
+
+    #[test]
+    fn memory_session_storage_tokens() {
+        let storage = MemorySessionStorage::new(2);
+        storage.put("a", vec![1]);
+        storage.put_token("a", vec![2]);
+        assert_eq!(storage.get("a"), Some(vec![1]));
+        assert_eq!(storage.take_token("a"), Some(vec![2]));
+        assert_eq!(storage.take_token("a"), None);
+    }
+}
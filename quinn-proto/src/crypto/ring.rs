@@ -90,11 +90,11 @@ impl From<hkdf::Okm<'_, IvLen>> for Iv {
 impl crypto::Keys for Crypto {
     type HeaderKeys = RingHeaderCrypto;
 
-    fn new_initial(id: &ConnectionId, side: Side) -> Self {
+    fn new_initial(id: &ConnectionId, side: Side, version: u32) -> Self {
         let cipher = &aead::AES_128_GCM;
         const CLIENT_LABEL: &[u8] = b"client in";
         const SERVER_LABEL: &[u8] = b"server in";
-        let hs_secret = initial_secret(id);
+        let hs_secret = initial_secret(id, version);
 
         let client_secret = expanded_initial_secret(&hs_secret, CLIENT_LABEL);
         let server_secret = expanded_initial_secret(&hs_secret, SERVER_LABEL);
@@ -245,11 +245,25 @@ where
     key.expand(&info, len).unwrap().into()
 }
 
-fn initial_secret(conn_id: &ConnectionId) -> hkdf::Prk {
-    hkdf::Salt::new(hkdf::HKDF_SHA256, &INITIAL_SALT).extract(conn_id)
+fn initial_secret(conn_id: &ConnectionId, version: u32) -> hkdf::Prk {
+    hkdf::Salt::new(hkdf::HKDF_SHA256, initial_salt(version)).extract(conn_id)
 }
 
-const INITIAL_SALT: [u8; 20] = [
+/// Salt used to derive Initial secrets, which changes between draft versions
+fn initial_salt(version: u32) -> &'static [u8] {
+    match version {
+        0xff00_0015..=0xff00_0016 => &INITIAL_SALT_DRAFT_21,
+        // Draft 23 onwards, including the version spoken by this implementation
+        _ => &INITIAL_SALT_DRAFT_23,
+    }
+}
+
+const INITIAL_SALT_DRAFT_21: [u8; 20] = [
+    0x7f, 0xbc, 0xdb, 0x0e, 0x7c, 0x66, 0xbb, 0xe9, 0x19, 0x3a, 0x96, 0xcd, 0x21, 0x51, 0x9e, 0xbd,
+    0x7a, 0x02, 0x64, 0x4a,
+];
+
+const INITIAL_SALT_DRAFT_23: [u8; 20] = [
     0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4, 0x63, 0x65,
     0xbe, 0xf9, 0xf5, 0x02,
 ];
@@ -280,7 +294,7 @@ mod test {
     use super::*;
     use crate::{
         crypto::{HeaderKeys, Keys},
-        MAX_CID_SIZE,
+        MAX_CID_SIZE, VERSION,
     };
     use hex_literal::hex;
     use rand;
@@ -288,8 +302,8 @@ mod test {
     #[test]
     fn handshake_crypto_roundtrip() {
         let conn = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE);
-        let client = Crypto::new_initial(&conn, Side::Client);
-        let server = Crypto::new_initial(&conn, Side::Server);
+        let client = Crypto::new_initial(&conn, Side::Client, VERSION);
+        let server = Crypto::new_initial(&conn, Side::Server, VERSION);
 
         let mut buf = b"headerpayload".to_vec();
         buf.resize(buf.len() + client.tag_len(), 0);
@@ -305,7 +319,7 @@ mod test {
     fn key_derivation() {
         let id = ConnectionId::new(&hex!("8394c8f03e515708"));
         let cipher = &aead::AES_128_GCM;
-        let initial_secret = initial_secret(&id);
+        let initial_secret = initial_secret(&id, 0xff00_0017);
         println!();
 
         // Key secrets are opaque, so we cannot check them
@@ -318,12 +332,27 @@ mod test {
         assert_eq!(&server_iv.0[..], hex!("5e5ae651fd1e8495af13508b"));
     }
 
+    #[test]
+    fn key_derivation_draft_21() {
+        let id = ConnectionId::new(&hex!("8394c8f03e515708"));
+        let cipher = &aead::AES_128_GCM;
+        let initial_secret = initial_secret(&id, 0xff00_0016);
+
+        let client_secret = expanded_initial_secret(&initial_secret, b"client in");
+        let (_, client_iv) = Crypto::get_keys(cipher, &client_secret);
+        assert_eq!(&client_iv.0[..], hex!("fb33da41a8f297d482df670e"));
+
+        let server_secret = expanded_initial_secret(&initial_secret, b"server in");
+        let (_, server_iv) = Crypto::get_keys(cipher, &server_secret);
+        assert_eq!(&server_iv.0[..], hex!("74fc8e534408a0b3928a3906"));
+    }
+
     #[test]
     fn packet_protection() {
         let id = ConnectionId::new(&hex!("8394c8f03e515708"));
-        let server = Crypto::new_initial(&id, Side::Server);
+        let server = Crypto::new_initial(&id, Side::Server, VERSION);
         let server_header = server.header_keys();
-        let client = Crypto::new_initial(&id, Side::Client);
+        let client = Crypto::new_initial(&id, Side::Client, VERSION);
         let client_header = client.header_keys();
         let plaintext = hex!(
             "c1ff00001205f067a5502a4262b50040740000
@@ -3,9 +3,13 @@ use std::{
     ops::{Deref, DerefMut},
     str,
     sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use ring::{hkdf, hmac};
+use ring::{
+    aead, hkdf, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
 pub use rustls::TLSError;
 use rustls::{
     self,
@@ -15,10 +19,13 @@ use rustls::{
 };
 use webpki::DNSNameRef;
 
+use self::hello::ClientHello;
 use super::ring::{hkdf_expand, Crypto};
 use crate::{
-    crypto, transport_parameters::TransportParameters, ConnectError, Side, TransportError,
-    TransportErrorCode,
+    crypto::{self, MemorySessionStorage, SessionStorage, DEFAULT_SESSION_CAPACITY},
+    generic,
+    transport_parameters::TransportParameters,
+    ConfigError, ConnectError, Side, TransportError, TransportErrorCode,
 };
 
 /// A rustls TLS session
@@ -26,14 +33,139 @@ pub enum TlsSession {
     #[doc(hidden)]
     Client(rustls::ClientSession),
     #[doc(hidden)]
-    Server(rustls::ServerSession),
+    Server(rustls::ServerSession, ClientHello),
+}
+
+/// Keeps `ClientHello` out of the public API while `TlsSession::Server` carries it
+mod hello {
+    use std::{mem, sync::Arc, time::Instant};
+
+    use rustls::{
+        internal::msgs::{
+            codec::{Codec, Reader},
+            handshake::{ConvertProtocolNameList, HandshakeMessagePayload, HandshakePayload},
+        },
+        quic::ServerQuicExt,
+    };
+
+    use crate::crypto;
+
+    /// The client's first handshake message, as far as it's been received
+    #[derive(Default)]
+    pub struct ClientHello {
+        buf: Vec<u8>,
+        done: bool,
+        /// Configuration and transport parameters to start over with if early data is refused
+        restart: Option<(Arc<rustls::ServerConfig>, Vec<u8>)>,
+        /// The cache to check the client's random value against, and the time to check it as of
+        anti_replay: Option<(Arc<dyn crypto::AntiReplay>, Instant)>,
+        refused_protocols: Vec<Vec<u8>>,
+        /// Application protocols offered by the client, once the message is complete
+        offered_protocols: Option<Vec<Vec<u8>>>,
+    }
+
+    impl ClientHello {
+        pub(super) fn new(config: Arc<rustls::ServerConfig>, params: Vec<u8>) -> Self {
+            Self {
+                restart: Some((config, params)),
+                ..Self::default()
+            }
+        }
+
+        pub(super) fn set_early_data_policy(
+            &mut self,
+            anti_replay: Option<Arc<dyn crypto::AntiReplay>>,
+            refused_protocols: Vec<Vec<u8>>,
+            now: Instant,
+        ) {
+            self.anti_replay = anti_replay.map(|x| (x, now));
+            self.refused_protocols = refused_protocols;
+        }
+
+        /// Returns `true` if the message has just been completed and offers early data to be refused
+        pub(super) fn read(&mut self, data: &[u8]) -> bool {
+            if self.done {
+                return false;
+            }
+            self.buf.extend_from_slice(data);
+            if self.buf.len() < 4 {
+                return false;
+            }
+            let len = 4 + u32::from_be_bytes([0, self.buf[1], self.buf[2], self.buf[3]]) as usize;
+            if self.buf.len() < len {
+                return false;
+            }
+            self.done = true;
+            // Malformed messages are rejected by rustls, so treat them as offering nothing
+            let msg = HandshakeMessagePayload::read_version(
+                &mut Reader::init(&self.buf[..len]),
+                rustls::ProtocolVersion::TLSv1_3,
+            );
+            let refuse = match msg {
+                Some(HandshakeMessagePayload {
+                    payload: HandshakePayload::ClientHello(ref hello),
+                    ..
+                }) => {
+                    let protocols = hello
+                        .get_alpn_extension()
+                        .map_or_else(Vec::new, |x| x.to_vecs());
+                    let refuse = hello.early_data_extension_offered()
+                        && self.refuse_early_data(&hello.random.get_encoding(), &protocols);
+                    self.offered_protocols = Some(protocols);
+                    refuse
+                }
+                _ => false,
+            };
+            if !refuse {
+                self.buf = Vec::new();
+                self.restart = None;
+            }
+            refuse
+        }
+
+        pub(super) fn offered_protocols(&self) -> Option<&[Vec<u8>]> {
+            self.offered_protocols.as_ref().map(|x| &x[..])
+        }
+
+        fn refuse_early_data(&self, random: &[u8], offered: &[Vec<u8>]) -> bool {
+            let config = match self.restart {
+                Some((ref config, _)) if config.max_early_data_size > 0 => config,
+                _ => return false,
+            };
+            // rustls negotiates the first of its protocols the client offers
+            if let Some(protocol) = config.alpn_protocols.iter().find(|x| offered.contains(x)) {
+                if self.refused_protocols.contains(protocol) {
+                    return true;
+                }
+            }
+            match self.anti_replay {
+                Some((ref anti_replay, now)) => {
+                    let mut key = [0; 32];
+                    key.copy_from_slice(random);
+                    !anti_replay.check(&key, now)
+                }
+                None => false,
+            }
+        }
+
+        /// Start a session that doesn't accept early data, returning it with the data read so far
+        pub(super) fn restart(&mut self) -> (rustls::ServerSession, Vec<u8>) {
+            let (config, params) = self.restart.take().unwrap();
+            let mut config = (*config).clone();
+            config.max_early_data_size = 0;
+            let mut data = Vec::new();
+            mem::swap(&mut data, &mut self.buf);
+            let session = rustls::ServerSession::new_quic(&Arc::new(config), params);
+            (session, data)
+        }
+    }
 }
 
 impl TlsSession {
     fn side(&self) -> Side {
         match self {
             TlsSession::Client(_) => Side::Client,
-            TlsSession::Server(_) => Side::Server,
+            TlsSession::Server(..) => Side::Server,
         }
     }
 }
@@ -48,6 +180,18 @@ impl crypto::Session for TlsSession {
         self.get_alpn_protocol()
     }
 
+    fn cipher_suite(&self) -> Option<String> {
+        let suite = self.get_negotiated_ciphersuite()?;
+        Some(format!("{:?}", suite.suite))
+    }
+
+    fn offered_protocols(&self) -> Option<&[Vec<u8>]> {
+        match self {
+            TlsSession::Server(_, hello) => hello.offered_protocols(),
+            TlsSession::Client(_) => None,
+        }
+    }
+
     fn early_crypto(&self) -> Option<Self::Keys> {
         let secret = self.get_early_secret()?;
         // If an early secret is known, TLS guarantees it's associated with a resumption
@@ -71,11 +215,22 @@ impl crypto::Session for TlsSession {
     fn is_handshaking(&self) -> bool {
         match self {
             TlsSession::Client(session) => session.is_handshaking(),
-            TlsSession::Server(session) => session.is_handshaking(),
+            TlsSession::Server(session, _) => session.is_handshaking(),
         }
     }
 
     fn read_handshake(&mut self, buf: &[u8]) -> Result<(), TransportError> {
+        let mut restarted = None;
+        if let TlsSession::Server(ref mut session, ref mut hello) = self {
+            if hello.read(buf) {
+                // Nothing has been processed yet, so feed everything to a session that won't
+                // accept early data
+                let (fresh, data) = hello.restart();
+                *session = fresh;
+                restarted = Some(data);
+            }
+        }
+        let buf = restarted.as_ref().map_or(buf, |x| &x[..]);
         self.read_hs(buf).map_err(|e| {
             if let Some(alert) = self.get_alert() {
                 TransportError {
@@ -89,10 +244,21 @@ impl crypto::Session for TlsSession {
         })
     }
 
+    fn set_early_data_policy(
+        &mut self,
+        anti_replay: Option<Arc<dyn crypto::AntiReplay>>,
+        refused_protocols: Vec<Vec<u8>>,
+        now: Instant,
+    ) {
+        if let TlsSession::Server(_, ref mut hello) = self {
+            hello.set_early_data_policy(anti_replay, refused_protocols, now);
+        }
+    }
+
     fn sni_hostname(&self) -> Option<&str> {
         match self {
             TlsSession::Client(_) => None,
-            TlsSession::Server(session) => session.get_sni_hostname(),
+            TlsSession::Server(session, _) => session.get_sni_hostname(),
         }
     }
 
@@ -145,7 +311,7 @@ impl Deref for TlsSession {
     fn deref(&self) -> &Self::Target {
         match *self {
             TlsSession::Client(ref session) => session,
-            TlsSession::Server(ref session) => session,
+            TlsSession::Server(ref session, _) => session,
         }
     }
 }
@@ -154,7 +320,7 @@ impl DerefMut for TlsSession {
     fn deref_mut(&mut self) -> &mut (dyn rustls::Session + 'static) {
         match *self {
             TlsSession::Client(ref mut session) => session,
-            TlsSession::Server(ref mut session) => session,
+            TlsSession::Server(ref mut session, _) => session,
         }
     }
 }
@@ -164,6 +330,9 @@ impl crypto::ClientConfig<TlsSession> for Arc<rustls::ClientConfig> {
         let mut cfg = rustls::ClientConfig::new();
         cfg.versions = vec![rustls::ProtocolVersion::TLSv1_3];
         cfg.enable_early_data = true;
+        cfg.set_persistence(Arc::new(Persistence(Arc::new(MemorySessionStorage::new(
+            DEFAULT_SESSION_CAPACITY,
+        )))));
         Arc::new(cfg)
     }
 
@@ -180,6 +349,14 @@ impl crypto::ClientConfig<TlsSession> for Arc<rustls::ClientConfig> {
             to_vec(params),
         )))
     }
+
+    fn early_data_enabled(&self) -> bool {
+        self.enable_early_data
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        validate_versions(&self.versions)
+    }
 }
 
 impl crypto::ServerConfig<TlsSession> for Arc<rustls::ServerConfig> {
@@ -191,10 +368,188 @@ impl crypto::ServerConfig<TlsSession> for Arc<rustls::ServerConfig> {
     }
 
     fn start_session(&self, params: &TransportParameters) -> TlsSession {
-        TlsSession::Server(rustls::ServerSession::new_quic(self, to_vec(params)))
+        let params = to_vec(params);
+        TlsSession::Server(
+            rustls::ServerSession::new_quic(self, params.clone()),
+            ClientHello::new(self.clone(), params),
+        )
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        validate_versions(&self.versions)
     }
 }
 
+/// Session ticket protection derived from a secret shared by a fleet of servers
+///
+/// Servers using the same secret and rotation interval accept each other's tickets, so a client
+/// can resume a session, and send 0-RTT data, with whichever server it reaches. Ticket keys are
+/// derived from the secret and the current rotation period of the wall clock, so servers rotate in
+/// step without coordinating beyond keeping their clocks roughly synchronized. Tickets issued
+/// during the neighbouring periods are accepted too, to tolerate clock skew and tickets issued just
+/// before a rotation.
+pub struct SharedTicketer {
+    secret: hkdf::Prk,
+    rotation_interval: u64,
+    rng: SystemRandom,
+}
+
+impl SharedTicketer {
+    /// Derive ticket keys from `secret`, rotating every `rotation_interval`
+    ///
+    /// Panics if `rotation_interval` is shorter than a second.
+    pub fn new(secret: &[u8], rotation_interval: Duration) -> Self {
+        assert!(
+            rotation_interval.as_secs() > 0,
+            "rotation interval must be at least one second"
+        );
+        Self {
+            secret: hkdf::Salt::new(hkdf::HKDF_SHA256, b"quinn session tickets").extract(secret),
+            rotation_interval: rotation_interval.as_secs(),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    fn period(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs())
+            / self.rotation_interval
+    }
+
+    fn key(&self, period: u64) -> aead::LessSafeKey {
+        let period = period.to_be_bytes();
+        let key: aead::UnboundKey = self
+            .secret
+            .expand(&[&period[..]], &aead::CHACHA20_POLY1305)
+            .unwrap()
+            .into();
+        aead::LessSafeKey::new(key)
+    }
+
+    fn encrypt_at(&self, period: u64, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; aead::NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+        let mut ticket = Vec::with_capacity(8 + nonce.len() + plain.len() + TICKET_TAG_LEN);
+        ticket.extend_from_slice(&period.to_be_bytes());
+        ticket.extend_from_slice(&nonce);
+        let mut payload = plain.to_vec();
+        self.key(period)
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(&ticket[..8]),
+                &mut payload,
+            )
+            .ok()?;
+        ticket.extend_from_slice(&payload);
+        Some(ticket)
+    }
+
+    fn decrypt_at(&self, now: u64, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < 8 + aead::NONCE_LEN + TICKET_TAG_LEN {
+            return None;
+        }
+        let (header, payload) = ticket.split_at(8 + aead::NONCE_LEN);
+        let mut period = [0; 8];
+        period.copy_from_slice(&header[..8]);
+        let period = u64::from_be_bytes(period);
+        if period.saturating_add(1) < now || period > now.saturating_add(1) {
+            return None;
+        }
+        let nonce = aead::Nonce::try_assume_unique_for_key(&header[8..]).ok()?;
+        let mut payload = payload.to_vec();
+        let len = self
+            .key(period)
+            .open_in_place(nonce, aead::Aad::from(&header[..8]), &mut payload)
+            .ok()?
+            .len();
+        payload.truncate(len);
+        Some(payload)
+    }
+}
+
+impl rustls::ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.rotation_interval.min(0xffff_ffff) as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.encrypt_at(self.period(), plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.decrypt_at(self.period(), cipher)
+    }
+}
+
+const TICKET_TAG_LEN: usize = 16;
+
+impl generic::ClientConfig<TlsSession> {
+    /// Keep the session tickets and address validation tokens received from servers in `storage`
+    ///
+    /// By default, they're kept in `MemorySessionStorage`s private to the configuration.
+    pub fn set_session_storage(&mut self, storage: Arc<dyn SessionStorage>) {
+        self.set_token_storage(storage.clone());
+        Arc::make_mut(&mut self.crypto).set_persistence(Arc::new(Persistence(storage)));
+    }
+}
+
+/// rustls client session persistence backed by a `SessionStorage`
+struct Persistence(Arc<dyn SessionStorage>);
+
+impl Persistence {
+    /// The server a rustls storage key refers to, if it's that of a session ticket
+    ///
+    /// rustls also stores the key exchange groups servers prefer, which aren't worth keeping.
+    fn server_name(key: &[u8]) -> Option<&str> {
+        if !key.starts_with(SESSION_KEY_PREFIX) {
+            return None;
+        }
+        let (&len, name) = key[SESSION_KEY_PREFIX.len()..].split_first()?;
+        if name.len() != usize::from(len) {
+            return None;
+        }
+        str::from_utf8(name).ok()
+    }
+}
+
+/// How rustls's keys for session tickets start, the length-prefixed server name following
+const SESSION_KEY_PREFIX: &[u8] = b"session";
+
+impl rustls::StoresClientSessions for Persistence {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        match Self::server_name(&key) {
+            Some(name) => {
+                self.0.put(name, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(Self::server_name(key)?)
+    }
+}
+
+/// QUIC is only defined for TLS 1.3, so forbid negotiating anything else
+fn validate_versions(versions: &[rustls::ProtocolVersion]) -> Result<(), ConfigError> {
+    if versions.is_empty()
+        || versions
+            .iter()
+            .any(|&v| v != rustls::ProtocolVersion::TLSv1_3)
+    {
+        return Err(ConfigError::IllegalValue(
+            "TLS versions other than 1.3 must not be enabled",
+        ));
+    }
+    Ok(())
+}
+
 fn update_secrets(hash_alg: HashAlgorithm, client: &hkdf::Prk, server: &hkdf::Prk) -> Secrets {
     let hkdf_alg = match hash_alg {
         HashAlgorithm::SHA256 => hkdf::HKDF_SHA256,
@@ -214,3 +569,41 @@ fn to_vec(params: &TransportParameters) -> Vec<u8> {
     params.write(&mut bytes);
     bytes
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_ticketer_rotation() {
+        let a = SharedTicketer::new(b"fleet secret", Duration::from_secs(60));
+        let b = SharedTicketer::new(b"fleet secret", Duration::from_secs(60));
+        let other = SharedTicketer::new(b"other secret", Duration::from_secs(60));
+        let ticket = a.encrypt_at(100, b"session").unwrap();
+        assert_eq!(b.decrypt_at(100, &ticket).unwrap(), b"session");
+        assert_eq!(b.decrypt_at(99, &ticket).unwrap(), b"session");
+        assert_eq!(b.decrypt_at(101, &ticket).unwrap(), b"session");
+        assert!(b.decrypt_at(102, &ticket).is_none());
+        assert!(b.decrypt_at(98, &ticket).is_none());
+        assert!(other.decrypt_at(100, &ticket).is_none());
+
+        // The period is authenticated, so a ticket can't be moved into a later one
+        let mut forged = ticket.clone();
+        forged[7] += 1;
+        assert!(b.decrypt_at(101, &forged).is_none());
+        assert!(b.decrypt_at(100, &ticket[..20]).is_none());
+    }
+
+    #[test]
+    fn persistence_keys() {
+        use rustls::StoresClientSessions;
+        let storage = Arc::new(MemorySessionStorage::new(2));
+        let persistence = Persistence(storage.clone());
+        assert!(persistence.put(b"session\x09localhost".to_vec(), vec![1]));
+        assert_eq!(storage.get("localhost"), Some(vec![1]));
+        assert_eq!(persistence.get(b"session\x09localhost"), Some(vec![1]));
+        assert!(!persistence.put(b"kx-hint\x09localhost".to_vec(), vec![2]));
+        assert_eq!(persistence.get(b"kx-hint\x09localhost"), None);
+        assert_eq!(persistence.get(b"session\x09local"), None);
+    }
+}
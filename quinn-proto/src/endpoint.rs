@@ -1,7 +1,7 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt, iter,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     ops::{Index, IndexMut},
     sync::Arc,
     time::{Duration, Instant, SystemTime},
@@ -15,7 +15,7 @@ use tracing::{debug, trace, warn};
 
 use crate::{
     coding::BufMutExt,
-    connection::{initial_close, Connection, ConnectionError},
+    connection::{initial_close, Connection, ConnectionError, InitialCids},
     crypto::{
         self, ClientConfig as ClientCryptoConfig, HmacKey, Keys, ServerConfig as ServerCryptoConfig,
     },
@@ -25,7 +25,7 @@ use crate::{
         EcnCodepoint, EndpointConfig, EndpointEvent, EndpointEventInner, IssuedCid, ResetToken,
         ServerConfig,
     },
-    transport_parameters::TransportParameters,
+    transport_parameters::{PreferredAddress, TransportParameters},
     Side, Transmit, TransportError, LOC_CID_COUNT, MAX_CID_SIZE, MIN_INITIAL_SIZE,
     RESET_TOKEN_SIZE, VERSION,
 };
@@ -42,6 +42,16 @@ where
     rng: StdRng,
     transmits: VecDeque<Transmit>,
     connection_ids_initial: HashMap<ConnectionId, ConnectionHandle>,
+    /// Initial destination CIDs of recently drained connections, oldest first
+    ///
+    /// Bounded by `ServerConfig.initial_cid_cache`.
+    recent_initial_cids: VecDeque<ConnectionId>,
+    /// Addresses sent a Version Negotiation packet within `ServerConfig.version_negotiation_interval`
+    ///
+    /// Oldest first, mirrored by `version_negotiation_addrs` for lookup. Bounded by
+    /// `ServerConfig.version_negotiation_cache`.
+    recent_version_negotiations: VecDeque<(Instant, IpAddr)>,
+    version_negotiation_addrs: HashSet<IpAddr>,
     connection_ids: HashMap<ConnectionId, ConnectionHandle>,
     /// Identifies connections with zero-length CIDs
     connection_remotes: HashMap<SocketAddr, ConnectionHandle>,
@@ -74,10 +84,18 @@ where
         server_config: Option<Arc<ServerConfig<S>>>,
     ) -> Result<Self, ConfigError> {
         config.validate()?;
+        if let Some(ref server_config) = server_config {
+            server_config.crypto.validate()?;
+        }
         Ok(Self {
-            rng: StdRng::from_entropy(),
+            rng: config
+                .rng_seed
+                .map_or_else(StdRng::from_entropy, StdRng::from_seed),
             transmits: VecDeque::new(),
             connection_ids_initial: HashMap::new(),
+            recent_initial_cids: VecDeque::new(),
+            recent_version_negotiations: VecDeque::new(),
+            version_negotiation_addrs: HashSet::new(),
             connection_ids: HashMap::new(),
             connection_remotes: HashMap::new(),
             connection_reset_tokens: ResetTokenTable::default(),
@@ -115,10 +133,13 @@ where
         match event.0 {
             NeedIdentifiers(max) => {
                 if self.config.local_cid_len != 0 {
-                    // We've already issued one CID as part of the normal handshake process.
-                    return Some(
-                        self.send_new_identifiers(ch, max.min(LOC_CID_COUNT - 1) as usize),
-                    );
+                    // We've already issued one CID as part of the normal handshake process, and
+                    // possibly another with the preferred address.
+                    let issued = 1 + self.connections[ch].cids_issued;
+                    return Some(self.send_new_identifiers(
+                        ch,
+                        max.min(LOC_CID_COUNT).saturating_sub(issued) as usize,
+                    ));
                 }
             }
             ResetToken(remote, token) => {
@@ -140,6 +161,7 @@ where
                 let conn = self.connections.remove(ch.0);
                 if conn.init_cid.len() > 0 {
                     self.connection_ids_initial.remove(&conn.init_cid);
+                    self.remember_initial_cid(conn.init_cid);
                 }
                 for cid in conn.loc_cids.values() {
                     self.connection_ids.remove(&cid);
@@ -172,7 +194,6 @@ where
                     debug!("dropping packet with unsupported version");
                     return None;
                 }
-                trace!("sending version negotiation");
                 // Negotiate versions
                 let mut buf = Vec::<u8>::new();
                 Header::VersionNegotiate {
@@ -183,9 +204,19 @@ where
                 .encode(&mut buf);
                 buf.write::<u32>(0x0a1a_2a3a); // reserved version
                 buf.write(VERSION); // supported version
+                if buf.len() > datagram_len {
+                    debug!("ignoring undersized packet with unsupported version");
+                    return None;
+                }
+                if !self.version_negotiation_permitted(now, remote.ip()) {
+                    debug!("rate limiting version negotiation to {}", remote.ip());
+                    return None;
+                }
+                trace!("sending version negotiation");
                 self.transmits.push_back(Transmit {
                     destination: remote,
                     ecn: None,
+                    dscp: 0,
                     contents: buf.into(),
                 });
                 return None;
@@ -266,8 +297,15 @@ where
                 debug!("ignoring short initial for connection {}", dst_cid);
                 return None;
             }
+            if self.recent_initial_cids.contains(&dst_cid) {
+                debug!(
+                    "ignoring duplicate initial for closed connection {}",
+                    dst_cid
+                );
+                return None;
+            }
 
-            let crypto = S::Keys::new_initial(&dst_cid, Side::Server);
+            let crypto = S::Keys::new_initial(&dst_cid, Side::Server, VERSION);
             let header_crypto = crypto.header_keys();
             return match first_decode.finish(Some(&header_crypto)) {
                 Ok(packet) => self
@@ -301,6 +339,49 @@ where
         None
     }
 
+    /// Recognize future Initial packets for `cid` as duplicates
+    fn remember_initial_cid(&mut self, cid: ConnectionId) {
+        let capacity = self
+            .server_config
+            .as_ref()
+            .map_or(0, |x| x.initial_cid_cache);
+        if capacity == 0 {
+            return;
+        }
+        while self.recent_initial_cids.len() >= capacity {
+            self.recent_initial_cids.pop_front();
+        }
+        self.recent_initial_cids.push_back(cid);
+    }
+
+    /// Whether a Version Negotiation packet may be sent to `addr` now, recording it if so
+    fn version_negotiation_permitted(&mut self, now: Instant, addr: IpAddr) -> bool {
+        let config = self.server_config.as_ref().unwrap();
+        let interval = Duration::from_micros(config.version_negotiation_interval);
+        let capacity = config.version_negotiation_cache;
+        while let Some(&(sent, old)) = self.recent_version_negotiations.front() {
+            if now.saturating_duration_since(sent) < interval
+                && self.recent_version_negotiations.len() <= capacity
+            {
+                break;
+            }
+            self.recent_version_negotiations.pop_front();
+            self.version_negotiation_addrs.remove(&old);
+        }
+        if interval == Duration::from_micros(0) || capacity == 0 {
+            return true;
+        }
+        if !self.version_negotiation_addrs.insert(addr) {
+            return false;
+        }
+        if self.recent_version_negotiations.len() == capacity {
+            let (_, old) = self.recent_version_negotiations.pop_front().unwrap();
+            self.version_negotiation_addrs.remove(&old);
+        }
+        self.recent_version_negotiations.push_back((now, addr));
+        true
+    }
+
     fn stateless_reset(
         &mut self,
         inciting_dgram_len: usize,
@@ -340,13 +421,17 @@ where
         self.transmits.push_back(Transmit {
             destination: remote,
             ecn: None,
+            dscp: 0,
             contents: buf.into(),
         });
     }
 
     /// Initiate a connection
+    ///
+    /// `now` is the time at which the connection's timers start.
     pub fn connect(
         &mut self,
+        now: Instant,
         config: ClientConfig<S>,
         remote: SocketAddr,
         server_name: &str,
@@ -355,9 +440,11 @@ where
             return Err(ConnectError::TooManyConnections);
         }
         config.transport.validate()?;
+        config.crypto.validate()?;
         let remote_id = ConnectionId::random(&mut self.rng, MAX_CID_SIZE);
         trace!(initial_dcid = %remote_id);
-        let (ch, conn) = self.add_connection(
+        let token_storage = config.token_storage.clone();
+        let (ch, mut conn) = self.add_connection(
             remote_id,
             remote_id,
             remote,
@@ -365,8 +452,9 @@ where
                 config,
                 server_name: server_name.into(),
             },
-            Instant::now(),
+            now,
         )?;
+        conn.set_token_storage(token_storage, server_name.into());
         Ok((ch, conn))
     }
 
@@ -407,56 +495,101 @@ where
         now: Instant,
     ) -> Result<(ConnectionHandle, Connection<S>), ConnectError> {
         let loc_cid = self.new_cid();
-        let (server_config, tls, transport_config) = match opts {
+        let mut early_data = true;
+        let mut preferred_cid = None;
+        let (server_config, tls, transport_config, params) = match opts {
             ConnectionOpts::Client {
                 config,
                 server_name,
             } => {
                 let params = TransportParameters::new::<S>(&config.transport, None);
+                early_data = config.crypto.early_data_enabled();
                 (
                     None,
                     config.crypto.start_session(&server_name, &params)?,
                     config.transport,
+                    params,
                 )
             }
             ConnectionOpts::Server { orig_dst_cid } => {
-                let config = self.server_config.as_ref().unwrap();
-                let params = TransportParameters::new(&config.transport, Some(config));
+                let config = self.server_config.clone().unwrap();
+                let params = TransportParameters::new(&config.transport, Some(&config));
+                // The preferred address comes with the CID of sequence number 1
+                preferred_cid = if self.config.local_cid_len != 0
+                    && (config.preferred_address_v4.is_some()
+                        || config.preferred_address_v6.is_some())
+                {
+                    Some(self.new_cid())
+                } else {
+                    None
+                };
                 let server_params = TransportParameters {
                     stateless_reset_token: Some(reset_token_for(&self.reset_key, &loc_cid)),
                     original_connection_id: orig_dst_cid,
+                    preferred_address: preferred_cid.map(|id| {
+                        let mut stateless_reset_token = [0; RESET_TOKEN_SIZE];
+                        stateless_reset_token
+                            .copy_from_slice(&reset_token_for(&self.reset_key, &id));
+                        PreferredAddress {
+                            address_v4: config.preferred_address_v4,
+                            address_v6: config.preferred_address_v6,
+                            connection_id: id,
+                            stateless_reset_token,
+                        }
+                    }),
                     ..params
                 };
+                let mut tls = config.crypto.start_session(&server_params);
+                tls.set_early_data_policy(
+                    config.anti_replay.clone(),
+                    config.refused_early_data_protocols.clone(),
+                    now,
+                );
                 (
                     Some(config.clone()),
-                    config.crypto.start_session(&server_params),
+                    tls,
                     config.transport.clone(),
+                    server_params,
                 )
             }
         };
 
-        let conn = Connection::new(
+        let mut conn = Connection::new(
             Arc::clone(&self.config),
             server_config,
             transport_config,
-            init_cid,
-            loc_cid,
-            rem_cid,
+            InitialCids {
+                init: init_cid,
+                local: loc_cid,
+                remote: rem_cid,
+            },
             remote,
             tls,
             now,
+            StdRng::from_seed(self.rng.gen()),
         );
-        let id = self.connections.insert(ConnectionMeta {
+        if !early_data {
+            conn.early_data_disabled();
+        }
+        conn.local_params_set(now, &params);
+        let mut meta = ConnectionMeta {
             init_cid,
             cids_issued: 0,
             loc_cids: iter::once((0, loc_cid)).collect(),
             initial_remote: remote,
             reset_token: None,
-        });
-        let ch = ConnectionHandle(id);
+        };
+        if let Some(id) = preferred_cid {
+            meta.cids_issued = 1;
+            meta.loc_cids.insert(1, id);
+        }
+        let ch = ConnectionHandle(self.connections.insert(meta));
 
         if self.config.local_cid_len > 0 {
             self.connection_ids.insert(loc_cid, ch);
+            if let Some(id) = preferred_cid {
+                self.connection_ids.insert(id, ch);
+            }
         } else {
             self.connection_remotes.insert(remote, ch);
         }
@@ -513,6 +646,7 @@ where
             self.transmits.push_back(Transmit {
                 destination: remote,
                 ecn: None,
+                dscp: 0,
                 contents: initial_close(
                     crypto,
                     header_crypto,
@@ -535,6 +669,7 @@ where
             self.transmits.push_back(Transmit {
                 destination: remote,
                 ecn: None,
+                dscp: 0,
                 contents: initial_close(
                     crypto,
                     header_crypto,
@@ -548,27 +683,36 @@ where
         }
 
         let mut retry_cid = None;
-        if server_config.use_stateless_retry {
-            if let Some((token_dst_cid, token_issued)) =
-                token::check(self.token_key.as_ref().unwrap(), &remote, &token)
-            {
-                let expires = token_issued
-                    + Duration::from_micros(
-                        self.server_config.as_ref().unwrap().retry_token_lifetime,
-                    );
-                if expires > SystemTime::now() {
-                    retry_cid = Some(token_dst_cid);
-                } else {
-                    trace!("sending stateless retry due to expired token");
+        // Whether the client presented a token from a NEW_TOKEN frame
+        let mut validated = false;
+        if server_config.use_stateless_retry || !token.is_empty() {
+            match token::check(self.token_key.as_ref().unwrap(), &remote, &token) {
+                Some((Some(token_dst_cid), issued)) => {
+                    let expires =
+                        issued + Duration::from_micros(server_config.retry_token_lifetime);
+                    if expires > SystemTime::now() {
+                        retry_cid = Some(token_dst_cid);
+                    } else {
+                        trace!("expired retry token");
+                    }
                 }
-            } else {
-                trace!("sending stateless retry due to invalid token");
+                Some((None, issued)) => {
+                    let expires = issued + Duration::from_micros(server_config.new_token_lifetime);
+                    if expires > SystemTime::now() {
+                        trace!("address validated by token");
+                        validated = true;
+                    } else {
+                        trace!("expired token");
+                    }
+                }
+                None => trace!("invalid token"),
             }
-            if retry_cid.is_none() {
+            if server_config.use_stateless_retry && retry_cid.is_none() && !validated {
+                trace!("sending stateless retry");
                 let token = token::generate(
                     self.token_key.as_ref().unwrap(),
                     &remote,
-                    &dst_cid,
+                    Some(&dst_cid),
                     SystemTime::now(),
                 );
                 let mut buf = Vec::new();
@@ -588,6 +732,7 @@ where
                 self.transmits.push_back(Transmit {
                     destination: remote,
                     ecn: None,
+                    dscp: 0,
                     contents: buf.into(),
                 });
                 return None;
@@ -608,6 +753,18 @@ where
         if dst_cid.len() != 0 {
             self.connection_ids_initial.insert(dst_cid, ch);
         }
+        if validated {
+            conn.address_validated();
+        }
+        if self.server_config.as_ref().unwrap().new_token_lifetime != 0 {
+            let token = token::generate(
+                self.token_key.as_ref().unwrap(),
+                &remote,
+                None,
+                SystemTime::now(),
+            );
+            conn.issue_token(token.into());
+        }
         match conn.handle_first_packet(now, remote, ecn, packet_number as u64, packet, rest) {
             Ok(()) => {
                 trace!(id = ch.0, icid = %dst_cid, "connection incoming");
@@ -621,6 +778,7 @@ where
                     self.transmits.push_back(Transmit {
                         destination: remote,
                         ecn: None,
+                        dscp: 0,
                         contents: initial_close(
                             crypto,
                             header_crypto,
@@ -687,6 +845,11 @@ where
             .field("rng", &self.rng)
             .field("transmits", &self.transmits)
             .field("connection_ids_initial", &self.connection_ids_initial)
+            .field("recent_initial_cids", &self.recent_initial_cids)
+            .field(
+                "recent_version_negotiations",
+                &self.recent_version_negotiations,
+            )
             .field("connection_ids", &self.connection_ids)
             .field("connection_remotes", &self.connection_remotes)
             .field("connection_reset_tokens", &self.connection_reset_tokens)
@@ -748,18 +911,32 @@ mod token {
     // - AEAD nonce is always set to 0
     // in other words, for each ticket, use different key derived from random using HKDF
 
+    /// Marks tokens sent in NEW_TOKEN frames, in place of the length of the original destination
+    /// CID carried by Retry tokens
+    const NEW_TOKEN: u8 = 0xff;
+
+    /// Generate a token for `address`
+    ///
+    /// Retry tokens carry the client's original destination CID, and are bound to its port too.
+    /// Tokens sent in NEW_TOKEN frames are only bound to its IP address, as later connections will
+    /// likely come from a different port.
     pub fn generate<K>(
         key: &K,
         address: &SocketAddr,
-        dst_cid: &ConnectionId,
+        orig_dst_cid: Option<&ConnectionId>,
         issued: SystemTime,
     ) -> Vec<u8>
     where
         K: HmacKey,
     {
         let mut buf = Vec::new();
-        buf.write(dst_cid.len() as u8);
-        buf.put_slice(dst_cid);
+        match orig_dst_cid {
+            Some(cid) => {
+                buf.write(cid.len() as u8);
+                buf.put_slice(cid);
+            }
+            None => buf.write(NEW_TOKEN),
+        }
         buf.write::<u64>(
             issued
                 .duration_since(UNIX_EPOCH)
@@ -767,11 +944,7 @@ mod token {
                 .unwrap_or(0),
         );
         let signature_pos = buf.len();
-        match address.ip() {
-            IpAddr::V4(x) => buf.put_slice(&x.octets()),
-            IpAddr::V6(x) => buf.put_slice(&x.octets()),
-        }
-        buf.write(address.port());
+        write_address(&mut buf, address, orig_dst_cid.is_some());
         let signature = key.sign(&buf);
         // No reason to actually encode the IP in the token, since we always have the remote addr for an incoming packet.
         buf.truncate(signature_pos);
@@ -779,34 +952,47 @@ mod token {
         buf
     }
 
+    /// Check a token presented by `address`, returning the CID it carries, if a Retry token, and
+    /// when it was issued
     pub fn check<K>(
         key: &K,
         address: &SocketAddr,
         data: &[u8],
-    ) -> Option<(ConnectionId, SystemTime)>
+    ) -> Option<(Option<ConnectionId>, SystemTime)>
     where
         K: HmacKey,
     {
         let mut reader = io::Cursor::new(data);
-        let dst_cid_len = reader.get::<u8>().ok()? as usize;
-        if dst_cid_len > reader.remaining() || dst_cid_len > MAX_CID_SIZE {
-            return None;
-        }
-        let dst_cid = ConnectionId::new(&data[1..=dst_cid_len]);
-        reader.advance(dst_cid_len);
+        let dst_cid_len = reader.get::<u8>().ok()?;
+        let orig_dst_cid = if dst_cid_len == NEW_TOKEN {
+            None
+        } else {
+            let dst_cid_len = dst_cid_len as usize;
+            if dst_cid_len > reader.remaining() || dst_cid_len > MAX_CID_SIZE {
+                return None;
+            }
+            reader.advance(dst_cid_len);
+            Some(ConnectionId::new(&data[1..=dst_cid_len]))
+        };
         let issued = UNIX_EPOCH + Duration::new(reader.get::<u64>().ok()?, 0);
         let signature_start = reader.position() as usize;
 
         let mut buf = Vec::new();
         buf.put_slice(&data[0..signature_start]);
+        write_address(&mut buf, address, orig_dst_cid.is_some());
+
+        key.verify(&buf, &data[signature_start..]).ok()?;
+        Some((orig_dst_cid, issued))
+    }
+
+    fn write_address(buf: &mut Vec<u8>, address: &SocketAddr, port: bool) {
         match address.ip() {
             IpAddr::V4(x) => buf.put_slice(&x.octets()),
             IpAddr::V6(x) => buf.put_slice(&x.octets()),
         }
-        buf.write(address.port());
-
-        key.verify(&buf, &data[signature_start..]).ok()?;
-        Some((dst_cid, issued))
+        if port {
+            buf.write(address.port());
+        }
     }
 }
 
@@ -926,9 +1112,45 @@ mod test {
         let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4433);
         let dst_cid = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE);
         let issued = UNIX_EPOCH + Duration::new(42, 0); // Fractional seconds would be lost
-        let token = token::generate(&key, &addr, &dst_cid, issued);
+        let token = token::generate(&key, &addr, Some(&dst_cid), issued);
         let (dst_cid2, issued2) = token::check(&key, &addr, &token).expect("token didn't validate");
-        assert_eq!(dst_cid, dst_cid2);
+        assert_eq!(Some(dst_cid), dst_cid2);
         assert_eq!(issued, issued2);
+
+        // Tokens for later connections don't depend on the client's port
+        let token = token::generate(&key, &addr, None, issued);
+        let other = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4434);
+        let (dst_cid2, issued2) =
+            token::check(&key, &other, &token).expect("token didn't validate");
+        assert_eq!(dst_cid2, None);
+        assert_eq!(issued, issued2);
+        let other = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 4433);
+        assert!(token::check(&key, &other, &token).is_none());
+    }
+
+    #[cfg(feature = "ring")]
+    #[test]
+    fn token_forgery() {
+        use super::*;
+        use crate::crypto::HmacKey;
+        use ring::hmac;
+        use std::{net::Ipv6Addr, time::SystemTime};
+
+        let mut key = [0; 64];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = <hmac::Key as HmacKey>::new(&key).unwrap();
+        let addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4433);
+        let dst_cid = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE);
+        let token = token::generate(&key, &addr, Some(&dst_cid), SystemTime::now());
+
+        // Tokens are bound to the address they were issued to
+        let other = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4434);
+        assert!(token::check(&key, &other, &token).is_none());
+        // and can't be altered, e.g. to extend their lifetime
+        let mut altered = token.clone();
+        altered[1 + dst_cid.len()] ^= 1;
+        assert!(token::check(&key, &addr, &altered).is_none());
+        assert!(token::check(&key, &addr, &token[..token.len() - 1]).is_none());
+        assert!(token::check(&key, &addr, &[]).is_none());
     }
 }
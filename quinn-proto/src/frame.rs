@@ -14,7 +14,7 @@ use crate::{
 };
 
 #[derive(Copy, Clone, Eq, PartialEq)]
-pub struct Type(u64);
+pub struct Type(pub(crate) u64);
 
 impl Type {
     fn stream(self) -> Option<StreamInfo> {
@@ -124,6 +124,8 @@ frame_types! {
     CONNECTION_CLOSE = 0x1c,
     APPLICATION_CLOSE = 0x1d,
     // DATAGRAM
+    IMMEDIATE_ACK = 0xac,
+    ACK_FREQUENCY = 0xaf,
 }
 
 const STREAM_TYS: RangeInclusive<u64> = RangeInclusive::new(0x08, 0x0f);
@@ -151,6 +153,8 @@ pub enum Frame {
     PathResponse(u64),
     Close(Close),
     Datagram(Datagram),
+    AckFrequency(AckFrequency),
+    ImmediateAck,
     Invalid { ty: Type, reason: &'static str },
 }
 
@@ -190,6 +194,8 @@ impl Frame {
             Crypto(_) => Type::CRYPTO,
             NewToken { .. } => Type::NEW_TOKEN,
             Datagram(_) => Type(*DATAGRAM_TYS.start()),
+            AckFrequency(_) => Type::ACK_FREQUENCY,
+            ImmediateAck => Type::IMMEDIATE_ACK,
             Invalid { ty, .. } => ty,
         }
     }
@@ -240,6 +246,9 @@ pub struct ConnectionClose {
 impl fmt::Display for ConnectionClose {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.error_code.fmt(f)?;
+        if let Some(ty) = self.frame_type {
+            write!(f, " in {}", ty)?;
+        }
         if !self.reason.as_ref().is_empty() {
             f.write_str(": ")?;
             f.write_str(&String::from_utf8_lossy(&self.reason))?;
@@ -625,6 +634,17 @@ impl Iter {
             Type::NEW_TOKEN => Frame::NewToken {
                 token: self.take_len()?,
             },
+            Type::ACK_FREQUENCY => Frame::AckFrequency(AckFrequency {
+                sequence: self.bytes.get_var()?,
+                packet_tolerance: self.bytes.get_var()?,
+                update_max_ack_delay: self.bytes.get_var()?,
+                ignore_order: match self.bytes.get::<u8>()? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(IterErr::Malformed),
+                },
+            }),
+            Type::IMMEDIATE_ACK => Frame::ImmediateAck,
             _ => {
                 if let Some(s) = ty.stream() {
                     Frame::Stream(Stream {
@@ -811,6 +831,32 @@ impl Datagram {
     }
 }
 
+/// A request for the peer to change how often it acknowledges packets
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AckFrequency {
+    pub sequence: u64,
+    /// Number of ack-eliciting packets the peer may receive before it must send an ACK
+    pub packet_tolerance: u64,
+    /// Maximum time (μs) the peer may delay an ACK
+    pub update_max_ack_delay: u64,
+    /// Whether the peer should refrain from immediately acknowledging out-of-order packets
+    pub ignore_order: bool,
+}
+
+impl FrameStruct for AckFrequency {
+    const SIZE_BOUND: usize = 2 + 8 + 8 + 8 + 1;
+}
+
+impl AckFrequency {
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        out.write(Type::ACK_FREQUENCY); // 2 bytes
+        out.write_var(self.sequence); // <= 8 bytes
+        out.write_var(self.packet_tolerance); // <= 8 bytes
+        out.write_var(self.update_max_ack_delay); // <= 8 bytes
+        out.write(self.ignore_order as u8); // 1 byte
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -841,4 +887,48 @@ mod test {
             ref x => panic!("incorrect frame {:?}", x),
         }
     }
+
+    #[test]
+    fn ack_frequency_coding() {
+        let frame = AckFrequency {
+            sequence: 3,
+            packet_tolerance: 10,
+            update_max_ack_delay: 25_000,
+            ignore_order: true,
+        };
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        buf.write(Type::IMMEDIATE_ACK);
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        match frames[0] {
+            Frame::AckFrequency(ref x) => assert_eq!(*x, frame),
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+        match frames[1] {
+            Frame::ImmediateAck => {}
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+    }
+
+    #[test]
+    fn ack_frequency_bad_ignore_order() {
+        let mut buf = Vec::new();
+        AckFrequency {
+            sequence: 0,
+            packet_tolerance: 2,
+            update_max_ack_delay: 1000,
+            ignore_order: false,
+        }
+        .encode(&mut buf);
+        *buf.last_mut().unwrap() = 2;
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        match frames[..] {
+            [Frame::Invalid {
+                ty: Type::ACK_FREQUENCY,
+                ..
+            }] => {}
+            ref x => panic!("incorrect frames {:?}", x),
+        }
+    }
 }
@@ -35,11 +35,14 @@ pub use timer::{Timer, TimerTable, TimerTableIter, TimerTableIterMut};
 
 mod connection;
 pub use crate::connection::{
-    ConnectionError, DatagramSender, DatagramTooLarge, Event, SendDatagramError, TimerSetting,
-    TimerUpdate,
+    ConnectionError, ConnectionStats, CryptoStats, DatagramSender, DatagramTooLarge,
+    EarlyDataStatus, Event, PathStats, SendDatagramError, TimerSetting, TimerUpdate,
 };
 
+pub mod congestion;
+
 pub mod crypto;
+pub use crate::crypto::{MemorySessionStorage, SessionStorage};
 
 mod frame;
 use crate::frame::Frame;
@@ -48,18 +51,28 @@ pub use crate::frame::{ApplicationClose, ConnectionClose, Datagram};
 mod endpoint;
 pub use crate::endpoint::{ConnectError, ConnectionHandle, DatagramEvent};
 
+mod qlog;
+pub use crate::qlog::QlogSink;
+
 mod shared;
 pub use crate::shared::{
     ConfigError, ConnectionEvent, ConnectionId, EcnCodepoint, EndpointConfig, EndpointEvent,
-    TransportConfig,
+    IpPreference, TransportConfig,
 };
 
 mod streams;
-pub use crate::streams::{FinishError, ReadError, UnknownStream, WriteError};
+pub use crate::streams::{
+    FinishError, ReadError, RecvInfo, RecvStreamState, SendInfo, SendStreamState, StreamInfo,
+    UnknownStream, WriteError, Written,
+};
 
 mod transport_error;
 pub use crate::transport_error::{Code as TransportErrorCode, Error as TransportError};
 
+pub use crate::transport_parameters::{
+    Error as TransportParameterError, TransportParameters, TransportParametersBuilder,
+};
+
 /// Types that are generic over the crypto protocol implementation
 pub mod generic {
     pub use crate::{
@@ -83,6 +96,8 @@ mod rustls_impls {
     pub type ServerConfig = generic::ServerConfig<crypto::rustls::TlsSession>;
 }
 
+#[cfg(feature = "rustls")]
+pub use crate::crypto::rustls::SharedTicketer;
 #[cfg(feature = "rustls")]
 pub use crate::rustls_impls::*;
 
@@ -213,6 +228,10 @@ pub struct Transmit {
     pub destination: SocketAddr,
     /// Explicit congestion notification bits to set on the packet
     pub ecn: Option<EcnCodepoint>,
+    /// Differentiated services code point to mark the packet with, 0 being the default class
+    ///
+    /// Occupies the upper six bits of the IPv4 TOS or IPv6 traffic class field, above the ECN bits.
+    pub dscp: u8,
     /// Contents of the datagram
     pub contents: Box<[u8]>,
 }
@@ -223,7 +242,7 @@ pub struct Transmit {
 
 /// The maximum number of CIDs we bother to issue per connection
 const LOC_CID_COUNT: u64 = 8;
-/// The maximum number of remote CIDs we're willing to store per connection
+/// The default maximum number of remote CIDs we're willing to store per connection
 const REM_CID_COUNT: u64 = 32;
 const RESET_TOKEN_SIZE: usize = 16;
 const MAX_CID_SIZE: usize = 20;
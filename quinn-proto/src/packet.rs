@@ -626,8 +626,12 @@ pub(crate) enum PacketNumber {
 }
 
 impl PacketNumber {
-    pub(crate) fn new(n: u64, largest_acked: u64) -> Self {
-        let range = (n - largest_acked) * 2;
+    /// Encode `n` in as few bytes as the peer can unambiguously decode
+    ///
+    /// The encoding must cover twice the distance from the largest acknowledged packet, or from
+    /// before packet 0 if none have been acknowledged (RFC 9000 §17.1).
+    pub(crate) fn new(n: u64, largest_acked: Option<u64>) -> Self {
+        let range = largest_acked.map_or(n + 1, |x| n - x) * 2;
         if range < 1 << 8 {
             PacketNumber::U8(n as u8)
         } else if range < 1 << 16 {
@@ -830,16 +834,37 @@ mod tests {
 
     #[test]
     fn pn_encode() {
-        check_pn(PacketNumber::new(0x10, 0), &hex!("10"));
-        check_pn(PacketNumber::new(0x100, 0), &hex!("0100"));
-        check_pn(PacketNumber::new(0x10000, 0), &hex!("010000"));
+        check_pn(PacketNumber::new(0x10, Some(0)), &hex!("10"));
+        check_pn(PacketNumber::new(0x100, Some(0)), &hex!("0100"));
+        check_pn(PacketNumber::new(0x10000, Some(0)), &hex!("010000"));
+    }
+
+    #[test]
+    fn pn_encode_length() {
+        // Examples from RFC 9000 §A.2
+        assert_eq!(PacketNumber::new(0xac5c02, Some(0xabe8b3)).len(), 2);
+        assert_eq!(PacketNumber::new(0xace8fe, Some(0xabe8b3)).len(), 3);
+
+        assert_eq!(PacketNumber::new(0, None).len(), 1);
+        assert_eq!(PacketNumber::new(0x7e, None).len(), 1);
+        assert_eq!(PacketNumber::new(0x7f, None).len(), 2);
+        assert_eq!(PacketNumber::new(0x7f, Some(0)).len(), 1);
+        assert_eq!(PacketNumber::new(0x80, Some(0)).len(), 2);
+        assert_eq!(PacketNumber::new(0x1000, Some(0xf81)).len(), 1);
+        assert_eq!(PacketNumber::new(0x1000, Some(0xf80)).len(), 2);
+        assert_eq!(PacketNumber::new(0x7fff, Some(0)).len(), 2);
+        assert_eq!(PacketNumber::new(0x8000, Some(0)).len(), 3);
+        assert_eq!(PacketNumber::new(0x80_0000, Some(0)).len(), 4);
     }
 
     #[test]
     fn pn_expand_roundtrip() {
         for expected in 0..1024 {
             for actual in expected..1024 {
-                assert_eq!(actual, PacketNumber::new(actual, expected).expand(expected));
+                assert_eq!(
+                    actual,
+                    PacketNumber::new(actual, Some(expected)).expand(expected)
+                );
             }
         }
     }
@@ -853,7 +878,7 @@ mod tests {
         };
 
         let dcid = ConnectionId::new(&hex!("06b858ec6f80452b"));
-        let client_crypto = Crypto::new_initial(&dcid, Side::Client);
+        let client_crypto = Crypto::new_initial(&dcid, Side::Client, VERSION);
         let client_header_crypto = client_crypto.header_keys();
         let mut buf = Vec::new();
         let header = Header::Initial {
@@ -879,7 +904,7 @@ mod tests {
             )[..]
         );
 
-        let server_crypto = Crypto::new_initial(&dcid, Side::Server);
+        let server_crypto = Crypto::new_initial(&dcid, Side::Server, VERSION);
         let server_header_crypto = server_crypto.header_keys();
         let decode = PartialDecode::new(buf.as_slice().into(), 0).unwrap().0;
         let mut packet = decode.finish(Some(&server_header_crypto)).unwrap();
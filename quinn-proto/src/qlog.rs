@@ -0,0 +1,526 @@
+//! Connection traces in the qlog format, for inspection in visualization tools such as qvis
+//!
+//! Events are written as JSON text sequences, the streaming serialization of qlog 0.3, with the
+//! events of each connection grouped under the destination CID of the client's first Initial.
+
+use std::{
+    fmt::{self, Write as _},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tracing::warn;
+
+use crate::{
+    frame::{self, Frame},
+    packet::{Header, LongType, SpaceId},
+    shared::ConnectionId,
+    transport_parameters::TransportParameters,
+    Dir, Side,
+};
+
+/// Destination of the qlog traces of connections, set through `TransportConfig::qlog_writer`
+///
+/// Every connection sharing the `TransportConfig` writes to it. Tracing stops if writing fails.
+pub struct QlogSink {
+    state: Mutex<SinkState>,
+}
+
+struct SinkState {
+    writer: Box<dyn Write + Send>,
+    /// When the first event occurred, which event times are relative to
+    reference: Option<Instant>,
+    failed: bool,
+}
+
+impl QlogSink {
+    /// Write traces to `writer`, which should be buffered
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            state: Mutex::new(SinkState {
+                writer,
+                reference: None,
+                failed: false,
+            }),
+        }
+    }
+
+    fn write(&self, now: Instant, side: Side, group_id: &str, name: &str, data: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.failed {
+            return;
+        }
+        let mut record = String::new();
+        let reference = match state.reference {
+            Some(x) => x,
+            None => {
+                let unix = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_else(|_| Duration::new(0, 0));
+                let vantage_point = match side {
+                    Side::Client => "client",
+                    Side::Server => "server",
+                };
+                writeln!(
+                    record,
+                    "\x1e{{\"qlog_version\":\"0.3\",\"qlog_format\":\"JSON-SEQ\",\"title\":\"quinn\",\
+                     \"trace\":{{\"common_fields\":{{\"time_format\":\"relative\",\
+                     \"reference_time\":{}}},\"vantage_point\":{{\"type\":\"{}\"}}}}}}",
+                    millis(unix),
+                    vantage_point
+                )
+                .unwrap();
+                state.reference = Some(now);
+                now
+            }
+        };
+        let time = if now > reference {
+            now - reference
+        } else {
+            Duration::new(0, 0)
+        };
+        writeln!(
+            record,
+            "\x1e{{\"time\":{},\"name\":\"{}\",\"group_id\":\"{}\",\"data\":{}}}",
+            millis(time),
+            name,
+            group_id,
+            data
+        )
+        .unwrap();
+        if let Err(e) = state.writer.write_all(record.as_bytes()) {
+            warn!("qlog tracing stopped: {}", e);
+            state.failed = true;
+        }
+    }
+}
+
+impl fmt::Debug for QlogSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QlogSink").finish()
+    }
+}
+
+/// Traces the events of a single connection to a `QlogSink`
+pub(crate) struct QlogTrace {
+    sink: Arc<QlogSink>,
+    side: Side,
+    group_id: String,
+    /// Most recently reported congestion and RTT metrics, so that only changes are reported
+    metrics: Metrics,
+}
+
+#[derive(Default, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Metrics {
+    pub congestion_window: u64,
+    pub bytes_in_flight: u64,
+    pub smoothed_rtt: Option<Duration>,
+    pub min_rtt: Duration,
+    pub latest_rtt: Duration,
+}
+
+impl QlogTrace {
+    pub(crate) fn new(sink: Arc<QlogSink>, side: Side, init_cid: &ConnectionId) -> Self {
+        Self {
+            sink,
+            side,
+            group_id: init_cid.to_string(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub(crate) fn parameters_set(&self, now: Instant, local: bool, params: &TransportParameters) {
+        let mut data = Object::new();
+        data.str("owner", if local { "local" } else { "remote" });
+        if let Some(ref cid) = params.original_connection_id {
+            data.str("original_destination_connection_id", &cid.to_string());
+        }
+        data.bool("disable_active_migration", params.disable_active_migration);
+        data.num("max_idle_timeout", params.idle_timeout);
+        data.num("max_udp_payload_size", params.max_packet_size);
+        data.num("ack_delay_exponent", params.ack_delay_exponent);
+        data.num("max_ack_delay", params.max_ack_delay);
+        data.num(
+            "active_connection_id_limit",
+            params.active_connection_id_limit,
+        );
+        data.num("initial_max_data", params.initial_max_data);
+        data.num(
+            "initial_max_stream_data_bidi_local",
+            params.initial_max_stream_data_bidi_local,
+        );
+        data.num(
+            "initial_max_stream_data_bidi_remote",
+            params.initial_max_stream_data_bidi_remote,
+        );
+        data.num(
+            "initial_max_stream_data_uni",
+            params.initial_max_stream_data_uni,
+        );
+        data.num("initial_max_streams_bidi", params.initial_max_streams_bidi);
+        data.num("initial_max_streams_uni", params.initial_max_streams_uni);
+        if let Some(x) = params.max_datagram_frame_size {
+            data.num("max_datagram_frame_size", x.into_inner());
+        }
+        self.event(now, "transport:parameters_set", data);
+    }
+
+    /// A packet was sent, `payload` holding its frames before encryption
+    pub(crate) fn packet_sent(
+        &self,
+        now: Instant,
+        header: &Header,
+        number: u64,
+        length: usize,
+        payload: &[u8],
+    ) {
+        let data = packet(header_type(header), Some(number), length, Some(payload));
+        self.event(now, "transport:packet_sent", data);
+    }
+
+    /// A packet was received, `payload` holding its decrypted frames if it has any
+    pub(crate) fn packet_received(
+        &self,
+        now: Instant,
+        header: &Header,
+        number: Option<u64>,
+        length: usize,
+        payload: &[u8],
+    ) {
+        // Retry and Version Negotiation packets don't carry frames
+        let payload = number.map(|_| payload);
+        let data = packet(header_type(header), number, length, payload);
+        self.event(now, "transport:packet_received", data);
+    }
+
+    pub(crate) fn packet_lost(&self, now: Instant, space: SpaceId, number: u64) {
+        let ty = match space {
+            SpaceId::Initial => "initial",
+            SpaceId::Handshake => "handshake",
+            SpaceId::Data => "1RTT",
+        };
+        let mut data = Object::new();
+        data.object("header", packet_header(ty, Some(number)));
+        self.event(now, "recovery:packet_lost", data);
+    }
+
+    /// Report the congestion and RTT metrics, if they changed since last reported
+    pub(crate) fn metrics_updated(&mut self, now: Instant, metrics: Metrics) {
+        if metrics == self.metrics {
+            return;
+        }
+        self.metrics = metrics;
+        let mut data = Object::new();
+        data.num("congestion_window", metrics.congestion_window);
+        data.num("bytes_in_flight", metrics.bytes_in_flight);
+        if let Some(x) = metrics.smoothed_rtt {
+            data.raw("smoothed_rtt", &millis(x));
+        }
+        data.raw("min_rtt", &millis(metrics.min_rtt));
+        data.raw("latest_rtt", &millis(metrics.latest_rtt));
+        self.event(now, "recovery:metrics_updated", data);
+    }
+
+    fn event(&self, now: Instant, name: &str, data: Object) {
+        self.sink
+            .write(now, self.side, &self.group_id, name, &data.finish());
+    }
+}
+
+fn header_type(header: &Header) -> &'static str {
+    match *header {
+        Header::Initial { .. } => "initial",
+        Header::Long {
+            ty: LongType::Handshake,
+            ..
+        } => "handshake",
+        Header::Long {
+            ty: LongType::ZeroRtt,
+            ..
+        } => "0RTT",
+        Header::Retry { .. } => "retry",
+        Header::Short { .. } => "1RTT",
+        Header::VersionNegotiate { .. } => "version_negotiation",
+    }
+}
+
+fn packet_header(ty: &str, number: Option<u64>) -> Object {
+    let mut header = Object::new();
+    header.str("packet_type", ty);
+    if let Some(number) = number {
+        header.num("packet_number", number);
+    }
+    header
+}
+
+fn packet(ty: &str, number: Option<u64>, length: usize, payload: Option<&[u8]>) -> Object {
+    let mut data = Object::new();
+    data.object("header", packet_header(ty, number));
+    let mut raw = Object::new();
+    raw.num("length", length as u64);
+    data.object("raw", raw);
+    if let Some(payload) = payload {
+        data.raw("frames", &frames(payload));
+    }
+    data
+}
+
+/// JSON array describing the frames in `payload`
+fn frames(payload: &[u8]) -> String {
+    let mut out = String::from("[");
+    let mut padding = 0;
+    let mut first = true;
+    for frame in frame::Iter::new(Bytes::copy_from_slice(payload)) {
+        if let Frame::Padding = frame {
+            padding += 1;
+            continue;
+        }
+        if !first {
+            out.push(',');
+        }
+        first = false;
+        out.push_str(&describe(&frame).finish());
+    }
+    if padding != 0 {
+        if !first {
+            out.push(',');
+        }
+        let mut frame = Object::new();
+        frame.str("frame_type", "padding");
+        frame.num("length", padding);
+        out.push_str(&frame.finish());
+    }
+    out.push(']');
+    out
+}
+
+fn describe(frame: &Frame) -> Object {
+    let mut out = Object::new();
+    match *frame {
+        Frame::Padding => out.str("frame_type", "padding"),
+        Frame::Ping => out.str("frame_type", "ping"),
+        Frame::Ack(ref ack) => {
+            out.str("frame_type", "ack");
+            let mut ranges = String::from("[");
+            for (i, range) in ack.iter().enumerate() {
+                if i != 0 {
+                    ranges.push(',');
+                }
+                write!(ranges, "[{},{}]", range.start, range.end - 1).unwrap();
+            }
+            ranges.push(']');
+            out.raw("acked_ranges", &ranges);
+        }
+        Frame::ResetStream(ref x) => {
+            out.str("frame_type", "reset_stream");
+            out.num("stream_id", x.id.0);
+            out.num("error_code", x.error_code.into_inner());
+            out.num("final_size", x.final_offset);
+        }
+        Frame::StopSending(ref x) => {
+            out.str("frame_type", "stop_sending");
+            out.num("stream_id", x.id.0);
+            out.num("error_code", x.error_code.into_inner());
+        }
+        Frame::Crypto(ref x) => {
+            out.str("frame_type", "crypto");
+            out.num("offset", x.offset);
+            out.num("length", x.data.len() as u64);
+        }
+        Frame::NewToken { ref token } => {
+            out.str("frame_type", "new_token");
+            out.num("length", token.len() as u64);
+        }
+        Frame::Stream(ref x) => {
+            out.str("frame_type", "stream");
+            out.num("stream_id", x.id.0);
+            out.num("offset", x.offset);
+            out.num("length", x.data.len() as u64);
+            out.bool("fin", x.fin);
+        }
+        Frame::MaxData(x) => {
+            out.str("frame_type", "max_data");
+            out.num("maximum", x);
+        }
+        Frame::MaxStreamData { id, offset } => {
+            out.str("frame_type", "max_stream_data");
+            out.num("stream_id", id.0);
+            out.num("maximum", offset);
+        }
+        Frame::MaxStreams { dir, count } => {
+            out.str("frame_type", "max_streams");
+            out.str("stream_type", stream_type(dir));
+            out.num("maximum", count);
+        }
+        Frame::DataBlocked { offset } => {
+            out.str("frame_type", "data_blocked");
+            out.num("limit", offset);
+        }
+        Frame::StreamDataBlocked { id, offset } => {
+            out.str("frame_type", "stream_data_blocked");
+            out.num("stream_id", id.0);
+            out.num("limit", offset);
+        }
+        Frame::StreamsBlocked { dir, limit } => {
+            out.str("frame_type", "streams_blocked");
+            out.str("stream_type", stream_type(dir));
+            out.num("limit", limit);
+        }
+        Frame::NewConnectionId(ref x) => {
+            out.str("frame_type", "new_connection_id");
+            out.num("sequence_number", x.sequence);
+            out.num("retire_prior_to", x.retire_prior_to);
+            out.str("connection_id", &x.id.to_string());
+        }
+        Frame::RetireConnectionId { sequence } => {
+            out.str("frame_type", "retire_connection_id");
+            out.num("sequence_number", sequence);
+        }
+        Frame::PathChallenge(x) => {
+            out.str("frame_type", "path_challenge");
+            out.str("data", &format!("{:016x}", x));
+        }
+        Frame::PathResponse(x) => {
+            out.str("frame_type", "path_response");
+            out.str("data", &format!("{:016x}", x));
+        }
+        Frame::Close(frame::Close::Connection(ref x)) => {
+            out.str("frame_type", "connection_close");
+            out.str("error_space", "transport");
+            out.num("error_code", u64::from(x.error_code));
+            out.str("reason", &String::from_utf8_lossy(&x.reason));
+        }
+        Frame::Close(frame::Close::Application(ref x)) => {
+            out.str("frame_type", "connection_close");
+            out.str("error_space", "application");
+            out.num("error_code", x.error_code.into_inner());
+            out.str("reason", &String::from_utf8_lossy(&x.reason));
+        }
+        Frame::Datagram(ref x) => {
+            out.str("frame_type", "datagram");
+            out.num("length", x.data.len() as u64);
+        }
+        Frame::AckFrequency(ref x) => {
+            out.str("frame_type", "ack_frequency");
+            out.num("sequence_number", x.sequence);
+            out.num("packet_tolerance", x.packet_tolerance);
+            out.num("update_max_ack_delay", x.update_max_ack_delay);
+            out.bool("ignore_order", x.ignore_order);
+        }
+        Frame::ImmediateAck => out.str("frame_type", "immediate_ack"),
+        Frame::Invalid { ty, reason } => {
+            out.str("frame_type", "unknown");
+            out.num("raw_frame_type", ty.0);
+            out.str("reason", reason);
+        }
+    }
+    out
+}
+
+fn stream_type(dir: Dir) -> &'static str {
+    match dir {
+        Dir::Bi => "bidirectional",
+        Dir::Uni => "unidirectional",
+    }
+}
+
+/// Milliseconds, with microsecond precision
+fn millis(x: Duration) -> String {
+    format!("{}.{:03}", x.as_millis(), x.subsec_micros() % 1000)
+}
+
+/// Builder for a JSON object
+struct Object(String);
+
+impl Object {
+    fn new() -> Self {
+        Object(String::from("{"))
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.0.len() > 1 {
+            self.0.push(',');
+        }
+        write!(self.0, "\"{}\":", key).unwrap();
+    }
+
+    fn num(&mut self, key: &str, value: u64) {
+        self.key(key);
+        write!(self.0, "{}", value).unwrap();
+    }
+
+    fn bool(&mut self, key: &str, value: bool) {
+        self.key(key);
+        write!(self.0, "{}", value).unwrap();
+    }
+
+    fn str(&mut self, key: &str, value: &str) {
+        self.key(key);
+        self.0.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => self.0.push_str("\\\""),
+                '\\' => self.0.push_str("\\\\"),
+                '\n' => self.0.push_str("\\n"),
+                '\r' => self.0.push_str("\\r"),
+                '\t' => self.0.push_str("\\t"),
+                c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32).unwrap(),
+                c => self.0.push(c),
+            }
+        }
+        self.0.push('"');
+    }
+
+    /// Insert `value`, already serialized as JSON
+    fn raw(&mut self, key: &str, value: &str) {
+        self.key(key);
+        self.0.push_str(value);
+    }
+
+    fn object(&mut self, key: &str, value: Object) {
+        self.raw(key, &value.finish());
+    }
+
+    fn finish(mut self) -> String {
+        self.0.push('}');
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape() {
+        let mut x = Object::new();
+        x.str("reason", "a \"quoted\"\\\n\u{1}");
+        x.num("n", 3);
+        assert_eq!(x.finish(), r#"{"reason":"a \"quoted\"\\\n\u0001","n":3}"#);
+    }
+
+    #[test]
+    fn stream_frames() {
+        let mut payload = Vec::new();
+        frame::Stream {
+            id: crate::StreamId(4),
+            offset: 10,
+            fin: true,
+            data: Bytes::from_static(b"hello"),
+        }
+        .encode(true, &mut payload);
+        payload.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(
+            frames(&payload),
+            r#"[{"frame_type":"stream","stream_id":4,"offset":10,"length":5,"fin":true},{"frame_type":"padding","length":3}]"#
+        );
+    }
+
+    #[test]
+    fn millis_precision() {
+        assert_eq!(millis(Duration::from_micros(12_345)), "12.345");
+        assert_eq!(millis(Duration::from_micros(7)), "0.007");
+    }
+}
@@ -1,4 +1,10 @@
-use std::{cmp, fmt, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    cmp, fmt,
+    io::Write,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::Instant,
+};
 
 use bytes::BytesMut;
 use err_derive::Error;
@@ -6,9 +12,11 @@ use rand::{Rng, RngCore};
 use tracing::warn;
 
 use crate::{
+    congestion,
     crypto::{self, ClientConfig as _, ServerConfig as _},
     packet::PartialDecode,
-    VarInt, MAX_CID_SIZE, RESET_TOKEN_SIZE,
+    qlog::QlogSink,
+    VarInt, MAX_CID_SIZE, REM_CID_COUNT, RESET_TOKEN_SIZE,
 };
 
 /// Parameters governing the core QUIC state machine
@@ -58,6 +66,9 @@ pub struct TransportConfig {
     /// stream doesn't monopolize receive buffers, which may otherwise occur if the application
     /// chooses not to read from a large stream for a time while still requiring data on other
     /// streams.
+    ///
+    /// The window is fixed rather than tuned to the observed throughput: credit is re-granted as
+    /// the application reads, but a stream never has more than this much unread data outstanding.
     pub stream_receive_window: u64,
     /// Maximum number of bytes the peer may transmit across all streams of a connection before
     /// becoming blocked.
@@ -65,6 +76,9 @@ pub struct TransportConfig {
     /// This should be set to at least the expected connection latency multiplied by the maximum
     /// desired throughput. Larger values can be useful to allow maximum throughput within a
     /// stream while another is blocked.
+    ///
+    /// Like `stream_receive_window`, this is a fixed bound on unread data rather than a starting
+    /// point for auto-tuning.
     pub receive_window: u64,
     /// Maximum number of bytes to transmit to a peer without acknowledgment
     ///
@@ -78,12 +92,35 @@ pub struct TransportConfig {
     pub max_tlps: u32,
     /// Maximum reordering in packet number space before FACK style loss detection considers a
     /// packet lost.
+    ///
+    /// Should not be less than 3. Raising this avoids spurious retransmissions on paths which
+    /// frequently reorder packets, at the cost of slower recovery from genuine loss.
     pub packet_threshold: u32,
     /// Maximum reordering in time space before time based loss detection considers a packet lost.
     /// 0.16 format, added to 1
+    ///
+    /// This scales the round trip time, so e.g. `0x2000` declares a packet lost once a later packet
+    /// has been acknowledged and it has been outstanding for 9/8 of the RTT. Raise alongside
+    /// `packet_threshold` on paths with heavy reordering.
     pub time_threshold: u16,
     /// The length of the peer’s delayed ack timer (μs).
+    ///
+    /// Requested from peers supporting the ACK frequency extension when `ack_packet_tolerance` is
+    /// greater than 1.
     pub delayed_ack_timeout: u64,
+    /// Smallest delay (μs) a peer may ask us to wait before acknowledging its packets, or None to
+    /// disable the ACK frequency extension
+    ///
+    /// Advertising this allows a peer to send ACK_FREQUENCY frames asking us to acknowledge its
+    /// packets less often, reducing ACK traffic on high-throughput paths. Must be at most 16383 ms.
+    pub min_ack_delay: Option<u64>,
+    /// Number of ack-eliciting packets a peer supporting the ACK frequency extension should receive
+    /// before acknowledging them
+    ///
+    /// Values greater than 1 are requested in an ACK_FREQUENCY frame once the handshake completes,
+    /// along with `delayed_ack_timeout` as the longest time the peer may wait. 1 leaves the peer's
+    /// ack behavior unchanged.
+    pub ack_packet_tolerance: u64,
     /// The RTT used before an RTT sample is taken (μs)
     pub initial_rtt: u64,
 
@@ -103,6 +140,11 @@ pub struct TransportConfig {
     pub loss_reduction_factor: u16,
     /// Number of consecutive PTOs after which network is considered to be experiencing persistent congestion.
     pub persistent_congestion_threshold: u32,
+    /// Congestion control algorithm to use, or None for `congestion::NewReno`
+    ///
+    /// `max_datagram_size`, `initial_window`, `minimum_window` and `loss_reduction_factor` only
+    /// apply to `NewReno`.
+    pub congestion_controller_factory: Option<Arc<dyn congestion::ControllerFactory + Send + Sync>>,
     /// Number of milliseconds of inactivity before sending a keep-alive packet
     ///
     /// Keep-alive packets prevent an inactive but otherwise healthy connection from timing out.
@@ -111,13 +153,35 @@ pub struct TransportConfig {
     /// enabled for the connection to be preserved. Must be set lower than the idle_timeout of both
     /// peers to be effective.
     pub keep_alive_interval: u32,
+    /// Fraction of a receive window the application must consume before the peer is issued more
+    /// flow control credit, in 0.16 format
+    ///
+    /// Applies to `receive_window` and `stream_receive_window` alike. Higher values save on
+    /// MAX_DATA and MAX_STREAM_DATA frames, at the risk of the peer stalling while it waits for
+    /// credit. 0 issues credit whenever anything is read.
+    pub window_update_threshold: u16,
     /// Maximum quantity of out-of-order crypto layer data to buffer
     pub crypto_buffer_size: usize,
+    /// Maximum quantity of crypto layer data to accept from the peer in any one packet number
+    /// space, or None for no limit
+    ///
+    /// Bounds the size of the peer's handshake, e.g. its certificate chain, as a whole. Data beyond
+    /// the limit is rejected before being buffered, closing the connection with
+    /// `CRYPTO_BUFFER_EXCEEDED`. Amounts received so far are reported by
+    /// `Connection::crypto_stats`.
+    pub max_handshake_data: Option<u64>,
     /// Whether the implementation is permitted to set the spin bit on this connection
     ///
     /// This allows passive observers to easily judge the round trip time of a connection, which can
     /// be useful for network administration but sacrifices a small amount of privacy.
     pub allow_spin: bool,
+    /// Whether to pin header bits that would otherwise vary randomly to fixed values
+    ///
+    /// Disables the spin bit as `allow_spin: false` would, but sends it as zero rather than
+    /// greasing it with random values, so that packet headers are a deterministic function of
+    /// connection state. Useful when comparing packet captures across runs, but makes the
+    /// connection easier to fingerprint, so it should be left off outside of testing and analysis.
+    pub deterministic_header_bits: bool,
     /// Maximum number of incoming application datagram bytes to buffer, or None to disable
     /// datagrams
     ///
@@ -131,6 +195,32 @@ pub struct TransportConfig {
     /// than the link, or even the underlying hardware, can transmit them. This limits the amount of
     /// memory that may be consumed in that case.
     pub datagram_send_buffer_size: usize,
+    /// Maximum number of bytes buffered by a connection in total, or None for no limit
+    ///
+    /// Covers unread and outgoing stream data, queued datagrams in either direction, and
+    /// out-of-order crypto data, as well as the flow control credit extended to the peer. As the
+    /// limit is approached, the connection stops issuing new credit and refuses further writes
+    /// until memory is released by the application reading or the peer acknowledging data. Should
+    /// buffered data exceed the limit anyway, e.g. due to incoming datagrams, the connection is
+    /// closed.
+    ///
+    /// Must be at least `receive_window`.
+    pub max_connection_memory: Option<u64>,
+    /// Maximum number of connection IDs issued by the peer that we're willing to store
+    ///
+    /// Advertised to the peer as `active_connection_id_limit`. A peer that issues more live
+    /// connection IDs than this is treated as malicious and the connection is closed with
+    /// `CONNECTION_ID_LIMIT_ERROR`. Must be at least 2.
+    pub active_connection_id_limit: u64,
+    /// Destination of qlog traces of connections' events, or None to disable tracing
+    ///
+    /// Usually set through `qlog_writer`.
+    pub qlog: Option<Arc<QlogSink>>,
+    /// Address family clients prefer where the server is reachable over both (clients only)
+    ///
+    /// Governs which of the server's preferred addresses a client migrates to. Applications racing
+    /// connection attempts to several addresses can consult it too.
+    pub ip_preference: IpPreference,
 }
 
 impl Default for TransportConfig {
@@ -154,6 +244,8 @@ impl Default for TransportConfig {
             packet_threshold: 3,
             time_threshold: 0x2000, // 1/8
             delayed_ack_timeout: 25 * 1000,
+            min_ack_delay: None,
+            ack_packet_tolerance: 1,
             initial_rtt: 500 * 1000, // 500ms per spec, intentionally distinct from EXPECTED_RTT
 
             max_datagram_size: MAX_DATAGRAM_SIZE,
@@ -164,16 +256,34 @@ impl Default for TransportConfig {
             minimum_window: 2 * MAX_DATAGRAM_SIZE,
             loss_reduction_factor: 0x8000, // 1/2
             persistent_congestion_threshold: 3,
+            congestion_controller_factory: None,
             keep_alive_interval: 0,
+            window_update_threshold: 0x2000, // 1/8
             crypto_buffer_size: 16 * 1024,
+            max_handshake_data: None,
             allow_spin: true,
+            deterministic_header_bits: false,
             datagram_receive_buffer_size: Some(STREAM_RWND as usize),
             datagram_send_buffer_size: 1024 * 1024,
+            max_connection_memory: None,
+            active_connection_id_limit: REM_CID_COUNT,
+            qlog: None,
+            ip_preference: IpPreference::LowestRtt,
         }
     }
 }
 
 impl TransportConfig {
+    /// Trace the events of connections to `writer` in the qlog format
+    ///
+    /// Records packets sent, received and lost, congestion window and RTT updates, and transport
+    /// parameters, for inspection in tools such as qvis. `writer` is written to for every packet,
+    /// so should be buffered.
+    pub fn qlog_writer(&mut self, writer: Box<dyn Write + Send>) -> &mut Self {
+        self.qlog = Some(Arc::new(QlogSink::new(writer)));
+        self
+    }
+
     pub(crate) fn validate(&self) -> Result<(), ConfigError> {
         if let Some((name, _)) = [
             ("stream_window_bidi", self.stream_window_bidi),
@@ -181,6 +291,10 @@ impl TransportConfig {
             ("receive_window", self.receive_window),
             ("stream_receive_window", self.stream_receive_window),
             ("idle_timeout", self.idle_timeout),
+            (
+                "active_connection_id_limit",
+                self.active_connection_id_limit,
+            ),
         ]
         .iter()
         .find(|&&(_, x)| x > VarInt::MAX.into_inner())
@@ -192,6 +306,35 @@ impl TransportConfig {
                 "crypto_buffer_size must be at least 4096",
             ));
         }
+        if self
+            .max_connection_memory
+            .map_or(false, |x| x < self.receive_window)
+        {
+            return Err(ConfigError::IllegalValue(
+                "max_connection_memory must be at least receive_window",
+            ));
+        }
+        if self.active_connection_id_limit < 2 {
+            return Err(ConfigError::IllegalValue(
+                "active_connection_id_limit must be at least 2",
+            ));
+        }
+        if self.min_ack_delay.map_or(false, |x| x > 16_383_000) {
+            return Err(ConfigError::IllegalValue(
+                "min_ack_delay must be at most 16383 ms",
+            ));
+        }
+        if self.ack_packet_tolerance == 0 {
+            return Err(ConfigError::IllegalValue(
+                "ack_packet_tolerance must be at least 1",
+            ));
+        }
+        if self.packet_threshold < 3 {
+            warn!(
+                "packet threshold {} is likely to cause spurious retransmissions",
+                self.packet_threshold
+            );
+        }
         if self.idle_timeout != 0 && u64::from(self.keep_alive_interval) >= self.idle_timeout {
             warn!(
                 "keep-alive interval {} is ineffective due to lower idle timeout {}",
@@ -218,6 +361,16 @@ pub struct EndpointConfig {
     /// Private key used to send authenticated connection resets to peers who were
     /// communicating with a previous instance of this endpoint.
     pub reset_key: Vec<u8>,
+
+    /// Seed for the random number generator used by the endpoint and its connections
+    ///
+    /// When set, connection IDs, stateless reset padding, path challenges and other values drawn
+    /// from that generator are derived deterministically from the seed, which is useful for
+    /// reproducible tests, fuzzing and simulation. They are then predictable to anyone who knows
+    /// the seed, so this must be left as `None`, the default, in production, where the generator is
+    /// seeded by the operating system. TLS randomness is unaffected, as are `reset_key` and
+    /// `ServerConfig::token_key`, which must be set explicitly for full determinism.
+    pub rng_seed: Option<[u8; 32]>,
 }
 
 impl fmt::Debug for EndpointConfig {
@@ -225,6 +378,7 @@ impl fmt::Debug for EndpointConfig {
         fmt.debug_struct("EndpointConfig")
             .field("local_cid_len", &self.local_cid_len)
             .field("reset_key", &"[ elided ]")
+            .field("rng_seed", &self.rng_seed.map(|_| "[ elided ]"))
             .finish()
     }
 }
@@ -236,6 +390,7 @@ impl Default for EndpointConfig {
         Self {
             local_cid_len: 8,
             reset_key,
+            rng_seed: None,
         }
     }
 }
@@ -271,9 +426,20 @@ where
     /// Whether to require clients to prove ownership of an address before committing resources.
     ///
     /// Introduces an additional round-trip to the handshake to make denial of service attacks more difficult.
+    /// Clients are sent a Retry packet carrying an address validation token, authenticated with
+    /// `token_key` and valid for `retry_token_lifetime`, which they must echo in their next Initial.
+    /// As of draft 24, Retry packets echo the client's original destination connection ID rather
+    /// than carrying an integrity tag.
     pub use_stateless_retry: bool,
     /// Microseconds after a stateless retry token was issued for which it's considered valid.
     pub retry_token_lifetime: u64,
+    /// Microseconds for which tokens sent to clients in NEW_TOKEN frames are valid
+    ///
+    /// Once a connection is established, the client is sent a token it can present when connecting
+    /// again, proving ownership of its address without the round trip of a stateless retry, and
+    /// lifting the limit on data sent before its address is validated. Tokens may be used any
+    /// number of times until they expire. Set to `0` to disable.
+    pub new_token_lifetime: u64,
 
     /// Maximum number of incoming connections to buffer.
     ///
@@ -285,6 +451,71 @@ where
     /// Improves behavior for clients that move between different internet connections or suffer NAT
     /// rebinding. Enabled by default.
     pub migration: bool,
+    /// Microseconds after a client migrates before another migration will be followed
+    ///
+    /// A migration arriving soon after the previous wait ended doubles the wait, up to
+    /// `max_migration_backoff`, so that a misbehaving peer or a flapping network can't keep the
+    /// server validating new paths. Packets from a new address during the wait are still processed,
+    /// but responses continue to use the current path. `0`, the default, disables the wait.
+    pub migration_backoff: u64,
+    /// Upper bound in microseconds on the wait imposed by `migration_backoff`
+    pub max_migration_backoff: u64,
+    /// Address IPv4 clients should migrate to once the handshake is confirmed
+    ///
+    /// Advertised in the `preferred_address` transport parameter, along with a connection ID
+    /// reserved for use on it. Allows a server to accept connections on a shared address, e.g. an
+    /// anycast address, then serve them from an address of its own. The endpoint must receive
+    /// packets sent to this address. Ignored if `EndpointConfig::local_cid_len` is 0.
+    pub preferred_address_v4: Option<SocketAddrV4>,
+    /// Address IPv6 clients should migrate to once the handshake is confirmed
+    ///
+    /// See `preferred_address_v4`.
+    pub preferred_address_v6: Option<SocketAddrV6>,
+
+    /// Number of recently closed connections for which duplicate Initial packets are recognized
+    ///
+    /// A late or duplicated copy of a client's first Initial packet that arrives after its
+    /// connection has been closed is ignored, rather than repeating the handshake work or starting
+    /// a new connection. Identified by the Initial's destination connection ID. Set to `0` to
+    /// disable.
+    pub initial_cid_cache: usize,
+
+    /// Minimum microseconds between Version Negotiation packets sent to the same IP address
+    ///
+    /// Packets with unsupported versions arriving from an address more often than this are
+    /// dropped, so that spoofed packets can't turn the server into a reflector. Version
+    /// Negotiation packets are also never larger than the datagram that triggered them. Set to `0`
+    /// to disable rate limiting.
+    pub version_negotiation_interval: u64,
+    /// Number of addresses `version_negotiation_interval` is enforced for at once
+    ///
+    /// Once this many addresses were recently sent a Version Negotiation packet, the one that was
+    /// sent it longest ago is forgotten to make room for the next, bounding the memory used.
+    pub version_negotiation_cache: usize,
+
+    /// Whether to reject clients with which ALPN negotiates no application protocol
+    ///
+    /// A protocol is negotiated when the client offers one of the protocols configured in the
+    /// crypto configuration. Rejected connections fail with the `no_application_protocol` crypto
+    /// error. Since a client offering none in common can't be told apart from one that doesn't use
+    /// ALPN at all, both are accepted by default. Has no effect if `default_protocol` is set.
+    pub require_protocol: bool,
+    /// Application protocol assumed for clients with which ALPN negotiates none
+    ///
+    /// `Connection::protocol` reports this protocol for such clients.
+    pub default_protocol: Option<Vec<u8>>,
+
+    /// Cache used to refuse 0-RTT data from replayed handshakes
+    ///
+    /// Without one, 0-RTT data replayed by an attacker is processed again. See
+    /// `crypto::AntiReplay`.
+    pub anti_replay: Option<Arc<dyn crypto::AntiReplay>>,
+    /// Application protocols for which 0-RTT data is never accepted
+    ///
+    /// For protocols whose requests aren't safe to process more than once, as 0-RTT data may be
+    /// replayed. Clients negotiating one of these send their early data again once the handshake
+    /// completes.
+    pub refused_early_data_protocols: Vec<Vec<u8>>,
 }
 
 impl<S> fmt::Debug for ServerConfig<S>
@@ -298,8 +529,26 @@ where
             .field("token_key", &"[ elided ]")
             .field("use_stateless_retry", &self.use_stateless_retry)
             .field("retry_token_lifetime", &self.retry_token_lifetime)
+            .field("new_token_lifetime", &self.new_token_lifetime)
             .field("accept_buffer", &self.accept_buffer)
             .field("migration", &self.migration)
+            .field("migration_backoff", &self.migration_backoff)
+            .field("max_migration_backoff", &self.max_migration_backoff)
+            .field("preferred_address_v4", &self.preferred_address_v4)
+            .field("preferred_address_v6", &self.preferred_address_v6)
+            .field("initial_cid_cache", &self.initial_cid_cache)
+            .field(
+                "version_negotiation_interval",
+                &self.version_negotiation_interval,
+            )
+            .field("version_negotiation_cache", &self.version_negotiation_cache)
+            .field("require_protocol", &self.require_protocol)
+            .field("default_protocol", &self.default_protocol)
+            .field("anti_replay", &self.anti_replay.is_some())
+            .field(
+                "refused_early_data_protocols",
+                &self.refused_early_data_protocols,
+            )
             .finish()
     }
 }
@@ -321,10 +570,23 @@ where
             token_key,
             use_stateless_retry: false,
             retry_token_lifetime: 15_000_000,
+            new_token_lifetime: 0,
 
             accept_buffer: 1024,
 
             migration: true,
+            migration_backoff: 0,
+            max_migration_backoff: 60_000_000,
+            preferred_address_v4: None,
+            preferred_address_v6: None,
+
+            initial_cid_cache: 256,
+            version_negotiation_interval: 1_000_000,
+            version_negotiation_cache: 4096,
+            require_protocol: false,
+            default_protocol: None,
+            anti_replay: None,
+            refused_early_data_protocols: Vec::new(),
         }
     }
 }
@@ -341,8 +603,20 @@ where
             token_key: self.token_key.clone(),
             use_stateless_retry: self.use_stateless_retry,
             retry_token_lifetime: self.retry_token_lifetime,
+            new_token_lifetime: self.new_token_lifetime,
             accept_buffer: self.accept_buffer,
             migration: self.migration,
+            migration_backoff: self.migration_backoff,
+            max_migration_backoff: self.max_migration_backoff,
+            preferred_address_v4: self.preferred_address_v4,
+            preferred_address_v6: self.preferred_address_v6,
+            initial_cid_cache: self.initial_cid_cache,
+            version_negotiation_interval: self.version_negotiation_interval,
+            version_negotiation_cache: self.version_negotiation_cache,
+            require_protocol: self.require_protocol,
+            default_protocol: self.default_protocol.clone(),
+            anti_replay: self.anti_replay.clone(),
+            refused_early_data_protocols: self.refused_early_data_protocols.clone(),
         }
     }
 }
@@ -359,6 +633,26 @@ where
 
     /// Cryptographic configuration to use
     pub crypto: S::ClientConfig,
+
+    /// Where address validation tokens received from servers are kept for later connections
+    pub(crate) token_storage: Arc<dyn crypto::SessionStorage>,
+}
+
+impl<S> ClientConfig<S>
+where
+    S: crypto::Session,
+{
+    /// Where address validation tokens received from servers are kept for later connections
+    pub fn token_storage(&self) -> &Arc<dyn crypto::SessionStorage> {
+        &self.token_storage
+    }
+
+    /// Keep the address validation tokens received from servers in `storage`
+    ///
+    /// By default, they're kept in a `MemorySessionStorage` private to the configuration.
+    pub fn set_token_storage(&mut self, storage: Arc<dyn crypto::SessionStorage>) {
+        self.token_storage = storage;
+    }
 }
 
 impl<S> Default for ClientConfig<S>
@@ -369,6 +663,9 @@ where
         Self {
             transport: Default::default(),
             crypto: S::ClientConfig::new(),
+            token_storage: Arc::new(crypto::MemorySessionStorage::new(
+                crypto::DEFAULT_SESSION_CAPACITY,
+            )),
         }
     }
 }
@@ -382,6 +679,7 @@ where
         Self {
             transport: self.transport.clone(),
             crypto: self.crypto.clone(),
+            token_storage: self.token_storage.clone(),
         }
     }
 }
@@ -522,6 +820,23 @@ impl fmt::Display for ConnectionId {
     }
 }
 
+/// Address family to prefer for a connection's path, where both IPv4 and IPv6 are usable
+///
+/// The preference is a tie-breaker: the other family is used when the preferred one is unusable,
+/// e.g. because the server has no address in it or the local socket can't reach it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IpPreference {
+    /// Prefer IPv6
+    Ipv6,
+    /// Prefer IPv4
+    Ipv4,
+    /// Prefer whichever family has the lower round-trip time
+    ///
+    /// After the handshake, that of the path the connection was established on, whose round-trip
+    /// time is known.
+    LowestRtt,
+}
+
 /// Explicit congestion notification codepoint
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
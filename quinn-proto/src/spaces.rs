@@ -49,6 +49,8 @@ where
     pub(crate) crypto_stream: Assembler,
     /// Current offset of outgoing cryptographic handshake stream
     pub(crate) crypto_offset: u64,
+    /// Highest offset of incoming cryptographic handshake data
+    pub(crate) crypto_received: u64,
 
     /// The time the most recently sent retransmittable packet was sent.
     pub(crate) time_of_last_sent_ack_eliciting_packet: Option<Instant>,
@@ -59,6 +61,8 @@ where
     /// Number of tail loss probes to send
     pub(crate) loss_probes: u32,
     pub(crate) ping_pending: bool,
+    /// Whether the next packet should ask the peer to acknowledge it without delay
+    pub(crate) immediate_ack_pending: bool,
 }
 
 impl<K> PacketSpace<K>
@@ -84,11 +88,13 @@ where
 
             crypto_stream: Assembler::new(),
             crypto_offset: 0,
+            crypto_received: 0,
 
             time_of_last_sent_ack_eliciting_packet: None,
             loss_time: None,
             loss_probes: 0,
             ping_pending: false,
+            immediate_ack_pending: false,
         }
     }
 
@@ -111,7 +117,7 @@ where
         &mut self,
         newly_acked: u64,
         ecn: frame::EcnCounts,
-    ) -> Result<bool, &'static str> {
+    ) -> Result<u64, &'static str> {
         let ect0_increase = ecn
             .ect0
             .checked_sub(self.ecn_feedback.ect0)
@@ -136,22 +142,25 @@ where
         // to count CE packets as CE or ECT0. Recording them as CE is more consistent and keeps the
         // congestion check obvious.
         self.ecn_feedback = ecn;
-        Ok(ce_increase != 0)
+        Ok(ce_increase)
     }
 
-    pub(crate) fn finish_stream(&mut self, id: StreamId, offset: u64) {
-        for frame in &mut self.pending.stream {
+    pub(crate) fn finish_stream(&mut self, id: StreamId, offset: u64, priority: i32) {
+        for frame in self.pending.stream.iter_mut() {
             if frame.id == id && frame.offset + frame.data.len() as u64 == offset {
                 frame.fin = true;
                 return;
             }
         }
-        self.pending.stream.push_back(frame::Stream {
-            id,
-            data: Bytes::new(),
-            offset,
-            fin: true,
-        });
+        self.pending.stream.push_back(
+            priority,
+            frame::Stream {
+                id,
+                data: Bytes::new(),
+                offset,
+                fin: true,
+            },
+        );
     }
 }
 
@@ -173,21 +182,25 @@ pub(crate) struct SentPacket {
 /// Retransmittable data queue
 #[derive(Debug, Clone)]
 pub struct Retransmits {
+    pub(crate) ping: bool,
     pub(crate) max_data: bool,
     pub(crate) max_uni_stream_id: bool,
     pub(crate) max_bi_stream_id: bool,
-    pub(crate) stream: VecDeque<frame::Stream>,
+    pub(crate) stream: PendingStreams,
     pub(crate) rst_stream: Vec<(StreamId, VarInt)>,
     pub(crate) stop_sending: Vec<frame::StopSending>,
     pub(crate) max_stream_data: HashSet<StreamId>,
     pub(crate) crypto: VecDeque<frame::Crypto>,
     pub(crate) new_cids: Vec<IssuedCid>,
     pub(crate) retire_cids: Vec<u64>,
+    pub(crate) new_tokens: Vec<Bytes>,
+    pub(crate) ack_frequency: bool,
 }
 
 impl Retransmits {
     pub fn is_empty(&self) -> bool {
-        !self.max_data
+        !self.ping
+            && !self.max_data
             && !self.max_uni_stream_id
             && !self.max_bi_stream_id
             && self.stream.is_empty()
@@ -197,22 +210,27 @@ impl Retransmits {
             && self.crypto.is_empty()
             && self.new_cids.is_empty()
             && self.retire_cids.is_empty()
+            && self.new_tokens.is_empty()
+            && !self.ack_frequency
     }
 }
 
 impl Default for Retransmits {
     fn default() -> Self {
         Self {
+            ping: false,
             max_data: false,
             max_uni_stream_id: false,
             max_bi_stream_id: false,
-            stream: VecDeque::new(),
+            stream: PendingStreams::default(),
             rst_stream: Vec::new(),
             stop_sending: Vec::new(),
             max_stream_data: HashSet::new(),
             crypto: VecDeque::new(),
             new_cids: Vec::new(),
             retire_cids: Vec::new(),
+            new_tokens: Vec::new(),
+            ack_frequency: false,
         }
     }
 }
@@ -221,12 +239,11 @@ impl ::std::ops::AddAssign for Retransmits {
     fn add_assign(&mut self, rhs: Self) {
         // We reduce in-stream head-of-line blocking by queueing retransmits before other data for
         // STREAM and CRYPTO frames.
+        self.ping |= rhs.ping;
         self.max_data |= rhs.max_data;
         self.max_uni_stream_id |= rhs.max_uni_stream_id;
         self.max_bi_stream_id |= rhs.max_bi_stream_id;
-        for stream in rhs.stream.into_iter().rev() {
-            self.stream.push_front(stream);
-        }
+        self.stream.prepend(rhs.stream);
         self.rst_stream.extend_from_slice(&rhs.rst_stream);
         self.stop_sending.extend_from_slice(&rhs.stop_sending);
         self.max_stream_data.extend(&rhs.max_stream_data);
@@ -235,6 +252,85 @@ impl ::std::ops::AddAssign for Retransmits {
         }
         self.new_cids.extend(&rhs.new_cids);
         self.retire_cids.extend(rhs.retire_cids);
+        self.new_tokens.extend(rhs.new_tokens);
+        self.ack_frequency |= rhs.ack_frequency;
+    }
+}
+
+/// STREAM frames awaiting transmission, grouped by the priority of their stream
+///
+/// Frames are queued under the priority their stream had at the time. Frames that are lost keep
+/// the priority they were sent with.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PendingStreams {
+    /// Never contains empty queues
+    queues: BTreeMap<i32, VecDeque<frame::Stream>>,
+}
+
+impl PendingStreams {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    pub(crate) fn push_back(&mut self, priority: i32, frame: frame::Stream) {
+        self.queues.entry(priority).or_default().push_back(frame);
+    }
+
+    pub(crate) fn push_front(&mut self, priority: i32, frame: frame::Stream) {
+        self.queues.entry(priority).or_default().push_front(frame);
+    }
+
+    /// Take the next frame to transmit, along with its priority
+    ///
+    /// Frames of the highest priority go first, in the order they were queued.
+    pub(crate) fn pop(&mut self) -> Option<(i32, frame::Stream)> {
+        let priority = *self.queues.keys().next_back()?;
+        let queue = self.queues.get_mut(&priority).unwrap();
+        let frame = queue.pop_front().unwrap();
+        if queue.is_empty() {
+            self.queues.remove(&priority);
+        }
+        Some((priority, frame))
+    }
+
+    /// Queue the frames of `other` ahead of those of equal priority
+    pub(crate) fn prepend(&mut self, other: Self) {
+        for (priority, frames) in other.queues {
+            for frame in frames.into_iter().rev() {
+                self.push_front(priority, frame);
+            }
+        }
+    }
+
+    /// Move the frames of stream `id` to `priority`, preserving their order
+    pub(crate) fn set_priority(&mut self, id: StreamId, priority: i32) {
+        let mut moved = Vec::new();
+        let mut emptied = Vec::new();
+        for (&old, queue) in self.queues.iter_mut() {
+            if old == priority || !queue.iter().any(|x| x.id == id) {
+                continue;
+            }
+            let (matching, rest) = queue.drain(..).partition::<Vec<_>, _>(|x| x.id == id);
+            moved.extend(matching);
+            queue.extend(rest);
+            if queue.is_empty() {
+                emptied.push(old);
+            }
+        }
+        for old in emptied {
+            self.queues.remove(&old);
+        }
+        for frame in moved {
+            self.push_back(priority, frame);
+        }
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut frame::Stream> {
+        self.queues.values_mut().flat_map(|x| x.iter_mut())
+    }
+
+    pub(crate) fn into_frames(self) -> impl Iterator<Item = frame::Stream> {
+        self.queues.into_iter().flat_map(|x| x.1)
     }
 }
 
@@ -397,4 +493,63 @@ mod test {
         assert_eq!(dedup.next, 2 * WINDOW_SIZE + 1);
         assert_eq!(dedup.window, 1 << (WINDOW_SIZE - 2));
     }
+
+    fn stream_frame(id: u64, offset: u64) -> frame::Stream {
+        frame::Stream {
+            id: StreamId(id),
+            offset,
+            fin: false,
+            data: Bytes::new(),
+        }
+    }
+
+    fn pop_all(pending: &mut PendingStreams) -> Vec<(i32, u64, u64)> {
+        let mut frames = Vec::new();
+        while let Some((priority, frame)) = pending.pop() {
+            frames.push((priority, frame.id.0, frame.offset));
+        }
+        frames
+    }
+
+    #[test]
+    fn pending_streams_priority_order() {
+        let mut pending = PendingStreams::default();
+        pending.push_back(0, stream_frame(2, 0));
+        pending.push_back(1, stream_frame(6, 0));
+        pending.push_back(0, stream_frame(2, 10));
+        pending.push_back(-1, stream_frame(10, 0));
+        pending.push_back(1, stream_frame(6, 10));
+
+        let mut lost = PendingStreams::default();
+        lost.push_back(0, stream_frame(14, 0));
+        pending.prepend(lost);
+
+        assert_eq!(
+            pop_all(&mut pending),
+            [
+                (1, 6, 0),
+                (1, 6, 10),
+                (0, 14, 0),
+                (0, 2, 0),
+                (0, 2, 10),
+                (-1, 10, 0)
+            ]
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn pending_streams_reprioritize() {
+        let mut pending = PendingStreams::default();
+        pending.push_back(0, stream_frame(2, 0));
+        pending.push_back(0, stream_frame(6, 0));
+        pending.push_back(0, stream_frame(2, 10));
+        pending.push_back(1, stream_frame(10, 0));
+
+        pending.set_priority(StreamId(2), 2);
+        assert_eq!(
+            pop_all(&mut pending),
+            [(2, 2, 0), (2, 2, 10), (1, 10, 0), (0, 6, 0)]
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use std::collections::{hash_map, HashMap};
+use std::collections::{hash_map, HashMap, HashSet};
 
 use bytes::Bytes;
 use err_derive::Error;
@@ -103,6 +103,26 @@ impl Streams {
         }
     }
 
+    pub(crate) fn read_chunk(
+        &mut self,
+        id: StreamId,
+        max_length: usize,
+    ) -> Result<Option<(Bytes, bool)>, ReadError> {
+        let rs = self.recv_mut(id).ok_or(ReadError::UnknownStream)?;
+        match rs.read_chunk(max_length) {
+            Ok(Some(bytes)) => Ok(Some((bytes, rs.receiving_unknown_size()))),
+            Ok(None) => {
+                self.maybe_cleanup(id);
+                Ok(None)
+            }
+            Err(e @ ReadError::Reset { .. }) => {
+                self.maybe_cleanup(id);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     pub(crate) fn read_unordered(
         &mut self,
         id: StreamId,
@@ -180,10 +200,56 @@ impl Streams {
         self.recv.get_mut(&id)
     }
 
+    pub(crate) fn send(&self, id: StreamId) -> Option<&Send> {
+        self.send.get(&id)
+    }
+
     pub(crate) fn send_mut(&mut self, id: StreamId) -> Option<&mut Send> {
         self.send.get_mut(&id)
     }
 
+    pub(crate) fn set_priority(
+        &mut self,
+        id: StreamId,
+        priority: i32,
+    ) -> Result<(), UnknownStream> {
+        let ss = self
+            .send
+            .get_mut(&id)
+            .ok_or(UnknownStream { _private: () })?;
+        ss.priority = priority;
+        Ok(())
+    }
+
+    /// Snapshot of the streams which were opened and aren't fully closed yet, ordered by ID
+    ///
+    /// `blocked` holds the streams whose writes are held back by connection-level flow or
+    /// congestion control.
+    pub(crate) fn info(
+        &self,
+        side: Side,
+        receive_window: u64,
+        blocked: &HashSet<StreamId>,
+    ) -> Vec<StreamInfo> {
+        // Streams the peer could open are allocated in advance, but only report those it did
+        let mut ids = self
+            .send
+            .keys()
+            .chain(self.recv.keys())
+            .filter(|id| id.initiator() == side || id.index() < self.next_remote[id.dir() as usize])
+            .cloned()
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        ids.into_iter()
+            .map(|id| StreamInfo {
+                id,
+                send: self.send.get(&id).map(|s| s.info(blocked.contains(&id))),
+                recv: self.recv.get(&id).map(|r| r.info(receive_window)),
+            })
+            .collect()
+    }
+
     /// Whether a locally initiated stream has never been open
     pub(crate) fn is_local_unopened(&self, id: StreamId) -> bool {
         id.index() >= self.next[id.dir() as usize]
@@ -207,6 +273,7 @@ pub(crate) struct Send {
     pub(crate) state: SendState,
     /// Number of bytes sent but unacked
     pub(crate) bytes_in_flight: u64,
+    pub(crate) priority: i32,
 }
 
 impl Send {
@@ -216,6 +283,7 @@ impl Send {
             max_data: 0,
             state: SendState::Ready,
             bytes_in_flight: 0,
+            priority: 0,
         }
     }
 
@@ -231,15 +299,29 @@ impl Send {
         }
     }
 
-    /// All data acknowledged and STOP_SENDING error code, if any, processed by application
-    pub(crate) fn is_closed(&self) -> bool {
+    /// Bytes that may be written before reaching the peer's flow control limit
+    ///
+    /// Unlike `write_budget`, leaves any STOP_SENDING error code to be reported by the next write.
+    pub(crate) fn credit(&self) -> Result<u64, WriteError> {
         use self::SendState::*;
         match self.state {
-            DataRecvd | ResetRecvd { stop_reason: None } => true,
-            _ => false,
+            Ready => Ok(self.max_data - self.offset),
+            ResetSent {
+                stop_reason: Some(error_code),
+            }
+            | ResetRecvd {
+                stop_reason: Some(error_code),
+            } => Err(WriteError::Stopped(error_code)),
+            _ => Err(WriteError::UnknownStream),
         }
     }
 
+    /// All data acknowledged and STOP_SENDING error code, if any, processed by application
+    pub(crate) fn is_closed(&self) -> bool {
+        use self::SendState::*;
+        matches!(self.state, DataRecvd | ResetRecvd { stop_reason: None })
+    }
+
     pub(crate) fn finish(&mut self) -> Result<(), FinishError> {
         if self.state == SendState::Ready {
             self.state = SendState::DataSent;
@@ -251,6 +333,22 @@ impl Send {
         }
     }
 
+    fn info(&self, connection_blocked: bool) -> SendInfo {
+        use self::SendState::*;
+        SendInfo {
+            state: match self.state {
+                Ready => SendStreamState::Open,
+                DataSent => SendStreamState::Finished,
+                DataRecvd => SendStreamState::Acknowledged,
+                ResetSent { .. } | ResetRecvd { .. } => SendStreamState::Reset,
+            },
+            written: self.offset,
+            max_data: self.max_data,
+            in_flight: self.bytes_in_flight,
+            connection_blocked,
+        }
+    }
+
     fn take_stop_reason(&mut self) -> Option<VarInt> {
         match self.state {
             SendState::ResetSent {
@@ -291,6 +389,8 @@ pub(crate) struct Recv {
     /// Number of bytes read by the application. Equal to assembler.offset when `unordered` is
     /// false.
     pub(crate) bytes_read: u64,
+    /// Value of `bytes_read` when flow control credit was last issued to the peer
+    pub(crate) bytes_read_credited: u64,
 }
 
 impl Recv {
@@ -301,6 +401,7 @@ impl Recv {
             unordered: false,
             assembler: Assembler::new(),
             bytes_read: 0,
+            bytes_read_credited: 0,
         }
     }
 
@@ -318,12 +419,7 @@ impl Recv {
             ));
         }
 
-        if let Some(final_offset) = self.final_offset() {
-            if end > final_offset || (frame.fin && end != final_offset) {
-                debug!(end, final_offset, "final offset error");
-                return Err(TransportError::FINAL_OFFSET_ERROR(""));
-            }
-        }
+        self.check_final_offset(&frame)?;
 
         let prev_end = self.limit();
         let new_bytes = end.saturating_sub(prev_end);
@@ -353,6 +449,18 @@ impl Recv {
         Ok(new_bytes)
     }
 
+    /// Ensure `frame` neither extends past nor moves the final size of the stream, if known
+    pub(crate) fn check_final_offset(&self, frame: &frame::Stream) -> Result<(), TransportError> {
+        let end = frame.offset + frame.data.len() as u64;
+        if let Some(final_offset) = self.final_offset() {
+            if end > final_offset || (frame.fin && end != final_offset) {
+                debug!(end, final_offset, "final offset error");
+                return Err(TransportError::FINAL_OFFSET_ERROR(""));
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, ReadError> {
         assert!(
             !self.unordered,
@@ -368,6 +476,21 @@ impl Recv {
         }
     }
 
+    pub(crate) fn read_chunk(&mut self, max_length: usize) -> Result<Option<Bytes>, ReadError> {
+        assert!(
+            !self.unordered,
+            "cannot perform ordered reads following unordered reads on a stream"
+        );
+
+        match self.assembler.read_chunk(max_length) {
+            Some(bytes) => {
+                self.bytes_read += bytes.len() as u64;
+                Ok(Some(bytes))
+            }
+            None => self.read_blocked().map(|()| None),
+        }
+    }
+
     pub(crate) fn read_unordered(&mut self) -> Result<Option<(Bytes, u64)>, ReadError> {
         self.unordered = true;
 
@@ -396,18 +519,17 @@ impl Recv {
     }
 
     pub(crate) fn receiving_unknown_size(&self) -> bool {
-        match self.state {
-            RecvState::Recv { size: None } => true,
-            _ => false,
-        }
+        matches!(self.state, RecvState::Recv { size: None })
     }
 
     /// No more data expected from peer
     pub(crate) fn is_finished(&self) -> bool {
-        match self.state {
-            RecvState::Recv { .. } => false,
-            _ => true,
-        }
+        !matches!(self.state, RecvState::Recv { .. })
+    }
+
+    /// Whether the peer has abandoned the stream and the application hasn't yet been told
+    pub(crate) fn is_reset(&self) -> bool {
+        matches!(self.state, RecvState::ResetRecvd { .. })
     }
 
     /// All data read by application
@@ -429,6 +551,21 @@ impl Recv {
         }
     }
 
+    fn info(&self, receive_window: u64) -> RecvInfo {
+        RecvInfo {
+            state: match self.state {
+                RecvState::Recv { .. } => RecvStreamState::Receiving,
+                RecvState::DataRecvd { .. } => RecvStreamState::AllReceived,
+                RecvState::ResetRecvd { error_code, .. } => RecvStreamState::Reset(error_code),
+                RecvState::Closed => RecvStreamState::Closed,
+            },
+            received: self.limit(),
+            read: self.bytes_read,
+            max_data: self.bytes_read_credited + receive_window,
+            final_size: self.final_offset(),
+        }
+    }
+
     pub(crate) fn reset(&mut self, error_code: VarInt, final_offset: u64) {
         if self.is_closed() {
             return;
@@ -480,10 +617,7 @@ pub(crate) enum SendState {
 impl SendState {
     pub(crate) fn was_reset(self) -> bool {
         use self::SendState::*;
-        match self {
-            ResetSent { .. } | ResetRecvd { .. } => true,
-            _ => false,
-        }
+        matches!(self, ResetSent { .. } | ResetRecvd { .. })
     }
 }
 
@@ -508,6 +642,85 @@ pub enum FinishError {
     UnknownStream,
 }
 
+/// Snapshot of a stream's state, as reported by `Connection::streams`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamInfo {
+    /// The stream's ID, which also tells its direction and which side opened it
+    pub id: StreamId,
+    /// State of the sending side, unless the stream is receive-only or that side is closed
+    pub send: Option<SendInfo>,
+    /// State of the receiving side, unless the stream is send-only or that side is closed
+    pub recv: Option<RecvInfo>,
+}
+
+/// State of the sending side of a stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendInfo {
+    /// How far the stream has progressed towards being closed
+    pub state: SendStreamState,
+    /// Bytes written by the application
+    pub written: u64,
+    /// Flow control limit set by the peer, which blocks writes once reached by `written`
+    pub max_data: u64,
+    /// Bytes sent but not acknowledged yet
+    pub in_flight: u64,
+    /// Whether writes are held back by connection-level flow control or congestion control
+    pub connection_blocked: bool,
+}
+
+/// Progress of the sending side of a stream
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SendStreamState {
+    /// Accepting writes
+    Open,
+    /// Finished by the application, with some data not acknowledged yet
+    Finished,
+    /// All data acknowledged by the peer
+    Acknowledged,
+    /// Reset locally, possibly in response to the peer's STOP_SENDING
+    Reset,
+}
+
+/// State of the receiving side of a stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecvInfo {
+    /// How far the stream has progressed towards being closed
+    pub state: RecvStreamState,
+    /// Offset after the largest byte received
+    pub received: u64,
+    /// Bytes read by the application
+    pub read: u64,
+    /// Flow control limit imposed on the peer
+    pub max_data: u64,
+    /// Size of the stream, once the peer has finished or reset it
+    pub final_size: Option<u64>,
+}
+
+/// Progress of the receiving side of a stream
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecvStreamState {
+    /// More data is expected from the peer
+    Receiving,
+    /// All data was received, but not all of it read yet
+    AllReceived,
+    /// The peer reset the stream with the given error code
+    Reset(VarInt),
+    /// All data was read
+    Closed,
+}
+
+/// Progress of a `Connection::write_chunks` call
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Written {
+    /// Total number of bytes written
+    pub bytes: usize,
+    /// Number of chunks written in their entirety
+    ///
+    /// If less than the number passed in, the first unfinished chunk has had its written prefix
+    /// split off.
+    pub chunks: usize,
+}
+
 /// Unknown stream ID
 #[derive(Debug)]
 pub struct UnknownStream {
@@ -1,18 +1,20 @@
 use std::{
+    collections::HashSet,
     convert::TryInto,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use assert_matches::assert_matches;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use hex_literal::hex;
 use rand::RngCore;
 use rustls::internal::msgs::enums::AlertDescription;
 use tracing::info;
 
 use super::*;
+use crate::shared::{ConnectionEventInner, IssuedCid};
 mod util;
 use util::*;
 
@@ -22,13 +24,7 @@ fn version_negotiate_server() {
     let client_addr = "[::2]:7890".parse().unwrap();
     let mut server = Endpoint::new(Default::default(), Some(Arc::new(server_config()))).unwrap();
     let now = Instant::now();
-    let event = server.handle(
-        now,
-        client_addr,
-        None,
-        // Long-header packet with reserved version number
-        hex!("80 0a1a2a3a 04 00000000 04 00000000 00")[..].into(),
-    );
+    let event = server.handle(now, client_addr, None, unsupported_version_packet());
     assert!(event.is_none());
 
     let io = server.poll_transmit();
@@ -43,6 +39,103 @@ fn version_negotiate_server() {
     assert_matches!(server.poll_transmit(), None);
 }
 
+#[test]
+fn version_negotiate_limits() {
+    let _guard = subscribe();
+    let client_addr = "[::2]:7890".parse().unwrap();
+    let mut server = Endpoint::new(Default::default(), Some(Arc::new(server_config()))).unwrap();
+    let mut now = Instant::now();
+
+    // Never larger than the triggering datagram
+    let short = hex!("80 0a1a2a3a 04 00000000 04 00000000 00")[..].into();
+    assert!(server.handle(now, client_addr, None, short).is_none());
+    assert_matches!(server.poll_transmit(), None);
+
+    let packet = unsupported_version_packet();
+    let len = packet.len();
+    assert!(server.handle(now, client_addr, None, packet).is_none());
+    assert_matches!(server.poll_transmit(), Some(Transmit { ref contents, .. }) if contents.len() <= len);
+
+    // Rate limited per source address
+    let other_port = "[::2]:7891".parse().unwrap();
+    assert!(server
+        .handle(now, other_port, None, unsupported_version_packet())
+        .is_none());
+    assert_matches!(server.poll_transmit(), None);
+    let other_addr = "[::3]:7890".parse().unwrap();
+    assert!(server
+        .handle(now, other_addr, None, unsupported_version_packet())
+        .is_none());
+    assert_matches!(server.poll_transmit(), Some(_));
+
+    now += Duration::from_micros(server_config().version_negotiation_interval);
+    assert!(server
+        .handle(now, client_addr, None, unsupported_version_packet())
+        .is_none());
+    assert_matches!(server.poll_transmit(), Some(_));
+}
+
+#[test]
+fn version_negotiate_cache_bounded() {
+    let _guard = subscribe();
+    let server_config = ServerConfig {
+        version_negotiation_cache: 1,
+        ..server_config()
+    };
+    let mut server = Endpoint::new(Default::default(), Some(Arc::new(server_config))).unwrap();
+    let now = Instant::now();
+    let first = "[::2]:7890".parse().unwrap();
+    let second = "[::3]:7890".parse().unwrap();
+
+    assert!(server
+        .handle(now, first, None, unsupported_version_packet())
+        .is_none());
+    assert_matches!(server.poll_transmit(), Some(_));
+    assert!(server
+        .handle(now, first, None, unsupported_version_packet())
+        .is_none());
+    assert_matches!(server.poll_transmit(), None);
+
+    // Tracking the second address forgets the first
+    assert!(server
+        .handle(now, second, None, unsupported_version_packet())
+        .is_none());
+    assert_matches!(server.poll_transmit(), Some(_));
+    assert!(server
+        .handle(now, first, None, unsupported_version_packet())
+        .is_none());
+    assert_matches!(server.poll_transmit(), Some(_));
+}
+
+/// Long-header packet with reserved version number, padded like a client's first Initial
+fn unsupported_version_packet() -> BytesMut {
+    let mut packet = BytesMut::from(&hex!("80 0a1a2a3a 04 00000000 04 00000000 00")[..]);
+    packet.resize(MIN_INITIAL_SIZE, 0);
+    packet
+}
+
+#[test]
+fn seeded_rng() {
+    let _guard = subscribe();
+    let server_addr = "[::2]:7890".parse().unwrap();
+    let rem_cid = |seed| {
+        let mut client = Endpoint::new(
+            Arc::new(EndpointConfig {
+                rng_seed: Some(seed),
+                ..Default::default()
+            }),
+            None,
+        )
+        .unwrap();
+        let (_, conn) = client
+            .connect(Instant::now(), client_config(), server_addr, "localhost")
+            .unwrap();
+        conn.rem_cid()
+    };
+    assert_eq!(rem_cid([1; 32]), rem_cid([1; 32]));
+    assert_ne!(rem_cid([1; 32]), rem_cid([2; 32]));
+}
+
 #[test]
 fn version_negotiate_client() {
     let _guard = subscribe();
@@ -55,10 +148,10 @@ fn version_negotiate_client() {
         None,
     )
     .unwrap();
+    let now = Instant::now();
     let (_, mut client_conn) = client
-        .connect(client_config(), server_addr, "localhost")
+        .connect(now, client_config(), server_addr, "localhost")
         .unwrap();
-    let now = Instant::now();
     let opt_event = client.handle(
         now,
         server_addr,
@@ -122,6 +215,50 @@ fn stateless_retry() {
     pair.connect();
 }
 
+#[test]
+fn new_token() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            use_stateless_retry: true,
+            new_token_lifetime: 60_000_000,
+            ..server_config()
+        },
+    );
+    let config = client_config();
+
+    let client_ch = pair.begin_connect(config.clone());
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert!(pair.client_conn_mut(client_ch).retried());
+    assert!(pair.server_conn_mut(server_ch).retried());
+    pair.client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .close(pair.time, VarInt(0), [][..].into());
+    pair.drive();
+    pair.client.connections.clear();
+    pair.server.connections.clear();
+
+    // The token isn't bound to the client's port
+    pair.client.addr = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    info!("connecting with token");
+    let client_ch = pair.begin_connect(config);
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert!(!pair.client_conn_mut(client_ch).retried());
+    assert!(!pair.server_conn_mut(server_ch).retried());
+}
+
 #[test]
 fn server_stateless_reset() {
     let _guard = subscribe();
@@ -215,6 +352,109 @@ fn finish_stream_simple() {
     assert_matches!(pair.server_conn_mut(server_ch).read_unordered(s), Ok(None));
 }
 
+#[test]
+fn chunked_stream_io() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            transport: Arc::new(TransportConfig {
+                stream_receive_window: 2000,
+                ..TransportConfig::default()
+            }),
+            ..server_config()
+        },
+    );
+    let (client_ch, server_ch) = pair.connect();
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    let mut chunks = [
+        Bytes::from(vec![1; 1000]),
+        Bytes::new(),
+        Bytes::from(vec![2; 1500]),
+        Bytes::from(vec![3; 500]),
+    ];
+    // Flow control admits the first chunk and part of the third
+    assert_eq!(
+        pair.client_conn_mut(client_ch)
+            .write_chunks(s, &mut chunks)
+            .unwrap(),
+        Written {
+            bytes: 2000,
+            chunks: 2
+        }
+    );
+    assert_eq!(chunks[2].len(), 500);
+    pair.drive();
+
+    assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    let mut received = Vec::new();
+    while let Ok(Some(chunk)) = pair.server_conn_mut(server_ch).read_chunk(s, 700) {
+        assert!(chunk.len() <= 700);
+        received.extend_from_slice(&chunk);
+    }
+    assert_eq!(received.len(), 2000);
+    assert!(received[..1000].iter().all(|&x| x == 1));
+    assert!(received[1000..].iter().all(|&x| x == 2));
+    pair.drive();
+
+    assert_eq!(
+        pair.client_conn_mut(client_ch)
+            .write_chunks(s, &mut chunks[2..])
+            .unwrap(),
+        Written {
+            bytes: 1000,
+            chunks: 2
+        }
+    );
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.drive();
+    received.clear();
+    while let Some(chunk) = pair
+        .server_conn_mut(server_ch)
+        .read_chunk(s, 4096)
+        .unwrap()
+    {
+        received.extend_from_slice(&chunk);
+    }
+    assert_eq!(received.len(), 1000);
+    assert!(received[..500].iter().all(|&x| x == 2));
+    assert!(received[500..].iter().all(|&x| x == 3));
+}
+
+#[test]
+fn stream_priority() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let low = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    let high = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch)
+        .set_priority(high, 1)
+        .unwrap();
+    assert_eq!(pair.client_conn_mut(client_ch).priority(high).unwrap(), 1);
+    assert_eq!(pair.client_conn_mut(client_ch).priority(low).unwrap(), 0);
+
+    const MSG: &[u8] = &[0xAB; 4000];
+    pair.client_conn_mut(client_ch).write(low, MSG).unwrap();
+    pair.client_conn_mut(client_ch).write(high, MSG).unwrap();
+    pair.drive_client();
+    // Deliver only the first packet sent, which should carry the later-written, higher-priority data
+    pair.server.inbound.truncate(1);
+    pair.drive_server();
+
+    let mut buf = [0; 4000];
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read(high, &mut buf),
+        Ok(Some(n)) if n > 0
+    );
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read(low, &mut buf),
+        Err(ReadError::Blocked)
+    );
+}
+
 #[test]
 fn reset_stream() {
     let _guard = subscribe();
@@ -282,95 +522,373 @@ fn stop_stream() {
     );
 }
 
-#[test]
-fn reject_self_signed_cert() {
+/// Finish a stream after writing `MSG`, then send `frame` on it as well
+fn stream_frame_after_fin(
+    offset: u64,
+    data: &'static [u8],
+    fin: bool,
+) -> (Pair, ConnectionHandle, ConnectionHandle, StreamId) {
+    const MSG: &[u8] = b"hello";
     let _guard = subscribe();
     let mut pair = Pair::default();
-    info!("connecting");
-    let client_ch = pair.begin_connect(ClientConfig::default());
+    let (client_ch, server_ch) = pair.connect();
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, MSG).unwrap();
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.client_conn_mut(client_ch)
+        .inject_stream_frame(frame::Stream {
+            id: s,
+            offset,
+            fin,
+            data: Bytes::from_static(data),
+        });
     pair.drive();
-    assert_matches!(pair.client_conn_mut(client_ch).poll(),
-                    Some(Event::ConnectionLost { reason: ConnectionError::TransportError(ref error)})
-                    if error.code == TransportErrorCode::crypto(AlertDescription::BadCertificate.get_u8()));
+    (pair, client_ch, server_ch, s)
 }
 
 #[test]
-fn congestion() {
+fn stream_retransmit_after_fin() {
+    let (mut pair, client_ch, server_ch, s) = stream_frame_after_fin(1, b"ello", true);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    let mut buf = [0; 16];
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read(s, &mut buf),
+        Ok(Some(5))
+    );
+    assert_eq!(&buf[..5], b"hello");
+    assert_matches!(pair.server_conn_mut(server_ch).read(s, &mut buf), Ok(None));
+    assert!(!pair.client_conn_mut(client_ch).is_closed());
+}
+
+#[test]
+fn stream_data_beyond_fin() {
+    let (mut pair, client_ch, _, _) = stream_frame_after_fin(5, b"!", false);
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason:
+                ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                    error_code: TransportErrorCode::FINAL_OFFSET_ERROR,
+                    frame_type: Some(ty),
+                    ..
+                }),
+        }) if ty.to_string() == "STREAM"
+    );
+}
+
+#[test]
+fn stream_conflicting_fin() {
+    let (mut pair, client_ch, _, _) = stream_frame_after_fin(0, b"hel", true);
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason:
+                ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                    error_code: TransportErrorCode::FINAL_OFFSET_ERROR,
+                    frame_type: Some(ty),
+                    ..
+                }),
+        }) if ty.to_string() == "STREAM"
+    );
+}
+
+#[test]
+fn stream_data_after_reset() {
     let _guard = subscribe();
     let mut pair = Pair::default();
-    let (client_ch, _) = pair.connect();
+    let (client_ch, server_ch) = pair.connect();
 
-    let initial_congestion_state = pair.client_conn_mut(client_ch).congestion_state();
     let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
-    loop {
-        match pair.client_conn_mut(client_ch).write(s, &[42; 1024]) {
-            Ok(n) => {
-                assert!(n <= 1024);
-                pair.drive_client();
-            }
-            Err(WriteError::Blocked) => {
-                break;
-            }
-            Err(e) => {
-                panic!("unexpected write error: {}", e);
-            }
-        }
-    }
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    pair.client.drive(pair.time, pair.server.addr);
+    pair.client.delay_outbound();
+
+    info!("resetting stream");
+    const ERROR: VarInt = VarInt(42);
+    pair.client_conn_mut(client_ch).reset(s, ERROR);
+    // Deliver the RESET_STREAM ahead of the data it abandons
+    pair.drive_client();
+    pair.client.finish_delay();
     pair.drive();
-    assert!(pair.client_conn_mut(client_ch).congestion_state() >= initial_congestion_state);
-    pair.client_conn_mut(client_ch)
-        .write(s, &[42; 1024])
-        .unwrap();
+
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read_unordered(s),
+        Err(ReadError::Reset(ERROR))
+    );
+    assert!(!pair.client_conn_mut(client_ch).is_closed());
 }
 
 #[test]
-fn high_latency_handshake() {
+fn stream_data_after_stop() {
     let _guard = subscribe();
     let mut pair = Pair::default();
-    pair.latency = Duration::from_micros(200 * 1000);
     let (client_ch, server_ch) = pair.connect();
-    assert_eq!(pair.client_conn_mut(client_ch).bytes_in_flight(), 0);
-    assert_eq!(pair.server_conn_mut(server_ch).bytes_in_flight(), 0);
-    assert!(pair.client_conn_mut(client_ch).using_ecn());
-    assert!(pair.server_conn_mut(server_ch).using_ecn());
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    pair.drive();
+
+    info!("stopping stream while more data is in flight");
+    const ERROR: VarInt = VarInt(42);
+    pair.server_conn_mut(server_ch)
+        .stop_sending(s, ERROR)
+        .unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"world").unwrap();
+    pair.drive();
+
+    assert!(!pair.client_conn_mut(client_ch).is_closed());
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read_unordered(s),
+        Err(ReadError::Reset(ERROR))
+    );
 }
 
 #[test]
-fn zero_rtt_happypath() {
+fn duplicate_reset_flow_control() {
+    const WINDOW: u64 = 16;
     let _guard = subscribe();
     let mut pair = Pair::new(
         Default::default(),
         ServerConfig {
-            use_stateless_retry: true,
+            transport: Arc::new(TransportConfig {
+                receive_window: WINDOW,
+                ..TransportConfig::default()
+            }),
             ..server_config()
         },
     );
-    let config = client_config();
+    let (client_ch, server_ch) = pair.connect();
+    let msg = [0xAB; 3 * WINDOW as usize];
 
-    // Establish normal connection
-    let client_ch = pair.begin_connect(config.clone());
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, &msg),
+        Ok(WINDOW as usize)
+    );
     pair.drive();
-    pair.server.assert_accept();
-    pair.client
-        .connections
-        .get_mut(&client_ch)
-        .unwrap()
-        .close(pair.time, VarInt(0), [][..].into());
+
+    info!("reordering RESET_STREAM behind enough packets for it to be retransmitted");
+    pair.client_conn_mut(client_ch).reset(s, VarInt(42));
+    pair.client.drive(pair.time, pair.server.addr);
+    pair.client.delay_outbound();
+    for _ in 0..3 {
+        pair.client_conn_mut(client_ch).ping();
+        pair.client.drive(pair.time, pair.server.addr);
+    }
     pair.drive();
+    assert_eq!(pair.client_conn_mut(client_ch).lost_packets(), 1);
+    pair.client.finish_delay();
+    pair.drive();
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read_unordered(s),
+        Err(ReadError::Reset(VarInt(42)))
+    );
 
-    pair.client.addr = SocketAddr::new(
-        Ipv6Addr::LOCALHOST.into(),
+    // The reset stream's data is credited back exactly once
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, &msg),
+        Ok(WINDOW as usize)
+    );
+}
+
+#[test]
+fn reject_self_signed_cert() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    info!("connecting");
+    let client_ch = pair.begin_connect(ClientConfig::default());
+    pair.drive();
+    assert_matches!(pair.client_conn_mut(client_ch).poll(),
+                    Some(Event::ConnectionLost { reason: ConnectionError::TransportError(ref error)})
+                    if error.code == TransportErrorCode::crypto(AlertDescription::BadCertificate.get_u8()));
+}
+
+#[test]
+fn oversized_handshake() {
+    let _guard = subscribe();
+    let cert = rustls::Certificate(CERTIFICATE.serialize_der().unwrap());
+    let key = rustls::PrivateKey(CERTIFICATE.serialize_private_key_der());
+    let mut server_config = server_config();
+    Arc::get_mut(&mut server_config.crypto)
+        .unwrap()
+        .set_single_cert(vec![cert; 32], key)
+        .unwrap();
+    let mut pair = Pair::new(Default::default(), server_config);
+
+    let mut client_config = client_config();
+    let transport = Arc::get_mut(&mut client_config.transport).unwrap();
+    transport.crypto_buffer_size = 64 * 1024;
+    transport.max_handshake_data = Some(8192);
+    let client_ch = pair.begin_connect(client_config);
+    pair.drive();
+    assert_matches!(pair.client_conn_mut(client_ch).poll(),
+                    Some(Event::ConnectionLost { reason: ConnectionError::TransportError(ref error)})
+                    if error.code == TransportErrorCode::CRYPTO_BUFFER_EXCEEDED);
+    let stats = pair.client_conn_mut(client_ch).crypto_stats();
+    assert!(stats.initial > 0);
+    assert!(stats.handshake > 0 && stats.handshake <= 8192);
+    assert_eq!(stats.data, 0);
+}
+
+#[test]
+fn tls_version() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(
+        pair.client_conn_mut(client_ch)
+            .crypto_session()
+            .get_protocol_version(),
+        Some(rustls::ProtocolVersion::TLSv1_3)
+    );
+    assert_eq!(
+        pair.server_conn_mut(server_ch)
+            .crypto_session()
+            .get_protocol_version(),
+        Some(rustls::ProtocolVersion::TLSv1_3)
+    );
+}
+
+#[test]
+fn reject_tls12_config() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    Arc::get_mut(&mut server_config.crypto)
+        .unwrap()
+        .versions
+        .push(rustls::ProtocolVersion::TLSv1_2);
+    assert_matches!(
+        Endpoint::new(Default::default(), Some(Arc::new(server_config))).err(),
+        Some(ConfigError::IllegalValue(_))
+    );
+
+    let mut pair = Pair::default();
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.crypto).unwrap().versions = vec![
+        rustls::ProtocolVersion::TLSv1_3,
+        rustls::ProtocolVersion::TLSv1_2,
+    ];
+    assert_matches!(
+        pair.client
+            .connect(pair.time, client_config, pair.server.addr, "localhost")
+            .err(),
+        Some(ConnectError::Config(ConfigError::IllegalValue(_)))
+    );
+}
+
+#[test]
+fn congestion() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+
+    let initial_congestion_state = pair.client_conn_mut(client_ch).congestion_state();
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    loop {
+        match pair.client_conn_mut(client_ch).write(s, &[42; 1024]) {
+            Ok(n) => {
+                assert!(n <= 1024);
+                pair.drive_client();
+            }
+            Err(WriteError::Blocked) => {
+                break;
+            }
+            Err(e) => {
+                panic!("unexpected write error: {}", e);
+            }
+        }
+    }
+    pair.drive();
+    assert!(pair.client_conn_mut(client_ch).congestion_state() >= initial_congestion_state);
+    pair.client_conn_mut(client_ch)
+        .write(s, &[42; 1024])
+        .unwrap();
+}
+
+#[test]
+fn high_latency_handshake() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_micros(200 * 1000);
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.client_conn_mut(client_ch).bytes_in_flight(), 0);
+    assert_eq!(pair.server_conn_mut(server_ch).bytes_in_flight(), 0);
+    assert!(pair.client_conn_mut(client_ch).using_ecn());
+    assert!(pair.server_conn_mut(server_ch).using_ecn());
+}
+
+#[test]
+fn zero_rtt_happypath() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            use_stateless_retry: true,
+            ..server_config()
+        },
+    );
+    let config = client_config();
+
+    // Establish normal connection
+    let client_ch = pair.begin_connect(config.clone());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).early_data_status(),
+        EarlyDataStatus::NoTicket
+    );
+    pair.drive();
+    pair.server.assert_accept();
+    pair.client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .close(pair.time, VarInt(0), [][..].into());
+    pair.drive();
+
+    pair.client.addr = SocketAddr::new(
+        Ipv6Addr::LOCALHOST.into(),
         CLIENT_PORTS.lock().unwrap().next().unwrap(),
     );
     info!("resuming session");
     let client_ch = pair.begin_connect(config.clone());
     assert!(pair.client_conn_mut(client_ch).has_0rtt());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).early_data_status(),
+        EarlyDataStatus::Attempted { accepted: None }
+    );
     let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
     const MSG: &[u8] = b"Hello, 0-RTT!";
     pair.client_conn_mut(client_ch).write(s, MSG).unwrap();
     pair.drive();
     assert!(pair.client_conn_mut(client_ch).accepted_0rtt());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).early_data_status(),
+        EarlyDataStatus::Attempted {
+            accepted: Some(true)
+        }
+    );
     let server_ch = pair.server.assert_accept();
+    assert_eq!(
+        pair.server_conn_mut(server_ch).early_data_status(),
+        EarlyDataStatus::Attempted {
+            accepted: Some(true)
+        }
+    );
     assert_matches!(
         pair.server_conn_mut(server_ch).read_unordered(s),
         Ok(Some((ref data, 0))) if data == MSG
@@ -426,7 +944,17 @@ fn zero_rtt_rejection() {
     pair.client_conn_mut(client_ch).write(s, MSG).unwrap();
     pair.drive();
     assert!(!pair.client_conn_mut(client_ch).accepted_0rtt());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).early_data_status(),
+        EarlyDataStatus::Attempted {
+            accepted: Some(false)
+        }
+    );
     let server_conn = pair.server.assert_accept();
+    assert_eq!(
+        pair.server_conn_mut(server_conn).early_data_status(),
+        EarlyDataStatus::NotAttempted
+    );
     assert_matches!(
         pair.server_conn_mut(server_conn).poll(),
         Some(Event::Connected)
@@ -441,6 +969,115 @@ fn zero_rtt_rejection() {
     assert_eq!(pair.client_conn_mut(client_ch).lost_packets(), 0);
 }
 
+#[test]
+fn zero_rtt_replay_refused() {
+    let _guard = subscribe();
+    struct Replayed;
+    impl crypto::AntiReplay for Replayed {
+        fn check(&self, _: &[u8; 32], _: Instant) -> bool {
+            false
+        }
+    }
+    let pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            anti_replay: Some(Arc::new(Replayed)),
+            ..server_config()
+        },
+    );
+    assert_0rtt_refused(pair, client_config());
+}
+
+#[test]
+fn zero_rtt_refused_protocol() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    Arc::get_mut(&mut server_config.crypto)
+        .unwrap()
+        .set_protocols(&["foo".into(), "bar".into()]);
+    server_config.refused_early_data_protocols = vec![b"bar".to_vec()];
+    let pair = Pair::new(Arc::new(EndpointConfig::default()), server_config);
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.crypto)
+        .unwrap()
+        .set_protocols(&["bar".into()]);
+    assert_0rtt_refused(pair, client_config);
+}
+
+/// Resume a session with 0-RTT data, which the server should refuse
+fn assert_0rtt_refused(mut pair: Pair, config: ClientConfig) {
+    let client_ch = pair.begin_connect(config.clone());
+    pair.drive();
+    pair.server.assert_accept();
+    pair.client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .close(pair.time, VarInt(0), [][..].into());
+    pair.drive();
+    pair.client.connections.clear();
+    pair.server.connections.clear();
+
+    info!("resuming session");
+    let client_ch = pair.begin_connect(config);
+    assert!(pair.client_conn_mut(client_ch).has_0rtt());
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch)
+        .write(s, b"Hello, 0-RTT!")
+        .unwrap();
+    pair.drive();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).early_data_status(),
+        EarlyDataStatus::Attempted {
+            accepted: Some(false)
+        }
+    );
+    let server_ch = pair.server.assert_accept();
+    assert_eq!(
+        pair.server_conn_mut(server_ch).early_data_status(),
+        EarlyDataStatus::NotAttempted
+    );
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert_eq!(pair.client_conn_mut(client_ch).lost_packets(), 0);
+}
+
+#[test]
+fn zero_rtt_disabled() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let mut config = client_config();
+
+    // Establish normal connection
+    let client_ch = pair.begin_connect(config.clone());
+    pair.drive();
+    pair.server.assert_accept();
+    pair.client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .close(pair.time, VarInt(0), [][..].into());
+    pair.drive();
+    pair.client.connections.clear();
+    pair.server.connections.clear();
+
+    // A ticket is available, but the client has opted out of early data
+    Arc::get_mut(&mut config.crypto).unwrap().enable_early_data = false;
+    let client_ch = pair.begin_connect(config);
+    assert!(!pair.client_conn_mut(client_ch).has_0rtt());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).early_data_status(),
+        EarlyDataStatus::NotAttempted
+    );
+    pair.drive();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).early_data_status(),
+        EarlyDataStatus::NotAttempted
+    );
+}
+
 #[test]
 fn alpn_success() {
     let _guard = subscribe();
@@ -469,42 +1106,157 @@ fn alpn_success() {
 }
 
 #[test]
-fn stream_id_backpressure() {
+fn alpn_mismatch() {
     let _guard = subscribe();
-    let server = ServerConfig {
-        transport: Arc::new(TransportConfig {
-            stream_window_uni: 1,
-            ..TransportConfig::default()
-        }),
-        ..server_config()
-    };
-    let mut pair = Pair::new(Default::default(), server);
-    let (client_ch, server_ch) = pair.connect();
-
-    let s = pair
-        .client
-        .connections
-        .get_mut(&client_ch)
+    let mut server_config = server_config();
+    Arc::get_mut(&mut server_config.crypto)
         .unwrap()
-        .open(Dir::Uni)
-        .expect("couldn't open first stream");
-    assert_eq!(
-        pair.client_conn_mut(client_ch).open(Dir::Uni),
-        None,
-        "only one stream is permitted at a time"
-    );
-    // Close the first stream to make room for the second
-    pair.client_conn_mut(client_ch).finish(s).unwrap();
+        .set_protocols(&["foo".into()]);
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.crypto)
+        .unwrap()
+        .set_protocols(&["bar".into(), "quux".into()]);
+
+    // Accepted without a protocol by default
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config.clone());
+    let client_ch = pair.begin_connect(client_config.clone());
     pair.drive();
     assert_matches!(
         pair.client_conn_mut(client_ch).poll(),
-        Some(Event::StreamFinished { stream, stop_reason: None }) if stream == s
+        Some(Event::Connected)
     );
-    assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
+    assert_eq!(pair.client_conn_mut(client_ch).protocol(), None);
+
+    server_config.require_protocol = true;
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config);
+    let client_ch = pair.begin_connect(client_config);
+    pair.drive();
     assert_matches!(
-        pair.server_conn_mut(server_ch).poll(),
-        Some(Event::StreamOpened { dir: Dir::Uni })
-    );
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(ref close)
+        }) if close.error_code == TransportErrorCode::NO_APPLICATION_PROTOCOL
+            && close.reason == "no application protocol negotiated, client offered bar, quux"
+    );
+}
+
+#[test]
+fn alpn_default_protocol() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    Arc::get_mut(&mut server_config.crypto)
+        .unwrap()
+        .set_protocols(&["foo".into()]);
+
+    // Clients that don't use ALPN are accepted by default
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config.clone());
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert_eq!(pair.server_conn_mut(server_ch).protocol(), None);
+
+    // ...and rejected if a protocol is required
+    server_config.require_protocol = true;
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config.clone());
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(ref close)
+        }) if close.error_code == TransportErrorCode::NO_APPLICATION_PROTOCOL
+    );
+
+    // ...unless the server assumes a protocol for them
+    server_config.default_protocol = Some(b"foo".to_vec());
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config);
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert_eq!(
+        pair.server_conn_mut(server_ch).protocol(),
+        Some(&b"foo"[..])
+    );
+    assert_eq!(pair.client_conn_mut(client_ch).protocol(), None);
+}
+
+#[test]
+fn handshake_span() {
+    let (_guard, captured) = subscribe_captured();
+    let mut server_config = ServerConfig {
+        use_stateless_retry: true,
+        ..server_config()
+    };
+    Arc::get_mut(&mut server_config.crypto)
+        .unwrap()
+        .set_protocols(&["foo".into()]);
+    let mut pair = Pair::new(Arc::new(EndpointConfig::default()), server_config);
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.crypto)
+        .unwrap()
+        .set_protocols(&["foo".into()]);
+    pair.begin_connect(client_config);
+    pair.drive();
+
+    let captured = String::from_utf8(captured.lock().unwrap().clone()).unwrap();
+    let established = captured
+        .lines()
+        .filter(|line| line.ends_with("established"))
+        .collect::<Vec<_>>();
+    assert_eq!(established.len(), 2, "{:#?}", established);
+    for line in established {
+        assert!(line.contains("alpn=foo"), "{}", line);
+        assert!(line.contains("cipher_suite=\"TLS13_"), "{}", line);
+        assert!(line.contains("retry=true"), "{}", line);
+        assert!(line.contains("zero_rtt="), "{}", line);
+    }
+}
+
+#[test]
+fn stream_id_backpressure() {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            stream_window_uni: 1,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, server_ch) = pair.connect();
+
+    let s = pair
+        .client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .open(Dir::Uni)
+        .expect("couldn't open first stream");
+    assert_eq!(
+        pair.client_conn_mut(client_ch).open(Dir::Uni),
+        None,
+        "only one stream is permitted at a time"
+    );
+    // Close the first stream to make room for the second
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::StreamFinished { stream, stop_reason: None }) if stream == s
+    );
+    assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
     assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
     assert_matches!(pair.server_conn_mut(server_ch).read_unordered(s), Ok(None));
     // Server will only send MAX_STREAM_ID now that the application's been notified
@@ -766,6 +1518,42 @@ fn server_hs_retransmit() {
     );
 }
 
+#[test]
+fn duplicate_initial() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    let initial = pair.server.inbound.front().unwrap().clone();
+    pair.server.inbound.push_back(initial.clone());
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert_eq!(pair.server.connections.len(), 1);
+    assert_eq!(pair.server.known_connections(), 1);
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::Connected)
+    );
+
+    // A copy arriving after the connection is gone must not start a new one
+    let now = pair.time;
+    pair.client_conn_mut(client_ch)
+        .close(now, VarInt(0), Bytes::new());
+    pair.drive();
+    assert_eq!(pair.server.known_connections(), 0);
+    pair.server
+        .inbound
+        .push_back((pair.time, initial.1, initial.2));
+    pair.drive_server();
+    assert_eq!(pair.server.connections.len(), 1);
+    assert_eq!(pair.server.known_connections(), 0);
+    assert!(pair.client.inbound.is_empty());
+}
+
 #[test]
 fn migration() {
     let _guard = subscribe();
@@ -781,6 +1569,307 @@ fn migration() {
     assert_eq!(pair.server_conn_mut(server_ch).remote(), pair.client.addr);
 }
 
+#[test]
+fn migration_cid_rotation() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            migration_backoff: 0,
+            ..server_config()
+        },
+    );
+    let (client_ch, server_ch) = pair.connect();
+    let mut cids = HashSet::new();
+    cids.insert(pair.server_conn_mut(server_ch).rem_cid());
+    // Each migration switches to an unused CID, the client issuing new ones as the server retires
+    // those it switched away from
+    for _ in 0..2 * LOC_CID_COUNT {
+        pair.client.addr = SocketAddr::new(
+            Ipv4Addr::new(127, 0, 0, 1).into(),
+            CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        );
+        pair.client_conn_mut(client_ch).ping();
+        pair.drive();
+        assert_eq!(pair.server_conn_mut(server_ch).remote(), pair.client.addr);
+        assert!(cids.insert(pair.server_conn_mut(server_ch).rem_cid()));
+    }
+}
+
+#[test]
+fn migration_reported() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let old_addr = pair.client.addr;
+    pair.client.addr = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive();
+    let new_addr = pair.client.addr;
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::RemoteAddressChanged { old, new }) if old == old_addr && new == new_addr
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).poll(), None);
+}
+
+#[test]
+fn local_migration() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    assert!(pair
+        .client_conn_mut(client_ch)
+        .path_stats()
+        .smoothed_rtt
+        .is_some());
+
+    let old_cid = pair.client_conn_mut(client_ch).rem_cid();
+    pair.client.addr = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    let now = pair.time;
+    pair.client_conn_mut(client_ch).local_address_changed(now);
+    assert_ne!(pair.client_conn_mut(client_ch).rem_cid(), old_cid);
+    // RTT estimation starts over on the new path
+    assert!(pair
+        .client_conn_mut(client_ch)
+        .path_stats()
+        .smoothed_rtt
+        .is_none());
+    pair.drive();
+    assert_eq!(pair.server_conn_mut(server_ch).remote(), pair.client.addr);
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::RemoteAddressChanged { .. })
+    );
+    // Only the client's own address changed
+    assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
+    assert!(pair.client_conn_mut(client_ch).is_path_validated());
+}
+
+#[test]
+fn preferred_address() {
+    let _guard = subscribe();
+    let preferred = SocketAddrV6::new(
+        Ipv6Addr::LOCALHOST,
+        SERVER_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            preferred_address_v6: Some(preferred),
+            ..server_config()
+        },
+    );
+    let client_ch = pair.begin_connect(client_config());
+    let original = pair.server.addr;
+    // Run the handshake until the client confirms it and moves to the preferred address
+    while pair.client_conn_mut(client_ch).remote() == original {
+        assert!(pair.step(), "client didn't migrate");
+    }
+    pair.server.addr = preferred.into();
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+
+    assert_eq!(pair.client_conn_mut(client_ch).remote(), preferred.into());
+    assert!(pair.client_conn_mut(client_ch).is_path_validated());
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::RemoteAddressChanged { old, new }) if old == original && new == preferred.into()
+    );
+    // The client's address didn't change
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert_matches!(pair.server_conn_mut(server_ch).poll(), None);
+
+    // The connection remains usable
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+    pair.client_conn_mut(client_ch).finish(s).unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamOpened { dir: Dir::Uni })
+    );
+}
+
+#[test]
+fn migration_backoff() {
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            migration_backoff: 500_000,
+            ..server_config()
+        },
+    );
+    let (client_ch, server_ch) = pair.connect();
+    let migrate = |pair: &mut Pair| {
+        pair.client.addr = SocketAddr::new(
+            Ipv4Addr::new(127, 0, 0, 1).into(),
+            CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        );
+        pair.client_conn_mut(client_ch).ping();
+        pair.step();
+        pair.client.addr
+    };
+
+    let first = migrate(&mut pair);
+    pair.drive();
+    assert_eq!(pair.server_conn_mut(server_ch).remote(), first);
+    // Too soon after the first migration
+    migrate(&mut pair);
+    assert_eq!(pair.server_conn_mut(server_ch).remote(), first);
+
+    pair.time += Duration::from_millis(600);
+    let third = migrate(&mut pair);
+    pair.drive();
+    assert_eq!(pair.server_conn_mut(server_ch).remote(), third);
+
+    // The rapid succession of changes doubled the wait
+    pair.time += Duration::from_millis(600);
+    migrate(&mut pair);
+    assert_eq!(pair.server_conn_mut(server_ch).remote(), third);
+    pair.time += Duration::from_millis(600);
+    let fifth = migrate(&mut pair);
+    pair.drive();
+    assert_eq!(pair.server_conn_mut(server_ch).remote(), fifth);
+}
+
+#[test]
+fn dscp_marking() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    let now = pair.time;
+    let conn = pair.client_conn_mut(client_ch);
+    conn.set_dscp(46);
+    conn.ping();
+    assert_matches!(conn.poll_transmit(now), Some(Transmit { dscp: 46, .. }));
+}
+
+#[test]
+fn deterministic_header_bits() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let mut config = client_config();
+    Arc::get_mut(&mut config.transport)
+        .unwrap()
+        .deterministic_header_bits = true;
+    let client_ch = pair.begin_connect(config);
+    pair.drive();
+    pair.server.assert_accept();
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_ch)
+        .write(s, &[0xab; 64 * 1024])
+        .unwrap();
+    let mut short_packets = 0;
+    loop {
+        pair.client.drive(pair.time, pair.server.addr);
+        for x in &pair.client.outbound {
+            if x.contents[0] & packet::LONG_HEADER_FORM == 0 {
+                assert_eq!(x.contents[0] & packet::SPIN_BIT, 0);
+                short_packets += 1;
+            }
+        }
+        if !pair.step() {
+            break;
+        }
+    }
+    assert!(short_packets > 0);
+}
+
+#[test]
+fn probe_rtt() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    pair.latency = Duration::from_millis(50);
+    pair.client_conn_mut(client_ch).probe_rtt();
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::PingAcknowledged { rtt }) if rtt >= 2 * pair.latency
+    );
+    assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
+}
+
+#[test]
+fn probe_rtt_lost() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    pair.client_conn_mut(client_ch).probe_rtt();
+    pair.drive_client();
+    pair.server.inbound.clear(); // Simulate loss of the PING
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::PingAcknowledged { .. })
+    );
+}
+
+/// Deliver the last of eight packets sent by the client before the others, returning the number of
+/// packets the client declared lost
+fn reordered_losses(config: TransportConfig) -> u64 {
+    const COUNT: usize = 8;
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_millis(10);
+    let client_ch = pair.begin_connect(ClientConfig {
+        transport: Arc::new(config),
+        ..client_config()
+    });
+    pair.drive();
+    pair.server.assert_accept();
+
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    for _ in 0..COUNT {
+        pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+        pair.client.drive(pair.time, pair.server.addr);
+    }
+    assert_eq!(pair.client.outbound.len(), COUNT);
+    let last = pair.client.outbound.pop_back().unwrap();
+    pair.client.delay_outbound();
+    pair.client.outbound.push_back(last);
+    pair.drive_client();
+    // Acknowledge the last packet before the others arrive
+    pair.time += pair.latency;
+    pair.drive_server();
+    pair.client.finish_delay();
+    pair.drive();
+    pair.client_conn_mut(client_ch).lost_packets()
+}
+
+#[test]
+fn reordering_spurious_loss() {
+    assert!(reordered_losses(TransportConfig::default()) > 0);
+}
+
+#[test]
+fn reordering_threshold() {
+    let config = TransportConfig {
+        packet_threshold: 8,
+        time_threshold: 0xffff, // ~2x
+        ..TransportConfig::default()
+    };
+    assert_eq!(reordered_losses(config), 0);
+}
+
 fn test_flow_control(config: TransportConfig, window_size: usize) {
     let _guard = subscribe();
     let mut pair = Pair::new(
@@ -848,58 +1937,523 @@ fn test_flow_control(config: TransportConfig, window_size: usize) {
     assert_eq!(cursor, window_size);
     pair.drive();
     assert_eq!(
-        pair.client_conn_mut(client_conn).write(s, &msg),
-        Ok(window_size)
+        pair.client_conn_mut(client_conn).write(s, &msg),
+        Ok(window_size)
+    );
+    assert_eq!(
+        pair.client_conn_mut(client_conn)
+            .write(s, &msg[window_size..]),
+        Err(WriteError::Blocked)
+    );
+    pair.drive();
+    let mut cursor = 0;
+    loop {
+        match pair
+            .server_conn_mut(server_conn)
+            .read(s, &mut buf[cursor..])
+        {
+            Ok(Some(n)) => {
+                cursor += n;
+            }
+            Ok(None) => {
+                panic!("end of stream");
+            }
+            Err(ReadError::Blocked) => {
+                break;
+            }
+            Err(e) => {
+                panic!(e);
+            }
+        }
+    }
+    assert_eq!(cursor, window_size);
+}
+
+#[test]
+fn stream_flow_control() {
+    test_flow_control(
+        TransportConfig {
+            stream_receive_window: 2000,
+            ..TransportConfig::default()
+        },
+        2000,
+    );
+}
+
+#[test]
+fn conn_flow_control() {
+    test_flow_control(
+        TransportConfig {
+            receive_window: 2000,
+            ..TransportConfig::default()
+        },
+        2000,
+    );
+}
+
+#[test]
+fn receive_windows_are_fixed() {
+    const STREAM_WINDOW: usize = 2000;
+    const CONN_WINDOW: usize = 3000;
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            transport: Arc::new(TransportConfig {
+                stream_receive_window: STREAM_WINDOW as u64,
+                receive_window: CONN_WINDOW as u64,
+                ..TransportConfig::default()
+            }),
+            ..server_config()
+        },
+    );
+    let (client_conn, server_conn) = pair.connect();
+    let streams = [
+        pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap(),
+        pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap(),
+    ];
+    let msg = [0xAB; 500];
+    let mut buf = [0; 700];
+    let mut written = [0; 2];
+    let mut read = [0; 2];
+
+    for _ in 0..50 {
+        // Keep the sender saturated, so it uses all the credit it has
+        for (i, &s) in streams.iter().enumerate() {
+            while let Ok(n) = pair.client_conn_mut(client_conn).write(s, &msg) {
+                written[i] += n;
+            }
+            assert!(written[i] - read[i] <= STREAM_WINDOW);
+        }
+        let unread = written[0] + written[1] - read[0] - read[1];
+        assert!(unread <= CONN_WINDOW, "{} bytes outstanding", unread);
+        pair.drive();
+
+        // Read slowly, so that credit is only re-granted a bit at a time
+        for (i, &s) in streams.iter().enumerate() {
+            if let Ok(Some(n)) = pair.server_conn_mut(server_conn).read(s, &mut buf) {
+                read[i] += n;
+            }
+        }
+        pair.drive();
+    }
+    // Both streams kept making progress
+    assert!(read.iter().all(|&n| n >= 10 * STREAM_WINDOW), "{:?}", read);
+}
+
+/// Stream data the server reads in 50 rounds of slow reads, and the number of times the client
+/// was issued more stream credit meanwhile
+fn window_updates(window_update_threshold: u16) -> (usize, usize) {
+    const STREAM_WINDOW: usize = 2000;
+    let _guard = subscribe();
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            transport: Arc::new(TransportConfig {
+                stream_receive_window: STREAM_WINDOW as u64,
+                window_update_threshold,
+                ..TransportConfig::default()
+            }),
+            ..server_config()
+        },
+    );
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap();
+    let msg = [0xAB; 500];
+    let mut buf = [0; 200];
+    let mut read = 0;
+    let mut max_data = STREAM_WINDOW as u64;
+    let mut updates = 0;
+
+    for _ in 0..50 {
+        while pair.client_conn_mut(client_conn).write(s, &msg).is_ok() {}
+        pair.drive();
+        if let Ok(Some(n)) = pair.server_conn_mut(server_conn).read(s, &mut buf) {
+            read += n;
+        }
+        pair.drive();
+        let info = pair.client_conn_mut(client_conn).streams()[0]
+            .send
+            .clone()
+            .unwrap();
+        if info.max_data > max_data {
+            updates += 1;
+            max_data = info.max_data;
+        }
+    }
+    (read, updates)
+}
+
+#[test]
+fn window_update_threshold() {
+    let (eager_read, eager_updates) = window_updates(0);
+    let (lazy_read, lazy_updates) = window_updates(0x8000);
+    // Every read is credited when eager, versus one in every five reads of 200 bytes at a
+    // threshold of 1/2
+    assert_eq!(eager_updates, 50);
+    assert_eq!(lazy_updates, 10);
+    // Both transfers ran at the speed of the reader, since credit arrived before the sender ran out
+    assert_eq!(eager_read, 50 * 200);
+    assert_eq!(lazy_read, 50 * 200);
+}
+
+#[test]
+fn path_stats() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert_eq!(
+        stats.congestion_window,
+        TransportConfig::default().initial_window
+    );
+    assert_eq!(stats.ssthresh, u64::max_value());
+    assert_eq!(stats.bytes_in_flight, 0);
+    assert!(stats.smoothed_rtt.is_some());
+    assert!(stats.min_rtt.unwrap() <= stats.latest_rtt.unwrap());
+
+    let s = pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_conn)
+        .write(s, &[0xAB; 1000])
+        .unwrap();
+    pair.drive_client();
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert!(stats.bytes_in_flight > 1000, "{:?}", stats);
+
+    pair.drive();
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert_eq!(stats.bytes_in_flight, 0);
+}
+
+#[test]
+fn connection_stats() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_conn)
+        .write(s, &[0xAB; 5000])
+        .unwrap();
+    pair.drive();
+    pair.client_conn_mut(client_conn).initiate_key_update();
+    pair.client_conn_mut(client_conn)
+        .write(s, b"hello")
+        .unwrap();
+    pair.drive();
+
+    let client = pair.client_conn_mut(client_conn).stats();
+    let server = pair.server_conn_mut(server_conn).stats();
+    assert_eq!(client.path, pair.client_conn_mut(client_conn).path_stats());
+    assert!(client.sent_bytes > 5000, "{:?}", client);
+    assert_eq!(client.sent_bytes, server.received_bytes);
+    assert_eq!(client.received_bytes, server.sent_bytes);
+    assert_eq!(client.sent_datagrams, server.received_datagrams);
+    assert_eq!(client.received_datagrams, server.sent_datagrams);
+    assert_eq!(client.sent_packets, server.received_packets);
+    assert_eq!(client.received_packets, server.sent_packets);
+    // Handshake packets are coalesced
+    assert!(client.sent_packets > client.sent_datagrams, "{:?}", client);
+    assert_eq!(client.path.lost_packets, 0);
+    assert_eq!(client.lost_bytes, 0);
+    assert_eq!(client.key_updates, 1);
+    assert_eq!(server.key_updates, 1);
+}
+
+#[test]
+fn congestion_experienced() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let window = pair
+        .client_conn_mut(client_conn)
+        .path_stats()
+        .congestion_window;
+    // Leave the recovery period the congestion controller starts in
+    pair.time += Duration::from_millis(10);
+    let s = pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_conn)
+        .write(s, &[0xAB; 2000])
+        .unwrap();
+    pair.drive_client();
+    // Have the network mark everything the client just sent
+    for (_, ecn, _) in &mut pair.server.inbound {
+        assert_eq!(*ecn, Some(EcnCodepoint::ECT0));
+        *ecn = Some(EcnCodepoint::CE);
+    }
+    let marked = pair.server.inbound.len() as u64;
+    pair.drive();
+
+    let stats = pair.client_conn_mut(client_conn).stats();
+    assert_eq!(stats.congestion_experienced, marked);
+    assert!(stats.path.congestion_window < window, "{:?}", stats);
+    assert_eq!(stats.path.lost_packets, 0);
+    assert!(pair.client_conn_mut(client_conn).using_ecn());
+    assert_eq!(
+        pair.server_conn_mut(server_conn)
+            .stats()
+            .congestion_experienced,
+        0
+    );
+}
+
+/// Congestion controller holding the window at a fixed size
+#[derive(Debug, Clone)]
+struct FixedWindow(u64);
+
+impl congestion::Controller for FixedWindow {
+    fn on_ack(&mut self, _: Instant, _: Instant, _: u64, _: bool) {}
+
+    fn on_loss(&mut self, _: Instant, _: Instant, _: bool) {}
+
+    fn window(&self) -> u64 {
+        self.0
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        Some(1_000_000)
+    }
+
+    fn clone_box(&self) -> Box<dyn congestion::Controller> {
+        Box::new(self.clone())
+    }
+}
+
+impl congestion::ControllerFactory for FixedWindow {
+    fn build(&self, _: Instant) -> Box<dyn congestion::Controller> {
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn congestion_controller_factory() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.transport)
+        .unwrap()
+        .congestion_controller_factory = Some(Arc::new(FixedWindow(4 * 1200)));
+    let client_conn = pair.begin_connect(client_config);
+    pair.drive();
+    pair.server.assert_accept();
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert_eq!(stats.congestion_window, 4 * 1200);
+    assert_eq!(stats.ssthresh, u64::max_value());
+    assert_eq!(stats.pacing_rate, Some(1_000_000));
+
+    let s = pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_conn)
+        .write(s, &[0xAB; 20_000])
+        .unwrap();
+    pair.drive_client();
+    // The window didn't grow, and a full MTU may exceed it
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert!(stats.bytes_in_flight > 3 * 1200, "{:?}", stats);
+    assert!(stats.bytes_in_flight <= 5 * 1200, "{:?}", stats);
+}
+
+#[test]
+fn window_pacing() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_millis(10);
+    let mut client_config = client_config();
+    Arc::get_mut(&mut client_config.transport)
+        .unwrap()
+        .initial_window = 100 * 1200;
+    let client_conn = pair.begin_connect(client_config);
+    pair.drive();
+    pair.server.assert_accept();
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    let rate = stats.pacing_rate.unwrap();
+    let rtt = stats.smoothed_rtt.unwrap();
+    assert_eq!(
+        rate,
+        (stats.congestion_window * 5 / 4) * 1_000_000_000 / rtt.as_nanos() as u64
     );
-    assert_eq!(
-        pair.client_conn_mut(client_conn)
-            .write(s, &msg[window_size..]),
-        Err(WriteError::Blocked)
+
+    // The window is released over time rather than all at once
+    let s = pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap();
+    pair.client_conn_mut(client_conn)
+        .write(s, &[0xAB; 100_000])
+        .unwrap();
+    pair.drive_client();
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert!(
+        stats.bytes_in_flight < stats.congestion_window / 2,
+        "{:?}",
+        stats
+    );
+    let burst = stats.bytes_in_flight;
+    pair.time = pair.client.next_wakeup().unwrap();
+    pair.drive_client();
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert!(stats.bytes_in_flight > burst, "{:?}", stats);
+}
+
+#[test]
+fn bbr_transfer() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_millis(10);
+    let mut client_config = client_config();
+    let transport = Arc::get_mut(&mut client_config.transport).unwrap();
+    let bbr = congestion::BbrConfig::new(transport);
+    transport.congestion_controller_factory = Some(Arc::new(bbr));
+    let client_conn = pair.begin_connect(client_config);
+    pair.drive();
+    let server_conn = pair.server.assert_accept();
+    let initial_rate = pair.client_conn_mut(client_conn).path_stats().pacing_rate;
+    assert!(initial_rate.is_some());
+
+    transfer(&mut pair, client_conn, server_conn, 256 * 1024, None);
+
+    // The bandwidth estimate has replaced the initial pacing rate, and the data in flight is
+    // bounded by the window it implies
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert_ne!(stats.pacing_rate, initial_rate, "{:?}", stats);
+    assert!(
+        stats.bytes_in_flight <= stats.congestion_window,
+        "{:?}",
+        stats
     );
+    assert_eq!(stats.lost_packets, 0, "{:?}", stats);
+}
+
+#[test]
+fn cubic_transfer() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_millis(10);
+    let mut client_config = client_config();
+    let transport = Arc::get_mut(&mut client_config.transport).unwrap();
+    let cubic = congestion::CubicConfig {
+        c: 0.8,
+        ..congestion::CubicConfig::new(transport)
+    };
+    transport.congestion_controller_factory = Some(Arc::new(cubic));
+    let client_conn = pair.begin_connect(client_config);
     pair.drive();
-    let mut cursor = 0;
-    loop {
-        match pair
-            .server_conn_mut(server_conn)
-            .read(s, &mut buf[cursor..])
-        {
-            Ok(Some(n)) => {
-                cursor += n;
-            }
-            Ok(None) => {
-                panic!("end of stream");
+    let server_conn = pair.server.assert_accept();
+    let initial_ssthresh = pair.client_conn_mut(client_conn).path_stats().ssthresh;
+
+    transfer(
+        &mut pair,
+        client_conn,
+        server_conn,
+        256 * 1024,
+        Some(64 * 1024),
+    );
+
+    // The loss ended slow start, and the window has grown back past the reduced threshold
+    let stats = pair.client_conn_mut(client_conn).path_stats();
+    assert!(stats.lost_packets >= 1, "{:?}", stats);
+    assert!(stats.ssthresh < initial_ssthresh, "{:?}", stats);
+    assert!(stats.congestion_window > stats.ssthresh, "{:?}", stats);
+}
+
+/// Send `size` bytes from client to server on a new stream, dropping the first client packet sent
+/// after `drop_after` bytes have been written
+fn transfer(
+    pair: &mut Pair,
+    client_conn: ConnectionHandle,
+    server_conn: ConnectionHandle,
+    size: usize,
+    mut drop_after: Option<usize>,
+) {
+    let msg = vec![0xAB; size];
+    let mut buf = [0; 4096];
+    let s = pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap();
+    let (mut sent, mut received) = (0, 0);
+    while received < size {
+        if sent < size {
+            match pair.client_conn_mut(client_conn).write(s, &msg[sent..]) {
+                Ok(n) => sent += n,
+                Err(WriteError::Blocked) => {}
+                Err(e) => panic!("unexpected write error: {}", e),
             }
-            Err(ReadError::Blocked) => {
-                break;
+        }
+        if drop_after.map(|x| sent >= x).unwrap_or(false) {
+            pair.drive_client();
+            if pair.server.inbound.pop_back().is_some() {
+                drop_after = None;
             }
-            Err(e) => {
-                panic!(e);
+        }
+        pair.step();
+        loop {
+            match pair.server_conn_mut(server_conn).read(s, &mut buf) {
+                Ok(Some(n)) => received += n,
+                Ok(None) => unreachable!(),
+                Err(ReadError::Blocked) | Err(ReadError::UnknownStream) => break,
+                Err(e) => panic!("unexpected read error: {}", e),
             }
         }
     }
-    assert_eq!(cursor, window_size);
 }
 
 #[test]
-fn stream_flow_control() {
-    test_flow_control(
-        TransportConfig {
-            stream_receive_window: 2000,
-            ..TransportConfig::default()
-        },
-        2000,
+fn stream_info() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    assert!(pair.client_conn_mut(client_conn).streams().is_empty());
+    // Streams the client may open aren't listed until it does
+    assert!(pair.server_conn_mut(server_conn).streams().is_empty());
+
+    let uni = pair.client_conn_mut(client_conn).open(Dir::Uni).unwrap();
+    let bi = pair.client_conn_mut(client_conn).open(Dir::Bi).unwrap();
+    pair.client_conn_mut(client_conn)
+        .write(uni, b"hello")
+        .unwrap();
+    pair.client_conn_mut(client_conn).finish(uni).unwrap();
+    pair.client_conn_mut(client_conn)
+        .write(bi, b"world")
+        .unwrap();
+    pair.client_conn_mut(client_conn).reset(bi, VarInt(42));
+
+    let streams = pair.client_conn_mut(client_conn).streams();
+    assert_eq!(
+        streams.iter().map(|s| s.id).collect::<Vec<_>>(),
+        vec![bi, uni]
+    );
+    let send = streams[1].send.as_ref().unwrap();
+    assert_eq!(send.state, SendStreamState::Finished);
+    assert_eq!(send.written, 5);
+    assert!(streams[1].recv.is_none());
+    assert_eq!(
+        streams[0].send.as_ref().unwrap().state,
+        SendStreamState::Reset
+    );
+    assert_eq!(
+        streams[0].recv.as_ref().unwrap().state,
+        RecvStreamState::Receiving
     );
-}
 
-#[test]
-fn conn_flow_control() {
-    test_flow_control(
-        TransportConfig {
-            receive_window: 2000,
-            ..TransportConfig::default()
-        },
-        2000,
+    pair.drive();
+    let streams = pair.server_conn_mut(server_conn).streams();
+    assert_eq!(
+        streams.iter().map(|s| s.id).collect::<Vec<_>>(),
+        vec![bi, uni]
+    );
+    let recv = streams[1].recv.as_ref().unwrap();
+    assert_eq!(recv.state, RecvStreamState::AllReceived);
+    assert_eq!(recv.received, 5);
+    assert_eq!(recv.read, 0);
+    assert_eq!(recv.final_size, Some(5));
+    assert!(streams[1].send.is_none());
+    assert_eq!(
+        streams[0].recv.as_ref().unwrap().state,
+        RecvStreamState::Reset(VarInt(42))
+    );
+    assert_eq!(
+        streams[0].send.as_ref().unwrap().state,
+        SendStreamState::Open
     );
+
+    // Fully closed streams are no longer listed
+    let client_streams = pair.client_conn_mut(client_conn).streams();
+    assert_eq!(client_streams.len(), 1);
+    assert_eq!(client_streams[0].id, bi);
+    assert_eq!(client_streams[0].send, None);
 }
 
 #[test]
@@ -979,6 +2533,44 @@ fn zero_length_cid() {
     pair.connect();
 }
 
+#[test]
+fn cid_limit_exceeded() {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            active_connection_id_limit: 2,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, server_ch) = pair.connect();
+    assert!(!pair.server_conn_mut(server_ch).is_closed());
+
+    // Issue more CIDs than the server is willing to store
+    let ids = (2..4)
+        .map(|sequence| IssuedCid {
+            sequence,
+            id: ConnectionId::new(&[sequence as u8; 8]),
+            reset_token: [0; RESET_TOKEN_SIZE].into(),
+        })
+        .collect();
+    pair.client_conn_mut(client_ch)
+        .handle_event(ConnectionEvent(ConnectionEventInner::NewIdentifiers(ids)));
+    pair.drive();
+
+    assert!(pair.server_conn_mut(server_ch).is_closed());
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                error_code: TransportErrorCode::CONNECTION_ID_LIMIT_ERROR,
+                ..
+            }),
+        })
+    );
+}
+
 #[test]
 fn keep_alive() {
     let _guard = subscribe();
@@ -1274,6 +2866,200 @@ fn datagram_unsupported() {
     }
 }
 
+/// Connect to a server whose connections may buffer 2000 bytes beyond their receive window
+fn memory_limited_pair() -> (Pair, ConnectionHandle, ConnectionHandle) {
+    let _guard = subscribe();
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            receive_window: 8000,
+            stream_receive_window: 8000,
+            max_connection_memory: Some(10_000),
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let (client_ch, server_ch) = pair.connect();
+    (pair, client_ch, server_ch)
+}
+
+fn send_datagrams(pair: &mut Pair, ch: ConnectionHandle, count: usize) {
+    for _ in 0..count {
+        pair.client_conn_mut(ch)
+            .send_datagram()
+            .unwrap()
+            .send(vec![0; 1000].into())
+            .unwrap();
+    }
+}
+
+#[test]
+fn memory_limit_blocks_writes() {
+    let (mut pair, _, server_ch) = memory_limited_pair();
+    // The client's receive window is reserved, so only 2000 bytes remain for outgoing data
+    let s = pair.server_conn_mut(server_ch).open(Dir::Uni).unwrap();
+    assert_eq!(
+        pair.server_conn_mut(server_ch).write(s, &[0; 5000]),
+        Ok(2000)
+    );
+    assert_eq!(
+        pair.server_conn_mut(server_ch).write(s, &[0; 5000]),
+        Err(WriteError::Blocked)
+    );
+
+    pair.drive();
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::StreamWritable { stream }) if stream == s
+    );
+    assert_eq!(
+        pair.server_conn_mut(server_ch).write(s, &[0; 5000]),
+        Ok(2000)
+    );
+}
+
+#[test]
+fn memory_limit_withholds_credit() {
+    let (mut pair, client_ch, server_ch) = memory_limited_pair();
+    send_datagrams(&mut pair, client_ch, 3);
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, &[0; 7000]),
+        Ok(7000)
+    );
+    pair.drive();
+
+    assert_matches!(pair.server_conn_mut(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    let mut buf = [0; 8000];
+    assert_matches!(
+        pair.server_conn_mut(server_ch).read(s, &mut buf),
+        Ok(Some(7000))
+    );
+    pair.drive();
+    // Credit for 1000 bytes is held back while the datagrams remain buffered
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, &[0; 8000]),
+        Ok(7000)
+    );
+    pair.drive();
+
+    while pair.server_conn_mut(server_ch).recv_datagram().is_some() {}
+    pair.drive();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, &[0; 8000]),
+        Ok(1000)
+    );
+    assert!(!pair.server_conn_mut(server_ch).is_closed());
+}
+
+#[test]
+fn memory_limit_exceeded() {
+    let (mut pair, client_ch, server_ch) = memory_limited_pair();
+    send_datagrams(&mut pair, client_ch, 3);
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).write(s, &[0; 8000]),
+        Ok(8000)
+    );
+    pair.drive();
+
+    assert!(pair.server_conn_mut(server_ch).is_closed());
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                error_code: TransportErrorCode::INTERNAL_ERROR,
+                ..
+            }),
+        })
+    );
+}
+
+/// Connect a client that asks for an ACK every ten packets to a server advertising `min_ack_delay`
+fn ack_frequency_pair(min_ack_delay: Option<u64>) -> (Pair, ConnectionHandle, ConnectionHandle) {
+    let server = ServerConfig {
+        transport: Arc::new(TransportConfig {
+            min_ack_delay,
+            ..TransportConfig::default()
+        }),
+        ..server_config()
+    };
+    let mut pair = Pair::new(Default::default(), server);
+    let client_ch = pair.begin_connect(ClientConfig {
+        transport: Arc::new(TransportConfig {
+            ack_packet_tolerance: 10,
+            ..TransportConfig::default()
+        }),
+        ..client_config()
+    });
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    (pair, client_ch, server_ch)
+}
+
+/// Deliver `count` packets of stream data from the client one at a time, returning the number of
+/// packets the server sent in response
+fn server_acks(pair: &mut Pair, client_ch: ConnectionHandle, count: usize) -> usize {
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    let mut acks = 0;
+    for _ in 0..count {
+        pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+        pair.drive_client();
+        pair.server.drive(pair.time, pair.client.addr);
+        acks += pair.server.outbound.len();
+        pair.drive_server();
+    }
+    acks
+}
+
+#[test]
+fn ack_frequency() {
+    let _guard = subscribe();
+    let (mut pair, client_ch, server_ch) = ack_frequency_pair(Some(1000));
+    assert_eq!(server_acks(&mut pair, client_ch, 20), 2);
+    pair.drive();
+    assert!(!pair.server_conn_mut(server_ch).is_closed());
+}
+
+#[test]
+fn ack_frequency_unsupported() {
+    let _guard = subscribe();
+    let (mut pair, client_ch, server_ch) = ack_frequency_pair(None);
+    assert_eq!(server_acks(&mut pair, client_ch, 20), 20);
+    pair.drive();
+    assert!(!pair.server_conn_mut(server_ch).is_closed());
+}
+
+#[test]
+fn ack_frequency_max_ack_delay() {
+    let _guard = subscribe();
+    let (mut pair, client_ch, _) = ack_frequency_pair(Some(1000));
+    assert_eq!(server_acks(&mut pair, client_ch, 1), 0);
+    // The client asks for the default delayed_ack_timeout of 25ms
+    pair.time += Duration::from_millis(25);
+    pair.server.drive(pair.time, pair.client.addr);
+    assert_eq!(pair.server.outbound.len(), 1);
+}
+
+#[test]
+fn immediate_ack() {
+    let _guard = subscribe();
+    let (mut pair, client_ch, _) = ack_frequency_pair(Some(1000));
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    pair.client_conn_mut(client_ch).probe_rtt();
+    pair.drive_client();
+    pair.server.drive(pair.time, pair.client.addr);
+    assert_eq!(pair.server.outbound.len(), 1);
+    pair.drive();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::PingAcknowledged { .. })
+    );
+}
+
 #[test]
 fn large_initial() {
     let _guard = subscribe();
@@ -1297,3 +3083,76 @@ fn large_initial() {
         Some(Event::Connected { .. })
     );
 }
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn qlog() {
+    let _guard = subscribe();
+    let buffer = SharedBuffer::default();
+    let mut config = TransportConfig::default();
+    config.qlog_writer(Box::new(buffer.clone()));
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_millis(10);
+    let client_ch = pair.begin_connect(ClientConfig {
+        transport: Arc::new(config),
+        ..client_config()
+    });
+    pair.drive();
+    pair.server.assert_accept();
+
+    // Reorder packets as in `reordered_losses`, so that some are declared lost
+    let s = pair.client_conn_mut(client_ch).open(Dir::Uni).unwrap();
+    for _ in 0..8 {
+        pair.client_conn_mut(client_ch).write(s, b"hello").unwrap();
+        pair.client.drive(pair.time, pair.server.addr);
+    }
+    let last = pair.client.outbound.pop_back().unwrap();
+    pair.client.delay_outbound();
+    pair.client.outbound.push_back(last);
+    pair.drive_client();
+    pair.time += pair.latency;
+    pair.drive_server();
+    pair.client.finish_delay();
+    pair.drive();
+    assert_ne!(pair.client_conn_mut(client_ch).lost_packets(), 0);
+
+    let trace = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let records = trace
+        .split('\x1e')
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    assert!(records.iter().all(|x| x.ends_with("}\n")));
+    assert!(records[0].contains("\"vantage_point\":{\"type\":\"client\"}"));
+    for name in &[
+        "transport:parameters_set",
+        "transport:packet_sent",
+        "transport:packet_received",
+        "recovery:packet_lost",
+        "recovery:metrics_updated",
+    ] {
+        assert!(
+            records[1..]
+                .iter()
+                .any(|x| x.contains(&format!("\"name\":\"{}\"", name))),
+            "no {} event",
+            name
+        );
+    }
+    assert!(trace.contains("\"owner\":\"local\""));
+    assert!(trace.contains("\"owner\":\"remote\""));
+    assert!(trace.contains("\"frame_type\":\"crypto\""));
+    assert!(trace.contains("\"frame_type\":\"stream\""));
+}
@@ -146,7 +146,7 @@ impl Pair {
         let _guard = span.enter();
         let (client_ch, client_conn) = self
             .client
-            .connect(config, self.server.addr, "localhost")
+            .connect(self.time, config, self.server.addr, "localhost")
             .unwrap();
         self.client.connections.insert(client_ch, client_conn);
         client_ch
@@ -340,6 +340,30 @@ pub fn subscribe() -> tracing::subscriber::DefaultGuard {
     tracing::subscriber::set_default(sub)
 }
 
+/// Like `subscribe`, but also collects the formatted log lines
+pub fn subscribe_captured() -> (tracing::subscriber::DefaultGuard, Arc<Mutex<Vec<u8>>>) {
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let writer = captured.clone();
+    let sub = tracing_subscriber::FmtSubscriber::builder()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer(move || CaptureWriter(writer.clone()))
+        .finish();
+    (tracing::subscriber::set_default(sub), captured)
+}
+
+struct CaptureWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        TestWriter.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        TestWriter.flush()
+    }
+}
+
 struct TestWriter;
 
 impl Write for TestWriter {
@@ -402,6 +426,6 @@ pub fn min_opt<T: Ord>(x: Option<T>, y: Option<T>) -> Option<T> {
 lazy_static! {
     pub static ref SERVER_PORTS: Mutex<RangeFrom<u16>> = Mutex::new(4433..);
     pub static ref CLIENT_PORTS: Mutex<RangeFrom<u16>> = Mutex::new(44433..);
-    static ref CERTIFICATE: rcgen::Certificate =
+    pub static ref CERTIFICATE: rcgen::Certificate =
         rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
 }
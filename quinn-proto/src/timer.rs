@@ -21,30 +21,36 @@ pub(crate) enum TimerKind {
     PathValidation = 4,
     /// When to send a `PING` frame to keep the connection alive
     KeepAlive = 5,
+    /// When to send an ACK that was delayed at the peer's request
+    MaxAckDelay = 6,
+    /// When the pacing rate permits sending the next packet
+    Pacing = 7,
 }
 
 impl TimerKind {
-    const VALUES: [Self; 6] = [
+    const VALUES: [Self; 8] = [
         TimerKind::LossDetection,
         TimerKind::Idle,
         TimerKind::Close,
         TimerKind::KeyDiscard,
         TimerKind::PathValidation,
         TimerKind::KeepAlive,
+        TimerKind::MaxAckDelay,
+        TimerKind::Pacing,
     ];
 }
 
 /// A table of data associated with each distinct kind of `Timer`
 #[derive(Debug, Copy, Clone, Default)]
 pub struct TimerTable<T> {
-    data: [T; 6],
+    data: [T; 8],
 }
 
 impl<T> TimerTable<T> {
     /// Create a table initialized with the value returned by `f` for each timer
     pub fn new(mut f: impl FnMut() -> T) -> Self {
         Self {
-            data: [f(), f(), f(), f(), f(), f()],
+            data: [f(), f(), f(), f(), f(), f(), f(), f()],
         }
     }
 
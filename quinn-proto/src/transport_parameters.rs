@@ -1,4 +1,4 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use bytes::{buf::ext::BufExt as _, Buf, BufMut};
 use err_derive::Error;
@@ -6,8 +6,8 @@ use err_derive::Error;
 use crate::{
     coding::{BufExt, BufMutExt, UnexpectedEnd},
     crypto,
-    shared::{ConnectionId, ResetToken, ServerConfig},
-    Side, TransportConfig, TransportError, VarInt, MAX_CID_SIZE, REM_CID_COUNT, RESET_TOKEN_SIZE,
+    shared::{ConnectionId, IpPreference, ResetToken, ServerConfig},
+    Side, TransportConfig, TransportError, VarInt, MAX_CID_SIZE, RESET_TOKEN_SIZE,
 };
 
 // Apply a given macro to a list of all the transport parameters having integer types, along with
@@ -39,17 +39,21 @@ macro_rules! apply_params {
 
 macro_rules! make_struct {
     {$($name:ident ($code:expr) = $default:expr,)*} => {
+        /// Transport parameters exchanged during the cryptographic handshake
+        ///
+        /// Construct validated parameters with `TransportParametersBuilder`.
         #[derive(Debug, Copy, Clone, Eq, PartialEq)]
         pub struct TransportParameters {
-            $(pub $name : u64,)*
+            $(pub(crate) $name : u64,)*
 
-            pub disable_active_migration: bool,
-            pub max_datagram_frame_size: Option<VarInt>,
+            pub(crate) disable_active_migration: bool,
+            pub(crate) max_datagram_frame_size: Option<VarInt>,
+            pub(crate) min_ack_delay: Option<VarInt>,
 
             // Server-only
-            pub original_connection_id: Option<ConnectionId>,
-            pub stateless_reset_token: Option<ResetToken>,
-            pub preferred_address: Option<PreferredAddress>,
+            pub(crate) original_connection_id: Option<ConnectionId>,
+            pub(crate) stateless_reset_token: Option<ResetToken>,
+            pub(crate) preferred_address: Option<PreferredAddress>,
         }
 
         impl Default for TransportParameters {
@@ -60,6 +64,7 @@ macro_rules! make_struct {
 
                     disable_active_migration: false,
                     max_datagram_frame_size: None,
+                    min_ack_delay: None,
 
                     original_connection_id: None,
                     stateless_reset_token: None,
@@ -72,8 +77,33 @@ macro_rules! make_struct {
 
 apply_params!(make_struct);
 
+macro_rules! make_accessors {
+    {$($name:ident ($code:expr) = $default:expr,)*} => {
+        impl TransportParameters {
+            $(
+                /// Value of the integer transport parameter of the same name
+                pub fn $name(&self) -> u64 {
+                    self.$name
+                }
+            )*
+        }
+
+        impl TransportParametersBuilder {
+            $(
+                /// Set the integer transport parameter of the same name
+                pub fn $name(&mut self, value: u64) -> &mut Self {
+                    self.params.$name = value;
+                    self
+                }
+            )*
+        }
+    }
+}
+
+apply_params!(make_accessors);
+
 impl TransportParameters {
-    pub fn new<S>(config: &TransportConfig, server_config: Option<&ServerConfig<S>>) -> Self
+    pub(crate) fn new<S>(config: &TransportConfig, server_config: Option<&ServerConfig<S>>) -> Self
     where
         S: crypto::Session,
     {
@@ -85,19 +115,23 @@ impl TransportParameters {
             initial_max_stream_data_bidi_remote: config.stream_receive_window,
             initial_max_stream_data_uni: config.stream_receive_window,
             idle_timeout: config.idle_timeout,
-            max_ack_delay: 0,
+            // We only delay acks at the peer's request, which can't be for less than min_ack_delay
+            max_ack_delay: config.min_ack_delay.map_or(0, |x| (x + 999) / 1000),
             disable_active_migration: server_config.map_or(false, |c| !c.migration),
-            active_connection_id_limit: REM_CID_COUNT,
+            active_connection_id_limit: config.active_connection_id_limit,
             max_datagram_frame_size: config
                 .datagram_receive_buffer_size
                 .map(|x| (x.min(u16::max_value().into()) as u16).into()),
+            min_ack_delay: config
+                .min_ack_delay
+                .map(|x| VarInt::from_u64(x).expect("min_ack_delay validated")),
             ..Self::default()
         }
     }
 
     /// Check that these parameters are legal when resuming from
     /// certain cached parameters
-    pub fn validate_0rtt(&self, cached: &TransportParameters) -> Result<(), TransportError> {
+    pub(crate) fn validate_0rtt(&self, cached: &TransportParameters) -> Result<(), TransportError> {
         if cached.initial_max_data < self.initial_max_data
             || cached.initial_max_stream_data_bidi_local < self.initial_max_stream_data_bidi_local
             || cached.initial_max_stream_data_bidi_remote < self.initial_max_stream_data_bidi_remote
@@ -112,17 +146,154 @@ impl TransportParameters {
         }
         Ok(())
     }
+
+    /// Whether the peer may not migrate to a new address
+    pub fn disable_active_migration(&self) -> bool {
+        self.disable_active_migration
+    }
+
+    /// Maximum size of DATAGRAM frames accepted, if any
+    pub fn max_datagram_frame_size(&self) -> Option<VarInt> {
+        self.max_datagram_frame_size
+    }
+
+    /// Smallest delay (μs) the peer can be asked to wait before acknowledging packets, if it
+    /// supports the ACK frequency extension
+    pub fn min_ack_delay(&self) -> Option<VarInt> {
+        self.min_ack_delay
+    }
+
+    /// The destination connection ID of the client's first Initial packet (server only)
+    pub fn original_connection_id(&self) -> Option<ConnectionId> {
+        self.original_connection_id
+    }
+
+    /// Token used by the peer to recognize a stateless reset (server only)
+    pub fn stateless_reset_token(&self) -> Option<&[u8]> {
+        self.stateless_reset_token.as_ref().map(|x| &x[..])
+    }
+
+    /// Check semantic constraints on parameters sent by `side`
+    fn validate(&self, side: Side) -> Result<(), Error> {
+        macro_rules! in_bounds {
+            {$($name:ident ($code:expr) = $default:expr,)*} => {
+                $(VarInt::from_u64(self.$name).is_ok() &&)* true
+            }
+        }
+        if !apply_params!(in_bounds)
+            || self.ack_delay_exponent > 20
+            || self.max_ack_delay >= 1 << 14
+            || self.min_ack_delay.map_or(false, |x| {
+                x.into_inner() >= 1 << 24 || x.into_inner() > self.max_ack_delay * 1000
+            })
+            || (side.is_client()
+                && (self.original_connection_id.is_some()
+                    || self.stateless_reset_token.is_some()
+                    || self.preferred_address.is_some()))
+        {
+            return Err(Error::IllegalValue);
+        }
+        Ok(())
+    }
+}
+
+/// Builder for validated `TransportParameters`
+///
+/// Parameters which are not set explicitly take the protocol-specified defaults. Mainly useful to
+/// exercise `crypto::Session` implementations without a live connection.
+#[derive(Debug, Clone, Default)]
+pub struct TransportParametersBuilder {
+    params: TransportParameters,
+}
+
+impl TransportParametersBuilder {
+    /// Create a builder starting from the protocol-specified defaults
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forbid the peer from migrating to a new address
+    pub fn disable_active_migration(&mut self, value: bool) -> &mut Self {
+        self.params.disable_active_migration = value;
+        self
+    }
+
+    /// Maximum size of DATAGRAM frames to accept, or `None` to refuse them
+    pub fn max_datagram_frame_size(&mut self, value: Option<VarInt>) -> &mut Self {
+        self.params.max_datagram_frame_size = value;
+        self
+    }
+
+    /// Smallest delay (μs) the peer can ask us to wait before acknowledging packets, or `None` to
+    /// refuse ACK_FREQUENCY frames
+    pub fn min_ack_delay(&mut self, value: Option<VarInt>) -> &mut Self {
+        self.params.min_ack_delay = value;
+        self
+    }
+
+    /// The destination connection ID of the client's first Initial packet (server only)
+    pub fn original_connection_id(&mut self, value: ConnectionId) -> &mut Self {
+        self.params.original_connection_id = Some(value);
+        self
+    }
+
+    /// Token allowing the peer to recognize a stateless reset (server only)
+    pub fn stateless_reset_token(&mut self, value: [u8; RESET_TOKEN_SIZE]) -> &mut Self {
+        self.params.stateless_reset_token = Some(value.into());
+        self
+    }
+
+    /// Construct the parameters to be sent by `side`
+    ///
+    /// Fails if the parameters would be rejected by a peer, e.g. because a client-sent set contains
+    /// server-only parameters.
+    pub fn build(&self, side: Side) -> Result<TransportParameters, Error> {
+        self.params.validate(side)?;
+        Ok(self.params)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PreferredAddress {
-    address_v4: Option<SocketAddrV4>,
-    address_v6: Option<SocketAddrV6>,
-    connection_id: ConnectionId,
-    stateless_reset_token: [u8; RESET_TOKEN_SIZE],
+    pub(crate) address_v4: Option<SocketAddrV4>,
+    pub(crate) address_v6: Option<SocketAddrV6>,
+    pub(crate) connection_id: ConnectionId,
+    pub(crate) stateless_reset_token: [u8; RESET_TOKEN_SIZE],
 }
 
 impl PreferredAddress {
+    /// The address to migrate to from a path to `current`, in the family `preference` picks
+    ///
+    /// A path to an IPv4-mapped address runs over a dual-stack socket, which reaches the IPv4
+    /// address in mapped form. IPv4 sockets can't reach the IPv6 address.
+    pub(crate) fn select(
+        &self,
+        current: SocketAddr,
+        preference: IpPreference,
+    ) -> Option<SocketAddr> {
+        let (v4, v6, established_v4) = match current {
+            SocketAddr::V4(_) => (self.address_v4.map(SocketAddr::V4), None, true),
+            SocketAddr::V6(ref x) => {
+                let mapped = x.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff];
+                let v4 = self
+                    .address_v4
+                    .filter(|_| mapped)
+                    .map(|x| SocketAddrV6::new(x.ip().to_ipv6_mapped(), x.port(), 0, 0).into());
+                (v4, self.address_v6.map(SocketAddr::V6), mapped)
+            }
+        };
+        let prefer_v4 = match preference {
+            IpPreference::Ipv4 => true,
+            IpPreference::Ipv6 => false,
+            IpPreference::LowestRtt => established_v4,
+        };
+        if prefer_v4 {
+            v4.or(v6)
+        } else {
+            v6.or(v4)
+        }
+    }
+
     fn wire_size(&self) -> u16 {
         4 + 2 + 16 + 2 + 1 + self.connection_id.len() as u16 + 16
     }
@@ -176,12 +347,16 @@ impl PreferredAddress {
     }
 }
 
+/// Errors encountered while decoding or validating `TransportParameters`
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
 pub enum Error {
+    /// Version negotiation was tampered with
     #[error(display = "version negotiation was tampered with")]
     VersionNegotiation,
+    /// A parameter had an illegal value
     #[error(display = "parameter had illegal value")]
     IllegalValue,
+    /// The parameters were malformed
     #[error(display = "parameters were malformed")]
     Malformed,
 }
@@ -189,7 +364,9 @@ pub enum Error {
 impl From<Error> for TransportError {
     fn from(e: Error) -> Self {
         match e {
-            Error::VersionNegotiation => TransportError::VERSION_NEGOTIATION_ERROR(""),
+            Error::VersionNegotiation => {
+                TransportError::TRANSPORT_PARAMETER_ERROR("version negotiation was tampered with")
+            }
             Error::IllegalValue => TransportError::TRANSPORT_PARAMETER_ERROR("illegal value"),
             Error::Malformed => TransportError::TRANSPORT_PARAMETER_ERROR("malformed"),
         }
@@ -203,6 +380,7 @@ impl From<UnexpectedEnd> for Error {
 }
 
 impl TransportParameters {
+    /// Encode the parameters into their wire format
     pub fn write<W: BufMut>(&self, w: &mut W) {
         let mut buf = Vec::new();
 
@@ -246,6 +424,12 @@ impl TransportParameters {
             buf.write(x);
         }
 
+        if let Some(x) = self.min_ack_delay {
+            buf.write::<u16>(0xde1a);
+            buf.write::<u16>(x.size() as u16);
+            buf.write(x);
+        }
+
         if let Some(ref x) = self.preferred_address {
             buf.write::<u16>(0x000d);
             buf.write::<u16>(x.wire_size());
@@ -256,6 +440,7 @@ impl TransportParameters {
         w.put_slice(&buf);
     }
 
+    /// Decode and validate parameters received by `side`
     pub fn read<R: Buf>(side: Side, r: &mut R) -> Result<Self, Error> {
         // Initialize to protocol-specified defaults
         let mut params = TransportParameters::default();
@@ -326,6 +511,13 @@ impl TransportParameters {
                     }
                     params.max_datagram_frame_size = Some(r.get().unwrap());
                 }
+                0xde1a => {
+                    let value = r.get::<VarInt>()?;
+                    if len != value.size() as u16 || params.min_ack_delay.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.min_ack_delay = Some(value);
+                }
                 _ => {
                     macro_rules! parse {
                         {$($name:ident ($code:expr) = $default:expr,)*} => {
@@ -345,15 +537,7 @@ impl TransportParameters {
         }
 
         // Semantic validation
-        if params.ack_delay_exponent > 20
-            || params.max_ack_delay >= 1 << 14
-            || (side.is_server()
-                && (params.original_connection_id.is_some()
-                    || params.stateless_reset_token.is_some()
-                    || params.preferred_address.is_some()))
-        {
-            return Err(Error::IllegalValue);
-        }
+        params.validate(!side)?;
 
         Ok(params)
     }
@@ -385,4 +569,118 @@ mod test {
             params
         );
     }
+
+    #[test]
+    fn preferred_address_selection() {
+        let v4 = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 4433);
+        let v6 = SocketAddrV6::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 4433, 0, 0);
+        let mapped = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().into(), 4433);
+        let both = PreferredAddress {
+            address_v4: Some(v4),
+            address_v6: Some(v6),
+            connection_id: ConnectionId::new(&[]),
+            stateless_reset_token: [0; RESET_TOKEN_SIZE],
+        };
+        let from_v4: SocketAddr = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 443).into();
+        let from_v6: SocketAddr = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 443, 0, 0).into();
+        let from_mapped = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 2).to_ipv6_mapped().into(), 443);
+
+        // IPv4 sockets can only use the IPv4 address
+        assert_eq!(both.select(from_v4, IpPreference::Ipv6), Some(v4.into()));
+        // Native IPv6 paths may not reach IPv4 at all
+        assert_eq!(both.select(from_v6, IpPreference::Ipv4), Some(v6.into()));
+        // Dual-stack sockets honor the preference
+        assert_eq!(both.select(from_mapped, IpPreference::Ipv4), Some(mapped));
+        assert_eq!(
+            both.select(from_mapped, IpPreference::Ipv6),
+            Some(v6.into())
+        );
+        assert_eq!(
+            both.select(from_mapped, IpPreference::LowestRtt),
+            Some(mapped)
+        );
+        assert_eq!(
+            both.select(from_v6, IpPreference::LowestRtt),
+            Some(v6.into())
+        );
+
+        // Falls back to the other family
+        let only_v4 = PreferredAddress {
+            address_v6: None,
+            ..both
+        };
+        assert_eq!(
+            only_v4.select(from_mapped, IpPreference::Ipv6),
+            Some(mapped)
+        );
+        assert_eq!(only_v4.select(from_v6, IpPreference::Ipv6), None);
+    }
+
+    #[test]
+    fn builder_coding() {
+        let params = TransportParametersBuilder::new()
+            .initial_max_streams_bidi(16)
+            .initial_max_data(1 << 20)
+            .max_datagram_frame_size(Some(VarInt::from_u32(1200)))
+            .min_ack_delay(Some(VarInt::from_u32(1000)))
+            .original_connection_id(ConnectionId::new(&[0xab; 8]))
+            .stateless_reset_token([0xcd; RESET_TOKEN_SIZE])
+            .build(Side::Server)
+            .unwrap();
+        assert_eq!(params.initial_max_streams_bidi(), 16);
+        assert_eq!(params.initial_max_data(), 1 << 20);
+        assert_eq!(params.ack_delay_exponent(), 3);
+        assert_eq!(params.min_ack_delay(), Some(VarInt::from_u32(1000)));
+        assert_eq!(
+            params.stateless_reset_token(),
+            Some(&[0xcd; RESET_TOKEN_SIZE][..])
+        );
+
+        let mut buf = Vec::new();
+        params.write(&mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Client, &mut buf.as_slice()).unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    fn builder_validation() {
+        let mut builder = TransportParametersBuilder::new();
+        builder.original_connection_id(ConnectionId::new(&[0xab; 8]));
+        assert_eq!(builder.build(Side::Client), Err(Error::IllegalValue));
+        assert!(builder.build(Side::Server).is_ok());
+
+        let mut builder = TransportParametersBuilder::new();
+        builder.ack_delay_exponent(21);
+        assert_eq!(builder.build(Side::Client), Err(Error::IllegalValue));
+
+        let mut builder = TransportParametersBuilder::new();
+        builder.max_ack_delay(1 << 14);
+        assert_eq!(builder.build(Side::Server), Err(Error::IllegalValue));
+
+        let mut builder = TransportParametersBuilder::new();
+        builder.initial_max_data(1 << 62);
+        assert_eq!(builder.build(Side::Server), Err(Error::IllegalValue));
+
+        let mut builder = TransportParametersBuilder::new();
+        builder
+            .max_ack_delay(1)
+            .min_ack_delay(Some(VarInt::from_u32(1001)));
+        assert_eq!(builder.build(Side::Client), Err(Error::IllegalValue));
+    }
+
+    #[test]
+    fn server_only_params_from_client() {
+        let mut buf = Vec::new();
+        TransportParameters {
+            original_connection_id: Some(ConnectionId::new(&[0xab; 8])),
+            ..TransportParameters::default()
+        }
+        .write(&mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Server, &mut buf.as_slice()),
+            Err(Error::IllegalValue)
+        );
+    }
 }
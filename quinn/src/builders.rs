@@ -1,13 +1,16 @@
-use std::{io, net::SocketAddr, str, sync::Arc};
+use std::{io, net::SocketAddr, str, sync::Arc, time::Duration};
 
 use err_derive::Error;
-use proto::{ClientConfig, EndpointConfig, ServerConfig};
+use proto::{crypto, ClientConfig, EndpointConfig, ServerConfig, SessionStorage, SharedTicketer};
 use rustls::TLSError;
 
 use crate::{
-    endpoint::{Endpoint, EndpointDriver, EndpointRef, Incoming},
-    udp::UdpSocket,
-    Certificate, CertificateChain, PrivateKey,
+    clock::{Clock, RuntimeClock},
+    endpoint::{Endpoint, EndpointDriver, EndpointRef, HandshakeLimit, Incoming},
+    runtime::{default_runtime, Runtime},
+    tls::ChainVerifier,
+    udp::AsyncUdpSocket,
+    Certificate, CertificateChain, PrivateKey, ServerNameVerifier,
 };
 
 /// A helper for constructing an `Endpoint`.
@@ -18,6 +21,9 @@ pub struct EndpointBuilder {
     server_config: Option<ServerConfig>,
     config: EndpointConfig,
     client_config: ClientConfig,
+    clock: Option<Arc<dyn Clock>>,
+    runtime: Option<Arc<dyn Runtime>>,
+    max_concurrent_handshakes: Option<usize>,
 }
 
 #[allow(missing_docs)]
@@ -45,19 +51,47 @@ impl EndpointBuilder {
     pub fn with_socket(
         self,
         socket: std::net::UdpSocket,
+    ) -> Result<(EndpointDriver, Endpoint, Incoming), EndpointError> {
+        let runtime = self.runtime.as_ref().ok_or(EndpointError::NoRuntime)?;
+        let socket = runtime
+            .wrap_udp_socket(socket)
+            .map_err(EndpointError::Socket)?;
+        self.build(socket)
+    }
+
+    /// Build an endpoint around a custom datagram transport.
+    ///
+    /// A `Runtime` is still needed for timers, unless a `clock` is set.
+    pub fn with_async_socket<S: AsyncUdpSocket>(
+        self,
+        socket: S,
+    ) -> Result<(EndpointDriver, Endpoint, Incoming), EndpointError> {
+        self.build(Box::new(socket))
+    }
+
+    fn build(
+        self,
+        socket: Box<dyn AsyncUdpSocket>,
     ) -> Result<(EndpointDriver, Endpoint, Incoming), EndpointError> {
         let addr = socket.local_addr().map_err(EndpointError::Socket)?;
-        let socket = UdpSocket::from_std(socket).map_err(EndpointError::Socket)?;
+        let clock = match (self.clock, &self.runtime) {
+            (Some(clock), _) => clock,
+            (None, Some(runtime)) => Arc::new(RuntimeClock(runtime.clone())),
+            (None, None) => return Err(EndpointError::NoRuntime),
+        };
         let rc = EndpointRef::new(
             socket,
             proto::Endpoint::new(Arc::new(self.config), self.server_config.map(Arc::new))?,
             addr.is_ipv6(),
+            clock,
+            self.runtime,
         );
         Ok((
             EndpointDriver(rc.clone()),
             Endpoint {
                 inner: rc.clone(),
                 default_client_config: self.client_config,
+                handshakes: Arc::new(HandshakeLimit::new(self.max_concurrent_handshakes)),
             },
             Incoming::new(rc),
         ))
@@ -76,6 +110,37 @@ impl EndpointBuilder {
         self.client_config = config;
         self
     }
+
+    /// Measure time, and hence all timeouts, using `clock`
+    ///
+    /// Defaults to the system clock, with timers from the `runtime`. A `ManualClock` allows
+    /// timeout behavior to be tested without waiting for real time to pass.
+    pub fn clock<C: Clock>(&mut self, clock: C) -> &mut Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Drive sockets and timers using `runtime`
+    ///
+    /// Defaults to `TokioRuntime` if the `runtime-tokio` feature is enabled, as it is by default,
+    /// and to `AsyncStdRuntime` if only the `runtime-async-std` feature is.
+    pub fn runtime<R: Runtime>(&mut self, runtime: R) -> &mut Self {
+        self.runtime = Some(Arc::new(runtime));
+        self
+    }
+
+    /// Limit the number of outgoing handshakes in progress at once
+    ///
+    /// Further connection attempts wait, in the order they were made, for an earlier one to
+    /// complete or fail before starting their own handshake. This smooths out the CPU and memory
+    /// cost of connecting to many hosts at once. Unlimited by default.
+    ///
+    /// Panics if `max` is zero.
+    pub fn max_concurrent_handshakes(&mut self, max: usize) -> &mut Self {
+        assert!(max > 0, "max_concurrent_handshakes must be at least 1");
+        self.max_concurrent_handshakes = Some(max);
+        self
+    }
 }
 
 impl Default for EndpointBuilder {
@@ -84,6 +149,9 @@ impl Default for EndpointBuilder {
             server_config: None,
             config: EndpointConfig::default(),
             client_config: ClientConfig::default(),
+            clock: None,
+            runtime: default_runtime(),
+            max_concurrent_handshakes: None,
         }
     }
 }
@@ -94,6 +162,9 @@ pub enum EndpointError {
     /// An error during setup of the underlying UDP socket.
     #[error(display = "failed to set up UDP socket: {}", _0)]
     Socket(io::Error),
+    /// Neither a `Runtime` nor, for `EndpointBuilder::with_async_socket`, a `Clock` was configured
+    #[error(display = "no async runtime configured")]
+    NoRuntime,
     /// An error in the Quinn transport configuration
     #[error(display = "configuration error: {:?}", _0)]
     Config(#[source] proto::ConfigError),
@@ -136,7 +207,8 @@ impl ServerConfigBuilder {
 
     /// Set the application-layer protocols to accept, in order of descending preference.
     ///
-    /// When set, clients which don't declare support for at least one of the supplied protocols will be rejected.
+    /// When set, clients which don't declare support for at least one of the supplied protocols will be rejected,
+    /// if `require_protocol` is enabled.
     ///
     /// The IANA maintains a [registry] of standard protocol IDs, but custom IDs may be used as well.
     ///
@@ -147,11 +219,83 @@ impl ServerConfigBuilder {
         self
     }
 
+    /// Protect session tickets with keys derived from `secret`, rotating every `rotation_interval`
+    ///
+    /// Servers configured with the same secret and interval accept each other's tickets, allowing
+    /// clients to resume sessions and send 0-RTT data to any server behind a load balancer. By
+    /// default, sessions can only be resumed with the server that issued the ticket. See
+    /// `SharedTicketer` for details.
+    pub fn session_ticket_secret(
+        &mut self,
+        secret: &[u8],
+        rotation_interval: Duration,
+    ) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto).ticketer =
+            Arc::new(SharedTicketer::new(secret, rotation_interval));
+        self
+    }
+
+    /// Whether to reject clients which support none of the `protocols`
+    ///
+    /// Clients which don't use ALPN at all are rejected too. Disabled by default.
+    pub fn require_protocol(&mut self, enabled: bool) -> &mut Self {
+        self.config.require_protocol = enabled;
+        self
+    }
+
+    /// Assume `protocol` for clients which support none of the `protocols`, rather than rejecting
+    /// them
+    ///
+    /// `Connection::protocol` reports this protocol for such clients.
+    pub fn default_protocol(&mut self, protocol: &[u8]) -> &mut Self {
+        self.config.default_protocol = Some(protocol.to_vec());
+        self
+    }
+
+    /// Refuse 0-RTT data from handshakes already recorded in `anti_replay`
+    ///
+    /// A `crypto::StrikeRegister` suits a single server; servers sharing a
+    /// `session_ticket_secret` should share a cache too.
+    pub fn anti_replay(&mut self, anti_replay: Arc<dyn crypto::AntiReplay>) -> &mut Self {
+        self.config.anti_replay = Some(anti_replay);
+        self
+    }
+
+    /// Never accept 0-RTT data for connections negotiating one of `protocols`
+    ///
+    /// For application protocols whose requests aren't safe to replay.
+    pub fn refuse_early_data(&mut self, protocols: &[&[u8]]) -> &mut Self {
+        self.config.refused_early_data_protocols = protocols.iter().map(|x| x.to_vec()).collect();
+        self
+    }
+
     /// Whether to require clients to prove they can receive packets before accepting a connection
     pub fn use_stateless_retry(&mut self, enabled: bool) -> &mut Self {
         self.config.use_stateless_retry = enabled;
         self
     }
+
+    /// Send clients tokens, valid for `lifetime`, that let them skip address validation later
+    ///
+    /// Clients connecting again with a token skip the stateless retry round trip. Clients keep
+    /// tokens in their `ClientConfigBuilder::session_storage`.
+    pub fn new_token_lifetime(&mut self, lifetime: Duration) -> &mut Self {
+        self.config.new_token_lifetime = lifetime.as_micros() as u64;
+        self
+    }
+
+    /// Ask clients of the same address family to migrate to `addr` once connected
+    ///
+    /// May be called once for each address family. The endpoint must receive packets sent to
+    /// `addr`, e.g. by being bound to the unspecified address. See
+    /// `ServerConfig::preferred_address_v4`.
+    pub fn preferred_address(&mut self, addr: SocketAddr) -> &mut Self {
+        match addr {
+            SocketAddr::V4(x) => self.config.preferred_address_v4 = Some(x),
+            SocketAddr::V6(x) => self.config.preferred_address_v6 = Some(x),
+        }
+        self
+    }
 }
 
 impl Default for ServerConfigBuilder {
@@ -197,6 +341,21 @@ impl ClientConfigBuilder {
         Ok(self)
     }
 
+    /// Let `verifier` decide whether a server's certificate is acceptable for the server name
+    ///
+    /// Certificate chains are still validated against the trusted certificate authorities, but the
+    /// check that the server name appears in the certificate is replaced. Useful when connecting by
+    /// IP address to a server whose certificate names a host, or when pinning the server's key.
+    pub fn server_name_verifier(
+        &mut self,
+        verifier: impl ServerNameVerifier + 'static,
+    ) -> &mut Self {
+        Arc::make_mut(&mut self.config.crypto)
+            .dangerous()
+            .set_certificate_verifier(Arc::new(ChainVerifier(verifier)));
+        self
+    }
+
     /// Enable NSS-compatible cryptographic key logging to the `SSLKEYLOGFILE` environment variable.
     ///
     /// Useful for debugging encrypted communications with protocol analyzers such as Wireshark.
@@ -218,6 +377,17 @@ impl ClientConfigBuilder {
         self
     }
 
+    /// Keep the session tickets and address validation tokens received from servers in `storage`
+    ///
+    /// Sessions can only be resumed, and 0-RTT data sent, with a ticket from a previous connection
+    /// to the same server. By default, tickets are kept in memory for the configuration's lifetime;
+    /// a persistent `storage` preserves them across restarts, and sharing one lets several
+    /// configurations resume each other's sessions.
+    pub fn session_storage(&mut self, storage: Arc<dyn SessionStorage>) -> &mut Self {
+        self.config.set_session_storage(storage);
+        self
+    }
+
     /// Begin connecting from `endpoint` to `addr`.
     pub fn build(self) -> ClientConfig {
         self.config
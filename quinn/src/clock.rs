@@ -0,0 +1,140 @@
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use crate::runtime::{AsyncTimer, Runtime};
+
+/// A source of time for an endpoint and its connections
+///
+/// Every timeout, including the idle timeout, loss detection and keep-alives, is measured against
+/// the endpoint's clock. The default is the system clock, with timers from the endpoint's
+/// `Runtime`; tests can substitute a `ManualClock` to trigger timeouts deterministically instead of
+/// sleeping.
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    /// The current time
+    fn now(&self) -> Instant;
+    /// A timer that fires once `now()` has reached `deadline`
+    fn delay_until(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>>;
+}
+
+/// The system's monotonic clock, with timers driven by tokio
+#[cfg(feature = "runtime-tokio")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+#[cfg(feature = "runtime-tokio")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay_until(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        crate::runtime::TokioRuntime.delay_until(deadline)
+    }
+}
+
+/// The system's monotonic clock, with timers driven by a `Runtime`
+#[derive(Debug)]
+pub(crate) struct RuntimeClock(pub(crate) Arc<dyn Runtime>);
+
+impl Clock for RuntimeClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay_until(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        self.0.delay_until(deadline)
+    }
+}
+
+/// A clock that only moves forward when `advance` is called
+///
+/// Clones share the same time, so one can be handed to `EndpointBuilder::clock` while another is
+/// kept to control it.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<ManualState>>);
+
+#[derive(Debug)]
+struct ManualState {
+    now: Instant,
+    /// Tasks waiting on a deadline that hasn't been reached yet
+    waiting: Vec<(Instant, Waker)>,
+}
+
+impl ManualClock {
+    /// Create a clock starting at the current system time
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(ManualState {
+            now: Instant::now(),
+            waiting: Vec::new(),
+        })))
+    }
+
+    /// Move the clock forward by `duration`, waking any timers that have expired as a result
+    pub fn advance(&self, duration: Duration) {
+        let expired = {
+            let state = &mut *self.0.lock().unwrap();
+            state.now += duration;
+            let now = state.now;
+            let (expired, waiting) = state
+                .waiting
+                .drain(..)
+                .partition::<Vec<_>, _>(|&(deadline, _)| deadline <= now);
+            state.waiting = waiting;
+            expired
+        };
+        for (_, waker) in expired {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().now
+    }
+
+    fn delay_until(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(ManualDelay {
+            clock: self.clone(),
+            deadline,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ManualDelay {
+    clock: ManualClock,
+    deadline: Instant,
+}
+
+impl AsyncTimer for ManualDelay {
+    fn reset(self: Pin<&mut Self>, deadline: Instant) {
+        self.get_mut().deadline = deadline;
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let state = &mut *self.clock.0.lock().unwrap();
+        if state.now >= self.deadline {
+            return Poll::Ready(());
+        }
+        let registered = state
+            .waiting
+            .iter()
+            .any(|(deadline, waker)| *deadline == self.deadline && waker.will_wake(cx.waker()));
+        if !registered {
+            state.waiting.push((self.deadline, cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+}
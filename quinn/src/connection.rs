@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt,
     future::Future,
     mem,
@@ -7,21 +7,26 @@ use std::{
     pin::Pin,
     sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use err_derive::Error;
 use futures::{
     channel::{mpsc, oneshot},
-    FutureExt, StreamExt,
+    ready, FutureExt, StreamExt,
+};
+use proto::{
+    crypto::Session as _, ConnectionError, ConnectionHandle, ConnectionId, ConnectionStats,
+    CryptoStats, Dir, EarlyDataStatus, PathStats, StreamId, StreamInfo, TimerUpdate, UnknownStream,
 };
-use proto::{ConnectionError, ConnectionHandle, ConnectionId, Dir, StreamId, TimerUpdate};
-use tokio::time::{delay_until, Delay, Instant as TokioInstant};
 use tracing::{info_span, trace};
 
 use crate::{
     broadcast::{self, Broadcast},
+    clock::Clock,
+    endpoint::HandshakePermit,
+    runtime::AsyncTimer,
     streams::{RecvStream, SendStream, WriteError},
     ConnectionEvent, EndpointEvent, VarInt,
 };
@@ -29,11 +34,18 @@ use crate::{
 /// In-progress connection attempt future
 ///
 /// Be sure to spawn the `ConnectionDriver` when complete.
-pub struct Connecting(Option<ConnectionDriver>);
+pub struct Connecting {
+    conn: Option<ConnectionDriver>,
+    /// Slot under the endpoint's handshake limit, for outgoing connections
+    permit: Option<HandshakePermit>,
+}
 
 impl Connecting {
-    pub(crate) fn new(conn: ConnectionRef) -> Self {
-        Self(Some(ConnectionDriver(conn)))
+    pub(crate) fn new(conn: ConnectionRef, permit: Option<HandshakePermit>) -> Self {
+        Self {
+            conn: Some(ConnectionDriver(conn)),
+            permit,
+        }
     }
 
     /// Convert into a 0-RTT or 0.5-RTT connection at the cost of weakened security. Be sure to
@@ -45,6 +57,8 @@ impl Connecting {
     /// for the handshake's cryptographic security guarantees to be established.
     ///
     /// When the `ZeroRttAccepted` future completes, the connection has been fully established.
+    /// Streams opened in the meantime carry on as usual if 0-RTT was accepted. If it was
+    /// rejected, the server never saw them, and using them fails with `ZeroRttRejected`.
     ///
     /// # Security
     ///
@@ -62,10 +76,13 @@ impl Connecting {
     /// ticket is found, `self` is returned unmodified.
     ///
     /// For incoming connections, a 0.5-RTT connection will always be successfully constructed.
+    ///
+    /// A converted connection no longer counts towards the endpoint's limit on concurrent
+    /// handshakes, even if it was still waiting for a slot.
     pub fn into_0rtt(mut self) -> Result<(NewConnection, ZeroRttAccepted), Self> {
         // This lock borrows `self` and would normally be dropped at the end of this scope, so we'll
         // have to release it explicitly before returning `self` by value.
-        let mut conn = (self.0.as_mut().unwrap().0).lock().unwrap();
+        let mut conn = (self.conn.as_mut().unwrap().0).lock().unwrap();
         if conn.inner.has_0rtt() || conn.inner.side().is_server() {
             let (send, recv) = oneshot::channel();
             if conn.connected {
@@ -74,7 +91,8 @@ impl Connecting {
                 conn.on_connected = Some(send);
             }
             drop(conn);
-            let ConnectionDriver(conn) = self.0.take().unwrap();
+            self.permit = None;
+            let ConnectionDriver(conn) = self.conn.take().unwrap();
             Ok((NewConnection::new(conn), ZeroRttAccepted(recv)))
         } else {
             drop(conn);
@@ -86,10 +104,24 @@ impl Connecting {
 impl Future for Connecting {
     type Output = Result<NewConnection, ConnectionError>;
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let connected = match self.0 {
+        if let Some(ref mut permit) = self.permit {
+            // Don't start the handshake until there's room for it
+            ready!(permit.poll_acquire(cx));
+        }
+        let result = self.poll_handshake(cx);
+        if result.is_ready() {
+            self.permit = None;
+        }
+        result
+    }
+}
+
+impl Connecting {
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<Result<NewConnection, ConnectionError>> {
+        let connected = match self.conn {
             Some(ref mut driver) => {
                 let r = driver.poll_unpin(cx)?;
-                let driver = self.0.as_mut().unwrap().0.lock().unwrap(); // borrowck workaround
+                let driver = self.conn.as_mut().unwrap().0.lock().unwrap(); // borrowck workaround
                 match r {
                     Poll::Ready(()) => {
                         return Poll::Ready(Err(driver.error.as_ref().unwrap().clone()));
@@ -100,7 +132,7 @@ impl Future for Connecting {
             None => panic!("polled after yielding Ready"),
         };
         if connected {
-            let ConnectionDriver(conn) = self.0.take().unwrap();
+            let ConnectionDriver(conn) = self.conn.take().unwrap();
             Poll::Ready(Ok(NewConnection::new(conn)))
         } else {
             Poll::Pending
@@ -113,9 +145,28 @@ impl Connecting {
     ///
     /// Will panic if called after `poll` has returned `Ready`.
     pub fn remote_address(&self) -> SocketAddr {
-        let conn_ref: &ConnectionRef = &self.0.as_ref().expect("used after yielding Ready").0;
+        let conn_ref: &ConnectionRef = &self.conn.as_ref().expect("used after yielding Ready").0;
         conn_ref.lock().unwrap().inner.remote()
     }
+
+    /// Whether 0-RTT keys are available for this connection
+    ///
+    /// For outgoing connections, this is true when a session ticket with a 0-RTT key was cached
+    /// from a previous connection to the same server, in which case `into_0rtt` will succeed.
+    ///
+    /// Will panic if called after `poll` has returned `Ready`.
+    pub fn has_0rtt(&self) -> bool {
+        let conn_ref: &ConnectionRef = &self.conn.as_ref().expect("used after yielding Ready").0;
+        conn_ref.lock().unwrap().inner.has_0rtt()
+    }
+
+    /// Whether 0-RTT is being attempted, and if not, why
+    ///
+    /// Will panic if called after `poll` has returned `Ready`.
+    pub fn early_data_status(&self) -> EarlyDataStatus {
+        let conn_ref: &ConnectionRef = &self.conn.as_ref().expect("used after yielding Ready").0;
+        conn_ref.lock().unwrap().inner.early_data_status()
+    }
 }
 
 /// Future that completes when a connection is fully established
@@ -131,6 +182,37 @@ impl Future for ZeroRttAccepted {
     }
 }
 
+/// Future produced by `Connection::ping`
+///
+/// Resolves to the measured round-trip time once the peer acknowledges the PING.
+#[derive(Debug)]
+pub struct Ping(oneshot::Receiver<Result<Duration, ConnectionError>>);
+
+impl Future for Ping {
+    type Output = Result<Duration, ConnectionError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.0
+            .poll_unpin(cx)
+            .map(|x| x.unwrap_or(Err(ConnectionError::LocallyClosed)))
+    }
+}
+
+/// Guard produced by `Connection::batch`
+///
+/// Outgoing packets are held back until every `Batch` for the connection has been dropped.
+#[derive(Debug)]
+pub struct Batch(ConnectionRef);
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        let conn = &mut *self.0.lock().unwrap();
+        conn.batches -= 1;
+        if conn.batches == 0 {
+            conn.wake();
+        }
+    }
+}
+
 /// Components of a newly established connection
 ///
 /// Ensure `driver` runs or the connection will not work.
@@ -207,13 +289,15 @@ impl Future for ConnectionDriver {
         let _guard = span.enter();
 
         loop {
-            let now = Instant::now();
+            let now = conn.clock.now();
             let mut keep_going = false;
             if let Err(e) = conn.process_conn_events(cx) {
                 conn.terminate(e.clone());
                 return Poll::Ready(Err(e));
             }
-            conn.drive_transmit(now);
+            if conn.batches == 0 || conn.inner.is_closed() {
+                conn.drive_transmit(now);
+            }
             keep_going |= conn.drive_timers(cx, now);
             keep_going |= conn.handle_timer_updates();
             conn.forward_endpoint_events();
@@ -286,6 +370,19 @@ impl Connection {
         conn.close(error_code, Bytes::copy_from_slice(reason));
     }
 
+    /// Wait for the connection to be closed, for any reason
+    ///
+    /// Resolves to why the connection ended, distinguishing a close by the peer's application
+    /// (`ApplicationClosed`), by its QUIC stack (`ConnectionClosed`), a protocol violation detected
+    /// locally (`TransportError`), an idle timeout (`TimedOut`), a stateless reset sent by a peer
+    /// that lost the connection's state (`Reset`), and `Connection::close` (`LocallyClosed`).
+    pub fn closed(&self) -> Closed {
+        Closed {
+            conn: self.0.clone(),
+            state: broadcast::State::default(),
+        }
+    }
+
     /// Transmit `data` as an unreliable, unordered application datagram
     ///
     /// Application datagrams are a low-level primitive. They may be lost or delivered out of order,
@@ -326,11 +423,79 @@ impl Connection {
         self.0.lock().unwrap().inner.max_datagram_size()
     }
 
+    /// Send a single PING to the peer as soon as possible
+    ///
+    /// Returns once the PING has been queued, without waiting for it to be acknowledged. This is
+    /// useful for refreshing NAT bindings before going idle, on the application's own schedule
+    /// rather than the keep-alive interval's. Use `ping` to wait for the acknowledgement.
+    pub fn send_ping(&self) -> Result<(), ConnectionError> {
+        let conn = &mut *self.0.lock().unwrap();
+        if let Some(ref e) = conn.error {
+            return Err(e.clone());
+        }
+        conn.inner.ping();
+        conn.wake();
+        Ok(())
+    }
+
+    /// Send a PING to the peer and wait for it to be acknowledged
+    ///
+    /// Resolves to the time elapsed between transmission of the PING and receipt of its
+    /// acknowledgement. Unlike the keep-alive mechanism, this gives the application control over
+    /// when probes are sent, which is useful for health-checking idle connections. Concurrent calls
+    /// issued before the PING is transmitted share the same measurement.
+    pub fn ping(&self) -> Ping {
+        let conn = &mut *self.0.lock().unwrap();
+        let (send, recv) = oneshot::channel();
+        if let Some(ref e) = conn.error {
+            let _ = send.send(Err(e.clone()));
+        } else {
+            conn.inner.probe_rtt();
+            conn.pings.push(send);
+            conn.wake();
+        }
+        Ping(recv)
+    }
+
+    /// Defer transmission of outgoing packets until the returned `Batch` is dropped
+    ///
+    /// Data written to streams while the batch is alive, such as the opening frames of many small
+    /// requests, is coalesced into as few packets as possible once it ends. Operations that
+    /// depend on the peer, e.g. opening more streams than it currently permits, cannot make
+    /// progress until then, so batches should be kept short. Closing the connection is never
+    /// deferred.
+    pub fn batch(&self) -> Batch {
+        self.0.lock().unwrap().batches += 1;
+        Batch(self.0.clone())
+    }
+
+    /// Mark the connection's outgoing packets with the differentiated services code point `dscp`
+    ///
+    /// The value is written to the upper six bits of the IPv4 TOS or IPv6 traffic class field of
+    /// each packet subsequently sent, letting networks that honor DiffServ prioritize the
+    /// connection's traffic; e.g. 46 requests expedited forwarding. Other connections on the same
+    /// endpoint are unaffected. Marking is only supported on Unix platforms, and is silently
+    /// ignored elsewhere. Panics if `dscp` doesn't fit in six bits.
+    pub fn set_dscp(&self, dscp: u8) {
+        self.0.lock().unwrap().inner.set_dscp(dscp);
+    }
+
     /// The peer's UDP address.
     pub fn remote_address(&self) -> SocketAddr {
         self.0.lock().unwrap().inner.remote()
     }
 
+    /// Stream of changes to the path the connection uses, such as the peer appearing at a new
+    /// address after a NAT rebinding
+    ///
+    /// Changes are reported only once the new path has been validated, so a spoofed source
+    /// address never produces an event. Only servers permitting `ServerConfig::migration` follow a
+    /// peer to a new address. Events are queued from the start of the connection and shared by all
+    /// `PathEvents` handles. The stream ends once the connection is closed.
+    pub fn path_events(&self) -> PathEvents {
+        PathEvents(self.0.clone())
+    }
+
     /// The `ConnectionId` defined for `conn` by the peer.
     pub fn remote_id(&self) -> ConnectionId {
         self.0.lock().unwrap().inner.rem_cid()
@@ -341,11 +506,123 @@ impl Connection {
         self.0.lock().unwrap().inner.protocol().map(|x| x.into())
     }
 
+    /// The negotiated TLS version
+    ///
+    /// Always TLS 1.3 once the handshake has progressed far enough to tell, as QUIC does not permit
+    /// other versions.
+    pub fn tls_version(&self) -> Option<rustls::ProtocolVersion> {
+        self.0
+            .lock()
+            .unwrap()
+            .inner
+            .crypto_session()
+            .get_protocol_version()
+    }
+
+    /// The negotiated TLS cipher suite, once the handshake has progressed far enough to tell
+    pub fn cipher_suite(&self) -> Option<String> {
+        self.0.lock().unwrap().inner.crypto_session().cipher_suite()
+    }
+
+    /// Details of the leaf certificate the peer authenticated with
+    ///
+    /// `None` if the peer didn't present a certificate, e.g. a client when client authentication
+    /// isn't required, or if it couldn't be parsed.
+    #[cfg(feature = "peer-identity")]
+    pub fn peer_identity(&self) -> Option<crate::PeerIdentity> {
+        let certs = self
+            .0
+            .lock()
+            .unwrap()
+            .inner
+            .crypto_session()
+            .get_peer_certificates()?;
+        crate::PeerIdentity::from_der(&certs.first()?.0).ok()
+    }
+
+    /// The QUIC version in use
+    pub fn quic_version(&self) -> u32 {
+        self.0.lock().unwrap().inner.version()
+    }
+
+    /// Whether 0-RTT was attempted on this connection, and if not, why
+    ///
+    /// For clients, whether the server accepted the attempt is known once the handshake completes.
+    pub fn early_data_status(&self) -> EarlyDataStatus {
+        self.0.lock().unwrap().inner.early_data_status()
+    }
+
+    /// Quantity of crypto layer data received from the peer in each packet number space
+    ///
+    /// See `TransportConfig::max_handshake_data`.
+    pub fn crypto_stats(&self) -> CryptoStats {
+        self.0.lock().unwrap().inner.crypto_stats()
+    }
+
+    /// Current congestion control and round-trip time state of the connection's path
+    ///
+    /// Cheap enough to be polled regularly by applications which adapt their sending rate.
+    pub fn path_stats(&self) -> PathStats {
+        self.0.lock().unwrap().inner.path_stats()
+    }
+
+    /// Totals of the traffic exchanged over the connection so far, along with `path_stats`
+    ///
+    /// Counters only ever grow, so applications exporting metrics can sample them periodically.
+    pub fn stats(&self) -> ConnectionStats {
+        self.0.lock().unwrap().inner.stats()
+    }
+
+    /// Snapshot of the connection's streams, for diagnostics
+    ///
+    /// Lists every stream which was opened and hasn't been fully closed yet, in order of ID, with
+    /// its flow control state and how far it got. The snapshot doesn't track later changes.
+    pub fn streams(&self) -> Vec<StreamInfo> {
+        self.0.lock().unwrap().inner.streams()
+    }
+
     // Update traffic keys spontaneously for testing purposes.
     #[doc(hidden)]
     pub fn force_key_update(&self) {
         self.0.lock().unwrap().inner.initiate_key_update()
     }
+
+    /// Reset the send side of `stream` without its `SendStream`
+    ///
+    /// Lets protocols built on top abort a stream whose send side is owned elsewhere. Does nothing
+    /// if the stream was already reset or all of its data acknowledged.
+    #[doc(hidden)]
+    pub fn reset_stream(&self, stream: StreamId, error_code: VarInt) {
+        let mut conn = self.0.lock().unwrap();
+        if conn.error.is_some() {
+            return;
+        }
+        conn.inner.reset(stream, error_code);
+        conn.wake();
+    }
+
+    /// Set the priority of the send side of `stream` without its `SendStream`
+    ///
+    /// Lets protocols built on top reprioritize streams in response to signals received elsewhere,
+    /// including streams the peer may open but hasn't yet. Fails for streams which are closed or
+    /// beyond the peer's limit.
+    #[doc(hidden)]
+    pub fn set_stream_priority(
+        &self,
+        stream: StreamId,
+        priority: i32,
+    ) -> Result<(), UnknownStream> {
+        let mut conn = self.0.lock().unwrap();
+        conn.inner.set_priority(stream, priority)
+    }
+
+    /// Number of streams in `dir` the peer may open, counting those it already did
+    ///
+    /// Lets protocols built on top validate the IDs of streams the peer refers to.
+    #[doc(hidden)]
+    pub fn remote_stream_limit(&self, dir: Dir) -> u64 {
+        self.0.lock().unwrap().inner.remote_stream_limit(dir)
+    }
 }
 
 /// A stream of unidirectional QUIC streams initiated by a remote peer.
@@ -432,6 +709,58 @@ impl futures::Stream for Datagrams {
     }
 }
 
+/// Stream of path changes observed on a connection, from `Connection::path_events`
+#[derive(Debug)]
+pub struct PathEvents(ConnectionRef);
+
+impl futures::Stream for PathEvents {
+    type Item = PathEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut conn = self.0.lock().unwrap();
+        if let Some(x) = conn.path_events.pop_front() {
+            Poll::Ready(Some(x))
+        } else if conn.error.is_some() {
+            Poll::Ready(None)
+        } else {
+            conn.path_events_reader = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// A change to the path a connection uses
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PathEvent {
+    /// The peer was observed at a new address, which has since been validated
+    RemoteAddressChanged {
+        /// The last validated address of the peer
+        old: SocketAddr,
+        /// The address now in use
+        new: SocketAddr,
+    },
+}
+
+/// A future that resolves to the reason a connection was closed, from `Connection::closed`
+pub struct Closed {
+    conn: ConnectionRef,
+    state: broadcast::State,
+}
+
+impl Future for Closed {
+    type Output = ConnectionError;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut conn = this.conn.lock().unwrap();
+        if let Some(ref e) = conn.error {
+            return Poll::Ready(e.clone());
+        }
+        conn.on_closed.register(cx, &mut this.state);
+        Poll::Pending
+    }
+}
+
 /// A future that will resolve into an opened outgoing unidirectional stream
 pub struct OpenUni {
     conn: ConnectionRef,
@@ -485,6 +814,7 @@ impl Future for OpenBi {
     }
 }
 
+/// Future produced by `Connection::send_datagram_ready`
 pub struct SendDatagramReady<'a> {
     conn: &'a ConnectionRef,
     state: broadcast::State,
@@ -506,6 +836,7 @@ impl<'a> Future for SendDatagramReady<'a> {
     }
 }
 
+/// Future produced by `Connection::send_datagram`
 pub struct SendDatagram<'a> {
     conn: &'a ConnectionRef,
     data: Bytes,
@@ -563,9 +894,11 @@ impl ConnectionRef {
         conn: proto::Connection,
         endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
         conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         Self(Arc::new(Mutex::new(ConnectionInner {
-            epoch: Instant::now(),
+            epoch: clock.now(),
+            clock,
             inner: conn,
             driver: None,
             handle,
@@ -581,10 +914,15 @@ impl ConnectionRef {
             incoming_uni_streams_reader: None,
             incoming_bi_streams_reader: None,
             datagram_reader: None,
+            path_events: VecDeque::new(),
+            path_events_reader: None,
             finishing: HashMap::new(),
             error: None,
             ref_count: 0,
             send_datagram_blocked: Broadcast::new(),
+            pings: Vec::new(),
+            batches: 0,
+            on_closed: Broadcast::new(),
         })))
     }
 }
@@ -621,12 +959,13 @@ impl std::ops::Deref for ConnectionRef {
 
 pub struct ConnectionInner {
     epoch: Instant,
+    clock: Arc<dyn Clock>,
     pub(crate) inner: proto::Connection,
     driver: Option<Waker>,
     handle: ConnectionHandle,
     on_connected: Option<oneshot::Sender<bool>>,
     connected: bool,
-    timers: proto::TimerTable<Option<Delay>>,
+    timers: proto::TimerTable<Option<Pin<Box<dyn AsyncTimer>>>>,
     conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
     endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
     pub(crate) blocked_writers: HashMap<StreamId, Waker>,
@@ -636,12 +975,18 @@ pub struct ConnectionInner {
     incoming_uni_streams_reader: Option<Waker>,
     incoming_bi_streams_reader: Option<Waker>,
     datagram_reader: Option<Waker>,
+    path_events: VecDeque<PathEvent>,
+    path_events_reader: Option<Waker>,
     pub(crate) finishing: HashMap<StreamId, oneshot::Sender<Option<WriteError>>>,
     /// Always set to Some before the connection becomes drained
     pub(crate) error: Option<ConnectionError>,
     /// Number of live handles that can be used to initiate or handle I/O; excludes the driver
     ref_count: usize,
     send_datagram_blocked: Broadcast,
+    pings: Vec<oneshot::Sender<Result<Duration, ConnectionError>>>,
+    /// Number of live `Batch` guards deferring transmission
+    batches: usize,
+    on_closed: Broadcast,
 }
 
 impl ConnectionInner {
@@ -673,6 +1018,9 @@ impl ConnectionInner {
                 Poll::Ready(Some(ConnectionEvent::Close { reason, error_code })) => {
                     self.close(error_code, reason);
                 }
+                Poll::Ready(Some(ConnectionEvent::Rebind)) => {
+                    self.inner.local_address_changed(self.clock.now());
+                }
                 Poll::Ready(None) => {
                     return Err(ConnectionError::TransportError(proto::TransportError {
                         code: proto::TransportErrorCode::INTERNAL_ERROR,
@@ -697,6 +1045,19 @@ impl ConnectionInner {
                         // We don't care if the on-connected future was dropped
                         let _ = x.send(self.inner.accepted_0rtt());
                     }
+                    let side = self.inner.side();
+                    if side.is_client() && self.inner.has_0rtt() && !self.inner.accepted_0rtt() {
+                        // Streams opened in rejected 0-RTT data are gone; let their users know
+                        for (_, writer) in self.blocked_writers.drain() {
+                            writer.wake()
+                        }
+                        for (_, reader) in self.blocked_readers.drain() {
+                            reader.wake()
+                        }
+                        for (_, x) in self.finishing.drain() {
+                            let _ = x.send(Some(WriteError::ZeroRttRejected));
+                        }
+                    }
                 }
                 ConnectionLost { reason } => {
                     self.terminate(reason);
@@ -745,6 +1106,19 @@ impl ConnectionInner {
                 DatagramSendUnblocked => {
                     self.send_datagram_blocked.wake();
                 }
+                PingAcknowledged { rtt } => {
+                    for x in self.pings.drain(..) {
+                        // We don't care if the ping future was dropped
+                        let _ = x.send(Ok(rtt));
+                    }
+                }
+                RemoteAddressChanged { old, new } => {
+                    self.path_events
+                        .push_back(PathEvent::RemoteAddressChanged { old, new });
+                    if let Some(x) = self.path_events_reader.take() {
+                        x.wake();
+                    }
+                }
             }
         }
     }
@@ -753,7 +1127,7 @@ impl ConnectionInner {
         let mut keep_going = false;
         for (timer, slot) in &mut self.timers {
             if let Some(ref mut delay) = slot {
-                match delay.poll_unpin(cx) {
+                match delay.as_mut().poll(cx) {
                     Poll::Ready(()) => {
                         *slot = None;
                         trace!("{:?} timeout", timer);
@@ -779,11 +1153,11 @@ impl ConnectionInner {
                 } => match self.timers[timer] {
                     ref mut x @ None => {
                         trace!(time = ?time.duration_since(self.epoch), "{:?} timer start", timer);
-                        *x = Some(delay_until(TokioInstant::from_std(time)));
+                        *x = Some(self.clock.delay_until(time));
                     }
                     Some(ref mut x) => {
                         trace!(time = ?time.duration_since(self.epoch), "{:?} timer reset", timer);
-                        x.reset(TokioInstant::from_std(time));
+                        x.as_mut().reset(time);
                     }
                 },
                 TimerUpdate {
@@ -826,18 +1200,28 @@ impl ConnectionInner {
         if let Some(x) = self.datagram_reader.take() {
             x.wake();
         }
+        if let Some(x) = self.path_events_reader.take() {
+            x.wake();
+        }
         for (_, x) in self.finishing.drain() {
             let _ = x.send(Some(WriteError::ConnectionClosed(reason.clone())));
         }
         self.send_datagram_blocked.wake();
+        for x in self.pings.drain(..) {
+            let _ = x.send(Err(reason.clone()));
+        }
         if let Some(x) = self.on_connected.take() {
             let _ = x.send(false);
         }
+        self.on_closed.wake();
     }
 
     fn close(&mut self, error_code: VarInt, reason: Bytes) {
-        self.inner.close(Instant::now(), error_code, reason);
-        self.terminate(ConnectionError::LocallyClosed);
+        self.inner.close(self.clock.now(), error_code, reason);
+        // Keep reporting why the connection was lost if that happened first
+        if self.error.is_none() {
+            self.terminate(ConnectionError::LocallyClosed);
+        }
         self.wake();
     }
 
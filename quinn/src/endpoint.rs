@@ -1,13 +1,14 @@
 use std::{
     collections::{HashMap, VecDeque},
     future::Future,
-    io,
+    io::{self, IoSliceMut},
+    mem::{self, MaybeUninit},
     net::{SocketAddr, SocketAddrV6},
     pin::Pin,
     str,
     sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
@@ -15,9 +16,12 @@ use futures::{channel::mpsc, FutureExt, StreamExt};
 use proto::{self as proto, ClientConfig, ConnectError, ConnectionHandle, DatagramEvent};
 
 use crate::{
+    broadcast::{self, Broadcast},
     builders::EndpointBuilder,
+    clock::Clock,
     connection::{Connecting, ConnectionDriver, ConnectionRef},
-    udp::UdpSocket,
+    runtime::{AsyncTimer, Runtime},
+    udp::{AsyncUdpSocket, RecvMeta, BATCH_SIZE},
     ConnectionEvent, EndpointEvent, VarInt, IO_LOOP_BOUND,
 };
 
@@ -31,6 +35,7 @@ use crate::{
 pub struct Endpoint {
     pub(crate) inner: EndpointRef,
     pub(crate) default_client_config: ClientConfig,
+    pub(crate) handshakes: Arc<HandshakeLimit>,
 }
 
 impl Endpoint {
@@ -47,6 +52,10 @@ impl Endpoint {
     ///
     /// May fail immediately due to configuration errors, or in the future if the connection could
     /// not be established.
+    ///
+    /// If the endpoint was built with `EndpointBuilder::max_concurrent_handshakes` and that many
+    /// outgoing handshakes are already in progress, the returned `Connecting` waits for one of them
+    /// to finish before starting its own.
     pub fn connect(
         &self,
         addr: &SocketAddr,
@@ -73,22 +82,54 @@ impl Endpoint {
         } else {
             *addr
         };
-        let (ch, conn) = endpoint.inner.connect(config, addr, server_name)?;
-        Ok(Connecting::new(endpoint.create_connection(ch, conn)))
+        let now = endpoint.clock.now();
+        let (ch, conn) = endpoint.inner.connect(now, config, addr, server_name)?;
+        Ok(Connecting::new(
+            endpoint.create_connection(ch, conn),
+            Some(self.handshakes.permit()),
+        ))
+    }
+
+    /// Number of outgoing handshakes currently in progress
+    ///
+    /// Connection attempts still waiting for a slot under `max_concurrent_handshakes` aren't
+    /// counted.
+    pub fn handshakes_in_progress(&self) -> usize {
+        self.handshakes.state.lock().unwrap().in_progress
     }
 
     /// Switch to a new UDP socket
     ///
-    /// Allows the endpoint's address to be updated live, affecting all active connections. Incoming
-    /// connections and connections to servers unreachable from the new address will be lost.
+    /// Allows the endpoint's address to be updated live, affecting all active connections, e.g. as
+    /// a mobile device moves from Wi-Fi to a cellular network. Established connections migrate to
+    /// the new address, validating the path to their peer and, where possible, switching to unused
+    /// connection IDs so the move can't be observed. Incoming connections and connections to
+    /// servers unreachable from the new address will be lost.
     ///
     /// On error, the old UDP socket is retained.
     pub fn rebind(&self, socket: std::net::UdpSocket) -> io::Result<()> {
+        let runtime = self.inner.lock().unwrap().runtime.clone();
+        let runtime = runtime
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no runtime configured"))?;
+        self.rebind_boxed(runtime.wrap_udp_socket(socket)?)
+    }
+
+    /// Switch to a new datagram transport
+    ///
+    /// As `rebind`, for transports supplied through `EndpointBuilder::with_async_socket`.
+    pub fn rebind_async_socket<S: AsyncUdpSocket>(&self, socket: S) -> io::Result<()> {
+        self.rebind_boxed(Box::new(socket))
+    }
+
+    fn rebind_boxed(&self, socket: Box<dyn AsyncUdpSocket>) -> io::Result<()> {
         let addr = socket.local_addr()?;
-        let socket = UdpSocket::from_std(socket)?;
         let mut inner = self.inner.lock().unwrap();
         inner.socket = socket;
         inner.ipv6 = addr.is_ipv6();
+        for sender in inner.connections.values() {
+            // Ignoring errors from dropped connections
+            let _ = sender.unbounded_send(ConnectionEvent::Rebind);
+        }
         Ok(())
     }
 
@@ -115,6 +156,49 @@ impl Endpoint {
             task.wake();
         }
     }
+
+    /// Close all of this endpoint's connections gracefully and wait for them to drain
+    ///
+    /// Ceases accepting new connections and closes existing ones as `close` does, then resolves
+    /// once every connection has drained, or `timeout` has elapsed, whichever comes first. The
+    /// result is the number of connections still draining at that point, so 0 indicates a clean
+    /// shutdown. Once all other handles to the endpoint are dropped, the `EndpointDriver` finishes
+    /// and the socket is released.
+    pub fn shutdown(&self, error_code: VarInt, reason: &[u8], timeout: Duration) -> Shutdown {
+        self.close(error_code, reason);
+        let endpoint = self.inner.lock().unwrap();
+        let delay = endpoint.clock.delay_until(endpoint.clock.now() + timeout);
+        drop(endpoint);
+        Shutdown {
+            endpoint: self.inner.clone(),
+            delay,
+            state: broadcast::State::default(),
+        }
+    }
+}
+
+/// A future that resolves once an endpoint's connections have drained, from `Endpoint::shutdown`
+pub struct Shutdown {
+    endpoint: EndpointRef,
+    delay: Pin<Box<dyn AsyncTimer>>,
+    state: broadcast::State,
+}
+
+impl Future for Shutdown {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut endpoint = this.endpoint.lock().unwrap();
+        if endpoint.connections.is_empty() {
+            return Poll::Ready(0);
+        }
+        if this.delay.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(endpoint.connections.len());
+        }
+        endpoint.drained.register(cx, &mut this.state);
+        Poll::Pending
+    }
 }
 
 /// A future that drives IO on an endpoint
@@ -139,7 +223,7 @@ impl Future for EndpointDriver {
             endpoint.driver = Some(cx.waker().clone());
         }
         loop {
-            let now = Instant::now();
+            let now = endpoint.clock.now();
             let mut keep_going = false;
             keep_going |= endpoint.drive_recv(cx, now)?;
             endpoint.drive_incoming(cx);
@@ -167,12 +251,13 @@ impl Drop for EndpointDriver {
         // Drop all outgoing channels, signaling the termination of the endpoint to the associated
         // connections.
         endpoint.connections.clear();
+        endpoint.drained.wake();
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct EndpointInner {
-    socket: UdpSocket,
+    socket: Box<dyn AsyncUdpSocket>,
     inner: proto::Endpoint,
     outgoing: VecDeque<proto::Transmit>,
     incoming: VecDeque<ConnectionDriver>,
@@ -182,6 +267,8 @@ pub(crate) struct EndpointInner {
     driver: Option<Waker>,
     ipv6: bool,
     connections: HashMap<ConnectionHandle, mpsc::UnboundedSender<ConnectionEvent>>,
+    /// Tasks waiting for `connections` to become empty
+    drained: Broadcast,
     // Stored to give out clones to new ConnectionInners
     sender: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
     events: mpsc::UnboundedReceiver<(ConnectionHandle, EndpointEvent)>,
@@ -190,38 +277,59 @@ pub(crate) struct EndpointInner {
     /// Set if the endpoint has been manually closed
     close: Option<(VarInt, Bytes)>,
     driver_lost: bool,
+    /// Space for `BATCH_SIZE` datagrams of up to 64KiB each
     recv_buf: Box<[u8]>,
+    clock: Arc<dyn Clock>,
+    /// Used to register sockets passed to `Endpoint::rebind`
+    runtime: Option<Arc<dyn Runtime>>,
 }
 
 impl EndpointInner {
     fn drive_recv(&mut self, cx: &mut Context, now: Instant) -> Result<bool, io::Error> {
+        // Moved out of `self` for the duration, so that datagrams can be handled while it's
+        // borrowed by `iovs`
+        let mut recv_buf = mem::replace(&mut self.recv_buf, Vec::new().into_boxed_slice());
+        let result = self.recv_batches(cx, now, &mut recv_buf);
+        self.recv_buf = recv_buf;
+        result
+    }
+
+    fn recv_batches(
+        &mut self,
+        cx: &mut Context,
+        now: Instant,
+        recv_buf: &mut [u8],
+    ) -> Result<bool, io::Error> {
         let mut recvd = 0;
+        let mut metas = [RecvMeta::default(); BATCH_SIZE];
+        let mut iovs = MaybeUninit::<[IoSliceMut; BATCH_SIZE]>::uninit();
+        recv_buf
+            .chunks_mut(recv_buf.len() / BATCH_SIZE)
+            .enumerate()
+            .for_each(|(i, buf)| unsafe {
+                iovs.as_mut_ptr()
+                    .cast::<IoSliceMut>()
+                    .add(i)
+                    .write(IoSliceMut::new(buf));
+            });
+        let mut iovs = unsafe { iovs.assume_init() };
         loop {
-            match self.socket.poll_recv(cx, &mut self.recv_buf) {
-                Poll::Ready(Ok((n, addr, ecn))) => {
-                    match self
-                        .inner
-                        .handle(now, addr, ecn, (&self.recv_buf[0..n]).into())
-                    {
-                        Some((handle, DatagramEvent::NewConnection(conn))) => {
-                            let conn = ConnectionDriver(self.create_connection(handle, conn));
-                            if !self.incoming_live {
-                                conn.0.lock().unwrap().implicit_close();
-                            }
-                            self.incoming.push_back(conn);
-                            if let Some(task) = self.incoming_reader.take() {
-                                task.wake();
-                            }
-                        }
-                        Some((handle, DatagramEvent::ConnectionEvent(event))) => {
-                            // Ignoring errors from dropped connections that haven't yet been cleaned up
-                            let _ = self
-                                .connections
-                                .get_mut(&handle)
-                                .unwrap()
-                                .unbounded_send(ConnectionEvent::Proto(event));
+            match self.socket.poll_recv(cx, &mut iovs, &mut metas) {
+                Poll::Ready(Ok(msgs)) => {
+                    recvd += msgs;
+                    for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
+                        let mut data = &buf[0..meta.len];
+                        // A stride of 0 means the buffer holds a single datagram
+                        let stride = if meta.stride == 0 {
+                            meta.len
+                        } else {
+                            meta.stride
+                        };
+                        while !data.is_empty() {
+                            let (datagram, rest) = data.split_at(stride.min(data.len()));
+                            self.handle_datagram(now, meta, datagram);
+                            data = rest;
                         }
-                        None => {}
                     }
                 }
                 Poll::Pending => {
@@ -236,7 +344,6 @@ impl EndpointInner {
                     return Err(e);
                 }
             }
-            recvd += 1;
             if recvd >= IO_LOOP_BOUND {
                 return Ok(true);
             }
@@ -244,6 +351,30 @@ impl EndpointInner {
         Ok(false)
     }
 
+    fn handle_datagram(&mut self, now: Instant, meta: &RecvMeta, data: &[u8]) {
+        match self.inner.handle(now, meta.addr, meta.ecn, data.into()) {
+            Some((handle, DatagramEvent::NewConnection(conn))) => {
+                let conn = ConnectionDriver(self.create_connection(handle, conn));
+                if !self.incoming_live {
+                    conn.0.lock().unwrap().implicit_close();
+                }
+                self.incoming.push_back(conn);
+                if let Some(task) = self.incoming_reader.take() {
+                    task.wake();
+                }
+            }
+            Some((handle, DatagramEvent::ConnectionEvent(event))) => {
+                // Ignoring errors from dropped connections that haven't yet been cleaned up
+                let _ = self
+                    .connections
+                    .get_mut(&handle)
+                    .unwrap()
+                    .unbounded_send(ConnectionEvent::Proto(event));
+            }
+            None => {}
+        }
+    }
+
     fn drive_incoming(&mut self, cx: &mut Context) {
         for i in (0..self.incoming.len()).rev() {
             match self.incoming[i].poll_unpin(cx) {
@@ -259,7 +390,7 @@ impl EndpointInner {
     fn drive_send(&mut self, cx: &mut Context) -> Result<bool, io::Error> {
         let mut calls = 0;
         loop {
-            while self.outgoing.len() < crate::udp::BATCH_SIZE {
+            while self.outgoing.len() < BATCH_SIZE {
                 match self.inner.poll_transmit() {
                     Some(x) => self.outgoing.push_back(x),
                     None => break,
@@ -297,6 +428,9 @@ impl EndpointInner {
                     Proto(e) => {
                         if e.is_drained() {
                             self.connections.remove(&ch);
+                            if self.connections.is_empty() {
+                                self.drained.wake();
+                            }
                         }
                         if let Some(event) = self.inner.handle_event(ch, e) {
                             // Ignoring errors from dropped connections that haven't yet been cleaned up
@@ -331,7 +465,7 @@ impl EndpointInner {
             .unwrap();
         }
         self.connections.insert(handle, send);
-        ConnectionRef::new(handle, conn, self.sender.clone(), recv)
+        ConnectionRef::new(handle, conn, self.sender.clone(), recv, self.clock.clone())
     }
 }
 
@@ -342,6 +476,114 @@ fn ensure_ipv6(x: SocketAddr) -> SocketAddrV6 {
     }
 }
 
+/// Bound on the number of outgoing handshakes an endpoint runs at once
+#[derive(Debug)]
+pub(crate) struct HandshakeLimit {
+    max: Option<usize>,
+    state: Mutex<HandshakeLimitState>,
+}
+
+#[derive(Debug, Default)]
+struct HandshakeLimitState {
+    in_progress: usize,
+    /// Connection attempts waiting for a slot, in the order they'll get one
+    waiting: VecDeque<(u64, Waker)>,
+    next_ticket: u64,
+}
+
+impl HandshakeLimit {
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            state: Mutex::new(HandshakeLimitState::default()),
+        }
+    }
+
+    /// Claim a slot for a new handshake, immediately if one is free and nobody is queued
+    fn permit(self: &Arc<Self>) -> HandshakePermit {
+        let mut state = self.state.lock().unwrap();
+        let held = state.waiting.is_empty() && self.has_room(&state);
+        if held {
+            state.in_progress += 1;
+        }
+        HandshakePermit {
+            limit: self.clone(),
+            ticket: None,
+            held,
+        }
+    }
+
+    fn has_room(&self, state: &HandshakeLimitState) -> bool {
+        self.max.map_or(true, |max| state.in_progress < max)
+    }
+
+    fn wake_next(&self, state: &HandshakeLimitState) {
+        if self.has_room(state) {
+            if let Some((_, waker)) = state.waiting.front() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// A connection attempt's place under a `HandshakeLimit`, released on drop
+#[derive(Debug)]
+pub(crate) struct HandshakePermit {
+    limit: Arc<HandshakeLimit>,
+    /// Position in the queue, if waiting for a slot
+    ticket: Option<u64>,
+    held: bool,
+}
+
+impl HandshakePermit {
+    pub(crate) fn poll_acquire(&mut self, cx: &mut Context) -> Poll<()> {
+        if self.held {
+            return Poll::Ready(());
+        }
+        let limit = &*self.limit;
+        let mut state = limit.state.lock().unwrap();
+        let first = match self.ticket {
+            None => state.waiting.is_empty(),
+            Some(ticket) => state.waiting.front().map(|x| x.0) == Some(ticket),
+        };
+        if first && limit.has_room(&state) {
+            if self.ticket.take().is_some() {
+                state.waiting.pop_front();
+            }
+            state.in_progress += 1;
+            self.held = true;
+            limit.wake_next(&state);
+            return Poll::Ready(());
+        }
+        match self.ticket {
+            Some(ticket) => {
+                let entry = state.waiting.iter_mut().find(|x| x.0 == ticket).unwrap();
+                entry.1 = cx.waker().clone();
+            }
+            None => {
+                let ticket = state.next_ticket;
+                state.next_ticket += 1;
+                state.waiting.push_back((ticket, cx.waker().clone()));
+                self.ticket = Some(ticket);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        let limit = &*self.limit;
+        let mut state = limit.state.lock().unwrap();
+        if self.held {
+            state.in_progress -= 1;
+        } else if let Some(ticket) = self.ticket {
+            state.waiting.retain(|x| x.0 != ticket);
+        }
+        limit.wake_next(&state);
+    }
+}
+
 /// Stream of incoming connections.
 #[derive(Debug)]
 pub struct Incoming(EndpointRef);
@@ -360,7 +602,7 @@ impl futures::Stream for Incoming {
             Poll::Ready(None)
         } else if let Some(conn) = endpoint.incoming.pop_front() {
             endpoint.inner.accept();
-            Poll::Ready(Some(Connecting::new(conn.0)))
+            Poll::Ready(Some(Connecting::new(conn.0, None)))
         } else if endpoint.close.is_some() {
             Poll::Ready(None)
         } else {
@@ -386,7 +628,13 @@ impl Drop for Incoming {
 pub(crate) struct EndpointRef(Arc<Mutex<EndpointInner>>);
 
 impl EndpointRef {
-    pub(crate) fn new(socket: UdpSocket, inner: proto::Endpoint, ipv6: bool) -> Self {
+    pub(crate) fn new(
+        socket: Box<dyn AsyncUdpSocket>,
+        inner: proto::Endpoint,
+        ipv6: bool,
+        clock: Arc<dyn Clock>,
+        runtime: Option<Arc<dyn Runtime>>,
+    ) -> Self {
         let (sender, events) = mpsc::unbounded();
         Self(Arc::new(Mutex::new(EndpointInner {
             socket,
@@ -400,10 +648,13 @@ impl EndpointRef {
             incoming_reader: None,
             driver: None,
             connections: HashMap::new(),
+            drained: Broadcast::new(),
             ref_count: 0,
             close: None,
             driver_lost: false,
-            recv_buf: vec![0; 64 * 1024].into(),
+            recv_buf: vec![0; 64 * 1024 * BATCH_SIZE].into(),
+            clock,
+            runtime,
         })))
     }
 }
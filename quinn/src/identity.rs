@@ -0,0 +1,155 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ring::digest;
+use yasna::{
+    models::ObjectIdentifier,
+    tags::{TAG_BMPSTRING, TAG_IA5STRING, TAG_PRINTABLESTRING, TAG_UTCTIME, TAG_UTF8STRING},
+    ASN1Error, ASN1ErrorKind, ASN1Result, BERReader, BERReaderSeq, Tag,
+};
+
+use crate::tls::ParseError;
+
+/// Details of a certificate presented by a peer, from `Connection::peer_identity`
+///
+/// Covers the attributes most commonly used for logging and application-level authorization. The
+/// certificate has already been validated by the TLS handshake by the time these are available.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// Common name (CN) of the certificate's subject, if any
+    pub common_name: Option<String>,
+    /// DNS names listed in the subject alternative name extension
+    pub dns_names: Vec<String>,
+    /// IP addresses listed in the subject alternative name extension
+    pub ip_addresses: Vec<IpAddr>,
+    /// SHA-256 digest of the DER-encoded certificate
+    pub fingerprint: [u8; 32],
+    /// Start of the certificate's validity period
+    pub not_before: SystemTime,
+    /// End of the certificate's validity period
+    pub not_after: SystemTime,
+}
+
+impl PeerIdentity {
+    /// Parse the details of a DER-formatted X.509 certificate
+    pub fn from_der(der: &[u8]) -> Result<Self, ParseError> {
+        let mut identity = yasna::parse_der(der, |r| {
+            r.read_sequence(|r| {
+                let identity = r.next().read_sequence(read_tbs_certificate)?;
+                r.next().read_der()?; // signatureAlgorithm
+                r.next().read_der()?; // signatureValue
+                Ok(identity)
+            })
+        })
+        .map_err(|_| ParseError("malformed certificate"))?;
+        identity
+            .fingerprint
+            .copy_from_slice(digest::digest(&digest::SHA256, der).as_ref());
+        Ok(identity)
+    }
+}
+
+fn read_tbs_certificate(r: &mut BERReaderSeq) -> ASN1Result<PeerIdentity> {
+    r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_u8()))?;
+    r.next().read_der()?; // serialNumber
+    r.next().read_der()?; // signature
+    r.next().read_der()?; // issuer
+    let (not_before, not_after) = r
+        .next()
+        .read_sequence(|r| Ok((read_time(r.next())?, read_time(r.next())?)))?;
+    let mut identity = PeerIdentity {
+        common_name: None,
+        dns_names: Vec::new(),
+        ip_addresses: Vec::new(),
+        fingerprint: [0; 32],
+        not_before,
+        not_after,
+    };
+    r.next().read_sequence_of(|r| {
+        r.read_set_of(|r| {
+            r.read_sequence(|r| {
+                let ty = r.next().read_oid()?;
+                let value = r.next().read_tagged_der()?;
+                if ty == ObjectIdentifier::from_slice(&[2, 5, 4, 3]) {
+                    identity.common_name = Some(read_string(value.tag(), value.value())?);
+                }
+                Ok(())
+            })
+        })
+    })?;
+    r.next().read_der()?; // subjectPublicKeyInfo
+
+    // Optional issuerUniqueID [1], subjectUniqueID [2] and extensions [3]
+    while let Some(field) = r.read_optional(|r| r.read_tagged_der())? {
+        if field.tag() == Tag::context(3) {
+            yasna::parse_der(field.value(), |r| {
+                r.read_sequence_of(|r| read_extension(r, &mut identity))
+            })?;
+        }
+    }
+    Ok(identity)
+}
+
+fn read_extension(r: BERReader, identity: &mut PeerIdentity) -> ASN1Result<()> {
+    r.read_sequence(|r| {
+        let id = r.next().read_oid()?;
+        r.read_optional(|r| r.read_bool())?; // critical
+        let value = r.next().read_bytes()?;
+        if id != ObjectIdentifier::from_slice(&[2, 5, 29, 17]) {
+            return Ok(());
+        }
+        yasna::parse_der(&value, |r| {
+            r.read_sequence_of(|r| {
+                let name = r.read_tagged_der()?;
+                let value = name.value();
+                if name.tag() == Tag::context(2) {
+                    identity.dns_names.push(read_string(TAG_IA5STRING, value)?);
+                } else if name.tag() == Tag::context(7) {
+                    identity.ip_addresses.push(match value.len() {
+                        4 => Ipv4Addr::new(value[0], value[1], value[2], value[3]).into(),
+                        16 => {
+                            let mut octets = [0; 16];
+                            octets.copy_from_slice(value);
+                            Ipv6Addr::from(octets).into()
+                        }
+                        _ => return Err(ASN1Error::new(ASN1ErrorKind::Invalid)),
+                    });
+                }
+                Ok(())
+            })
+        })
+    })
+}
+
+fn read_time(r: BERReader) -> ASN1Result<SystemTime> {
+    let timestamp = if r.lookahead_tag()? == TAG_UTCTIME {
+        r.read_utctime()?.datetime().timestamp()
+    } else {
+        r.read_generalized_time()?.datetime().timestamp()
+    };
+    Ok(if timestamp >= 0 {
+        UNIX_EPOCH + Duration::from_secs(timestamp as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(-timestamp as u64)
+    })
+}
+
+/// Decode the value of a DirectoryString or IA5String
+fn read_string(tag: Tag, value: &[u8]) -> ASN1Result<String> {
+    if tag == TAG_BMPSTRING {
+        let units = value
+            .chunks(2)
+            .map(|x| match *x {
+                [a, b] => Ok(u16::from_be_bytes([a, b])),
+                _ => Err(ASN1Error::new(ASN1ErrorKind::Invalid)),
+            })
+            .collect::<ASN1Result<Vec<_>>>()?;
+        return String::from_utf16(&units).map_err(|_| ASN1Error::new(ASN1ErrorKind::Invalid));
+    }
+    if tag != TAG_UTF8STRING && tag != TAG_PRINTABLESTRING && tag != TAG_IA5STRING {
+        return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+    }
+    String::from_utf8(value.to_vec()).map_err(|_| ASN1Error::new(ASN1ErrorKind::Invalid))
+}
@@ -47,35 +47,56 @@
 
 mod broadcast;
 mod builders;
+mod clock;
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
 mod platform;
+mod runtime;
 mod udp;
 
 pub use proto::{
     crypto, ApplicationClose, ClientConfig, ConnectError, ConnectionClose, ConnectionError,
-    ConnectionId, ServerConfig, Transmit, TransportConfig, VarInt,
+    ConnectionId, ConnectionStats, CryptoStats, EarlyDataStatus, IpPreference,
+    MemorySessionStorage, PathStats, QlogSink, RecvInfo, RecvStreamState, SendInfo,
+    SendStreamState, ServerConfig, SessionStorage, SharedTicketer, StreamId, StreamInfo, Transmit,
+    TransportConfig, VarInt, Written,
 };
 
 pub use crate::builders::{
     ClientConfigBuilder, EndpointBuilder, EndpointError, ServerConfigBuilder,
 };
+#[cfg(feature = "runtime-tokio")]
+pub use crate::clock::SystemClock;
+pub use crate::clock::{Clock, ManualClock};
+#[cfg(feature = "runtime-async-std")]
+pub use crate::runtime::AsyncStdRuntime;
+#[cfg(feature = "runtime-tokio")]
+pub use crate::runtime::TokioRuntime;
+pub use crate::runtime::{AsyncTimer, Runtime};
+pub use crate::udp::{AsyncUdpSocket, RecvMeta};
 
 mod connection;
 pub use connection::{
-    Connecting, Connection, ConnectionDriver, Datagrams, IncomingBiStreams, IncomingUniStreams,
-    NewConnection, OpenBi, OpenUni,
+    Batch, Closed, Connecting, Connection, ConnectionDriver, Datagrams, IncomingBiStreams,
+    IncomingUniStreams, NewConnection, OpenBi, OpenUni, PathEvent, PathEvents, Ping, SendDatagram,
+    SendDatagramError, SendDatagramReady, ZeroRttAccepted,
 };
 
 mod endpoint;
-pub use endpoint::{Endpoint, EndpointDriver, Incoming};
+pub use endpoint::{Endpoint, EndpointDriver, Incoming, Shutdown};
 
 mod streams;
 pub use streams::{
-    Read, ReadError, ReadExact, ReadExactError, ReadToEnd, ReadToEndError, RecvStream, SendStream,
-    WriteError,
+    Read, ReadChunk, ReadError, ReadExact, ReadExactError, ReadToEnd, ReadToEndError,
+    ReadUnordered, RecvStream, SendStream, WriteAllChunks, WriteChunks, WriteError,
 };
 
+#[cfg(feature = "peer-identity")]
+mod identity;
+#[cfg(feature = "peer-identity")]
+pub use identity::PeerIdentity;
+
 mod tls;
-pub use tls::{Certificate, CertificateChain, PrivateKey};
+pub use tls::{Certificate, CertificateChain, PrivateKey, ServerNameVerifier};
 
 #[cfg(test)]
 mod tests;
@@ -87,6 +108,8 @@ enum ConnectionEvent {
         reason: bytes::Bytes,
     },
     Proto(proto::ConnectionEvent),
+    /// The endpoint switched to a new socket
+    Rebind,
 }
 
 #[derive(Debug)]
@@ -1,15 +1,16 @@
-use std::{io, net::SocketAddr};
+use std::io::{self, IoSliceMut};
 
-use mio::net::UdpSocket;
+use proto::Transmit;
 
-use proto::{EcnCodepoint, Transmit};
+use super::{UdpSocketBase, UdpState};
+use crate::udp::RecvMeta;
 
-impl super::UdpExt for UdpSocket {
-    fn init_ext(&self) -> io::Result<()> {
-        Ok(())
+impl<T: UdpSocketBase> super::UdpExt for T {
+    fn init_ext(&self) -> io::Result<UdpState> {
+        Ok(UdpState::default())
     }
 
-    fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize> {
+    fn send_ext(&self, _state: &UdpState, transmits: &[Transmit]) -> io::Result<usize> {
         let mut sent = 0;
         for transmit in transmits {
             match self.send_to(&transmit.contents, &transmit.destination) {
@@ -30,7 +31,15 @@ impl super::UdpExt for UdpSocket {
         Ok(sent)
     }
 
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)> {
-        self.recv_from(buf).map(|(x, y)| (x, y, None))
+    fn recv_ext(&self, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let (len, addr) = self.recv_from(&mut bufs[0])?;
+        meta[0] = RecvMeta {
+            addr,
+            len,
+            stride: len,
+            ecn: None,
+            dscp: 0,
+        };
+        Ok(1)
     }
 }
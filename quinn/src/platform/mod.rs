@@ -1,18 +1,82 @@
 //! Uniform interface to send/recv UDP packets with ECN information.
-use proto::{EcnCodepoint, Transmit};
-use std::{io, net::SocketAddr};
+//!
+//! The ECN and DSCP markings of outgoing packets are set per packet where the platform supports
+//! it, and ignored otherwise. Where available, `sendmmsg` and `recvmmsg` move a batch of datagrams
+//! per system call, and segmentation offload on Linux lets the kernel split up and coalesce runs of
+//! datagrams.
+use proto::Transmit;
+use std::{
+    io::{self, IoSliceMut},
+    net::SocketAddr,
+    sync::atomic::AtomicBool,
+};
+
+use crate::udp::RecvMeta;
 
 #[cfg(unix)]
 mod cmsg;
 #[cfg(unix)]
 mod unix;
 
-// No ECN support
+// No ECN or DSCP support
 #[cfg(not(unix))]
 mod fallback;
 
+/// The plain socket operations `UdpExt` is built on, provided by the socket of each runtime
+///
+/// Unix platforms send and receive through the raw file descriptor instead of `send_to` and
+/// `recv_from`.
+pub trait UdpSocketBase {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn only_v6(&self) -> io::Result<bool>;
+    #[cfg(not(unix))]
+    fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize>;
+    #[cfg(not(unix))]
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+macro_rules! udp_socket_base {
+    ($ty:ty) => {
+        impl UdpSocketBase for $ty {
+            fn local_addr(&self) -> io::Result<SocketAddr> {
+                <$ty>::local_addr(self)
+            }
+
+            fn only_v6(&self) -> io::Result<bool> {
+                <$ty>::only_v6(self)
+            }
+
+            #[cfg(not(unix))]
+            fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+                <$ty>::send_to(self, buf, target)
+            }
+
+            #[cfg(not(unix))]
+            fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+                <$ty>::recv_from(self, buf)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "runtime-tokio")]
+udp_socket_base!(mio::net::UdpSocket);
+#[cfg(feature = "runtime-async-std")]
+udp_socket_base!(std::net::UdpSocket);
+
 pub trait UdpExt {
-    fn init_ext(&self) -> io::Result<()>;
-    fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize>;
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)>;
+    fn init_ext(&self) -> io::Result<UdpState>;
+    fn send_ext(&self, state: &UdpState, transmits: &[Transmit]) -> io::Result<usize>;
+    /// Receive up to one datagram per buffer in `bufs`, describing each in `meta`
+    fn recv_ext(&self, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize>;
+}
+
+/// Optional features of a socket, detected by `UdpExt::init_ext`
+#[derive(Debug, Default)]
+pub struct UdpState {
+    /// Whether runs of equally sized datagrams may be passed to the kernel as one buffer to be split
+    /// up (generic segmentation offload)
+    ///
+    /// Cleared if sending turns out to fail anyway.
+    pub gso: AtomicBool,
 }
@@ -1,23 +1,25 @@
 use std::{
-    io,
+    io::{self, IoSliceMut},
     mem::{self, MaybeUninit},
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     os::unix::io::AsRawFd,
     ptr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use mio::net::UdpSocket;
 use proto::{EcnCodepoint, Transmit};
+use tracing::debug;
 
-use super::cmsg;
+use super::{cmsg, UdpSocketBase, UdpState};
+use crate::udp::RecvMeta;
 
 #[cfg(target_os = "freebsd")]
 type IpTosTy = libc::c_uchar;
 #[cfg(not(target_os = "freebsd"))]
 type IpTosTy = libc::c_int;
 
-impl super::UdpExt for UdpSocket {
-    fn init_ext(&self) -> io::Result<()> {
+impl<T: UdpSocketBase + AsRawFd> super::UdpExt for T {
+    fn init_ext(&self) -> io::Result<UdpState> {
         // Safety
         assert_eq!(
             mem::size_of::<SocketAddrV4>(),
@@ -68,51 +70,72 @@ impl super::UdpExt for UdpSocket {
                 return Err(io::Error::last_os_error());
             }
         }
-        Ok(())
+        Ok(UdpState {
+            gso: AtomicBool::new(gso::init(self)),
+        })
     }
 
     #[cfg(not(target_os = "macos"))]
-    fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize> {
+    fn send_ext(&self, state: &UdpState, transmits: &[Transmit]) -> io::Result<usize> {
         use crate::udp::BATCH_SIZE;
         let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
         let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
         let mut cmsgs = [cmsg::Aligned(MaybeUninit::uninit()); BATCH_SIZE];
-        for (i, transmit) in transmits.iter().enumerate().take(BATCH_SIZE) {
-            prepare_msg(
-                transmit,
-                &mut msgs[i].msg_hdr,
-                &mut iovecs[i],
-                &mut cmsgs[i],
-            );
-        }
+        // Number of transmits carried by each message
+        let mut segments = [0; BATCH_SIZE];
+        let transmits = &transmits[..transmits.len().min(BATCH_SIZE)];
         loop {
-            let n = unsafe {
-                libc::sendmmsg(
-                    self.as_raw_fd(),
-                    msgs.as_mut_ptr(),
-                    transmits.len().min(crate::udp::BATCH_SIZE) as _,
-                    0,
-                )
-            };
+            let gso = state.gso.load(Ordering::Relaxed);
+            let mut msg_count = 0;
+            let mut start = 0;
+            while start < transmits.len() {
+                let n = if gso {
+                    gso_segments(&transmits[start..])
+                } else {
+                    1
+                };
+                prepare_msg(
+                    &transmits[start..start + n],
+                    &mut msgs[msg_count].msg_hdr,
+                    &mut iovecs[start..start + n],
+                    &mut cmsgs[msg_count],
+                );
+                segments[msg_count] = n;
+                msg_count += 1;
+                start += n;
+            }
+            let n =
+                unsafe { libc::sendmmsg(self.as_raw_fd(), msgs.as_mut_ptr(), msg_count as _, 0) };
             if n == -1 {
                 let e = io::Error::last_os_error();
                 if e.kind() == io::ErrorKind::Interrupted {
                     continue;
                 }
+                if gso && e.raw_os_error() == Some(libc::EIO) {
+                    // Some network devices can't checksum segmented datagrams
+                    debug!("disabling GSO after send failure: {}", e);
+                    state.gso.store(false, Ordering::Relaxed);
+                    continue;
+                }
                 return Err(e);
             }
-            return Ok(n as usize);
+            return Ok(segments[..n as usize].iter().sum());
         }
     }
 
     #[cfg(target_os = "macos")]
-    fn send_ext(&self, transmits: &[Transmit]) -> io::Result<usize> {
+    fn send_ext(&self, _state: &UdpState, transmits: &[Transmit]) -> io::Result<usize> {
         let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
         let mut iov: libc::iovec = unsafe { mem::zeroed() };
         let mut ctrl = cmsg::Aligned(MaybeUninit::uninit());
         let mut sent = 0;
         while sent < transmits.len() {
-            prepare_msg(&transmits[sent], &mut hdr, &mut iov, &mut ctrl);
+            prepare_msg(
+                &transmits[sent..sent + 1],
+                &mut hdr,
+                std::slice::from_mut(&mut iov),
+                &mut ctrl,
+            );
             let n = unsafe { libc::sendmsg(self.as_raw_fd(), &hdr, 0) };
             if n == -1 {
                 let e = io::Error::last_os_error();
@@ -133,21 +156,52 @@ impl super::UdpExt for UdpSocket {
         Ok(sent)
     }
 
-    fn recv_ext(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)> {
-        let mut name = MaybeUninit::<libc::sockaddr_storage>::uninit();
-        let mut iov = libc::iovec {
-            iov_base: buf.as_ptr() as *mut _,
-            iov_len: buf.len(),
+    #[cfg(not(target_os = "macos"))]
+    fn recv_ext(&self, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        use crate::udp::BATCH_SIZE;
+        let mut names = [MaybeUninit::<libc::sockaddr_storage>::uninit(); BATCH_SIZE];
+        let mut ctrls = [cmsg::Aligned(MaybeUninit::<[u8; CMSG_LEN]>::uninit()); BATCH_SIZE];
+        let mut hdrs = unsafe { mem::zeroed::<[libc::mmsghdr; BATCH_SIZE]>() };
+        let max_msg_count = bufs.len().min(meta.len()).min(BATCH_SIZE);
+        for i in 0..max_msg_count {
+            prepare_recv(
+                &mut bufs[i],
+                &mut names[i],
+                &mut ctrls[i],
+                &mut hdrs[i].msg_hdr,
+            );
+        }
+        let msg_count = loop {
+            let n = unsafe {
+                libc::recvmmsg(
+                    self.as_raw_fd(),
+                    hdrs.as_mut_ptr(),
+                    max_msg_count as _,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+            if n == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            break n as usize;
         };
+        for i in 0..msg_count {
+            meta[i] = decode_recv(&names[i], &hdrs[i].msg_hdr, hdrs[i].msg_len as usize);
+        }
+        Ok(msg_count)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn recv_ext(&self, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let mut name = MaybeUninit::<libc::sockaddr_storage>::uninit();
         let mut ctrl = cmsg::Aligned(MaybeUninit::<[u8; CMSG_LEN]>::uninit());
         let mut hdr = unsafe { mem::zeroed::<libc::msghdr>() };
-        hdr.msg_name = name.as_mut_ptr() as _;
-        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
-        hdr.msg_iov = &mut iov;
-        hdr.msg_iovlen = 1;
-        hdr.msg_control = ctrl.0.as_mut_ptr() as _;
-        hdr.msg_controllen = CMSG_LEN as _;
-        hdr.msg_flags = 0;
+        prepare_recv(&mut bufs[0], &mut name, &mut ctrl, &mut hdr);
         let n = loop {
             let n = unsafe { libc::recvmsg(self.as_raw_fd(), &mut hdr, 0) };
             if n == -1 {
@@ -162,48 +216,26 @@ impl super::UdpExt for UdpSocket {
             }
             break n;
         };
-        let name = unsafe { name.assume_init() };
-        let ecn_bits = match unsafe { cmsg::Iter::new(&hdr).next() } {
-            Some(cmsg) => match (cmsg.cmsg_level, cmsg.cmsg_type) {
-                // FreeBSD uses IP_RECVTOS here, and we can be liberal because cmsgs are opt-in.
-                (libc::IPPROTO_IP, libc::IP_TOS) | (libc::IPPROTO_IP, libc::IP_RECVTOS) => unsafe {
-                    cmsg::decode::<u8>(cmsg)
-                },
-                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => unsafe {
-                    // Temporary hack around broken macos ABI. Remove once upstream fixes it.
-                    // https://bugreport.apple.com/web/?problemID=48761855
-                    if cfg!(target_os = "macos")
-                        && cmsg.cmsg_len as usize
-                            == libc::CMSG_LEN(mem::size_of::<u8>() as _) as usize
-                    {
-                        cmsg::decode::<u8>(cmsg)
-                    } else {
-                        cmsg::decode::<libc::c_int>(cmsg) as u8
-                    }
-                },
-                _ => 0,
-            },
-            None => 0,
-        };
-        let addr = match libc::c_int::from(name.ss_family) {
-            libc::AF_INET => unsafe { SocketAddr::V4(ptr::read(&name as *const _ as _)) },
-            libc::AF_INET6 => unsafe { SocketAddr::V6(ptr::read(&name as *const _ as _)) },
-            _ => unreachable!(),
-        };
-        Ok((n as usize, addr, EcnCodepoint::from_bits(ecn_bits)))
+        meta[0] = decode_recv(&name, &hdr, n as usize);
+        Ok(1)
     }
 }
 
-const CMSG_LEN: usize = 24;
+const CMSG_LEN: usize = 88;
 
+/// Prepare a message sending `transmits`, which must be a single transmit unless they were
+/// grouped by `gso_segments`
 fn prepare_msg(
-    transmit: &Transmit,
+    transmits: &[Transmit],
     hdr: &mut libc::msghdr,
-    iov: &mut libc::iovec,
+    iovecs: &mut [libc::iovec],
     ctrl: &mut cmsg::Aligned<MaybeUninit<[u8; CMSG_LEN]>>,
 ) {
-    iov.iov_base = transmit.contents.as_ptr() as *const _ as *mut _;
-    iov.iov_len = transmit.contents.len();
+    for (iov, transmit) in iovecs.iter_mut().zip(transmits) {
+        iov.iov_base = transmit.contents.as_ptr() as *const _ as *mut _;
+        iov.iov_len = transmit.contents.len();
+    }
+    let transmit = &transmits[0];
 
     let (name, namelen) = match transmit.destination {
         SocketAddr::V4(ref addr) => (addr as *const _ as _, mem::size_of::<libc::sockaddr_in>()),
@@ -211,17 +243,171 @@ fn prepare_msg(
     };
     hdr.msg_name = name;
     hdr.msg_namelen = namelen as _;
-    hdr.msg_iov = iov;
-    hdr.msg_iovlen = 1;
+    hdr.msg_iov = iovecs.as_mut_ptr();
+    hdr.msg_iovlen = transmits.len() as _;
 
     hdr.msg_control = ctrl.0.as_mut_ptr() as _;
     hdr.msg_controllen = CMSG_LEN as _;
     let mut encoder = unsafe { cmsg::Encoder::new(hdr) };
-    let ecn = transmit.ecn.map_or(0, |x| x as libc::c_int);
+    let tos = libc::c_int::from(transmit.dscp) << 2 | transmit.ecn.map_or(0, |x| x as libc::c_int);
     if transmit.destination.is_ipv4() {
-        encoder.push(libc::IPPROTO_IP, libc::IP_TOS, ecn as IpTosTy);
+        encoder.push(libc::IPPROTO_IP, libc::IP_TOS, tos as IpTosTy);
     } else {
-        encoder.push(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, ecn);
+        encoder.push(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos);
+    }
+    if transmits.len() > 1 {
+        gso::set_segment_size(&mut encoder, transmit.contents.len() as u16);
     }
     encoder.finish();
 }
+
+/// Number of leading `transmits` which can be sent as one segmented datagram
+///
+/// They must share a destination and markings, and all but the last must be of the same size, which
+/// the last may not exceed.
+fn gso_segments(transmits: &[Transmit]) -> usize {
+    let first = &transmits[0];
+    let size = first.contents.len();
+    let mut total = size;
+    let mut n = 1;
+    for transmit in &transmits[1..] {
+        let len = transmit.contents.len();
+        if n == gso::MAX_SEGMENTS
+            || total + len > gso::MAX_BYTES
+            || len > size
+            || transmit.destination != first.destination
+            || transmit.ecn != first.ecn
+            || transmit.dscp != first.dscp
+        {
+            break;
+        }
+        total += len;
+        n += 1;
+        if len < size {
+            break;
+        }
+    }
+    n
+}
+
+fn prepare_recv(
+    buf: &mut IoSliceMut,
+    name: &mut MaybeUninit<libc::sockaddr_storage>,
+    ctrl: &mut cmsg::Aligned<MaybeUninit<[u8; CMSG_LEN]>>,
+    hdr: &mut libc::msghdr,
+) {
+    hdr.msg_name = name.as_mut_ptr() as _;
+    hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+    // `IoSliceMut` is ABI-compatible with `iovec` on unix
+    hdr.msg_iov = buf as *mut IoSliceMut as *mut libc::iovec;
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = ctrl.0.as_mut_ptr() as _;
+    hdr.msg_controllen = CMSG_LEN as _;
+    hdr.msg_flags = 0;
+}
+
+fn decode_recv(
+    name: &MaybeUninit<libc::sockaddr_storage>,
+    hdr: &libc::msghdr,
+    len: usize,
+) -> RecvMeta {
+    let name = unsafe { name.as_ptr().read() };
+    let mut tos = 0;
+    let mut stride = len;
+    for cmsg in unsafe { cmsg::Iter::new(hdr) } {
+        match (cmsg.cmsg_level, cmsg.cmsg_type) {
+            // FreeBSD uses IP_RECVTOS here, and we can be liberal because cmsgs are opt-in.
+            (libc::IPPROTO_IP, libc::IP_TOS) | (libc::IPPROTO_IP, libc::IP_RECVTOS) => unsafe {
+                tos = cmsg::decode::<u8>(cmsg);
+            },
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => unsafe {
+                // Temporary hack around broken macos ABI. Remove once upstream fixes it.
+                // https://bugreport.apple.com/web/?problemID=48761855
+                if cfg!(target_os = "macos")
+                    && cmsg.cmsg_len as usize == libc::CMSG_LEN(mem::size_of::<u8>() as _) as usize
+                {
+                    tos = cmsg::decode::<u8>(cmsg);
+                } else {
+                    tos = cmsg::decode::<libc::c_int>(cmsg) as u8;
+                }
+            },
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_UDP, gso::UDP_GRO) => unsafe {
+                stride = cmsg::decode::<libc::c_int>(cmsg) as usize;
+            },
+            _ => {}
+        }
+    }
+    let addr = match libc::c_int::from(name.ss_family) {
+        libc::AF_INET => unsafe { SocketAddr::V4(ptr::read(&name as *const _ as _)) },
+        libc::AF_INET6 => unsafe { SocketAddr::V6(ptr::read(&name as *const _ as _)) },
+        _ => unreachable!(),
+    };
+    RecvMeta {
+        len,
+        stride,
+        addr,
+        ecn: EcnCodepoint::from_bits(tos),
+        dscp: tos >> 2,
+    }
+}
+
+/// Generic segmentation and receive offload, sending or receiving many datagrams in one buffer
+#[cfg(target_os = "linux")]
+mod gso {
+    use std::{mem, os::unix::io::AsRawFd};
+
+    use super::cmsg;
+
+    // Not yet exposed by libc
+    const UDP_SEGMENT: libc::c_int = 103;
+    pub(super) const UDP_GRO: libc::c_int = 104;
+
+    /// Kernel limit on the number of segments in one send
+    pub(super) const MAX_SEGMENTS: usize = 64;
+    /// Largest UDP payload that fits in an IPv4 packet
+    pub(super) const MAX_BYTES: usize = 65507;
+
+    /// Enable GRO if possible, returning whether GSO is available
+    pub(super) fn init(socket: &impl AsRawFd) -> bool {
+        // Coalesced datagrams are split by `RecvMeta::stride`, so failure only costs performance
+        let _ = set_option(socket, UDP_GRO, 1);
+        // Zero is the default segment size, so this only checks that the kernel knows the option
+        set_option(socket, UDP_SEGMENT, 0)
+    }
+
+    fn set_option(socket: &impl AsRawFd, name: libc::c_int, value: libc::c_int) -> bool {
+        let rc = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_UDP,
+                name,
+                &value as *const _ as _,
+                mem::size_of_val(&value) as _,
+            )
+        };
+        rc == 0
+    }
+
+    pub(super) fn set_segment_size(encoder: &mut cmsg::Encoder, size: u16) {
+        encoder.push(libc::IPPROTO_UDP, UDP_SEGMENT, size);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod gso {
+    use std::os::unix::io::AsRawFd;
+
+    use super::cmsg;
+
+    pub(super) const MAX_SEGMENTS: usize = 1;
+    pub(super) const MAX_BYTES: usize = 0;
+
+    pub(super) fn init(_: &impl AsRawFd) -> bool {
+        false
+    }
+
+    pub(super) fn set_segment_size(_: &mut cmsg::Encoder, _: u16) {
+        panic!("segmentation offload is unsupported on this platform");
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use crate::udp::AsyncUdpSocket;
+
+/// The facilities an endpoint needs from an async runtime
+///
+/// Endpoints wait on timers and on their socket becoming readable or writable, which requires the
+/// cooperation of whatever executor they're spawned on. `TokioRuntime` and `AsyncStdRuntime` are
+/// provided by the `runtime-tokio` and `runtime-async-std` features respectively. The default is
+/// `TokioRuntime` when its feature is enabled and `AsyncStdRuntime` otherwise, unless
+/// `EndpointBuilder::runtime` supplies another implementation.
+///
+/// There is no way to spawn tasks: quinn never does so itself, instead handing endpoint and
+/// connection drivers to the application, which spawns them on the executor of its choice.
+pub trait Runtime: fmt::Debug + Send + Sync + 'static {
+    /// A timer that fires once the system time has reached `deadline`
+    fn delay_until(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>>;
+    /// Register `socket` with the runtime's reactor, for use by an endpoint
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>>;
+}
+
+/// A timer that can be rearmed in place
+///
+/// Connections reset their timers far more often than they fire, so each is allocated once and
+/// then moved with `reset`.
+pub trait AsyncTimer: fmt::Debug + Send + 'static {
+    /// Change the deadline, whether or not the timer has already fired
+    fn reset(self: Pin<&mut Self>, deadline: Instant);
+    /// Complete once the deadline has been reached
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>;
+}
+
+/// Drives endpoints using tokio's reactor and timers
+///
+/// Requires the `runtime-tokio` feature, which is enabled by default.
+#[cfg(feature = "runtime-tokio")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "runtime-tokio")]
+impl Runtime for TokioRuntime {
+    fn delay_until(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(tokio::time::delay_until(tokio::time::Instant::from_std(
+            deadline,
+        )))
+    }
+
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Ok(Box::new(crate::udp::UdpSocket::from_std(socket)?))
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+impl AsyncTimer for tokio::time::Delay {
+    fn reset(self: Pin<&mut Self>, deadline: Instant) {
+        tokio::time::Delay::reset(self.get_mut(), tokio::time::Instant::from_std(deadline));
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        std::future::Future::poll(self, cx)
+    }
+}
+
+/// Drives endpoints using async-std's reactor and timers
+///
+/// Requires the `runtime-async-std` feature.
+#[cfg(feature = "runtime-async-std")]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "runtime-async-std")]
+impl Runtime for AsyncStdRuntime {
+    fn delay_until(&self, deadline: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(async_io::Timer::at(deadline))
+    }
+
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Ok(Box::new(crate::udp::AsyncStdUdpSocket::from_std(socket)?))
+    }
+}
+
+#[cfg(feature = "runtime-async-std")]
+impl AsyncTimer for async_io::Timer {
+    fn reset(self: Pin<&mut Self>, deadline: Instant) {
+        self.get_mut().set_at(deadline);
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        std::future::Future::poll(self, cx).map(|_| ())
+    }
+}
+
+/// The runtime endpoints use unless another is configured
+pub(crate) fn default_runtime() -> Option<Arc<dyn Runtime>> {
+    #[cfg(feature = "runtime-tokio")]
+    {
+        Some(Arc::new(TokioRuntime))
+    }
+    #[cfg(all(not(feature = "runtime-tokio"), feature = "runtime-async-std"))]
+    {
+        Some(Arc::new(AsyncStdRuntime))
+    }
+    #[cfg(not(any(feature = "runtime-tokio", feature = "runtime-async-std")))]
+    {
+        None
+    }
+}
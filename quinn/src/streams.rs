@@ -1,7 +1,6 @@
 use std::{
     future::Future,
     io,
-    mem::MaybeUninit,
     pin::Pin,
     str,
     task::{Context, Poll},
@@ -14,14 +13,20 @@ use futures::{
     io::{AsyncRead, AsyncWrite},
     ready, FutureExt,
 };
-use proto::{ConnectionError, StreamId};
+use proto::{ConnectionError, StreamId, Written};
 
-use crate::{connection::ConnectionRef, VarInt};
+use crate::{
+    connection::{ConnectionInner, ConnectionRef},
+    VarInt,
+};
 
 /// A stream that can only be used to send data
 ///
 /// If dropped, streams that haven't been explicitly `reset` will continue to (re)transmit
 /// previously written data until it has been fully acknowledged or the connection is closed.
+///
+/// Implements the `AsyncWrite` traits of both `futures` and, with the `runtime-tokio` feature,
+/// tokio. Closing or shutting down the writer finishes the stream.
 #[derive(Debug)]
 pub struct SendStream {
     conn: ConnectionRef,
@@ -53,14 +58,40 @@ impl SendStream {
         WriteAll { stream: self, buf }
     }
 
+    /// Write owned chunks of data to the stream without copying them
+    ///
+    /// Yields the number of bytes and whole chunks written on success. Congestion and flow control
+    /// may cause only a prefix of `bufs` to be written, in which case a partially written chunk has
+    /// its written prefix split off.
+    pub fn write_chunks<'a>(&'a mut self, bufs: &'a mut [Bytes]) -> WriteChunks<'a> {
+        WriteChunks { stream: self, bufs }
+    }
+
+    /// Convenience method to write every chunk in `bufs` to the stream
+    pub fn write_all_chunks<'a>(&'a mut self, bufs: &'a mut [Bytes]) -> WriteAllChunks<'a> {
+        WriteAllChunks {
+            stream: self,
+            bufs,
+            written: 0,
+        }
+    }
+
     fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, WriteError>> {
+        self.poll_write_with(cx, |conn, stream| conn.write(stream, buf))
+    }
+
+    fn poll_write_with<T>(
+        &mut self,
+        cx: &mut Context,
+        write: impl FnOnce(&mut proto::Connection, StreamId) -> Result<T, proto::WriteError>,
+    ) -> Poll<Result<T, WriteError>> {
         use proto::WriteError::*;
         let mut conn = self.conn.lock().unwrap();
         if self.is_0rtt {
             conn.check_0rtt()
                 .map_err(|()| WriteError::ZeroRttRejected)?;
         }
-        let n = match conn.inner.write(self.stream, buf) {
+        let n = match write(&mut conn.inner, self.stream) {
             Ok(n) => n,
             Err(Blocked) => {
                 if let Some(ref x) = conn.error {
@@ -80,6 +111,49 @@ impl SendStream {
         Poll::Ready(Ok(n))
     }
 
+    /// Bytes that can be written before reaching the flow control limit set by the peer
+    ///
+    /// Writes may still wait for connection-level flow control or congestion control.
+    pub fn credit(&self) -> Result<u64, WriteError> {
+        credit(&self.conn.lock().unwrap(), self.stream)
+    }
+
+    /// Wait until the peer permits writing more data to the stream
+    ///
+    /// Resolves to the stream's `credit` immediately if there is some, and otherwise once the peer
+    /// raises the stream's flow control limit.
+    pub fn credit_available(&mut self) -> CreditAvailable<'_> {
+        CreditAvailable { stream: self }
+    }
+
+    fn poll_credit_available(&mut self, cx: &mut Context) -> Poll<Result<u64, WriteError>> {
+        let mut conn = self.conn.lock().unwrap();
+        match credit(&conn, self.stream) {
+            Ok(0) => {
+                conn.blocked_writers.insert(self.stream, cx.waker().clone());
+                Poll::Pending
+            }
+            x => Poll::Ready(x),
+        }
+    }
+
+    /// Set the priority of the stream
+    ///
+    /// Every stream has an initial priority of 0. When congestion control limits transmission,
+    /// data written to streams with higher priority is sent before that of lower-priority streams.
+    /// Streams of equal priority are served in the order their data was written.
+    pub fn set_priority(&self, priority: i32) -> Result<(), UnknownStream> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.inner.set_priority(self.stream, priority)?;
+        Ok(())
+    }
+
+    /// Get the priority of the stream
+    pub fn priority(&self) -> Result<i32, UnknownStream> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.inner.priority(self.stream)?)
+    }
+
     /// Shut down the send stream gracefully.
     ///
     /// No new data may be written after calling this method. Completes when the peer has
@@ -126,7 +200,7 @@ impl SendStream {
         conn.wake();
     }
 
-    #[doc(hidden)]
+    /// Get the identity of this stream
     pub fn id(&self) -> StreamId {
         self.stream
     }
@@ -146,6 +220,7 @@ impl AsyncWrite for SendStream {
     }
 }
 
+#[cfg(feature = "runtime-tokio")]
 impl tokio::io::AsyncWrite for SendStream {
     fn poll_write(
         self: Pin<&mut Self>,
@@ -192,11 +267,37 @@ impl Future for Finish<'_> {
     }
 }
 
+fn credit(conn: &ConnectionInner, stream: StreamId) -> Result<u64, WriteError> {
+    if let Some(ref x) = conn.error {
+        return Err(WriteError::ConnectionClosed(x.clone()));
+    }
+    conn.inner.write_credit(stream).map_err(|e| match e {
+        proto::WriteError::Stopped(error_code) => WriteError::Stopped(error_code),
+        _ => WriteError::UnknownStream,
+    })
+}
+
+/// Future produced by `SendStream::credit_available`
+pub struct CreditAvailable<'a> {
+    stream: &'a mut SendStream,
+}
+
+impl Future for CreditAvailable<'_> {
+    type Output = Result<u64, WriteError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().stream.poll_credit_available(cx)
+    }
+}
+
 /// A stream that can only be used to receive data
 ///
 /// `stop(0)` is implicitly called on drop unless:
 /// - `ReadError::Finished` has been emitted, or
 /// - `stop` was called explicitly
+///
+/// Implements the `AsyncRead` traits of both `futures` and, with the `runtime-tokio` feature,
+/// tokio. Reads return 0 once the stream is finished.
 #[derive(Debug)]
 pub struct RecvStream {
     conn: ConnectionRef,
@@ -248,30 +349,17 @@ impl RecvStream {
         cx: &mut Context,
         buf: &mut [u8],
     ) -> Poll<Result<Option<usize>, ReadError>> {
-        self.any_data_read = true;
-        use proto::ReadError::*;
-        let mut conn = self.conn.lock().unwrap();
-        if self.is_0rtt {
-            conn.check_0rtt().map_err(|()| ReadError::ZeroRttRejected)?;
-        }
-        match conn.inner.read(self.stream, buf) {
-            Ok(Some(n)) => Poll::Ready(Ok(Some(n))),
-            Ok(None) => {
-                self.all_data_read = true;
-                Poll::Ready(Ok(None))
-            }
-            Err(Blocked) => {
-                if let Some(ref x) = conn.error {
-                    return Poll::Ready(Err(ReadError::ConnectionClosed(x.clone())));
-                }
-                conn.blocked_readers.insert(self.stream, cx.waker().clone());
-                Poll::Pending
-            }
-            Err(Reset(error_code)) => {
-                self.all_data_read = true;
-                Poll::Ready(Err(ReadError::Reset(error_code)))
-            }
-            Err(UnknownStream) => Poll::Ready(Err(ReadError::UnknownStream)),
+        self.poll_read_with(cx, |conn, stream| conn.read(stream, buf))
+    }
+
+    /// Read the next segment of data from the stream without copying it
+    ///
+    /// Yields at most `max_length` contiguous bytes, or `None` if the stream was finished. Like
+    /// `read`, data is returned in order, so this may not be mixed with `read_unordered`.
+    pub fn read_chunk(&mut self, max_length: usize) -> ReadChunk<'_> {
+        ReadChunk {
+            stream: self,
+            max_length,
         }
     }
 
@@ -281,7 +369,8 @@ impl RecvStream {
     /// finished. Segments may be received in any order and may overlap.
     ///
     /// Unordered reads have reduced overhead and higher throughput, and should therefore be
-    /// preferred when applicable.
+    /// preferred when applicable. Since data is yielded as soon as it arrives, loss of one segment
+    /// doesn't hold back delivery of those after it.
     pub fn read_unordered(&mut self) -> ReadUnordered<'_> {
         ReadUnordered { stream: self }
     }
@@ -290,14 +379,26 @@ impl RecvStream {
         &mut self,
         cx: &mut Context,
     ) -> Poll<Result<Option<(Bytes, u64)>, ReadError>> {
+        self.poll_read_with(cx, |conn, stream| conn.read_unordered(stream))
+    }
+
+    fn poll_read_with<T>(
+        &mut self,
+        cx: &mut Context,
+        read: impl FnOnce(&mut proto::Connection, StreamId) -> Result<Option<T>, proto::ReadError>,
+    ) -> Poll<Result<Option<T>, ReadError>> {
         self.any_data_read = true;
         use proto::ReadError::*;
         let mut conn = self.conn.lock().unwrap();
         if self.is_0rtt {
             conn.check_0rtt().map_err(|()| ReadError::ZeroRttRejected)?;
         }
-        match conn.inner.read_unordered(self.stream) {
-            Ok(Some((bytes, offset))) => Poll::Ready(Ok(Some((bytes, offset)))),
+        match read(&mut conn.inner, self.stream) {
+            Ok(Some(x)) => {
+                // Flow control credit may need to be issued
+                conn.wake();
+                Poll::Ready(Ok(Some(x)))
+            }
             Ok(None) => {
                 self.all_data_read = true;
                 Poll::Ready(Ok(None))
@@ -355,6 +456,11 @@ impl RecvStream {
         self.all_data_read = true;
         Ok(())
     }
+
+    /// Get the identity of this stream
+    pub fn id(&self) -> StreamId {
+        self.stream
+    }
 }
 
 /// Future produced by `read_to_end`
@@ -424,8 +530,9 @@ impl AsyncRead for RecvStream {
     }
 }
 
+#[cfg(feature = "runtime-tokio")]
 impl tokio::io::AsyncRead for RecvStream {
-    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [MaybeUninit<u8>]) -> bool {
+    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [std::mem::MaybeUninit<u8>]) -> bool {
         false
     }
 
@@ -582,6 +689,22 @@ impl<'a> Future for ReadUnordered<'a> {
     }
 }
 
+/// Future produced by `RecvStream::read_chunk`
+pub struct ReadChunk<'a> {
+    stream: &'a mut RecvStream,
+    max_length: usize,
+}
+
+impl<'a> Future for ReadChunk<'a> {
+    type Output = Result<Option<Bytes>, ReadError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let max_length = this.max_length;
+        this.stream
+            .poll_read_with(cx, |conn, stream| conn.read_chunk(stream, max_length))
+    }
+}
+
 /// Future produced by `SendStream::write`
 pub struct Write<'a> {
     stream: &'a mut SendStream,
@@ -616,6 +739,46 @@ impl<'a> Future for WriteAll<'a> {
     }
 }
 
+/// Future produced by `SendStream::write_chunks`
+pub struct WriteChunks<'a> {
+    stream: &'a mut SendStream,
+    bufs: &'a mut [Bytes],
+}
+
+impl<'a> Future for WriteChunks<'a> {
+    type Output = Result<Written, WriteError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let bufs = &mut *this.bufs;
+        this.stream
+            .poll_write_with(cx, |conn, stream| conn.write_chunks(stream, bufs))
+    }
+}
+
+/// Future produced by `SendStream::write_all_chunks`
+pub struct WriteAllChunks<'a> {
+    stream: &'a mut SendStream,
+    bufs: &'a mut [Bytes],
+    written: usize,
+}
+
+impl<'a> Future for WriteAllChunks<'a> {
+    type Output = Result<(), WriteError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if this.written == this.bufs.len() {
+                return Poll::Ready(Ok(()));
+            }
+            let bufs = &mut this.bufs[this.written..];
+            let written = ready!(this
+                .stream
+                .poll_write_with(cx, |conn, stream| conn.write_chunks(stream, bufs)))?;
+            this.written += written.chunks;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UnknownStream {}
 
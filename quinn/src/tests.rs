@@ -1,10 +1,14 @@
 use std::{
-    io,
+    collections::{HashMap, VecDeque},
+    io::{self, IoSliceMut},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    pin::Pin,
     str,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
 };
 
+use bytes::Bytes;
 use futures::{future, FutureExt, StreamExt, TryFutureExt};
 use tokio::{
     runtime::{Builder, Runtime},
@@ -14,8 +18,10 @@ use tracing::{info, info_span};
 use tracing_futures::Instrument as _;
 
 use super::{
-    ClientConfigBuilder, Endpoint, EndpointDriver, Incoming, NewConnection, RecvStream, SendStream,
-    ServerConfigBuilder,
+    crypto::StrikeRegister, AsyncUdpSocket, ClientConfig, ClientConfigBuilder, EarlyDataStatus,
+    Endpoint, EndpointBuilder, EndpointDriver, Incoming, ManualClock, MemorySessionStorage,
+    NewConnection, ReadError, ReadToEndError, RecvMeta, RecvStream, SendStream,
+    ServerConfigBuilder, SessionStorage, TokioRuntime, Transmit, WriteError,
 };
 
 #[test]
@@ -202,10 +208,1048 @@ fn read_after_close() {
     });
 }
 
+#[test]
+fn send_ping() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(|| endpoint());
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        new_conn.driver.unwrap_or_else(|_| ()).await;
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        new_conn.connection.send_ping().expect("send_ping");
+        new_conn.connection.close(0u32.into(), b"done");
+        match new_conn.connection.send_ping() {
+            Err(crate::ConnectionError::LocallyClosed) => {}
+            x => panic!("unexpected send_ping result: {:?}", x),
+        }
+    });
+}
+
+#[test]
+fn datagrams() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(endpoint);
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let mut new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        // Echo datagrams back until the connection closes
+        while let Some(Ok(data)) = new_conn.datagrams.next().await {
+            new_conn
+                .connection
+                .send_datagram(data)
+                .await
+                .expect("send_datagram");
+        }
+    });
+    runtime.block_on(async move {
+        let mut new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        assert!(new_conn.connection.max_datagram_size().is_some());
+        new_conn
+            .connection
+            .send_datagram(Bytes::from_static(b"hello"))
+            .await
+            .expect("send_datagram");
+        let echo = new_conn
+            .datagrams
+            .next()
+            .await
+            .expect("datagrams")
+            .expect("datagram");
+        assert_eq!(&echo[..], b"hello");
+        new_conn.connection.close(0u32.into(), b"done");
+    });
+}
+
+#[test]
+fn closed_by_peer() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(|| endpoint());
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        new_conn.connection.close(42u32.into(), b"bye");
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        match new_conn.connection.closed().await {
+            crate::ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, 42u32.into());
+                assert_eq!(&close.reason[..], b"bye");
+            }
+            e => panic!("unexpected close reason: {}", e),
+        }
+    });
+}
+
+#[test]
+fn endpoint_shutdown() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(endpoint);
+    let driver = runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    let (accepted_send, accepted) = futures::channel::oneshot::channel();
+    let server = runtime.spawn(async move {
+        let new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        accepted_send.send(()).unwrap();
+        // No more connections are accepted once the endpoint shuts down
+        assert!(incoming.next().await.is_none());
+        new_conn.connection
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        accepted.await.unwrap();
+        let remaining = endpoint
+            .shutdown(7u32.into(), b"shutdown", Duration::from_secs(10))
+            .await;
+        assert_eq!(remaining, 0);
+        match new_conn.connection.closed().await {
+            crate::ConnectionError::LocallyClosed => {}
+            e => panic!("unexpected close reason: {}", e),
+        }
+        drop(server.await.unwrap());
+        drop((endpoint, new_conn.connection));
+        driver.await.unwrap();
+    });
+}
+
+#[cfg(feature = "peer-identity")]
+#[test]
+fn peer_identity() {
+    let _guard = subscribe();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let der = cert.serialize_der().unwrap();
+    let mut endpoint = Endpoint::builder();
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .certificate(
+            crate::CertificateChain::from_certs(vec![crate::Certificate::from_der(&der).unwrap()]),
+            crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap(),
+        )
+        .unwrap();
+    endpoint.listen(server_config.build());
+    let mut client_config = ClientConfigBuilder::default();
+    client_config
+        .add_certificate_authority(crate::Certificate::from_der(&der).unwrap())
+        .unwrap();
+    endpoint.default_client_config(client_config.build());
+
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(|| {
+        endpoint
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    let server = runtime.spawn(async move {
+        let new_conn = incoming.next().await.unwrap().await.expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        // The client didn't authenticate itself
+        assert_eq!(new_conn.connection.peer_identity(), None);
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let identity = new_conn.connection.peer_identity().expect("peer identity");
+        assert_eq!(identity.dns_names, ["localhost"]);
+        assert!(identity.ip_addresses.is_empty());
+        assert_eq!(
+            identity.common_name.as_ref().map(|x| &x[..]),
+            Some("rcgen self signed cert")
+        );
+        assert_eq!(
+            &identity.fingerprint[..],
+            ring::digest::digest(&ring::digest::SHA256, &der).as_ref()
+        );
+        let now = std::time::SystemTime::now();
+        assert!(identity.not_before < now && now < identity.not_after);
+        server.await.unwrap();
+    });
+}
+
+#[test]
+fn closed_by_stateless_reset() {
+    let _guard = subscribe();
+    let mut server_config = ServerConfigBuilder::default();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let cert_chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+    server_config.certificate(cert_chain, key).unwrap();
+    let server_config = server_config.build();
+    // Shared by both incarnations of the server, so the second can reset the first's connections
+    let endpoint_config = proto::EndpointConfig::default();
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut client = Endpoint::builder();
+    client.default_client_config(client_config.build());
+    let mut runtime = rt_basic();
+    let (client_driver, client, _) = runtime.enter(|| {
+        client
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    runtime.spawn(client_driver.unwrap_or_else(|e| panic!("{}", e)));
+
+    // Dropping the server's runtime drops its endpoint and connection without notifying the client
+    let server_runtime = rt_threaded();
+    let mut server = EndpointBuilder::new(endpoint_config.clone());
+    server.listen(server_config.clone());
+    let (server_driver, server_endpoint, mut incoming) = server_runtime.enter(|| {
+        server
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    let server_addr = server_endpoint.local_addr().unwrap();
+    server_runtime.spawn(server_driver.unwrap_or_else(|e| panic!("{}", e)));
+    server_runtime.spawn(async move {
+        let new_conn = incoming.next().await.unwrap().await.unwrap();
+        new_conn.driver.unwrap_or_else(|_| ()).await;
+    });
+    let NewConnection {
+        driver, connection, ..
+    } = runtime
+        .block_on(client.connect(&server_addr, "localhost").unwrap())
+        .expect("connect");
+    runtime.spawn(driver.unwrap_or_else(|_| ()));
+    drop((server_endpoint, server_runtime));
+
+    let mut server = EndpointBuilder::new(endpoint_config);
+    server.listen(server_config);
+    let (server_driver, _server, _incoming) = runtime.enter(|| server.bind(&server_addr).unwrap());
+    runtime.spawn(server_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.block_on(async move {
+        connection.send_ping().unwrap();
+        match connection.closed().await {
+            crate::ConnectionError::Reset => {}
+            e => panic!("unexpected close reason: {}", e),
+        }
+    });
+}
+
+#[test]
+fn ping() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(|| endpoint());
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        new_conn.driver.unwrap_or_else(|_| ()).await;
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let rtt = new_conn.connection.ping().await.expect("ping");
+        assert!(rtt < Duration::from_secs(1));
+        new_conn.connection.close(0u32.into(), b"done");
+        match new_conn.connection.ping().await {
+            Err(crate::ConnectionError::LocallyClosed) => {}
+            x => panic!("unexpected ping result: {:?}", x),
+        }
+    });
+}
+
+#[test]
+fn path_events() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let builder = configure(Endpoint::builder());
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let (server_driver, server, mut incoming) =
+        runtime.enter(|| builder.clone().bind(&addr).unwrap());
+    let (client_driver, client, _) = runtime.enter(|| builder.bind(&addr).unwrap());
+    runtime.spawn(server_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(client_driver.unwrap_or_else(|e| panic!("{}", e)));
+    let server_task = runtime.spawn(async move {
+        let new_conn = incoming.next().await.unwrap().await.expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let event = new_conn.connection.path_events().next().await;
+        (event, new_conn.connection.remote_address())
+    });
+    runtime.block_on(async move {
+        let new_conn = client
+            .connect(&server.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let old = client.local_addr().unwrap();
+        client.rebind(UdpSocket::bind(addr).unwrap()).unwrap();
+        let new = client.local_addr().unwrap();
+        new_conn.connection.send_ping().unwrap();
+        let (event, remote) = server_task.await.unwrap();
+        assert_eq!(
+            event,
+            Some(crate::PathEvent::RemoteAddressChanged { old, new })
+        );
+        assert_eq!(remote, new);
+    });
+}
+
+#[test]
+fn manual_clock_idle_timeout() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let clock = ManualClock::new();
+    let mut builder = Endpoint::builder();
+    builder.clock(clock.clone());
+    let (driver, endpoint, mut incoming) = runtime.enter(|| endpoint_with(builder));
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        new_conn.driver.unwrap_or_else(|_| ()).await;
+    });
+    runtime.block_on(async move {
+        let start = Instant::now();
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        // Idle timeout defaults to 10 seconds, which would otherwise take that long to elapse
+        clock.advance(Duration::from_secs(11));
+        match new_conn.driver.await {
+            Err(crate::ConnectionError::TimedOut) => {}
+            x => panic!("unexpected driver result: {:?}", x),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    });
+}
+
+#[test]
+fn manual_clock_connect() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let clock = ManualClock::new();
+    let mut builder = Endpoint::builder();
+    builder.clock(clock.clone());
+    let (driver, endpoint, mut incoming) = runtime.enter(|| endpoint_with(builder));
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let mut new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let stream = new_conn.uni_streams.next().await.unwrap().unwrap();
+        stream.read_to_end(1 << 21).await.unwrap();
+    });
+    runtime.block_on(async move {
+        // Let the system clock run ahead of the endpoint's
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let initial_window = new_conn.connection.path_stats().congestion_window;
+        clock.advance(Duration::from_millis(1));
+        let mut stream = new_conn.connection.open_uni().await.unwrap();
+        stream.write_all(&vec![0; 1 << 20]).await.unwrap();
+        stream.finish().await.unwrap();
+        // Had the connection been created at the system clock's time, every packet sent since
+        // would be considered part of a recovery period, preventing the window from growing
+        assert!(new_conn.connection.path_stats().congestion_window > initial_window);
+    });
+}
+
+#[test]
+fn dscp_marking() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let marks = Arc::new(Mutex::new(Vec::new()));
+    // Both endpoints trust the same certificate
+    let builder = configure(Endpoint::builder());
+    let (server_driver, server, mut incoming) = runtime.enter(|| {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        builder
+            .clone()
+            .with_async_socket(DscpSocket {
+                inner: crate::udp::UdpSocket::from_std(socket).unwrap(),
+                marks: marks.clone(),
+            })
+            .unwrap()
+    });
+    let server_addr = server.local_addr().unwrap();
+    let (client_driver, client, _) =
+        runtime.enter(|| builder.bind(&"127.0.0.1:0".parse().unwrap()).unwrap());
+    runtime.spawn(server_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(client_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let mut new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let stream = new_conn.uni_streams.next().await.unwrap().unwrap();
+        let data = stream.read_to_end(1 << 16).await.unwrap();
+        let mut reply = new_conn.connection.open_uni().await.unwrap();
+        reply.write_all(&data).await.unwrap();
+        reply.finish().await.unwrap();
+    });
+    runtime.block_on(async move {
+        let mut new_conn = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        // Expedited forwarding
+        new_conn.connection.set_dscp(46);
+        let mut stream = new_conn.connection.open_uni().await.unwrap();
+        stream.write_all(b"marked").await.unwrap();
+        stream.finish().await.unwrap();
+        let reply = new_conn.uni_streams.next().await.unwrap().unwrap();
+        assert_eq!(reply.read_to_end(1 << 16).await.unwrap(), b"marked");
+    });
+    let marks = marks.lock().unwrap();
+    // Handshake packets were sent before the connection was marked
+    assert_eq!(marks.first(), Some(&0));
+    if cfg!(unix) {
+        assert!(marks.contains(&46));
+    }
+}
+
+/// UDP socket recording the DSCP of the datagrams it receives
+#[derive(Debug)]
+struct DscpSocket {
+    inner: crate::udp::UdpSocket,
+    marks: Arc<Mutex<Vec<u8>>>,
+}
+
+impl AsyncUdpSocket for DscpSocket {
+    fn poll_send(&self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        self.inner.poll_send(cx, transmits)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let n = futures::ready!(self.inner.poll_recv(cx, bufs, meta))?;
+        let mut marks = self.marks.lock().unwrap();
+        marks.extend(meta[..n].iter().map(|x| x.dscp));
+        Poll::Ready(Ok(n))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[test]
+fn batched_recv() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    runtime.block_on(async {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        let receiver = crate::udp::UdpSocket::from_std(receiver).unwrap();
+        for i in 0..5u8 {
+            sender.send_to(&[i; 100], receiver_addr).unwrap();
+        }
+
+        let mut storage = [[0; 1500]; crate::udp::BATCH_SIZE];
+        let mut bufs = storage
+            .iter_mut()
+            .map(|x| IoSliceMut::new(x))
+            .collect::<Vec<_>>();
+        let mut metas = [RecvMeta::default(); crate::udp::BATCH_SIZE];
+        let mut recvd = 0;
+        let mut calls = 0;
+        while recvd < 5 {
+            let n = future::poll_fn(|cx| receiver.poll_recv(cx, &mut bufs[recvd..], &mut metas))
+                .await
+                .unwrap();
+            for (meta, buf) in metas.iter().zip(bufs[recvd..].iter()).take(n) {
+                assert_eq!(meta.addr, sender.local_addr().unwrap());
+                assert_eq!(buf[..meta.len], [recvd as u8; 100][..]);
+                recvd += 1;
+            }
+            calls += 1;
+        }
+        assert_eq!(recvd, 5);
+        // Datagrams already queued are received in a single system call
+        if cfg!(target_os = "linux") {
+            assert_eq!(calls, 1);
+        }
+    });
+}
+
+#[test]
+fn segmented_send_recv() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    runtime.block_on(async {
+        let sender = crate::udp::UdpSocket::from_std(UdpSocket::bind("[::1]:0").unwrap()).unwrap();
+        let receiver =
+            crate::udp::UdpSocket::from_std(UdpSocket::bind("[::1]:0").unwrap()).unwrap();
+        let destination = receiver.local_addr().unwrap();
+        // A run of equally sized datagrams, as segmentation offload expects, and a shorter one
+        let transmits = (0..10u8)
+            .map(|i| Transmit {
+                destination,
+                ecn: None,
+                dscp: 0,
+                contents: vec![i; if i == 9 { 500 } else { 1200 }].into(),
+            })
+            .collect::<Vec<_>>();
+        let mut sent = 0;
+        while sent < transmits.len() {
+            sent += future::poll_fn(|cx| sender.poll_send(cx, &transmits[sent..]))
+                .await
+                .unwrap();
+        }
+
+        let mut storage = vec![0; 64 * 1024];
+        let mut metas = [RecvMeta::default(); 1];
+        let mut recvd = Vec::new();
+        while recvd.len() < transmits.len() {
+            let mut bufs = [IoSliceMut::new(&mut storage)];
+            future::poll_fn(|cx| receiver.poll_recv(cx, &mut bufs, &mut metas))
+                .await
+                .unwrap();
+            let meta = metas[0];
+            assert_eq!(meta.addr, sender.local_addr().unwrap());
+            recvd.extend(storage[..meta.len].chunks(meta.stride).map(|x| x.to_vec()));
+        }
+        assert_eq!(
+            recvd,
+            transmits
+                .iter()
+                .map(|x| x.contents.to_vec())
+                .collect::<Vec<_>>()
+        );
+    });
+}
+
+#[test]
+fn async_socket() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let network = MemoryNetwork::default();
+    let server_addr = "10.0.0.1:4433".parse().unwrap();
+    let client_addr = "10.0.0.2:1234".parse().unwrap();
+    // Both endpoints trust the same certificate
+    let builder = configure(Endpoint::builder());
+    let (server_driver, _, mut incoming) = runtime.enter(|| {
+        builder
+            .clone()
+            .with_async_socket(network.socket(server_addr))
+            .unwrap()
+    });
+    let (client_driver, client, _) = runtime.enter(|| {
+        builder
+            .with_async_socket(network.socket(client_addr))
+            .unwrap()
+    });
+    assert_eq!(client.local_addr().unwrap(), client_addr);
+    runtime.spawn(server_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(client_driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let mut stream = new_conn.connection.open_uni().await.unwrap();
+        stream.write_all(b"simulated").await.unwrap();
+        stream.finish().await.unwrap();
+    });
+    runtime.block_on(async move {
+        let mut new_conn = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let stream = new_conn.uni_streams.next().await.unwrap().unwrap();
+        assert_eq!(stream.read_to_end(1 << 16).await.unwrap(), b"simulated");
+    });
+}
+
+/// Lossless in-memory network connecting `MemorySocket`s
+#[derive(Debug, Clone, Default)]
+struct MemoryNetwork(Arc<Mutex<HashMap<SocketAddr, MemoryInbox>>>);
+
+#[derive(Debug, Default)]
+struct MemoryInbox {
+    datagrams: VecDeque<(SocketAddr, Box<[u8]>)>,
+    reader: Option<Waker>,
+}
+
+impl MemoryNetwork {
+    fn socket(&self, addr: SocketAddr) -> MemorySocket {
+        self.0.lock().unwrap().insert(addr, MemoryInbox::default());
+        MemorySocket {
+            addr,
+            network: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MemorySocket {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+}
+
+impl AsyncUdpSocket for MemorySocket {
+    fn poll_send(&self, _: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        let mut network = self.network.0.lock().unwrap();
+        for transmit in transmits {
+            // Datagrams to unknown addresses are dropped
+            if let Some(inbox) = network.get_mut(&transmit.destination) {
+                inbox
+                    .datagrams
+                    .push_back((self.addr, transmit.contents.clone()));
+                if let Some(reader) = inbox.reader.take() {
+                    reader.wake();
+                }
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut network = self.network.0.lock().unwrap();
+        let inbox = network.get_mut(&self.addr).unwrap();
+        if inbox.datagrams.is_empty() {
+            inbox.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let mut n = 0;
+        for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()) {
+            let (source, contents) = match inbox.datagrams.pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+            buf[..contents.len()].copy_from_slice(&contents);
+            // Leaves `stride` unset, as datagrams are never coalesced
+            *meta = RecvMeta {
+                addr: source,
+                len: contents.len(),
+                ..RecvMeta::default()
+            };
+            n += 1;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+#[test]
+fn custom_runtime() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let counts = Arc::new(Mutex::new((0, 0)));
+    let (driver, endpoint, mut incoming) = runtime.enter(|| {
+        let mut builder = Endpoint::builder();
+        builder.runtime(CountingRuntime(counts.clone()));
+        endpoint_with(builder)
+    });
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        new_conn.driver.unwrap_or_else(|_| ()).await;
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        new_conn.connection.close(0u32.into(), b"done");
+    });
+    let (sockets, delays) = *counts.lock().unwrap();
+    assert_eq!(sockets, 1);
+    assert!(delays > 0);
+}
+
+#[cfg(feature = "runtime-async-std")]
+#[test]
+fn async_std_runtime() {
+    let _guard = subscribe();
+    async_std::task::block_on(async {
+        let mut builder = Endpoint::builder();
+        builder.runtime(crate::AsyncStdRuntime);
+        let (driver, endpoint, mut incoming) = endpoint_with(builder);
+        async_std::task::spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+        let server = async_std::task::spawn(async move {
+            let NewConnection {
+                driver,
+                mut uni_streams,
+                ..
+            } = incoming
+                .next()
+                .await
+                .expect("endpoint")
+                .await
+                .expect("connection");
+            async_std::task::spawn(driver.unwrap_or_else(|_| ()));
+            let stream = uni_streams.next().await.expect("stream").expect("stream");
+            stream.read_to_end(usize::max_value()).await.expect("read")
+        });
+
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        async_std::task::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let mut send = new_conn.connection.open_uni().await.expect("open_uni");
+        send.write_all(b"hello").await.expect("write");
+        send.finish().await.expect("finish");
+        assert_eq!(server.await, b"hello");
+    });
+}
+
+/// Runtime counting the sockets and timers it provides, which are tokio's
+#[derive(Debug)]
+struct CountingRuntime(Arc<Mutex<(usize, usize)>>);
+
+impl crate::Runtime for CountingRuntime {
+    fn delay_until(&self, deadline: std::time::Instant) -> Pin<Box<dyn crate::AsyncTimer>> {
+        self.0.lock().unwrap().1 += 1;
+        crate::Runtime::delay_until(&TokioRuntime, deadline)
+    }
+
+    fn wrap_udp_socket(&self, socket: UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        self.0.lock().unwrap().0 += 1;
+        crate::Runtime::wrap_udp_socket(&TokioRuntime, socket)
+    }
+}
+
+#[test]
+fn stream_io_traits() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(endpoint);
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let mut new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        // Echo using tokio's traits
+        let (mut send, mut recv) = new_conn.bi_streams.next().await.unwrap().unwrap();
+        tokio::io::copy(&mut recv, &mut send).await.unwrap();
+        tokio::io::AsyncWriteExt::shutdown(&mut send).await.unwrap();
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        // Send and receive using those of futures
+        use futures::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut send, mut recv) = new_conn.connection.open_bi().await.unwrap();
+        let msg = vec![0xAB; 100_000];
+        AsyncWriteExt::write_all(&mut send, &msg).await.unwrap();
+        AsyncWriteExt::close(&mut send).await.unwrap();
+        let mut echo = Vec::new();
+        AsyncReadExt::read_to_end(&mut recv, &mut echo)
+            .await
+            .unwrap();
+        assert_eq!(echo, msg);
+    });
+}
+
+#[test]
+fn stream_chunks() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(endpoint);
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        let mut new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        // Echo without copying
+        let (mut send, mut recv) = new_conn.bi_streams.next().await.unwrap().unwrap();
+        while let Some(chunk) = recv.read_chunk(64 * 1024).await.unwrap() {
+            send.write_all_chunks(&mut [chunk]).await.unwrap();
+        }
+        send.finish().await.unwrap();
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let (mut send, recv) = new_conn.connection.open_bi().await.unwrap();
+        // Enough data to exceed the stream's flow control window
+        let mut chunks = (0..64u8)
+            .map(|i| Bytes::from(vec![i; 32 * 1024]))
+            .collect::<Vec<_>>();
+        let expected = chunks.concat();
+        send.write_all_chunks(&mut chunks).await.unwrap();
+        send.finish().await.unwrap();
+        let echo = recv.read_to_end(expected.len()).await.unwrap();
+        assert!(echo == expected);
+    });
+}
+
+#[test]
+fn unordered_reads() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, mut incoming) = runtime.enter(endpoint);
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    const LEN: usize = 256 * 1024;
+    runtime.spawn(async move {
+        let mut new_conn = incoming
+            .next()
+            .await
+            .expect("endpoint")
+            .await
+            .expect("connection");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        // Reassemble by offset, then echo
+        let (mut send, mut recv) = new_conn.bi_streams.next().await.unwrap().unwrap();
+        let mut buf = vec![0; LEN];
+        while let Some((data, offset)) = recv.read_unordered().await.unwrap() {
+            let offset = offset as usize;
+            buf[offset..offset + data.len()].copy_from_slice(&data);
+        }
+        send.write_all(&buf).await.unwrap();
+        send.finish().await.unwrap();
+    });
+    runtime.block_on(async move {
+        let new_conn = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        let (mut send, recv) = new_conn.connection.open_bi().await.unwrap();
+        let msg = (0..LEN).map(|i| i as u8).collect::<Vec<_>>();
+        send.write_all(&msg).await.unwrap();
+        send.finish().await.unwrap();
+        let echo = recv.read_to_end(LEN).await.unwrap();
+        assert!(echo == msg);
+    });
+}
+
+#[test]
+fn max_concurrent_handshakes() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let mut builder = Endpoint::builder();
+    builder.max_concurrent_handshakes(1);
+    let (driver, endpoint, mut incoming) = runtime.enter(|| endpoint_with(builder));
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        while let Some(connecting) = incoming.next().await {
+            let new_conn = connecting.await.expect("connection");
+            tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        }
+    });
+    runtime.block_on(async move {
+        let addr = endpoint.local_addr().unwrap();
+        let first = endpoint.connect(&addr, "localhost").unwrap();
+        let mut second = endpoint.connect(&addr, "localhost").unwrap();
+        assert_eq!(endpoint.handshakes_in_progress(), 1);
+
+        // The second handshake can't start while the first holds the only slot
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut second)
+                .await
+                .is_err()
+        );
+        assert_eq!(endpoint.handshakes_in_progress(), 1);
+
+        let first = first.await.expect("connect");
+        tokio::spawn(first.driver.unwrap_or_else(|_| ()));
+        let second = second.await.expect("connect");
+        tokio::spawn(second.driver.unwrap_or_else(|_| ()));
+        assert_eq!(endpoint.handshakes_in_progress(), 0);
+    });
+}
+
+#[test]
+fn server_name_verifier() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let mut server_config = ServerConfigBuilder::default();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let cert_chain = crate::CertificateChain::from_certs(vec![cert.clone()]);
+    server_config.certificate(cert_chain, key).unwrap();
+    let mut endpoint = Endpoint::builder();
+    endpoint.listen(server_config.build());
+    let (driver, endpoint, mut incoming) = runtime.enter(|| {
+        endpoint
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(async move {
+        while let Some(connecting) = incoming.next().await {
+            if let Ok(new_conn) = connecting.await {
+                tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+            }
+        }
+    });
+
+    let names = Arc::new(Mutex::new(Vec::new()));
+    let verifier = {
+        let names = names.clone();
+        move |name: &str, chain: &[rustls::Certificate]| {
+            assert_eq!(chain.len(), 1);
+            names.lock().unwrap().push(name.to_owned());
+            Ok(())
+        }
+    };
+    let mut trusting = ClientConfigBuilder::new(ClientConfig::default());
+    trusting
+        .add_certificate_authority(cert)
+        .unwrap()
+        .server_name_verifier(verifier.clone());
+    let trusting = trusting.build();
+    let mut untrusting = ClientConfigBuilder::new(ClientConfig::default());
+    untrusting.server_name_verifier(verifier);
+    let untrusting = untrusting.build();
+
+    runtime.block_on(async move {
+        let addr = endpoint.local_addr().unwrap();
+        // The certificate doesn't name the server, but the verifier accepts it anyway
+        let new_conn = endpoint
+            .connect_with(trusting, &addr, "example.com")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(new_conn.driver.unwrap_or_else(|_| ()));
+        assert_eq!(*names.lock().unwrap(), ["example.com"]);
+
+        // The verifier isn't consulted when the chain doesn't lead to a trust anchor
+        let result = endpoint
+            .connect_with(untrusting, &addr, "localhost")
+            .unwrap()
+            .await;
+        assert!(result.is_err());
+        assert_eq!(names.lock().unwrap().len(), 1);
+    });
+}
+
 /// Construct an endpoint suitable for connecting to itself
 fn endpoint() -> (EndpointDriver, Endpoint, Incoming) {
-    let mut endpoint = Endpoint::builder();
+    endpoint_with(Endpoint::builder())
+}
+
+fn endpoint_with(endpoint: EndpointBuilder) -> (EndpointDriver, Endpoint, Incoming) {
+    let (x, y, z) = configure(endpoint)
+        .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+        .unwrap();
+    (x, y, z)
+}
 
+/// Make `endpoint` serve and trust a fresh self-signed certificate
+fn configure(mut endpoint: EndpointBuilder) -> EndpointBuilder {
     let mut server_config = ServerConfigBuilder::default();
     let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
     let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
@@ -217,11 +1261,7 @@ fn endpoint() -> (EndpointDriver, Endpoint, Incoming) {
     let mut client_config = ClientConfigBuilder::default();
     client_config.add_certificate_authority(cert).unwrap();
     endpoint.default_client_config(client_config.build());
-
-    let (x, y, z) = endpoint
-        .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
-        .unwrap();
-    (x, y, z)
+    endpoint
 }
 
 #[test]
@@ -253,13 +1293,16 @@ fn zero_rtt() {
         }
     }));
     runtime.block_on(async {
+        let connecting = endpoint
+            .connect(&endpoint.local_addr().unwrap(), "localhost")
+            .unwrap();
+        assert!(!connecting.has_0rtt());
+        assert_eq!(connecting.early_data_status(), EarlyDataStatus::NoTicket);
         let NewConnection {
             driver,
             mut uni_streams,
             ..
-        } = endpoint
-            .connect(&endpoint.local_addr().unwrap(), "localhost")
-            .unwrap()
+        } = connecting
             .into_0rtt()
             .err()
             .expect("0-RTT succeeded without keys")
@@ -283,6 +1326,14 @@ fn zero_rtt() {
         driver.unwrap_or_else(|_| ()).await
     });
     info!("initial connection complete");
+    let connecting = endpoint
+        .connect(&endpoint.local_addr().unwrap(), "localhost")
+        .unwrap();
+    assert!(connecting.has_0rtt());
+    assert_eq!(
+        connecting.early_data_status(),
+        EarlyDataStatus::Attempted { accepted: None }
+    );
     let (
         NewConnection {
             connection,
@@ -291,12 +1342,7 @@ fn zero_rtt() {
             ..
         },
         zero_rtt,
-    ) = endpoint
-        .connect(&endpoint.local_addr().unwrap(), "localhost")
-        .unwrap()
-        .into_0rtt()
-        .ok()
-        .expect("missing 0-RTT keys");
+    ) = connecting.into_0rtt().ok().expect("missing 0-RTT keys");
     // Send something before the driver starts to ensure it's 0-RTT
     runtime.spawn(async move {
         let mut s = connection.open_uni().await.expect("0-RTT open uni");
@@ -324,6 +1370,433 @@ fn zero_rtt() {
     runtime.block_on(handle).unwrap();
 }
 
+#[test]
+fn zero_rtt_stream_outlives_handshake() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    let (driver, endpoint, incoming) = runtime.enter(endpoint);
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    // Echo bidirectional streams, starting in 0.5-RTT
+    runtime.spawn(incoming.for_each(|incoming| async {
+        let NewConnection {
+            driver,
+            mut bi_streams,
+            ..
+        } = incoming.into_0rtt().unwrap_or_else(|_| unreachable!()).0;
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        tokio::spawn(async move {
+            while let Some(Ok((mut send, recv))) = bi_streams.next().await {
+                let msg = recv.read_to_end(64).await.expect("read_to_end");
+                send.write_all(&msg).await.expect("write");
+                send.finish().await.expect("finish");
+            }
+        });
+    }));
+    let addr = endpoint.local_addr().unwrap();
+    runtime.block_on(async move {
+        let echo = |connection: crate::Connection, msg: &'static [u8]| async move {
+            let (mut send, recv) = connection.open_bi().await.expect("open bi");
+            send.write_all(msg).await.expect("write");
+            send.finish().await.expect("finish");
+            assert_eq!(recv.read_to_end(64).await.expect("read_to_end"), msg);
+        };
+        // Get a session ticket, which has arrived by the time the echo completes
+        let NewConnection {
+            driver, connection, ..
+        } = endpoint
+            .connect(&addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        echo(connection, b"ticket").await;
+
+        let (
+            NewConnection {
+                driver, connection, ..
+            },
+            zero_rtt,
+        ) = endpoint
+            .connect(&addr, "localhost")
+            .unwrap()
+            .into_0rtt()
+            .ok()
+            .expect("missing 0-RTT keys");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        let (mut send, recv) = connection.open_bi().await.expect("0-RTT open bi");
+        send.write_all(b"early").await.expect("0-RTT write");
+        assert!(zero_rtt.await);
+        send.write_all(b" and late").await.expect("1-RTT write");
+        send.finish().await.expect("finish");
+        let msg = recv.read_to_end(64).await.expect("read_to_end");
+        assert_eq!(msg, b"early and late");
+    });
+}
+
+#[test]
+fn session_storage_outlives_endpoint() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    const MSG: &[u8] = b"goodbye!";
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .certificate(crate::CertificateChain::from_certs(vec![cert.clone()]), key)
+        .unwrap();
+    let mut server = Endpoint::builder();
+    server.listen(server_config.build());
+    let (driver, server, incoming) = runtime.enter(|| {
+        server
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    let server_addr = server.local_addr().unwrap();
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(incoming.for_each(|incoming| async {
+        let NewConnection {
+            driver, connection, ..
+        } = incoming.await.expect("accept");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        let mut s = connection.open_uni().await.expect("open_uni");
+        s.write_all(MSG).await.expect("write");
+        s.finish().await.expect("finish");
+    }));
+
+    // Standing in for storage on disk, used by a client endpoint in each run of the process
+    let storage = Arc::new(MemorySessionStorage::new(1));
+    let make_client = |runtime: &mut Runtime| {
+        let mut client_config = ClientConfigBuilder::default();
+        client_config
+            .add_certificate_authority(cert.clone())
+            .unwrap()
+            .session_storage(storage.clone());
+        let mut client = Endpoint::builder();
+        client.default_client_config(client_config.build());
+        let (driver, client, _) = runtime.enter(|| {
+            client
+                .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+                .unwrap()
+        });
+        runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+        client
+    };
+
+    let client = make_client(&mut runtime);
+    runtime.block_on(async {
+        let connecting = client.connect(&server_addr, "localhost").unwrap();
+        assert!(!connecting.has_0rtt());
+        let NewConnection {
+            driver,
+            mut uni_streams,
+            ..
+        } = connecting.await.expect("connect");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        let stream = uni_streams.next().await.unwrap().unwrap();
+        assert_eq!(stream.read_to_end(MSG.len()).await.unwrap(), MSG);
+    });
+    assert!(storage.get("localhost").is_some());
+    drop(client);
+
+    let client = make_client(&mut runtime);
+    runtime.block_on(async {
+        let connecting = client.connect(&server_addr, "localhost").unwrap();
+        assert!(connecting.has_0rtt());
+        let (
+            NewConnection {
+                driver,
+                connection: _connection,
+                ..
+            },
+            zero_rtt,
+        ) = connecting.into_0rtt().ok().expect("missing 0-RTT keys");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        assert!(zero_rtt.await);
+    });
+}
+
+#[test]
+fn zero_rtt_shared_ticket_secret() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    const MSG: &[u8] = b"goodbye!";
+
+    // Two servers sharing a certificate and ticket secret, as if behind a load balancer
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let mut server_addrs = Vec::new();
+    for _ in 0..2 {
+        // Each server has its own TLS configuration, and hence its own session storage
+        let mut server_config = ServerConfigBuilder::default();
+        server_config
+            .certificate(
+                crate::CertificateChain::from_certs(vec![cert.clone()]),
+                key.clone(),
+            )
+            .unwrap()
+            .session_ticket_secret(b"fleet secret", Duration::from_secs(3600));
+        let mut endpoint = Endpoint::builder();
+        endpoint.listen(server_config.build());
+        let (driver, endpoint, incoming) = runtime.enter(|| {
+            endpoint
+                .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+                .unwrap()
+        });
+        server_addrs.push(endpoint.local_addr().unwrap());
+        runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+        runtime.spawn(incoming.take(1).for_each(|incoming| async {
+            let NewConnection {
+                driver,
+                mut uni_streams,
+                connection,
+                ..
+            } = incoming.into_0rtt().unwrap_or_else(|_| unreachable!()).0;
+            tokio::spawn(driver.unwrap_or_else(|_| ()));
+            tokio::spawn(async move {
+                while let Some(Ok(x)) = uni_streams.next().await {
+                    let msg = x.read_to_end(MSG.len()).await.unwrap();
+                    assert_eq!(msg, MSG);
+                }
+            });
+            let mut s = connection.open_uni().await.expect("open_uni");
+            s.write_all(MSG).await.expect("write");
+            s.finish().await.expect("finish");
+        }));
+    }
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.add_certificate_authority(cert).unwrap();
+    let mut client = Endpoint::builder();
+    client.default_client_config(client_config.build());
+    let (driver, client, _) = runtime.enter(|| {
+        client
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+
+    runtime.block_on(async {
+        let NewConnection {
+            driver,
+            mut uni_streams,
+            ..
+        } = client
+            .connect(&server_addrs[0], "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        // Buy time for the driver to process the server's NewSessionTicket
+        tokio::time::delay_until(Instant::now() + Duration::from_millis(100)).await;
+        let stream = uni_streams
+            .next()
+            .await
+            .expect("incoming streams")
+            .expect("missing stream");
+        let msg = stream.read_to_end(MSG.len()).await.expect("read_to_end");
+        assert_eq!(msg, MSG);
+    });
+    info!("initial connection complete");
+
+    // A ticket issued by the first server is accepted by the second
+    let connecting = client.connect(&server_addrs[1], "localhost").unwrap();
+    assert!(connecting.has_0rtt());
+    let (
+        NewConnection {
+            connection,
+            driver,
+            mut uni_streams,
+            ..
+        },
+        zero_rtt,
+    ) = connecting.into_0rtt().ok().expect("missing 0-RTT keys");
+    runtime.spawn(async move {
+        let mut s = connection.open_uni().await.expect("0-RTT open uni");
+        s.write_all(MSG).await.expect("0-RTT write");
+        s.finish().await.expect("0-RTT finish");
+    });
+    runtime.spawn(driver.unwrap_or_else(|_| ()));
+    runtime.block_on(async move {
+        let stream = uni_streams
+            .next()
+            .await
+            .expect("incoming streams")
+            .expect("missing stream");
+        let msg = stream.read_to_end(MSG.len()).await.expect("read_to_end");
+        assert_eq!(msg, MSG);
+        assert!(zero_rtt.await);
+    });
+}
+
+#[test]
+fn zero_rtt_refused_protocol() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    const MSG: &[u8] = b"goodbye!";
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .certificate(crate::CertificateChain::from_certs(vec![cert.clone()]), key)
+        .unwrap()
+        .protocols(&[b"get", b"post"])
+        .refuse_early_data(&[b"post"])
+        .anti_replay(Arc::new(StrikeRegister::new(Duration::from_secs(60), 16)));
+    let mut server = Endpoint::builder();
+    server.listen(server_config.build());
+    let (driver, server, incoming) = runtime.enter(|| {
+        server
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    let server_addr = server.local_addr().unwrap();
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(incoming.for_each(|incoming| async {
+        let NewConnection {
+            driver, connection, ..
+        } = incoming.into_0rtt().unwrap_or_else(|_| unreachable!()).0;
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        let mut s = connection.open_uni().await.expect("open_uni");
+        s.write_all(MSG).await.expect("write");
+        s.finish().await.expect("finish");
+    }));
+
+    // Whether a client negotiating `protocol` has its 0-RTT data accepted when resuming
+    let mut resume_0rtt = |protocol: &[u8]| {
+        let mut client_config = ClientConfigBuilder::default();
+        client_config
+            .add_certificate_authority(cert.clone())
+            .unwrap()
+            .protocols(&[protocol]);
+        let mut client = Endpoint::builder();
+        client.default_client_config(client_config.build());
+        let (driver, client, _) = runtime.enter(|| {
+            client
+                .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+                .unwrap()
+        });
+        runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+        runtime.block_on(async {
+            let NewConnection {
+                driver,
+                mut uni_streams,
+                ..
+            } = client
+                .connect(&server_addr, "localhost")
+                .unwrap()
+                .await
+                .expect("connect");
+            tokio::spawn(driver.unwrap_or_else(|_| ()));
+            // Buy time for the driver to process the server's NewSessionTicket
+            tokio::time::delay_until(Instant::now() + Duration::from_millis(100)).await;
+            let stream = uni_streams.next().await.unwrap().unwrap();
+            assert_eq!(stream.read_to_end(MSG.len()).await.unwrap(), MSG);
+
+            let connecting = client.connect(&server_addr, "localhost").unwrap();
+            assert!(connecting.has_0rtt());
+            let (
+                NewConnection {
+                    driver,
+                    connection: _connection,
+                    ..
+                },
+                zero_rtt,
+            ) = connecting.into_0rtt().ok().expect("missing 0-RTT keys");
+            tokio::spawn(driver.unwrap_or_else(|_| ()));
+            zero_rtt.await
+        })
+    };
+    assert!(resume_0rtt(b"get"));
+    assert!(!resume_0rtt(b"post"));
+}
+
+#[test]
+fn zero_rtt_rejected_streams() {
+    let _guard = subscribe();
+    let mut runtime = rt_basic();
+    const MSG: &[u8] = b"goodbye!";
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = crate::PrivateKey::from_der(&cert.serialize_private_key_der()).unwrap();
+    let cert = crate::Certificate::from_der(&cert.serialize_der().unwrap()).unwrap();
+    let mut server_config = ServerConfigBuilder::default();
+    server_config
+        .certificate(crate::CertificateChain::from_certs(vec![cert.clone()]), key)
+        .unwrap()
+        .protocols(&[b"post"])
+        .refuse_early_data(&[b"post"]);
+    let mut server = Endpoint::builder();
+    server.listen(server_config.build());
+    let (driver, server, incoming) = runtime.enter(|| {
+        server
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    let server_addr = server.local_addr().unwrap();
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.spawn(incoming.for_each(|incoming| async {
+        let NewConnection {
+            driver, connection, ..
+        } = incoming.into_0rtt().unwrap_or_else(|_| unreachable!()).0;
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        let mut s = connection.open_uni().await.expect("open_uni");
+        s.write_all(MSG).await.expect("write");
+        s.finish().await.expect("finish");
+    }));
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config
+        .add_certificate_authority(cert)
+        .unwrap()
+        .protocols(&[b"post"]);
+    let mut client = Endpoint::builder();
+    client.default_client_config(client_config.build());
+    let (driver, client, _) = runtime.enter(|| {
+        client
+            .bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap()
+    });
+    runtime.spawn(driver.unwrap_or_else(|e| panic!("{}", e)));
+    runtime.block_on(async {
+        let NewConnection {
+            driver,
+            mut uni_streams,
+            ..
+        } = client
+            .connect(&server_addr, "localhost")
+            .unwrap()
+            .await
+            .expect("connect");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        // Buy time for the driver to process the server's NewSessionTicket
+        tokio::time::delay_until(Instant::now() + Duration::from_millis(100)).await;
+        let stream = uni_streams.next().await.unwrap().unwrap();
+        assert_eq!(stream.read_to_end(MSG.len()).await.unwrap(), MSG);
+
+        let connecting = client.connect(&server_addr, "localhost").unwrap();
+        assert!(connecting.has_0rtt());
+        let (
+            NewConnection {
+                driver, connection, ..
+            },
+            zero_rtt,
+        ) = connecting.into_0rtt().ok().expect("missing 0-RTT keys");
+        let (mut send, recv) = connection.open_bi().await.expect("0-RTT open bi");
+        send.write_all(MSG).await.expect("0-RTT write");
+        tokio::spawn(driver.unwrap_or_else(|_| ()));
+        // The read is blocked until the handshake reveals the stream never reached the server
+        let (accepted, read) = future::join(zero_rtt, recv.read_to_end(MSG.len())).await;
+        assert!(!accepted);
+        assert_eq!(read, Err(ReadToEndError::Read(ReadError::ZeroRttRejected)));
+        assert_eq!(send.write_all(MSG).await, Err(WriteError::ZeroRttRejected));
+    });
+}
+
 #[test]
 fn echo_v6() {
     run_echo(
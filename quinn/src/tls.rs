@@ -1,6 +1,7 @@
-use std::fmt;
+use std::{fmt, time::SystemTime};
 
-use rustls::internal::pemfile;
+use rustls::{internal::pemfile, RootCertStore, ServerCertVerified, TLSError};
+use webpki::DNSNameRef;
 
 /// A single TLS certificate
 #[derive(Debug, Clone)]
@@ -89,9 +90,93 @@ impl PrivateKey {
     }
 }
 
+/// Decides whether a server's certificate is acceptable for the name a connection was made to
+///
+/// Installed with `ClientConfigBuilder::server_name_verifier` in place of the usual check that the
+/// name appears among the end-entity certificate's subject alternative names. The certificate
+/// chain is still validated against the client's trust anchors first.
+pub trait ServerNameVerifier: Send + Sync {
+    /// Accept or reject `chain`, presented by the server for `server_name`
+    ///
+    /// `server_name` is the name passed to `Endpoint::connect` and sent as SNI. `chain` begins with
+    /// the end-entity certificate and is known to lead to a trust anchor.
+    fn verify_server_name(
+        &self,
+        server_name: &str,
+        chain: &[rustls::Certificate],
+    ) -> Result<(), TLSError>;
+}
+
+impl<F> ServerNameVerifier for F
+where
+    F: Fn(&str, &[rustls::Certificate]) -> Result<(), TLSError> + Send + Sync,
+{
+    fn verify_server_name(
+        &self,
+        server_name: &str,
+        chain: &[rustls::Certificate],
+    ) -> Result<(), TLSError> {
+        self(server_name, chain)
+    }
+}
+
+/// Validates certificate chains like rustls's `WebPKIVerifier`, deferring the name check
+pub(crate) struct ChainVerifier<V>(pub(crate) V);
+
+impl<V: ServerNameVerifier> rustls::ServerCertVerifier for ChainVerifier<V> {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let end_entity = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let cert = webpki::EndEntityCert::from(&end_entity.0).map_err(TLSError::WebPKIError)?;
+        let chain = presented_certs[1..]
+            .iter()
+            .map(|x| x.0.as_ref())
+            .collect::<Vec<_>>();
+        let anchors = roots
+            .roots
+            .iter()
+            .map(|x| x.to_trust_anchor())
+            .collect::<Vec<_>>();
+        let now = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+        cert.verify_is_valid_tls_server_cert(
+            SUPPORTED_SIG_ALGS,
+            &webpki::TLSServerTrustAnchors(&anchors),
+            &chain,
+            now,
+        )
+        .map_err(TLSError::WebPKIError)?;
+        let server_name: &str = dns_name.into();
+        self.0.verify_server_name(server_name, presented_certs)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The signature algorithms rustls accepts in certificates
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
 /// Errors encountered while parsing a TLS certificate or private key
 #[derive(Debug, Clone)]
-pub struct ParseError(&'static str);
+pub struct ParseError(pub(crate) &'static str);
 
 impl std::error::Error for ParseError {}
 
@@ -1,42 +1,78 @@
 use std::{
-    io,
-    net::SocketAddr,
+    fmt,
+    io::{self, IoSliceMut},
+    net::{Ipv6Addr, SocketAddr},
     task::{Context, Poll},
 };
 
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
 use futures::ready;
-use mio;
-
+#[cfg(feature = "runtime-tokio")]
 use tokio::io::PollEvented;
 
 use proto::{EcnCodepoint, Transmit};
 
-use crate::platform::UdpExt;
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+use crate::platform::{UdpExt, UdpState};
+
+/// A datagram transport for an endpoint
+///
+/// Endpoints built by `EndpointBuilder::bind` or `with_socket` use a UDP socket registered with
+/// their `Runtime`. Other transports, such as a userspace network stack, a tunnel or a simulated
+/// network, can be supplied to `EndpointBuilder::with_async_socket` instead.
+pub trait AsyncUdpSocket: fmt::Debug + Send + 'static {
+    /// Send a prefix of `transmits`, returning how many were sent
+    ///
+    /// If none can be sent yet, returns `Poll::Pending` and arranges for the task to be woken once
+    /// that changes.
+    fn poll_send(&self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>>;
+
+    /// Receive up to one datagram per buffer in `bufs`, describing each in `meta`, returning how
+    /// many were received
+    ///
+    /// Buffers are large enough for any UDP datagram. If none is available, returns
+    /// `Poll::Pending` and arranges for the task to be woken once that changes.
+    ///
+    /// Transports that coalesce several datagrams from the same source into one buffer set
+    /// `RecvMeta::stride` to the size of each; others can leave it at 0, its default, which means
+    /// the buffer holds a single datagram of `RecvMeta::len` bytes.
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>>;
+
+    /// The local address datagrams are sent from and received on
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
 
 /// Tokio-compatible UDP socket with some useful specializations.
 ///
 /// Unlike a standard tokio UDP socket, this allows ECN bits to be read and written on some
 /// platforms.
+#[cfg(feature = "runtime-tokio")]
 #[derive(Debug)]
 pub struct UdpSocket {
     io: PollEvented<mio::net::UdpSocket>,
+    state: UdpState,
 }
 
+#[cfg(feature = "runtime-tokio")]
 impl UdpSocket {
     pub fn from_std(socket: std::net::UdpSocket) -> io::Result<UdpSocket> {
         let io = mio::net::UdpSocket::from_socket(socket)?;
-        io.init_ext()?;
+        let state = io.init_ext()?;
         let io = PollEvented::new(io)?;
-        Ok(UdpSocket { io })
+        Ok(UdpSocket { io, state })
     }
+}
 
-    pub fn poll_send(
-        &self,
-        cx: &mut Context,
-        transmits: &[Transmit],
-    ) -> Poll<Result<usize, io::Error>> {
+#[cfg(feature = "runtime-tokio")]
+impl AsyncUdpSocket for UdpSocket {
+    fn poll_send(&self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
         ready!(self.io.poll_write_ready(cx))?;
-        match self.io.get_ref().send_ext(transmits) {
+        match self.io.get_ref().send_ext(&self.state, transmits) {
             Ok(n) => Poll::Ready(Ok(n)),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 self.io.clear_write_ready(cx)?;
@@ -46,13 +82,16 @@ impl UdpSocket {
         }
     }
 
-    pub fn poll_recv(
+    /// Truncated datagrams are not reported
+    fn poll_recv(
         &self,
         cx: &mut Context,
-        buf: &mut [u8],
-    ) -> Poll<Result<(usize, SocketAddr, Option<EcnCodepoint>), io::Error>> {
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        debug_assert!(!bufs.is_empty());
         ready!(self.io.poll_read_ready(cx, mio::Ready::readable()))?;
-        match self.io.get_ref().recv_ext(buf) {
+        match self.io.get_ref().recv_ext(bufs, meta) {
             Ok(n) => Poll::Ready(Ok(n)),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 self.io.clear_read_ready(cx, mio::Ready::readable())?;
@@ -62,12 +101,95 @@ impl UdpSocket {
         }
     }
 
-    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
         self.io.get_ref().local_addr()
     }
 }
 
-/// Number of UDP packets to send at a time
+/// UDP socket driven by async-std's reactor, with the same specializations as `UdpSocket`
+#[cfg(feature = "runtime-async-std")]
+#[derive(Debug)]
+pub struct AsyncStdUdpSocket {
+    io: async_io::Async<std::net::UdpSocket>,
+    state: UdpState,
+}
+
+#[cfg(feature = "runtime-async-std")]
+impl AsyncStdUdpSocket {
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<AsyncStdUdpSocket> {
+        let state = socket.init_ext()?;
+        let io = async_io::Async::new(socket)?;
+        Ok(AsyncStdUdpSocket { io, state })
+    }
+}
+
+#[cfg(feature = "runtime-async-std")]
+impl AsyncUdpSocket for AsyncStdUdpSocket {
+    fn poll_send(&self, cx: &mut Context, transmits: &[Transmit]) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.io.poll_writable(cx))?;
+            match self.io.get_ref().send_ext(&self.state, transmits) {
+                // Polling again waits for the socket to become writable anew
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    /// Truncated datagrams are not reported
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        debug_assert!(!bufs.is_empty());
+        loop {
+            ready!(self.io.poll_readable(cx))?;
+            match self.io.get_ref().recv_ext(bufs, meta) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+}
+
+/// Description of a datagram received by `AsyncUdpSocket::poll_recv`
+#[derive(Debug, Copy, Clone)]
+pub struct RecvMeta {
+    /// The source address of the datagram
+    pub addr: SocketAddr,
+    /// Number of bytes written to the buffer
+    pub len: usize,
+    /// Size of each of the datagrams coalesced into the buffer, the last of which may be shorter
+    ///
+    /// Equal to `len` unless the platform coalesced datagrams from the same source. 0 is treated
+    /// as `len`.
+    pub stride: usize,
+    /// The ECN codepoint the datagram was marked with, if the transport reports it
+    pub ecn: Option<EcnCodepoint>,
+    /// The differentiated services code point the datagram was marked with, or 0 if the transport
+    /// doesn't report it
+    pub dscp: u8,
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+            len: 0,
+            stride: 0,
+            ecn: None,
+            dscp: 0,
+        }
+    }
+}
+
+/// Number of UDP packets to send or receive at a time
 ///
 /// Chosen somewhat arbitrarily; might benefit from additional tuning.
 pub const BATCH_SIZE: usize = 32;